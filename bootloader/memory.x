@@ -0,0 +1,7 @@
+MEMORY
+{
+  /* The application follows, at 0x08003000, see `amodem_boot::layout` */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 12K
+  /* The last 8 bytes hold the boot request word */
+  RAM : ORIGIN = 0x20000000, LENGTH = 8K - 8
+}
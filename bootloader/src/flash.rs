@@ -0,0 +1,97 @@
+//! Flash regions, through the flash controller
+//!
+//! The same as the application's config flash, but for any run of pages. The
+//! bootloader only ever reads the config pages.
+
+use amodem_boot::layout::{
+    APP_FIRST_PAGE, APP_PAGES, CONFIG_FIRST_PAGE, FLASH_BASE, PAGE_SIZE,
+};
+use amodem_store::flash::{Flash, FlashError};
+use stm32g0xx_hal::pac::{FLASH, flash::RegisterBlock as FlashRb};
+
+/// The error flags in `FLASH.SR`, each cleared by writing a one
+const SR_ERRORS: u32 = 0x0000_C3FA;
+
+/// `PAGES` pages of flash, from page `FIRST_PAGE`
+pub struct PageFlash<const FIRST_PAGE: usize, const PAGES: usize>;
+
+/// The application region, the header and image
+pub type AppFlash = PageFlash<APP_FIRST_PAGE, APP_PAGES>;
+
+/// The saved config pages
+pub type ConfigFlash = PageFlash<CONFIG_FIRST_PAGE, 2>;
+
+impl<const FIRST_PAGE: usize, const PAGES: usize> PageFlash<FIRST_PAGE, PAGES> {
+    const ADDR: usize = FLASH_BASE + (FIRST_PAGE * PAGE_SIZE);
+}
+
+impl<const FIRST_PAGE: usize, const PAGES: usize> Flash for PageFlash<FIRST_PAGE, PAGES> {
+    const PAGE_SIZE: usize = PAGE_SIZE;
+    const PAGES: usize = PAGES;
+    const WRITE_SIZE: usize = 8;
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        let src = (Self::ADDR + offset) as *const u8;
+        buf.iter_mut().enumerate().for_each(|(i, b)| {
+            *b = unsafe { src.add(i).read_volatile() };
+        });
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), FlashError> {
+        if page >= PAGES {
+            return Err(FlashError);
+        }
+        unlocked(|flash| {
+            flash.cr.modify(|_r, w| {
+                unsafe { w.pnb().bits((FIRST_PAGE + page) as u8) };
+                w.per().set_bit();
+                w
+            });
+            flash.cr.modify(|_r, w| w.strt().set_bit());
+            while flash.sr.read().bsy().bit_is_set() { }
+            flash.cr.modify(|_r, w| w.per().clear_bit());
+        })
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        if offset + data.len() > PAGES * PAGE_SIZE {
+            return Err(FlashError);
+        }
+        unlocked(|flash| {
+            // One double word at a time
+            flash.cr.modify(|_r, w| w.pg().set_bit());
+            data.chunks_exact(8).enumerate().for_each(|(i, dw)| {
+                let words = (Self::ADDR + offset + (i * 8)) as *mut u32;
+                unsafe {
+                    words.write_volatile(u32::from_le_bytes([dw[0], dw[1], dw[2], dw[3]]));
+                    words.add(1).write_volatile(u32::from_le_bytes([dw[4], dw[5], dw[6], dw[7]]));
+                }
+                while flash.sr.read().bsy().bit_is_set() { }
+            });
+            flash.cr.modify(|_r, w| w.pg().clear_bit());
+        })
+    }
+}
+
+/// Run `op` with the flash controller unlocked. Fails if `op` raised any
+/// errors.
+fn unlocked<F: FnOnce(&FlashRb)>(op: F) -> Result<(), FlashError> {
+    let flash = unsafe { &*FLASH::PTR };
+    if flash.cr.read().lock().bit_is_set() {
+        flash.keyr.write(|w| unsafe { w.keyr().bits(0x4567_0123) });
+        flash.keyr.write(|w| unsafe { w.keyr().bits(0xCDEF_89AB) });
+    }
+
+    // Clear any stale error flags
+    while flash.sr.read().bsy().bit_is_set() { }
+    flash.sr.write(|w| unsafe { w.bits(SR_ERRORS) });
+
+    op(flash);
+    let errors = flash.sr.read().bits() & SR_ERRORS;
+
+    flash.cr.modify(|_r, w| w.lock().set_bit());
+    match errors {
+        0 => Ok(()),
+        _ => Err(FlashError),
+    }
+}
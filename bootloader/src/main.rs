@@ -0,0 +1,151 @@
+//! amodem bootloader
+//!
+//! Sits in the first 12K of flash, ahead of the application. At reset, it
+//! starts the application straight away, before touching the clocks or any
+//! peripheral, unless the application left a boot request (see
+//! `amodem_boot::layout`), or the image in the application region does not
+//! verify.
+//!
+//! Otherwise, it stays, and takes the commands in `amodem_hostif::boot` from
+//! the host over SPI, using the saved SPI config. `BOOT_RUN` resets the modem
+//! once the image verifies, so the application always starts from a reset.
+//!
+//! A node with a saved address also takes updates from the router over RS-485
+//! at the same time, see `amodem_boot::update`. The application comes here as
+//! soon as it hears one start, and once the update is committed, the node
+//! acknowledges the commit, then resets into the new image.
+//!
+//! Flash it once with a probe (`cargo run --release`). An application flashed
+//! by a probe has no image header, so will not be started: install images
+//! through the bootloader instead.
+
+#![no_main]
+#![no_std]
+
+mod flash;
+mod rs485;
+mod spi;
+
+use core::panic::PanicInfo;
+
+use amodem_boot::{
+    image,
+    layout::{APP_VECTORS, BOOT_REQUEST_ADDR, BOOT_REQUEST_MAGIC},
+    loader::{Loader, Next},
+    update::{Action, Updater},
+};
+use amodem_bus::{node::Node, update::UPDATE_ADDR};
+use amodem_hostif::{boot::BOOT_WRITE_MAX, regmap::SPI_CFG_MASK};
+use amodem_store::{config::SavedConfig, store::Store};
+use cortex_m::peripheral::SCB;
+use stm32g0xx_hal::{
+    pac,
+    rcc::{Config, PllConfig, Prescaler, RccExt},
+};
+
+use flash::{AppFlash, ConfigFlash};
+use rs485::{BusLink, Mailbox};
+use spi::HostLink;
+
+/// The system clock, as set up in [main]
+const SYSCLK_HZ: u32 = 64_000_000;
+
+/// How long a node waits to acknowledge a commit, before it starts the new
+/// image anyway
+const COMMIT_ACK_MS: u32 = 100;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    let requested = take_boot_request();
+    if !requested && image::verify(&AppFlash).is_ok() {
+        unsafe { start_app() }
+    }
+
+    let board = pac::Peripherals::take().unwrap();
+    let config = Config::pll()
+        .pll_cfg(PllConfig::with_hsi(1, 8, 2))
+        .ahb_psc(Prescaler::NotDivided)
+        .apb_psc(Prescaler::NotDivided);
+    let mut rcc = board.RCC.freeze(config);
+
+    let saved = SavedConfig::load(&Store::new(ConfigFlash));
+    let spi_cfg = saved.map_or(0, |c| c.spi_cfg & SPI_CFG_MASK);
+    let mut link = HostLink::new(&mut rcc, &board.GPIOA, &board.GPIOB, board.EXTI, board.SPI1, spi_cfg);
+    let mut loader = Loader::new(AppFlash);
+
+    let mut bus = saved.and_then(|c| {
+        BusLink::new(&mut rcc, &board.GPIOA, &board.GPIOB, board.USART1, board.TIM2, &c)
+    });
+    let mut node = Node::new(bus.as_ref().map_or(0, |(_, addr)| *addr));
+    node.set_group(Some(UPDATE_ADDR));
+    let mut mailbox = Mailbox::new();
+    let mut updater = Updater::new(AppFlash, node.addr());
+    // Once committed, the polls answered so far, and when to stop waiting for
+    // the next, which acknowledges the commit
+    let mut starting: Option<(u32, u32)> = None;
+
+    // The offset, the most data, and one more byte, to catch longer writes
+    let mut buf = [0u8; 4 + BOOT_WRITE_MAX + 1];
+    link.ready();
+    loop {
+        if let Some((cmd, len)) = link.transaction(loader.status(), &mut buf) {
+            let len = len.min(buf.len());
+            if loader.command(cmd, &buf[..len]) == Next::Start {
+                SCB::sys_reset();
+            }
+            link.ready();
+        }
+
+        let Some((bus, _)) = bus.as_mut() else {
+            continue;
+        };
+        bus.poll(&mut node, &mut mailbox);
+
+        // Flash writes stall the CPU, so frames are only handled between polls
+        if !node.is_muted() {
+            continue;
+        }
+        if let Some((polls, until)) = starting {
+            let overdue = (bus.now().wrapping_sub(until) as i32) >= 0;
+            if (node.stats().polls != polls) || overdue {
+                bus.flush();
+                SCB::sys_reset();
+            }
+        }
+        let Some(frame) = mailbox.take() else {
+            continue;
+        };
+        match updater.handle(frame) {
+            Action::None => {},
+            Action::Report => mailbox.send(|buf| updater.report(buf)),
+            Action::Start => {
+                let until = bus.now().wrapping_add(bus.ms_to_bits(COMMIT_ACK_MS));
+                starting = Some((node.stats().polls, until));
+            },
+        }
+    }
+}
+
+/// Was the bootloader asked for? Clears the request, so that the next reset
+/// starts the application again.
+fn take_boot_request() -> bool {
+    let word = BOOT_REQUEST_ADDR as *mut u32;
+    unsafe {
+        let requested = word.read_volatile() == BOOT_REQUEST_MAGIC;
+        word.write_volatile(0);
+        requested
+    }
+}
+
+/// Hand over to the application, as if it had been reset into
+unsafe fn start_app() -> ! {
+    let scb = &*SCB::PTR;
+    scb.vtor.write(APP_VECTORS as u32);
+    cortex_m::asm::bootload(APP_VECTORS as *const u32)
+}
+
+/// Reset, rather than hang, so the modem at least comes back
+#[panic_handler]
+fn panic(_info: &PanicInfo) -> ! {
+    SCB::sys_reset()
+}
@@ -0,0 +1,265 @@
+//! Polled RS-485 node
+//!
+//! Set up as the application does, from the saved RS-485 config, on the same
+//! pins, but with no interrupts or DMA, and without mute mode: every word is
+//! handed to the [Node] state machine, which picks out its own polls. TIM2
+//! counts bit times, as the node's clock.
+//!
+//! Only a node with an address takes part in updates, as it is polled by
+//! address to report. A router, or a node with no saved config or address,
+//! gets no bus link. Nor does a line with `RS485_CFG_PARITY`, as the [Node]
+//! tells address words apart by their mark, which parity takes the place of.
+//!
+//! | Port  | Pin  | Role      | Mode |
+//! | :--   | :--  | :--       | :--  |
+//! | GPIOA | PA12 | RS485 DE  | AF1  |
+//! | GPIOB | PB06 | RS485 TXD | AF0  |
+//! | GPIOB | PB07 | RS485 RXD | AF0  |
+
+use amodem_bus::{
+    discovery::is_assignable,
+    node::Node,
+    update::{REPORT_MAX, UPDATE_MSG_MAX},
+    Queues,
+};
+use amodem_hostif::regmap::{
+    RS485_CFG_ADDR_MASK, RS485_CFG_ADDR_SHIFT, RS485_CFG_OVER8, RS485_CFG_PARITY,
+    RS485_CFG_ROUTER,
+};
+use amodem_store::config::SavedConfig;
+use stm32g0xx_hal::{
+    pac::{GPIOA, GPIOB, RCC, TIM2, USART1},
+    rcc::{Enable, Rcc, Reset},
+};
+
+/// Maximum value of the DEAT and DEDT fields
+const MAX_DE_TIME: u8 = 0b1_1111;
+
+/// The saved line settings, checked as the application checks them
+struct Line {
+    usartdiv: u32,
+    over8: bool,
+    deat: u8,
+    dedt: u8,
+    addr: u8,
+}
+
+impl Line {
+    fn from_saved(saved: &SavedConfig) -> Option<Self> {
+        let cfg = saved.rs485_cfg;
+        let addr = ((cfg & RS485_CFG_ADDR_MASK) >> RS485_CFG_ADDR_SHIFT) as u8;
+        if ((cfg & (RS485_CFG_ROUTER | RS485_CFG_PARITY)) != 0) || !is_assignable(addr) {
+            return None;
+        }
+
+        let over8 = (cfg & RS485_CFG_OVER8) != 0;
+        let brr = saved.rs485_brr as u32;
+        let usartdiv = match over8 {
+            // BRR[3] must be kept cleared, BRR[2:0] holds USARTDIV[3:0] >> 1.
+            true if (brr & 0b1000) != 0 => return None,
+            true => (brr & !0b1111) | ((brr & 0b0111) << 1),
+            false => brr,
+        };
+        let deat = (saved.rs485_de & 0xFF) as u8;
+        let dedt = (saved.rs485_de >> 8) as u8;
+        if (usartdiv < 16) || (deat > MAX_DE_TIME) || (dedt > MAX_DE_TIME) {
+            return None;
+        }
+        Some(Self { usartdiv, over8, deat, dedt, addr })
+    }
+
+    /// Clock cycles in each bit time
+    fn bit_cycles(&self) -> u32 {
+        match self.over8 {
+            true => self.usartdiv / 2,
+            false => self.usartdiv,
+        }
+    }
+}
+
+/// The bus link, once set up
+pub struct BusLink {
+    bit_cycles: u32,
+}
+
+impl BusLink {
+    /// Set up from `saved`, returning the link and our address, or `None` if
+    /// this modem does not take part in updates
+    pub fn new(
+        rcc: &mut Rcc,
+        gpioa: &GPIOA,
+        gpiob: &GPIOB,
+        usart1: USART1,
+        tim2: TIM2,
+        saved: &SavedConfig,
+    ) -> Option<(Self, u8)> {
+        let line = Line::from_saved(saved)?;
+
+        GPIOA::enable(rcc);
+        GPIOB::enable(rcc);
+        gpioa.afrh.modify(|_r, w| w.afsel12().af1()); // DE
+        gpiob.afrl.modify(|_r, w| {
+            w.afsel6().af0(); // TXD
+            w.afsel7().af0(); // RXD
+            w
+        });
+        gpioa.moder.modify(|_r, w| w.moder12().alternate()); // DE
+        gpiob.moder.modify(|_r, w| {
+            w.moder6().alternate(); // TXD
+            w.moder7().alternate(); // RXD
+            w
+        });
+
+        // A free running count of bit times
+        let rcc_regs = unsafe { &*RCC::PTR };
+        rcc_regs.apbenr1.modify(|_r, w| w.tim2en().set_bit());
+        rcc_regs.apbrstr1.modify(|_r, w| w.tim2rst().set_bit());
+        rcc_regs.apbrstr1.modify(|_r, w| w.tim2rst().clear_bit());
+        tim2.psc.write(|w| w.psc().bits((line.bit_cycles() - 1) as u16));
+        tim2.arr.write(|w| unsafe { w.bits(0xFFFF_FFFF) });
+        tim2.egr.write(|w| w.ug().set_bit());
+        tim2.cr1.modify(|_r, w| w.cen().set_bit().urs().set_bit());
+
+        USART1::enable(rcc);
+        USART1::reset(rcc);
+
+        usart1.cr1.modify(|_r, w| {
+            w.fifoen().enabled();
+            w.m1().m0();
+            w.m0().bit9();
+            w.mme().disabled();
+            // No parity, as in the application
+            w.pce().disabled();
+            w.deat().variant(line.deat);
+            w.dedt().variant(line.dedt);
+            if line.over8 {
+                w.over8().oversampling8();
+            } else {
+                w.over8().oversampling16();
+            }
+            w.ue().disabled();
+            w
+        });
+        usart1.cr2.modify(|_r, w| {
+            w.msbfirst().lsb();
+            w.stop().stop1();
+            w
+        });
+        usart1.cr3.modify(|_r, w| {
+            w.dep().high();
+            w.dem().enabled();
+            // Overruns are cleared in [BusLink::poll], the node times out
+            w.ovrdis().disabled();
+            w.onebit().sample3();
+            w
+        });
+        usart1.brr.modify(|_r, w| w.brr().variant(saved.rs485_brr));
+        usart1.cr1.modify(|_r, w| w.ue().enabled());
+        usart1.cr1.modify(|_r, w| {
+            w.te().enabled();
+            w.re().enabled();
+            w
+        });
+
+        Some((Self { bit_cycles: line.bit_cycles() }, line.addr))
+    }
+
+    /// The node's time, in bit times
+    pub fn now(&self) -> u32 {
+        let tim2 = unsafe { &*TIM2::PTR };
+        tim2.cnt.read().bits()
+    }
+
+    /// Bit times in `ms` milliseconds
+    pub fn ms_to_bits(&self, ms: u32) -> u32 {
+        (crate::SYSCLK_HZ / 1_000 / self.bit_cycles) * ms
+    }
+
+    /// Wait for the words sent to leave the USART
+    pub fn flush(&self) {
+        let usart1 = unsafe { &*USART1::PTR };
+        while usart1.isr.read().tc().bit_is_clear() { }
+    }
+
+    /// Hand `node` the words heard, check its timeouts, and send its next
+    /// word. Call this often: the receive FIFO only holds eight words.
+    pub fn poll<Q: Queues>(&mut self, node: &mut Node, queues: &mut Q) {
+        let usart1 = unsafe { &*USART1::PTR };
+        let now = self.now();
+
+        let isr = usart1.isr.read();
+        if isr.fe().bit_is_set() || isr.ne().bit_is_set() || isr.ore().bit_is_set() {
+            usart1.icr.write(|w| {
+                w.fecf().set_bit();
+                w.necf().set_bit();
+                w.orecf().set_bit();
+                w
+            });
+        }
+        while usart1.isr.read().rxne().bit_is_set() {
+            let word = usart1.rdr.read().rdr().bits();
+            node.receive(now, word, queues);
+        }
+
+        node.poll(now);
+        if usart1.isr.read().txe().bit_is_set() {
+            if let Some(word) = node.transmit(now) {
+                usart1.tdr.write(|w| w.tdr().bits(word));
+            }
+        }
+    }
+}
+
+/// Frame queues for the node: the last frame received, and our report
+pub struct Mailbox {
+    // Starts with the address the frame was sent to
+    rx: [u8; 1 + UPDATE_MSG_MAX],
+    rx_len: Option<usize>,
+    tx: [u8; REPORT_MAX],
+    tx_len: Option<usize>,
+}
+
+impl Mailbox {
+    pub const fn new() -> Self {
+        Self { rx: [0; 1 + UPDATE_MSG_MAX], rx_len: None, tx: [0; REPORT_MAX], tx_len: None }
+    }
+
+    /// Take the frame received, if any, starting with the address it was sent
+    /// to
+    pub fn take(&mut self) -> Option<&[u8]> {
+        let len = self.rx_len.take()?;
+        Some(&self.rx[..len])
+    }
+
+    /// Send a frame, written by `write`, replacing any not sent yet
+    pub fn send<F: FnOnce(&mut [u8]) -> usize>(&mut self, write: F) {
+        self.tx_len = Some(write(&mut self.tx));
+    }
+}
+
+impl Queues for Mailbox {
+    fn tx_head(&mut self) -> Option<&[u8]> {
+        Some(&self.tx[..self.tx_len?])
+    }
+
+    fn tx_pop(&mut self) {
+        self.tx_len = None;
+    }
+
+    fn rx_capacity(&mut self) -> usize {
+        match self.rx_len {
+            // Nothing more until the last frame is handled
+            Some(_) => 0,
+            None => self.rx.len(),
+        }
+    }
+
+    fn rx_push(&mut self, frame: &[u8]) -> bool {
+        let Some(buf) = self.rx.get_mut(..frame.len()) else {
+            return false;
+        };
+        buf.copy_from_slice(frame);
+        self.rx_len = Some(frame.len());
+        true
+    }
+}
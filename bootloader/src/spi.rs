@@ -0,0 +1,203 @@
+//! Polled SPI host link
+//!
+//! Set up as the application does, from the saved `SPI_CFG`, on the same pins,
+//! but with no interrupts or DMA. The bootloader does one thing at a time, so
+//! RXrdy (IO2) goes low as soon as a command byte arrives, and only goes high
+//! again once the command is carried out and the FIFOs are flushed.
+//!
+//! | Port  | Pin  | Role      | Mode |
+//! | :--   | :--  | :--       | :--  |
+//! | GPIOA | PA01 | SPI SCLK  | AF0  |
+//! | GPIOA | PA02 | SPI MOSI  | AF0  |
+//! | GPIOA | PA06 | SPI MISO  | AF0  |
+//! | GPIOA | PA07 | SPI RXrdy | Out  |
+//! | GPIOB | PB00 | SPI CSn   | AF0  |
+
+use amodem_hostif::{
+    boot::{BootCommand, BootStatus},
+    regmap::{SPI_CFG_3WIRE, SPI_CFG_CPHA, SPI_CFG_CPOL, SPI_CFG_LSBFIRST},
+};
+use stm32g0xx_hal::{
+    exti::{Event, ExtiExt},
+    gpio::SignalEdge,
+    pac::{EXTI, GPIOA, GPIOB, RCC, SPI1},
+    rcc::{Enable, Rcc, Reset},
+};
+
+/// The host link, once set up
+pub struct HostLink {
+    three_wire: bool,
+}
+
+impl HostLink {
+    pub fn new(rcc: &mut Rcc, gpioa: &GPIOA, gpiob: &GPIOB, exti: EXTI, spi1: SPI1, spi_cfg: u16) -> Self {
+        GPIOA::enable(rcc);
+        GPIOB::enable(rcc);
+
+        gpioa.afrl.modify(|_r, w| {
+            w.afsel1().af0(); // SCLK
+            w.afsel2().af0(); // MOSI
+            w.afsel6().af0(); // MISO
+            w
+        });
+        gpiob.afrl.modify(|_r, w| w.afsel0().af0()); // CSn
+        gpioa.otyper.modify(|_r, w| w.ot7().push_pull());
+        gpioa.odr.modify(|_r, w| w.odr7().low());
+        gpioa.moder.modify(|_r, w| {
+            w.moder1().alternate(); // SCLK
+            w.moder2().alternate(); // MOSI
+            w.moder6().alternate(); // MISO
+            w.moder7().output();    // IO2
+            w
+        });
+        gpiob.moder.modify(|_r, w| w.moder0().alternate()); // CSn
+
+        // CSn rising edges are polled for, the interrupt is never unmasked
+        exti.exticr1.modify(|_r, w| w.exti0_7().pb());
+        exti.listen(Event::GPIO0, SignalEdge::Rising);
+
+        SPI1::enable(rcc);
+        SPI1::reset(rcc);
+
+        let three_wire = (spi_cfg & SPI_CFG_3WIRE) != 0;
+        spi1.cr1.modify(|_r, w| {
+            if three_wire {
+                w.bidimode().bidirectional();
+                w.bidioe().output_disabled();
+            } else {
+                w.bidimode().unidirectional();
+            }
+            w.crcen().disabled();
+            w.rxonly().full_duplex();
+            w.ssm().disabled();
+            w.br().div2();
+            w.mstr().slave();
+            if (spi_cfg & SPI_CFG_LSBFIRST) != 0 {
+                w.lsbfirst().lsbfirst();
+            } else {
+                w.lsbfirst().msbfirst();
+            }
+            if (spi_cfg & SPI_CFG_CPOL) != 0 {
+                w.cpol().idle_high();
+            } else {
+                w.cpol().idle_low();
+            }
+            if (spi_cfg & SPI_CFG_CPHA) != 0 {
+                w.cpha().second_edge();
+            } else {
+                w.cpha().first_edge();
+            }
+            w
+        });
+        spi1.cr2.modify(|_r, w| {
+            w.frxth().quarter(); // 8-bit
+            w.ds().eight_bit();
+            w.txeie().masked();
+            w.rxneie().masked();
+            w.errie().masked();
+            w.frf().motorola();
+            w.nssp().no_pulse();
+            w.ssoe().disabled();
+            w.txdmaen().disabled();
+            w.rxdmaen().disabled();
+            w
+        });
+        spi1.cr1.modify(|_r, w| w.spe().enabled());
+
+        Self { three_wire }
+    }
+
+    /// Take the next command, if one has started, sending `status` if it is a
+    /// status read. Once CSn rises, returns the command byte, and how much the
+    /// host sent after it, which is stored in `buf` (as much of it as fits).
+    /// Returns `None` straight away if no command byte has arrived.
+    ///
+    /// RXrdy is left low, call [HostLink::ready] once the command is done.
+    pub fn transaction(&mut self, status: BootStatus, buf: &mut [u8]) -> Option<(u8, usize)> {
+        let spi1 = unsafe { &*SPI1::PTR };
+        let gpioa = unsafe { &*GPIOA::PTR };
+        let dr8b: *mut u8 = spi1.dr.as_ptr().cast();
+
+        // Check before the command byte, so a short transaction that already
+        // ended is not waited out, and an empty one is dropped
+        let mut done = csn_rose();
+        if spi1.sr.read().rxne().is_empty() {
+            return None;
+        }
+        let cmd = unsafe { dr8b.read_volatile() };
+        gpioa.odr.modify(|_r, w| w.odr7().low());
+
+        let status = status.to_bytes();
+        let mut to_send = match BootCommand::parse(cmd) {
+            Some(BootCommand::Status) => &status[..],
+            _ => &[],
+        };
+        if self.three_wire && !to_send.is_empty() {
+            spi1.cr1.modify(|_r, w| w.bidioe().output_enabled());
+        }
+
+        let mut rcvd = 0;
+        loop {
+            // Check before draining, so nothing sent before CSn rose is missed
+            done |= csn_rose();
+            while !spi1.sr.read().rxne().is_empty() {
+                let byte = unsafe { dr8b.read_volatile() };
+                if let Some(b) = buf.get_mut(rcvd) {
+                    *b = byte;
+                }
+                rcvd += 1;
+            }
+            if done {
+                break;
+            }
+            if let Some((byte, rest)) = to_send.split_first() {
+                if spi1.sr.read().txe().is_empty() {
+                    unsafe { dr8b.write_volatile(*byte) };
+                    to_send = rest;
+                }
+            }
+        }
+
+        if self.three_wire {
+            spi1.cr1.modify(|_r, w| w.bidioe().output_disabled());
+        }
+        Some((cmd, rcvd))
+    }
+
+    /// Flush the FIFOs, and tell the host we are ready for another command
+    pub fn ready(&mut self) {
+        let spi1 = unsafe { &*SPI1::PTR };
+        let rcc = unsafe { &*RCC::PTR };
+        let gpioa = unsafe { &*GPIOA::PTR };
+
+        // Wait out any transaction the host started while we were busy
+        while !csn_high() { }
+        let _ = csn_rose();
+
+        // The TX FIFO can only be flushed by resetting the peripheral
+        let cr1 = spi1.cr1.read().bits();
+        let cr2 = spi1.cr2.read().bits();
+        spi1.cr1.modify(|_r, w| w.spe().disabled());
+        rcc.apbrstr2.modify(|_r, w| w.spi1rst().set_bit());
+        rcc.apbrstr2.modify(|_r, w| w.spi1rst().clear_bit());
+        spi1.cr2.write(|w| unsafe { w.bits(cr2) });
+        spi1.cr1.write(|w| unsafe { w.bits(cr1) });
+
+        gpioa.odr.modify(|_r, w| w.odr7().high());
+    }
+}
+
+/// Has CSn risen since last asked?
+fn csn_rose() -> bool {
+    let exti = unsafe { &*EXTI::PTR };
+    let rose = exti.rpr1.read().rpif0().bit_is_set();
+    if rose {
+        exti.rpr1.write(|w| w.rpif0().set_bit());
+    }
+    rose
+}
+
+fn csn_high() -> bool {
+    let gpiob = unsafe { &*GPIOB::PTR };
+    gpiob.idr.read().idr0().bit_is_set()
+}
@@ -0,0 +1,286 @@
+//! Firmware images
+//!
+//! An image is the application as linked, from its vector table on, as a flat
+//! binary (`objcopy -O binary`). The application region holds its header in
+//! the first [HEADER_REGION] bytes, then the image:
+//!
+//! | Bytes     | Contents                      |
+//! | :--       | :--                           |
+//! | 0..4      | [IMAGE_MAGIC], LE             |
+//! | 4..8      | Length of the image, LE       |
+//! | 8..12     | CRC-32 of the image, LE       |
+//! | 12..16    | Version, LE                   |
+//! | 16..0x100 | Unused, left erased           |
+//!
+//! An image is only started if it [verify]s: its header is there, the image
+//! fits the region, its CRC matches, and its vector table points into RAM and
+//! into the image.
+
+use amodem_bus::update::Campaign;
+use amodem_store::flash::Flash;
+
+use crate::layout::{APP_VECTORS, BOOT_REQUEST_ADDR, HEADER_REGION, RAM_BASE};
+
+/// "AMI1"
+pub const IMAGE_MAGIC: u32 = 0x414D_4931;
+
+/// Bytes of the header in use
+pub const HEADER_LEN: usize = 16;
+
+/// The initial stack pointer and the reset vector
+const VECTORS_LEN: usize = 8;
+
+const CRC32_TABLE: [u32; 256] = make_table();
+
+const fn make_table() -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if (crc & 1) != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continue a CRC-32 (as used by zlib) of earlier data with more data. The
+/// CRC of no data is zero.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    !data.iter().fold(!crc, |crc, b| {
+        (crc >> 8) ^ CRC32_TABLE[((crc as u8) ^ *b) as usize]
+    })
+}
+
+/// CRC-32 of `data`
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Why an image was not started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageError {
+    /// There is no header, or the region is erased
+    NoHeader,
+    /// The image is too short to have a vector table, or does not fit
+    BadLength,
+    /// The image does not match its CRC
+    BadCrc,
+    /// The vector table does not point into RAM and the image
+    BadVectors,
+}
+
+/// The header in front of an image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub len: u32,
+    pub crc: u32,
+    pub version: u32,
+}
+
+impl ImageHeader {
+    /// The header for `image`
+    pub fn for_image(image: &[u8], version: u32) -> Self {
+        Self { len: image.len() as u32, crc: crc32(image), version }
+    }
+
+    pub fn to_bytes(&self) -> [u8; HEADER_LEN] {
+        let mut bytes = [0; HEADER_LEN];
+        [IMAGE_MAGIC, self.len, self.crc, self.version]
+            .iter()
+            .zip(bytes.chunks_exact_mut(4))
+            .for_each(|(word, out)| out.copy_from_slice(&word.to_le_bytes()));
+        bytes
+    }
+
+    /// Decode a header, returning `None` without [IMAGE_MAGIC]
+    pub fn from_bytes(bytes: &[u8; HEADER_LEN]) -> Option<Self> {
+        let mut words = bytes.chunks_exact(4).map(|w| u32::from_le_bytes([w[0], w[1], w[2], w[3]]));
+        let mut next = || words.next().unwrap_or_default();
+        if next() != IMAGE_MAGIC {
+            return None;
+        }
+        Some(Self { len: next(), crc: next(), version: next() })
+    }
+}
+
+/// The update campaign that sends the image behind `header` over the bus
+impl From<&ImageHeader> for Campaign {
+    fn from(header: &ImageHeader) -> Self {
+        Self { len: header.len, crc: header.crc, version: header.version }
+    }
+}
+
+/// Check that an image of `len` bytes with these vectors could run: the stack
+/// starts in RAM, and the reset vector is Thumb code in the image
+pub fn check_vectors(initial_sp: u32, reset: u32, len: usize) -> Result<(), ImageError> {
+    let sp = initial_sp as usize;
+    let entry = (reset & !1) as usize;
+    let sp_ok = (RAM_BASE < sp) && (sp <= BOOT_REQUEST_ADDR) && sp.is_multiple_of(4);
+    let entry_ok = ((reset & 1) != 0) && (APP_VECTORS + VECTORS_LEN <= entry) && (entry < APP_VECTORS + len);
+    match sp_ok && entry_ok {
+        true => Ok(()),
+        false => Err(ImageError::BadVectors),
+    }
+}
+
+/// Check the image in an application region, returning its header if it can
+/// be started
+pub fn verify<F: Flash>(flash: &F) -> Result<ImageHeader, ImageError> {
+    let mut buf = [0; HEADER_LEN];
+    flash.read(0, &mut buf);
+    let header = ImageHeader::from_bytes(&buf).ok_or(ImageError::NoHeader)?;
+    check_image(flash, &header)?;
+    Ok(header)
+}
+
+/// Check the image in an application region against `header`, whether or not
+/// the header is written yet
+pub fn check_image<F: Flash>(flash: &F, header: &ImageHeader) -> Result<(), ImageError> {
+    let len = header.len as usize;
+    if (len < VECTORS_LEN) || (len > (F::PAGE_SIZE * F::PAGES) - HEADER_REGION) {
+        return Err(ImageError::BadLength);
+    }
+
+    let mut crc = 0;
+    let mut chunk = [0; 64];
+    (0..len).step_by(chunk.len()).for_each(|at| {
+        let part = &mut chunk[..(len - at).min(64)];
+        flash.read(HEADER_REGION + at, part);
+        crc = crc32_update(crc, part);
+    });
+    if crc != header.crc {
+        return Err(ImageError::BadCrc);
+    }
+
+    let mut vectors = [0; VECTORS_LEN];
+    flash.read(HEADER_REGION, &mut vectors);
+    let [s0, s1, s2, s3, r0, r1, r2, r3] = vectors;
+    check_vectors(u32::from_le_bytes([s0, s1, s2, s3]), u32::from_le_bytes([r0, r1, r2, r3]), len)
+}
+
+/// The contents of an application region for `image`: the header region,
+/// then the image, padded with erased bytes to whole writes
+#[cfg(feature = "std")]
+pub fn package(image: &[u8], version: u32) -> Vec<u8> {
+    use amodem_hostif::boot::BOOT_WRITE_ALIGN;
+    use amodem_store::flash::ERASED;
+
+    let mut out = vec![ERASED; HEADER_REGION];
+    out[..HEADER_LEN].copy_from_slice(&ImageHeader::for_image(image, version).to_bytes());
+    out.extend_from_slice(image);
+    out.resize(out.len().next_multiple_of(BOOT_WRITE_ALIGN), ERASED);
+    out
+}
+
+/// The header and image in a package made by [package], if the image matches
+/// its header
+#[cfg(feature = "std")]
+pub fn unpackage(package: &[u8]) -> Result<(ImageHeader, &[u8]), ImageError> {
+    let header = package
+        .first_chunk::<HEADER_LEN>()
+        .and_then(ImageHeader::from_bytes)
+        .ok_or(ImageError::NoHeader)?;
+    let image = package
+        .get(HEADER_REGION..)
+        .and_then(|rest| rest.get(..header.len as usize))
+        .filter(|image| image.len() >= VECTORS_LEN)
+        .ok_or(ImageError::BadLength)?;
+    if crc32(image) != header.crc {
+        return Err(ImageError::BadCrc);
+    }
+    let word = |at: usize| u32::from_le_bytes([image[at], image[at + 1], image[at + 2], image[at + 3]]);
+    check_vectors(word(0), word(4), image.len())?;
+    Ok((header, image))
+}
+
+/// An image of `len` bytes that verifies, for tests
+#[cfg(test)]
+pub(crate) fn test_image(len: usize) -> Vec<u8> {
+    let mut image: Vec<u8> = (0..len).map(|i| (i * 7) as u8).collect();
+    image[..4].copy_from_slice(&(BOOT_REQUEST_ADDR as u32).to_le_bytes());
+    image[4..8].copy_from_slice(&((APP_VECTORS + 0xC0) as u32 | 1).to_le_bytes());
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amodem_store::flash::RamFlash;
+    use crate::layout::{APP_PAGES, PAGE_SIZE};
+
+    type AppFlash = RamFlash<PAGE_SIZE, APP_PAGES>;
+
+    fn flashed(package: &[u8]) -> AppFlash {
+        let mut flash = AppFlash::new();
+        flash.write(0, package).unwrap();
+        flash
+    }
+
+    #[test]
+    fn crc_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32_update(crc32(b"1234"), b"56789"), crc32(b"123456789"));
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn header() {
+        let header = ImageHeader { len: 0x1234, crc: 0xDEAD_BEEF, version: 7 };
+        let bytes = header.to_bytes();
+        assert_eq!(bytes[..4], IMAGE_MAGIC.to_le_bytes());
+        assert_eq!(ImageHeader::from_bytes(&bytes), Some(header));
+        assert_eq!(ImageHeader::from_bytes(&[0xFF; HEADER_LEN]), None);
+    }
+
+    #[test]
+    fn verified() {
+        let image = test_image(1001);
+        let package = package(&image, 3);
+        assert_eq!(package.len(), HEADER_REGION + 1008);
+        assert_eq!(verify(&flashed(&package)), Ok(ImageHeader::for_image(&image, 3)));
+    }
+
+    #[test]
+    fn unpackaged() {
+        let image = test_image(1001);
+        let mut package = package(&image, 3);
+        assert_eq!(unpackage(&package), Ok((ImageHeader::for_image(&image, 3), image.as_slice())));
+
+        assert_eq!(unpackage(&package[..HEADER_REGION + 1000]), Err(ImageError::BadLength));
+        package[HEADER_REGION + 500] ^= 0x01;
+        assert_eq!(unpackage(&package), Err(ImageError::BadCrc));
+        assert_eq!(unpackage(&image), Err(ImageError::NoHeader));
+    }
+
+    #[test]
+    fn rejected() {
+        assert_eq!(verify(&AppFlash::new()), Err(ImageError::NoHeader));
+
+        let mut package = package(&test_image(1001), 3);
+        package[HEADER_REGION + 500] ^= 0x01;
+        assert_eq!(verify(&flashed(&package)), Err(ImageError::BadCrc));
+
+        let too_long = ImageHeader { len: (APP_PAGES * PAGE_SIZE) as u32, crc: 0, version: 0 };
+        assert_eq!(verify(&flashed(&too_long.to_bytes())), Err(ImageError::BadLength));
+
+        // Starts past the end of the image
+        assert_eq!(verify(&flashed(&super::package(&test_image(0x80), 3))), Err(ImageError::BadVectors));
+    }
+
+    #[test]
+    fn vectors() {
+        let sp = BOOT_REQUEST_ADDR as u32;
+        let entry = (APP_VECTORS + 0x100) as u32;
+        assert_eq!(check_vectors(sp, entry | 1, 0x200), Ok(()));
+        // ARM state, outside the image, and a stack outside RAM
+        assert_eq!(check_vectors(sp, entry, 0x200), Err(ImageError::BadVectors));
+        assert_eq!(check_vectors(sp, entry | 1, 0x100), Err(ImageError::BadVectors));
+        assert_eq!(check_vectors(sp + 8, entry | 1, 0x200), Err(ImageError::BadVectors));
+        assert_eq!(check_vectors(0xFFFF_FFFF, 0xFFFF_FFFF, 0x200), Err(ImageError::BadVectors));
+    }
+}
@@ -0,0 +1,59 @@
+//! Flash and RAM layout
+//!
+//! | Pages  | Address      | Contents                                     |
+//! | :--    | :--          | :--                                          |
+//! | 0..6   | `0x08000000` | Bootloader                                   |
+//! | 6..30  | `0x08003000` | Application region: image header, then image |
+//! | 30..32 | `0x0800F000` | Saved config, see `amodem_store`             |
+//!
+//! The image is linked to run from [APP_VECTORS], just after the header, and
+//! its vector table is where the bootloader starts it from. Both `memory.x`
+//! files must agree with this.
+//!
+//! The last 8 bytes of RAM are left out of both programs' `RAM` regions, so
+//! the application can leave [BOOT_REQUEST_MAGIC] at [BOOT_REQUEST_ADDR] for
+//! the bootloader to find after a reset.
+
+/// Start of flash
+pub const FLASH_BASE: usize = 0x0800_0000;
+
+/// Bytes in a flash page, the unit of erase
+pub const PAGE_SIZE: usize = 2048;
+
+/// Pages taken by the bootloader, with its bus node for updates
+pub const BOOT_PAGES: usize = 6;
+
+/// The first page of the application region
+pub const APP_FIRST_PAGE: usize = BOOT_PAGES;
+
+/// Pages in the application region
+pub const APP_PAGES: usize = 24;
+
+/// Start of the application region, and its image header
+pub const APP_ADDR: usize = FLASH_BASE + (APP_FIRST_PAGE * PAGE_SIZE);
+
+/// Bytes in the application region
+pub const APP_SIZE: usize = APP_PAGES * PAGE_SIZE;
+
+/// Bytes set aside for the image header, at the start of the region. The
+/// vector table that follows must be aligned to this, for `VTOR`.
+pub const HEADER_REGION: usize = 0x100;
+
+/// Where the image, starting with its vector table, is linked to run
+pub const APP_VECTORS: usize = APP_ADDR + HEADER_REGION;
+
+/// The first of the saved config pages, just after the application region
+pub const CONFIG_FIRST_PAGE: usize = APP_FIRST_PAGE + APP_PAGES;
+
+/// Start of RAM
+pub const RAM_BASE: usize = 0x2000_0000;
+
+/// Bytes of RAM, including the boot request word
+pub const RAM_SIZE: usize = 8 * 1024;
+
+/// The boot request word, past the end of both programs' `RAM` regions
+pub const BOOT_REQUEST_ADDR: usize = RAM_BASE + RAM_SIZE - 8;
+
+/// "BOOT", left at [BOOT_REQUEST_ADDR] by the application to stay in the
+/// bootloader after the next reset. The bootloader clears it on every reset.
+pub const BOOT_REQUEST_MAGIC: u32 = 0x424F_4F54;
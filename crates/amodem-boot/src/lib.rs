@@ -0,0 +1,22 @@
+//! # amodem bootloader
+//!
+//! The bootloader sits at the start of the modem's flash, and takes firmware
+//! images from the host over SPI, or from the router over RS-485, so the modem
+//! can be updated without a debug probe:
+//!
+//! * [layout] has where the bootloader, the application, and the saved config
+//!   live, and how the application asks for the bootloader.
+//! * [image] has the image header, and checks an image before it is started.
+//! * [loader] carries out the bootloader's commands, see
+//!   `amodem_hostif::boot`.
+//! * [update] takes an image sent over the bus, see `amodem_bus::update`.
+//!
+//! This crate is `no_std`, and has no hardware dependencies, so the logic can
+//! be unit tested on the host with `cargo test`.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod layout;
+pub mod image;
+pub mod loader;
+pub mod update;
@@ -0,0 +1,218 @@
+//! Carrying out bootloader commands
+//!
+//! The bootloader's SPI handling collects each command byte, with whatever the
+//! host sent after it, and hands them to a [Loader] once CSn rises. The
+//! [Loader] keeps the status the host reads back, see `amodem_hostif::boot`.
+
+use amodem_hostif::boot::{
+    BootCommand, BootStatus,
+    BOOT_RESULT_BAD_COMMAND, BOOT_RESULT_BAD_IMAGE, BOOT_RESULT_BAD_RANGE,
+    BOOT_RESULT_FLASH, BOOT_RESULT_OK,
+    BOOT_STATUS_IMAGE_OK, BOOT_STATUS_VALID,
+    BOOT_WRITE_ALIGN, BOOT_WRITE_MAX,
+};
+use amodem_store::flash::Flash;
+
+use crate::image::{self, ImageHeader};
+
+/// What the bootloader does once a command is carried out
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    /// Wait for the next command
+    Wait,
+    /// Start the image, which was just verified
+    Start,
+}
+
+/// The application region, and the status of the commands on it
+pub struct Loader<F> {
+    flash: F,
+    status: BootStatus,
+}
+
+impl<F: Flash> Loader<F> {
+    /// Take over the application region, checking the image already there
+    pub fn new(flash: F) -> Self {
+        let mut loader = Self {
+            flash,
+            status: BootStatus { flags: BOOT_STATUS_VALID, result: BOOT_RESULT_OK, version: 0 },
+        };
+        let _ = loader.check();
+        loader.status.result = BOOT_RESULT_OK;
+        loader
+    }
+
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    /// The status to send for `BOOT_STATUS`
+    pub fn status(&self) -> BootStatus {
+        self.status
+    }
+
+    /// Carry out command byte `cmd`, with `data` sent after it. Status reads
+    /// leave the status as it was.
+    pub fn command(&mut self, cmd: u8, data: &[u8]) -> Next {
+        let result = match (BootCommand::parse(cmd), data.is_empty()) {
+            (Some(BootCommand::Status), _) => return Next::Wait,
+            (Some(BootCommand::Erase), true) => self.erase(),
+            (Some(BootCommand::Write), false) => self.write(data),
+            (Some(BootCommand::Verify), true) => self.check().map(|_| ()),
+            (Some(BootCommand::Run), true) => match self.check() {
+                Ok(_) => {
+                    self.status.result = BOOT_RESULT_OK;
+                    return Next::Start;
+                },
+                Err(e) => Err(e),
+            },
+            _ => Err(BOOT_RESULT_BAD_COMMAND),
+        };
+        self.status.result = result.err().unwrap_or(BOOT_RESULT_OK);
+        Next::Wait
+    }
+
+    /// Forget the checked image, before changing the region
+    fn invalidate(&mut self) {
+        self.status.flags &= !BOOT_STATUS_IMAGE_OK;
+        self.status.version = 0;
+    }
+
+    fn erase(&mut self) -> Result<(), u8> {
+        self.invalidate();
+        (0..F::PAGES).try_for_each(|page| self.flash.erase(page)).map_err(|_| BOOT_RESULT_FLASH)
+    }
+
+    /// Write the data after the offset at the start of `data`
+    fn write(&mut self, data: &[u8]) -> Result<(), u8> {
+        let Some((&[o0, o1, o2, o3], payload)) = data.split_first_chunk::<4>() else {
+            return Err(BOOT_RESULT_BAD_COMMAND);
+        };
+        if payload.is_empty() || (payload.len() > BOOT_WRITE_MAX) {
+            return Err(BOOT_RESULT_BAD_COMMAND);
+        }
+        let offset = u32::from_le_bytes([o0, o1, o2, o3]) as usize;
+        let aligned = offset.is_multiple_of(BOOT_WRITE_ALIGN) && payload.len().is_multiple_of(BOOT_WRITE_ALIGN);
+        let fits = offset.checked_add(payload.len()).is_some_and(|end| end <= F::PAGE_SIZE * F::PAGES);
+        if !aligned || !fits {
+            return Err(BOOT_RESULT_BAD_RANGE);
+        }
+        self.invalidate();
+        self.flash.write(offset, payload).map_err(|_| BOOT_RESULT_FLASH)
+    }
+
+    /// Verify the image, noting the outcome in the status flags
+    fn check(&mut self) -> Result<ImageHeader, u8> {
+        self.invalidate();
+        let header = image::verify(&self.flash).map_err(|_| BOOT_RESULT_BAD_IMAGE)?;
+        self.status.flags |= BOOT_STATUS_IMAGE_OK;
+        self.status.version = header.version;
+        Ok(header)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amodem_hostif::boot::{BOOT_ERASE, BOOT_RUN, BOOT_STATUS, BOOT_VERIFY, BOOT_WRITE};
+    use amodem_hostif::cmd::MODE_BOOT;
+    use amodem_store::flash::RamFlash;
+    use crate::{
+        image::{package, test_image},
+        layout::{APP_PAGES, PAGE_SIZE},
+    };
+
+    type AppFlash = RamFlash<PAGE_SIZE, APP_PAGES>;
+
+    /// A `BOOT_WRITE` of `data` at `offset`
+    fn write_cmd(offset: u32, data: &[u8]) -> Vec<u8> {
+        let mut out = offset.to_le_bytes().to_vec();
+        out.extend_from_slice(data);
+        out
+    }
+
+    /// Send `package` the way the host does
+    fn load(loader: &mut Loader<AppFlash>, package: &[u8]) {
+        assert_eq!(loader.command(MODE_BOOT | BOOT_ERASE, &[]), Next::Wait);
+        assert_eq!(loader.status().result, BOOT_RESULT_OK);
+        package.chunks(BOOT_WRITE_MAX).enumerate().for_each(|(i, chunk)| {
+            loader.command(MODE_BOOT | BOOT_WRITE, &write_cmd((i * BOOT_WRITE_MAX) as u32, chunk));
+            assert_eq!(loader.status().result, BOOT_RESULT_OK);
+        });
+    }
+
+    #[test]
+    fn loaded_and_started() {
+        let mut loader = Loader::new(AppFlash::new());
+        assert_eq!(loader.status().flags, BOOT_STATUS_VALID);
+        assert_eq!(loader.command(MODE_BOOT | BOOT_RUN, &[]), Next::Wait);
+        assert_eq!(loader.status().result, BOOT_RESULT_BAD_IMAGE);
+
+        load(&mut loader, &package(&test_image(5000), 42));
+        assert_eq!(loader.status().flags & BOOT_STATUS_IMAGE_OK, 0);
+        loader.command(MODE_BOOT | BOOT_VERIFY, &[]);
+        let status = loader.status();
+        assert_eq!(status, BootStatus { flags: BOOT_STATUS_VALID | BOOT_STATUS_IMAGE_OK, result: BOOT_RESULT_OK, version: 42 });
+
+        // Status reads don't change the status
+        assert_eq!(loader.command(MODE_BOOT | BOOT_STATUS, &[0; 6]), Next::Wait);
+        assert_eq!(loader.status(), status);
+        assert_eq!(loader.command(MODE_BOOT | BOOT_RUN, &[]), Next::Start);
+
+        // And the image is found again after a reset
+        let loader = Loader::new(loader.flash().clone());
+        assert_eq!(loader.status(), status);
+    }
+
+    #[test]
+    fn damaged_images_are_not_started() {
+        let mut loader = Loader::new(AppFlash::new());
+        let mut package = package(&test_image(5000), 42);
+        package[3000] ^= 0x80;
+        load(&mut loader, &package);
+        assert_eq!(loader.command(MODE_BOOT | BOOT_RUN, &[]), Next::Wait);
+        assert_eq!(loader.status().result, BOOT_RESULT_BAD_IMAGE);
+        assert_eq!(loader.status().flags, BOOT_STATUS_VALID);
+    }
+
+    #[test]
+    fn changes_invalidate() {
+        let mut loader = Loader::new(AppFlash::new());
+        load(&mut loader, &package(&test_image(5000), 42));
+        loader.command(MODE_BOOT | BOOT_VERIFY, &[]);
+        assert_ne!(loader.status().flags & BOOT_STATUS_IMAGE_OK, 0);
+
+        loader.command(MODE_BOOT | BOOT_WRITE, &write_cmd(0x8000, &[0; 8]));
+        assert_eq!(loader.status(), BootStatus { flags: BOOT_STATUS_VALID, result: BOOT_RESULT_OK, version: 0 });
+        loader.command(MODE_BOOT | BOOT_VERIFY, &[]);
+        assert_ne!(loader.status().flags & BOOT_STATUS_IMAGE_OK, 0);
+
+        loader.command(MODE_BOOT | BOOT_ERASE, &[]);
+        assert_eq!(loader.status().flags, BOOT_STATUS_VALID);
+        assert_eq!(loader.command(MODE_BOOT | BOOT_RUN, &[]), Next::Wait);
+    }
+
+    #[test]
+    fn bad_commands() {
+        let mut loader = Loader::new(AppFlash::new());
+        let size = (PAGE_SIZE * APP_PAGES) as u32;
+        let cases: &[(u8, Vec<u8>, u8)] = &[
+            (MODE_BOOT | 0x1F, vec![], BOOT_RESULT_BAD_COMMAND),
+            (MODE_BOOT | BOOT_ERASE, vec![0], BOOT_RESULT_BAD_COMMAND),
+            (MODE_BOOT | BOOT_WRITE, vec![0; 3], BOOT_RESULT_BAD_COMMAND),
+            (MODE_BOOT | BOOT_WRITE, write_cmd(0, &[]), BOOT_RESULT_BAD_COMMAND),
+            (MODE_BOOT | BOOT_WRITE, write_cmd(0, &[0; BOOT_WRITE_MAX + 8]), BOOT_RESULT_BAD_COMMAND),
+            (MODE_BOOT | BOOT_WRITE, write_cmd(4, &[0; 8]), BOOT_RESULT_BAD_RANGE),
+            (MODE_BOOT | BOOT_WRITE, write_cmd(0, &[0; 12]), BOOT_RESULT_BAD_RANGE),
+            (MODE_BOOT | BOOT_WRITE, write_cmd(size - 8, &[0; 16]), BOOT_RESULT_BAD_RANGE),
+            (MODE_BOOT | BOOT_WRITE, write_cmd(u32::MAX - 7, &[0; 16]), BOOT_RESULT_BAD_RANGE),
+            (MODE_BOOT | BOOT_WRITE, write_cmd(size - 8, &[0; 8]), BOOT_RESULT_OK),
+            // Written since the last erase
+            (MODE_BOOT | BOOT_WRITE, write_cmd(size - 8, &[0; 8]), BOOT_RESULT_FLASH),
+        ];
+        cases.iter().for_each(|(cmd, data, result)| {
+            assert_eq!(loader.command(*cmd, data), Next::Wait);
+            assert_eq!(loader.status().result, *result, "{cmd:#x} {data:?}");
+        });
+    }
+}
@@ -0,0 +1,346 @@
+//! Taking images over the bus
+//!
+//! The bootloader runs a bus node on the saved RS-485 config, listening on
+//! `UPDATE_ADDR`, and hands each frame it receives to an [Updater], see
+//! `amodem_bus::update` for the messages.
+//!
+//! A start for an image that is not installed erases the whole application
+//! region. Chunks are written as they arrive, each in its place after the
+//! header region, and once the last is in, the image is checked against the
+//! start's length and CRC. The header is only written when the update is
+//! committed, so an image cut short by a reset or a power cut is never started:
+//! the bootloader stays, and the next start erases it.
+//!
+//! Each write stalls the CPU, so the bootloader only hands frames over between
+//! polls, and the router's host leaves time for them.
+
+use amodem_bus::update::{
+    Campaign, ChunkMap, Report, UpdateMsg, UpdateState, CHUNK_LEN, UPDATE_ADDR,
+};
+use amodem_store::flash::{Flash, ERASED};
+
+use crate::{
+    image::{self, ImageHeader},
+    layout::HEADER_REGION,
+};
+
+/// What the bootloader does once a frame is handled
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Nothing more
+    None,
+    /// Send a report, see [Updater::report]
+    Report,
+    /// Start the image, which was just installed
+    Start,
+}
+
+/// The application region, and the update being taken into it
+pub struct Updater<F> {
+    flash: F,
+    addr: u8,
+    campaign: Option<Campaign>,
+    state: UpdateState,
+    missing: ChunkMap,
+    /// The image that verifies in the region, if any
+    installed: Option<ImageHeader>,
+}
+
+impl<F: Flash> Updater<F> {
+    /// Take over the application region, for the node at `addr`
+    pub fn new(flash: F, addr: u8) -> Self {
+        let installed = image::verify(&flash).ok();
+        Self { flash, addr, campaign: None, state: UpdateState::Idle, missing: ChunkMap::new(), installed }
+    }
+
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    pub fn state(&self) -> UpdateState {
+        self.state
+    }
+
+    /// Handle a frame received by the node, starting with the address it was
+    /// sent to
+    pub fn handle(&mut self, frame: &[u8]) -> Action {
+        let Some((&to, msg)) = frame.split_first() else {
+            return Action::None;
+        };
+        if (to != self.addr) && (to != UPDATE_ADDR) {
+            return Action::None;
+        }
+        match UpdateMsg::parse(msg) {
+            Some(UpdateMsg::Start(campaign)) => self.start(campaign),
+            Some(UpdateMsg::Chunk { crc, index, data }) => self.chunk(crc, index as usize, data),
+            Some(UpdateMsg::Query { addr }) if addr == self.addr => return Action::Report,
+            Some(UpdateMsg::Commit { crc }) => return self.commit(crc),
+            _ => {},
+        }
+        Action::None
+    }
+
+    /// Write our report to `buf`, returning its length
+    pub fn report(&self, buf: &mut [u8]) -> usize {
+        let missing = match self.state {
+            UpdateState::Receiving => self.missing.as_bytes(),
+            _ => &[],
+        };
+        Report {
+            state: self.state,
+            crc: self.campaign.map_or(0, |c| c.crc),
+            version: self.installed.map_or(0, |h| h.version),
+            missing,
+        }
+        .write(buf)
+    }
+
+    fn start(&mut self, campaign: Campaign) {
+        if (self.campaign == Some(campaign)) && (self.state != UpdateState::Failed) {
+            return;
+        }
+        self.campaign = Some(campaign);
+
+        let fits = (campaign.len as usize) <= ((F::PAGE_SIZE * F::PAGES) - HEADER_REGION);
+        if !campaign.is_valid() || !fits {
+            self.state = UpdateState::Failed;
+            return;
+        }
+        if self.installed == Some(header(&campaign)) {
+            self.state = UpdateState::Installed;
+            return;
+        }
+
+        self.installed = None;
+        self.state = match (0..F::PAGES).try_for_each(|page| self.flash.erase(page)) {
+            Ok(()) => UpdateState::Receiving,
+            Err(_) => UpdateState::Failed,
+        };
+        self.missing = ChunkMap::all_missing(campaign.chunks()).unwrap_or_default();
+    }
+
+    fn chunk(&mut self, crc: u32, index: usize, data: &[u8]) {
+        let Some(campaign) = self.campaign.filter(|c| c.crc == crc) else {
+            return;
+        };
+        let wanted = (self.state == UpdateState::Receiving) && self.missing.is_missing(index);
+        if !wanted || (campaign.chunk_len(index) != Some(data.len())) {
+            return;
+        }
+
+        // The last chunk is padded out to whole writes
+        let mut buf = [ERASED; CHUNK_LEN];
+        buf[..data.len()].copy_from_slice(data);
+        let len = data.len().next_multiple_of(F::WRITE_SIZE);
+        if self.flash.write(HEADER_REGION + (index * CHUNK_LEN), &buf[..len]).is_err() {
+            self.state = UpdateState::Failed;
+            return;
+        }
+
+        self.missing.received(index);
+        if self.missing.missing() == 0 {
+            self.state = match image::check_image(&self.flash, &header(&campaign)) {
+                Ok(()) => UpdateState::Ready,
+                Err(_) => UpdateState::Failed,
+            };
+        }
+    }
+
+    fn commit(&mut self, crc: u32) -> Action {
+        let campaign = self.campaign.filter(|c| c.crc == crc);
+        if let (Some(campaign), UpdateState::Ready) = (campaign, self.state) {
+            let header = header(&campaign);
+            let written = self.flash.write(0, &header.to_bytes()).is_ok();
+            self.installed = image::verify(&self.flash).ok().filter(|_| written);
+            self.state = match self.installed {
+                Some(_) => UpdateState::Installed,
+                None => UpdateState::Failed,
+            };
+        }
+        match self.installed {
+            Some(h) if h.crc == crc => Action::Start,
+            _ => Action::None,
+        }
+    }
+}
+
+/// The header an update's image is installed with
+fn header(campaign: &Campaign) -> ImageHeader {
+    ImageHeader { len: campaign.len, crc: campaign.crc, version: campaign.version }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amodem_bus::update::{REPORT_MAX, UPDATE_MSG_MAX};
+    use amodem_store::flash::RamFlash;
+    use crate::{
+        image::{package, test_image},
+        layout::{APP_PAGES, PAGE_SIZE},
+    };
+
+    type AppFlash = RamFlash<PAGE_SIZE, APP_PAGES>;
+
+    const ADDR: u8 = 0x12;
+
+    /// `msg`, as received by the node at `to`
+    fn frame(to: u8, msg: UpdateMsg) -> Vec<u8> {
+        let mut buf = [0; 1 + UPDATE_MSG_MAX];
+        buf[0] = to;
+        let len = msg.write(&mut buf[1..]);
+        buf[..1 + len].to_vec()
+    }
+
+    fn campaign(image: &[u8], version: u32) -> Campaign {
+        Campaign::from(&ImageHeader::for_image(image, version))
+    }
+
+    /// Send every chunk of `image`, but those in `skip`
+    fn send_chunks(updater: &mut Updater<AppFlash>, image: &[u8], skip: &[usize]) {
+        let crc = campaign(image, 0).crc;
+        image.chunks(CHUNK_LEN).enumerate().filter(|(i, _)| !skip.contains(i)).for_each(|(i, data)| {
+            let msg = UpdateMsg::Chunk { crc, index: i as u16, data };
+            assert_eq!(updater.handle(&frame(UPDATE_ADDR, msg)), Action::None);
+        });
+    }
+
+    fn report(updater: &mut Updater<AppFlash>) -> (UpdateState, u32, u32, Vec<usize>) {
+        assert_eq!(updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Query { addr: ADDR })), Action::Report);
+        let mut buf = [0; REPORT_MAX];
+        let len = updater.report(&mut buf);
+        let report = Report::parse(&buf[..len]).unwrap();
+        let chunks = updater.campaign.map_or(0, |c| c.chunks());
+        let missing = match report.missing {
+            [] => vec![],
+            bytes => ChunkMap::from_bytes(chunks, bytes).unwrap().iter_missing().collect(),
+        };
+        (report.state, report.crc, report.version, missing)
+    }
+
+    #[test]
+    fn updated() {
+        let image = test_image(1000);
+        let update = campaign(&image, 5);
+        let mut updater = Updater::new(AppFlash::new(), ADDR);
+        assert_eq!(report(&mut updater), (UpdateState::Idle, 0, 0, vec![]));
+
+        updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Start(update)));
+        assert_eq!(report(&mut updater), (UpdateState::Receiving, update.crc, 0, (0..8).collect()));
+        send_chunks(&mut updater, &image, &[2, 7]);
+        assert_eq!(report(&mut updater).3, vec![2, 7]);
+
+        // Starting again, or sending a chunk again, changes nothing
+        updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Start(update)));
+        send_chunks(&mut updater, &image, &[2, 7]);
+        assert_eq!(report(&mut updater).3, vec![2, 7]);
+        // Nor does committing too early
+        assert_eq!(updater.handle(&frame(ADDR, UpdateMsg::Commit { crc: update.crc })), Action::None);
+
+        send_chunks(&mut updater, &image, &[]);
+        assert_eq!(report(&mut updater), (UpdateState::Ready, update.crc, 0, vec![]));
+        assert_eq!(image::verify(updater.flash()), Err(image::ImageError::NoHeader));
+
+        // A commit for another image is ignored
+        assert_eq!(updater.handle(&frame(ADDR, UpdateMsg::Commit { crc: !update.crc })), Action::None);
+        assert_eq!(updater.handle(&frame(ADDR, UpdateMsg::Commit { crc: update.crc })), Action::Start);
+        assert_eq!(report(&mut updater), (UpdateState::Installed, update.crc, 5, vec![]));
+        let mut region = vec![0; HEADER_REGION + 1000];
+        updater.flash().read(0, &mut region);
+        assert_eq!(region, package(&image, 5)[..HEADER_REGION + 1000]);
+    }
+
+    #[test]
+    fn installed_image_kept() {
+        let image = test_image(1000);
+        let update = campaign(&image, 5);
+        let mut flash = AppFlash::new();
+        flash.write(0, &package(&image, 5)).unwrap();
+        let mut updater = Updater::new(flash, ADDR);
+        assert_eq!(report(&mut updater), (UpdateState::Idle, 0, 5, vec![]));
+
+        // Nothing is erased, and it can be started straight away
+        updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Start(update)));
+        assert_eq!(report(&mut updater), (UpdateState::Installed, update.crc, 5, vec![]));
+        assert_eq!(updater.flash().erases(), &[0; APP_PAGES]);
+        assert_eq!(updater.handle(&frame(ADDR, UpdateMsg::Commit { crc: update.crc })), Action::Start);
+
+        // Another version replaces it
+        updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Start(campaign(&image, 6))));
+        assert_eq!(report(&mut updater).0, UpdateState::Receiving);
+        assert_eq!(image::verify(updater.flash()), Err(image::ImageError::NoHeader));
+    }
+
+    #[test]
+    fn bad_images_fail() {
+        let mut updater = Updater::new(AppFlash::new(), ADDR);
+        let too_long = Campaign { len: (APP_PAGES * PAGE_SIZE) as u32, crc: 1, version: 1 };
+        updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Start(too_long)));
+        assert_eq!(report(&mut updater), (UpdateState::Failed, 1, 0, vec![]));
+
+        // Chunks that don't match the start's CRC
+        let image = test_image(1000);
+        let mut update = campaign(&image, 5);
+        update.crc ^= 1;
+        updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Start(update)));
+        image.chunks(CHUNK_LEN).enumerate().for_each(|(i, data)| {
+            updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Chunk { crc: update.crc, index: i as u16, data }));
+        });
+        assert_eq!(report(&mut updater).0, UpdateState::Failed);
+        assert_eq!(updater.handle(&frame(ADDR, UpdateMsg::Commit { crc: update.crc })), Action::None);
+
+        // The same start tries again
+        updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Start(update)));
+        assert_eq!(report(&mut updater).0, UpdateState::Receiving);
+    }
+
+    #[test]
+    fn ignored() {
+        let image = test_image(1000);
+        let update = campaign(&image, 5);
+        let mut updater = Updater::new(AppFlash::new(), ADDR);
+        let ignored = [
+            // Broadcasts, and other nodes' frames
+            frame(0x7F, UpdateMsg::Start(update)),
+            frame(ADDR + 1, UpdateMsg::Start(update)),
+            frame(UPDATE_ADDR, UpdateMsg::Query { addr: ADDR + 1 }),
+            vec![ADDR, 0x01, 0x02],
+            vec![],
+        ];
+        ignored.iter().for_each(|f| assert_eq!(updater.handle(f), Action::None));
+        assert_eq!(updater.state(), UpdateState::Idle);
+
+        updater.handle(&frame(ADDR, UpdateMsg::Start(update)));
+        let crc = update.crc;
+        let bad_chunks = [
+            UpdateMsg::Chunk { crc, index: 8, data: &[0; 8] },
+            UpdateMsg::Chunk { crc, index: 0, data: &image[..CHUNK_LEN - 1] },
+            UpdateMsg::Chunk { crc, index: 7, data: &image[896..992] },
+        ];
+        bad_chunks.iter().for_each(|msg| assert_eq!(updater.handle(&frame(ADDR, *msg)), Action::None));
+        assert_eq!(report(&mut updater).3, (0..8).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn power_cut_while_committing() {
+        let image = test_image(1000);
+        let update = campaign(&image, 5);
+        let mut updater = Updater::new(AppFlash::new(), ADDR);
+        updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Start(update)));
+        send_chunks(&mut updater, &image, &[]);
+
+        // Half the header is written
+        let mut flash = updater.flash().clone();
+        flash.cut_power_after(8);
+        let mut updater = Updater { flash, ..updater };
+        assert_eq!(updater.handle(&frame(ADDR, UpdateMsg::Commit { crc: update.crc })), Action::None);
+        assert_eq!(updater.state(), UpdateState::Failed);
+
+        // The bootloader stays after a reset, and the update starts over
+        let mut flash = updater.flash().clone();
+        flash.power_on();
+        let mut updater = Updater::new(flash, ADDR);
+        assert_eq!(image::verify(updater.flash()), Err(image::ImageError::BadCrc));
+        updater.handle(&frame(UPDATE_ADDR, UpdateMsg::Start(update)));
+        assert_eq!(report(&mut updater).0, UpdateState::Receiving);
+    }
+}
@@ -0,0 +1,54 @@
+//! CRC-16/CCITT-FALSE, for payload trailers
+//!
+//! Polynomial `0x1021`, initial value `0xFFFF`, no reflection, no final XOR.
+//! The trailer is sent LE, after the payload.
+
+/// Starting value, for use with [crc16_update]
+pub const CRC_INIT: u16 = 0xFFFF;
+
+const TABLE: [u16; 256] = make_table();
+
+const fn make_table() -> [u16; 256] {
+    let mut table = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if (crc & 0x8000) != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Continue a CRC with more data
+pub fn crc16_update(crc: u16, data: &[u8]) -> u16 {
+    data.iter().fold(crc, |crc, b| {
+        (crc << 8) ^ TABLE[(((crc >> 8) as u8) ^ *b) as usize]
+    })
+}
+
+/// CRC of `data`
+pub fn crc16(data: &[u8]) -> u16 {
+    crc16_update(CRC_INIT, data)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check_value() {
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(b""), CRC_INIT);
+    }
+
+    #[test]
+    fn incremental() {
+        let crc = crc16_update(crc16(b"1234"), b"56789");
+        assert_eq!(crc, crc16(b"123456789"));
+    }
+}
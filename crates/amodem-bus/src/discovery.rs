@@ -0,0 +1,244 @@
+//! Node discovery and address assignment
+//!
+//! Nodes with no address listen on [UNASSIGNED_ADDR]. While discovering, the
+//! router enumerates them in place of every other poll, each enumeration a
+//! round:
+//!
+//! | From   | Words | Contents                                                         |
+//! | :--    | :--   | :--                                                              |
+//! | Router | 6     | Header: [UNASSIGNED_ADDR], rx cap [UID_LEN], tx len [ASSIGN_LEN], last round's outcome |
+//! | Node   | 5     | Reply: tx amount [UID_LEN], rx amount [ASSIGN_LEN], [STATUS_NONE] |
+//! | Node   | 14    | Offer: the node's unique ID, then its CRC LE                     |
+//! | Router | 15    | Assignment: the unique ID offered, the address, then its CRC LE  |
+//!
+//! Every unaddressed node hears the header, and makes an offer with a chance
+//! set by its [Backoff]. If more than one does, their offers collide, and fail
+//! their CRC. The router only sends an assignment for an intact offer, to a free
+//! address, and adds that address to its poll list. The node whose ID it is
+//! takes the address. Any other node that made an offer, and does not hear its
+//! own ID back, halves its chance of offering again.
+//!
+//! The header's status tells the nodes how the last round went: [STATUS_ACK]
+//! if an address was assigned, [STATUS_NAK] if offers were heard but none was
+//! intact, and [STATUS_NONE] if there were none. After a round with no offers,
+//! every node offers in the next, so two in a row mean every node has an
+//! address, and discovery ends.
+//!
+//! A node that misses its assignment offers again in a later round. The
+//! address it was assigned is dropped from the poll list if the first poll of
+//! it goes unanswered.
+
+use crate::{
+    crc::crc16,
+    wire::{Header, CRC_WORDS, GROUP_ADDR_MIN, STATUS_ACK, STATUS_NAK, STATUS_NONE},
+};
+
+/// The address word nodes with no address listen on. Never polled.
+pub const UNASSIGNED_ADDR: u8 = 0x00;
+
+/// Bytes in a node's unique ID: the STM32's 96-bit UID
+pub const UID_LEN: usize = 12;
+
+/// Bytes in an assignment: the unique ID, then the address
+pub const ASSIGN_LEN: usize = UID_LEN + 1;
+
+/// Most times a node's chance of offering is halved, to 1 in 16
+const MAX_BACKOFF: u32 = 4;
+
+/// Rounds with no offers, in a row, that end discovery
+const EMPTY_ROUNDS: u8 = 2;
+
+pub type Uid = [u8; UID_LEN];
+
+/// The router's enumeration header, with the outcome of the last round
+pub fn enumeration(outcome: u8) -> Header {
+    Header { addr: UNASSIGNED_ADDR, rx_cap: UID_LEN as u16, tx_len: ASSIGN_LEN as u16, status: outcome }
+}
+
+/// An offer of `uid`, with its CRC
+pub fn offer(uid: &Uid) -> [u8; UID_LEN + CRC_WORDS] {
+    let mut buf = [0; UID_LEN + CRC_WORDS];
+    buf[..UID_LEN].copy_from_slice(uid);
+    buf[UID_LEN..].copy_from_slice(&crc16(uid).to_le_bytes());
+    buf
+}
+
+/// The unique ID offered, if the offer is intact
+pub fn parse_offer(buf: &[u8; UID_LEN + CRC_WORDS]) -> Option<Uid> {
+    let (uid, crc) = buf.split_at(UID_LEN);
+    (crc16(uid).to_le_bytes() == crc).then(|| uid.try_into().ok()).flatten()
+}
+
+/// An assignment of `addr` to the node with `uid`, with its CRC
+pub fn assignment(uid: &Uid, addr: u8) -> [u8; ASSIGN_LEN + CRC_WORDS] {
+    let mut buf = [0; ASSIGN_LEN + CRC_WORDS];
+    buf[..UID_LEN].copy_from_slice(uid);
+    buf[UID_LEN] = addr;
+    let crc = crc16(&buf[..ASSIGN_LEN]);
+    buf[ASSIGN_LEN..].copy_from_slice(&crc.to_le_bytes());
+    buf
+}
+
+/// The address assigned to `uid`, if the assignment is intact and for it
+pub fn parse_assignment(buf: &[u8; ASSIGN_LEN + CRC_WORDS], uid: &Uid) -> Option<u8> {
+    let (data, crc) = buf.split_at(ASSIGN_LEN);
+    if (crc16(data).to_le_bytes() != crc) || (data[..UID_LEN] != uid[..]) {
+        return None;
+    }
+    Some(data[UID_LEN]).filter(|a| is_assignable(*a))
+}
+
+/// Can `addr` be assigned to a node? Not [UNASSIGNED_ADDR], nor a broadcast or
+/// group address.
+pub fn is_assignable(addr: u8) -> bool {
+    (addr != UNASSIGNED_ADDR) && (addr < GROUP_ADDR_MIN)
+}
+
+/// The lowest address that can be assigned, and is not `used`
+pub fn free_addr<U: Fn(u8) -> bool>(used: U) -> Option<u8> {
+    (0..GROUP_ADDR_MIN).filter(|a| is_assignable(*a)).find(|a| !used(*a))
+}
+
+/// A node's chance of offering in each round
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Backoff {
+    /// xorshift32 state, never zero
+    rng: u32,
+    /// The chance of offering is 1 in `2^halved`
+    halved: u32,
+}
+
+impl Backoff {
+    /// Seeded from the node's unique ID, so each node makes its own choices
+    pub const fn new(uid: &Uid) -> Self {
+        let mut seed: u32 = 0;
+        let mut i = 0;
+        while i < UID_LEN {
+            seed = seed.rotate_left(8) ^ (uid[i] as u32);
+            i += 1;
+        }
+        Self { rng: if seed == 0 { 1 } else { seed }, halved: 0 }
+    }
+
+    /// An enumeration was heard, with the outcome of the last round. Returns
+    /// true if we make an offer in this one.
+    pub fn offer(&mut self, outcome: u8) -> bool {
+        if outcome == STATUS_NONE {
+            // Nobody offered last time, so nobody is in the way
+            self.halved = 0;
+        }
+        (self.next() & ((1 << self.halved) - 1)) == 0
+    }
+
+    /// Our offer was not answered with our ID
+    pub fn collided(&mut self) {
+        self.halved = (self.halved + 1).min(MAX_BACKOFF);
+    }
+
+    fn next(&mut self) -> u32 {
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 17;
+        self.rng ^= self.rng << 5;
+        self.rng
+    }
+}
+
+/// What was heard in a round of enumeration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Round {
+    /// No offers
+    Empty,
+    /// Offers, but none intact
+    Collided,
+    /// An address was assigned
+    Assigned,
+}
+
+/// The router's side of a run of discovery
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Discovery {
+    /// Status sent in the next enumeration header
+    outcome: u8,
+    /// Rounds with no offers, in a row
+    empty: u8,
+}
+
+impl Default for Discovery {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Discovery {
+    pub const fn new() -> Self {
+        Self { outcome: STATUS_NONE, empty: 0 }
+    }
+
+    /// The header of the next round
+    pub fn header(&self) -> Header {
+        enumeration(self.outcome)
+    }
+
+    /// A round is over. Returns true if discovery is done.
+    pub fn round(&mut self, round: Round) -> bool {
+        self.outcome = match round {
+            Round::Empty => STATUS_NONE,
+            Round::Collided => STATUS_NAK,
+            Round::Assigned => STATUS_ACK,
+        };
+        self.empty = match round {
+            Round::Empty => self.empty + 1,
+            _ => 0,
+        };
+        self.empty >= EMPTY_ROUNDS
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const UID: Uid = [0x10, 0x20, 0x30, 0x40, 0x50, 0x60, 0x70, 0x80, 0x90, 0xA0, 0xB0, 0xC0];
+
+    #[test]
+    fn offers_and_assignments() {
+        let mut buf = offer(&UID);
+        assert_eq!(parse_offer(&buf), Some(UID));
+        buf[3] ^= 0x04;
+        assert_eq!(parse_offer(&buf), None);
+
+        let mut other = UID;
+        other[11] = 0;
+        let buf = assignment(&UID, 0x21);
+        assert_eq!(parse_assignment(&buf, &UID), Some(0x21));
+        assert_eq!(parse_assignment(&buf, &other), None);
+        assert_eq!(parse_assignment(&assignment(&UID, GROUP_ADDR_MIN), &UID), None);
+    }
+
+    #[test]
+    fn free_addresses() {
+        assert_eq!(free_addr(|_| false), Some(1));
+        assert_eq!(free_addr(|a| a < 5), Some(5));
+        assert_eq!(free_addr(|_| true), None);
+    }
+
+    #[test]
+    fn backs_off_until_a_quiet_round() {
+        let mut backoff = Backoff::new(&UID);
+        assert!(backoff.offer(STATUS_NONE));
+        (0..MAX_BACKOFF + 2).for_each(|_| backoff.collided());
+        let offers = (0..1600).filter(|_| backoff.offer(STATUS_NAK)).count();
+        assert!((50..150).contains(&offers), "{offers}");
+        assert!(backoff.offer(STATUS_NONE));
+    }
+
+    #[test]
+    fn ends_after_quiet_rounds() {
+        let mut disc = Discovery::new();
+        assert!(!disc.round(Round::Empty));
+        assert!(!disc.round(Round::Collided));
+        assert_eq!(disc.header().status, STATUS_NAK);
+        assert!(!disc.round(Round::Empty));
+        assert!(disc.round(Round::Empty));
+    }
+}
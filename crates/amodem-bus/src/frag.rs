@@ -0,0 +1,240 @@
+//! Fragments, for frames longer than one payload
+//!
+//! Frames of up to [MAX_FRAME] bytes are split into fragments of up to
+//! [FRAG_DATA_MAX] bytes, one per payload. Every payload starts with a one
+//! byte fragment header, even for frames that fit in one:
+//!
+//! | Bits | Contents                                         |
+//! | :--  | :--                                              |
+//! | 7    | [FRAG_MORE]: more fragments of this frame follow |
+//! | 0..7 | Index of this fragment in its frame, from zero   |
+//!
+//! Each fragment is acknowledged before the next is sent, and the fragments of
+//! a broadcast are sent one after another, so they always arrive in order. The
+//! far end reassembles them in place (see [Reassembly]), and only delivers the
+//! frame once the last one is in. A fragment out of order, or a gap of more
+//! than [REASSEMBLY_TIMEOUT_BITS], drops the partial frame. A fragment sent
+//! again because its acknowledgement was lost is ignored.
+
+use crate::{elapsed, wire::MAX_PAYLOAD};
+
+/// Bytes of fragment header at the start of each payload
+pub const FRAG_HEADER_LEN: usize = 1;
+
+/// Set in the fragment header if more fragments follow
+pub const FRAG_MORE: u8 = 0x80;
+
+/// Bits of the fragment header holding the fragment index
+pub const FRAG_INDEX_MASK: u8 = 0x7F;
+
+/// Most frame bytes in one fragment
+pub const FRAG_DATA_MAX: usize = MAX_PAYLOAD - FRAG_HEADER_LEN;
+
+/// Largest frame, once reassembled: 510 bytes. Each end needs this much room
+/// (plus the address prefix, see [crate::Queues]) to take the first fragment.
+/// This is a multiple of [FRAG_DATA_MAX], so while a frame is partly
+/// reassembled there is always room for a whole fragment after it, even one
+/// sent again.
+///
+/// Two fragments is the ceiling set by the firmware's pipes, which are 1024
+/// byte framed bbqueues. Each write grant is claimed for a whole frame before
+/// its length is known: `1 + MAX_FRAME` bytes, and a two byte frame header, or
+/// 513 bytes. Grants never wrap, so a pipe has room for about two of them, one
+/// frame waiting to be taken while the next arrives. With a third fragment, a
+/// grant would take three quarters of the pipe, so the next one would usually
+/// have to wait for the previous frame to be taken. This is also the largest
+/// frame the host can send or receive, see `amodem_hostif::long`.
+pub const MAX_FRAME: usize = 2 * FRAG_DATA_MAX;
+
+/// Longest wait for the next fragment of a frame, in bit times (100mS at
+/// 8 Mbaud)
+pub const REASSEMBLY_TIMEOUT_BITS: u32 = 800_000;
+
+/// The fragment of a `frame_len` byte frame starting at `offset`: its header,
+/// and how many bytes of the frame it carries
+pub fn fragment(frame_len: usize, offset: usize) -> (u8, usize) {
+    let len = frame_len.saturating_sub(offset).min(FRAG_DATA_MAX);
+    let index = ((offset / FRAG_DATA_MAX) as u8) & FRAG_INDEX_MASK;
+    let more = if (offset + len) < frame_len { FRAG_MORE } else { 0 };
+    (more | index, len)
+}
+
+/// Largest payload that fits in `capacity` bytes of room for a frame and its
+/// address prefix, with `offset` bytes of the frame already reassembled
+pub fn payload_room(capacity: usize, offset: usize) -> usize {
+    match capacity.min(1 + MAX_FRAME).checked_sub(1 + offset) {
+        Some(room) => (FRAG_HEADER_LEN + room).min(MAX_PAYLOAD),
+        None => 0,
+    }
+}
+
+/// What [Reassembly::accept] made of a fragment
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Accepted {
+    /// Stored, and more fragments are to come
+    Partial,
+    /// The frame is complete, and is this long
+    Complete(usize),
+    /// A fragment that was already stored, sent again
+    Repeat,
+    /// Out of order. The partial frame, if any, was dropped.
+    Dropped,
+}
+
+/// Reassembly of one frame at a time, into a buffer owned by the caller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reassembly {
+    len: usize,
+    /// Index of the next fragment, kept once a frame is complete to spot
+    /// repeats of its last fragment
+    next: u8,
+    active: bool,
+    since: u32,
+}
+
+impl Reassembly {
+    pub const fn new() -> Self {
+        Self { len: 0, next: 0, active: false, since: 0 }
+    }
+
+    /// Is part of a frame being held?
+    pub fn is_active(&self) -> bool {
+        self.active
+    }
+
+    /// Where the next fragment's data goes in the frame buffer
+    pub fn offset(&self) -> usize {
+        if self.active { self.len } else { 0 }
+    }
+
+    /// Take a fragment, with fragment header `header`, whose `len` data bytes
+    /// have been stored in `buf` at [Self::offset]. If it turns out to start a
+    /// new frame, it is moved to the start of `buf`.
+    pub fn accept(&mut self, now: u32, buf: &mut [u8], header: u8, len: usize) -> Accepted {
+        let index = header & FRAG_INDEX_MASK;
+        if index == 0 {
+            // A new frame, or the first fragment again
+            let offset = self.offset();
+            buf.copy_within(offset..offset + len, 0);
+            self.len = 0;
+        } else if index.wrapping_add(1) == self.next {
+            return Accepted::Repeat;
+        } else if !self.active || (index != self.next) {
+            *self = Self::new();
+            return Accepted::Dropped;
+        }
+
+        self.len += len;
+        self.next = index.wrapping_add(1);
+        self.since = now;
+        self.active = (header & FRAG_MORE) != 0;
+        if self.active {
+            Accepted::Partial
+        } else {
+            Accepted::Complete(self.len)
+        }
+    }
+
+    /// Drop a partial frame if its next fragment is more than `timeout`
+    /// overdue. Returns true if it was dropped.
+    pub fn expire(&mut self, now: u32, timeout: u32) -> bool {
+        if self.active && (elapsed(now, self.since) > timeout) {
+            *self = Self::new();
+            return true;
+        }
+        false
+    }
+
+    /// Forget any partial frame
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Default for Reassembly {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        assert_eq!(fragment(0, 0), (0, 0));
+        assert_eq!(fragment(FRAG_DATA_MAX, 0), (0, FRAG_DATA_MAX));
+        assert_eq!(fragment(MAX_FRAME, 0), (FRAG_MORE, FRAG_DATA_MAX));
+        assert_eq!(fragment(MAX_FRAME, FRAG_DATA_MAX), (1, FRAG_DATA_MAX));
+        assert_eq!(fragment(300, FRAG_DATA_MAX), (1, 300 - FRAG_DATA_MAX));
+    }
+
+    #[test]
+    fn room() {
+        assert_eq!(payload_room(0, 0), 0);
+        assert_eq!(payload_room(1, 0), FRAG_HEADER_LEN);
+        assert_eq!(payload_room(1 + MAX_FRAME, 0), MAX_PAYLOAD);
+        assert_eq!(payload_room(1 + MAX_FRAME, FRAG_DATA_MAX), MAX_PAYLOAD);
+        assert_eq!(payload_room(1 + MAX_FRAME + 100, MAX_FRAME - 10), FRAG_HEADER_LEN + 10);
+        assert_eq!(payload_room(100, 200), 0);
+    }
+
+    /// Feed fragments `from..to` of `frame`, as they would arrive over the bus
+    fn feed(reasm: &mut Reassembly, buf: &mut [u8], frame: &[u8], from: usize, to: usize) -> Accepted {
+        let mut last = Accepted::Dropped;
+        for offset in (from..to).map(|i| i * FRAG_DATA_MAX) {
+            let (header, len) = fragment(frame.len(), offset);
+            let at = reasm.offset();
+            buf[at..at + len].copy_from_slice(&frame[offset..offset + len]);
+            last = reasm.accept(0, buf, header, len);
+        }
+        last
+    }
+
+    #[test]
+    fn in_order() {
+        let frame: Vec<u8> = (0..MAX_FRAME).map(|i| i as u8).collect();
+        let mut buf = [0; MAX_FRAME];
+        let mut reasm = Reassembly::new();
+        assert_eq!(feed(&mut reasm, &mut buf, &frame, 0, 1), Accepted::Partial);
+        assert_eq!(reasm.offset(), FRAG_DATA_MAX);
+        assert_eq!(feed(&mut reasm, &mut buf, &frame, 1, 2), Accepted::Complete(MAX_FRAME));
+        assert_eq!(&buf[..], &frame[..]);
+        assert!(!reasm.is_active());
+    }
+
+    #[test]
+    fn repeats_ignored() {
+        let frame: Vec<u8> = (0..MAX_FRAME).map(|i| !(i as u8)).collect();
+        let mut buf = [0; MAX_FRAME];
+        let mut reasm = Reassembly::new();
+
+        // The first fragment again starts over
+        feed(&mut reasm, &mut buf, &frame, 0, 1);
+        assert_eq!(feed(&mut reasm, &mut buf, &frame, 0, 1), Accepted::Partial);
+        assert_eq!(feed(&mut reasm, &mut buf, &frame, 1, 2), Accepted::Complete(MAX_FRAME));
+        assert_eq!(&buf[..], &frame[..]);
+
+        // Later ones are only stored once
+        assert_eq!(feed(&mut reasm, &mut buf, &frame, 1, 2), Accepted::Repeat);
+        assert!(!reasm.is_active());
+    }
+
+    #[test]
+    fn gaps_dropped() {
+        let frame = [0x55; MAX_FRAME];
+        let mut buf = [0; MAX_FRAME];
+        let mut reasm = Reassembly::new();
+        assert_eq!(feed(&mut reasm, &mut buf, &frame, 1, 2), Accepted::Dropped);
+
+        feed(&mut reasm, &mut buf, &frame, 0, 1);
+        assert_eq!(reasm.accept(0, &mut buf, 2, 0), Accepted::Dropped);
+        assert!(!reasm.is_active());
+
+        feed(&mut reasm, &mut buf, &frame, 0, 1);
+        assert!(!reasm.expire(REASSEMBLY_TIMEOUT_BITS, REASSEMBLY_TIMEOUT_BITS));
+        assert!(reasm.expire(REASSEMBLY_TIMEOUT_BITS + 1, REASSEMBLY_TIMEOUT_BITS));
+        assert_eq!(reasm.offset(), 0);
+    }
+}
@@ -0,0 +1,119 @@
+//! # amodem RS-485 bus protocol
+//!
+//! The polled, multi-drop protocol spoken between a router and its nodes, as
+//! implemented by the firmware's `rs485` (node) and `router` modules:
+//!
+//! * [wire] has the word formats, the node's side of the length negotiation,
+//!   and the timeouts, which the firmware uses directly.
+//! * [crc] has the CRC-16 sent after each payload.
+//! * [frag] splits frames into payloads, and puts them back together.
+//! * [route] picks where a router sends frames from one node to another.
+//! * [discovery] finds nodes with no address, and assigns them one.
+//! * [timesync] keeps the nodes' clocks in step with the router's.
+//! * [update] sends new firmware to every node, and tracks which chunks each
+//!   is missing.
+//! * [node::Node] and [router::Router] are word-at-a-time state machines for
+//!   each end. The bootloader runs [node::Node] as its bus node. The
+//!   application firmware does not run either: its `rs485` and `router`
+//!   modules are separate code, moving payloads with DMA, which shares only
+//!   the modules above.
+//! * With the `std` feature (on by default), [sim] runs any number of nodes
+//!   and routers on a simulated bus, one word time per step, with collisions
+//!   and noise.
+//!
+//! The tests here, the simulated bus included, cover the shared modules, the
+//! state machines, and so the bootloader. They check the protocol itself, but
+//! not the firmware's implementation of it, which has to be tested on
+//! hardware. A change to the protocol has to be made on both sides.
+//!
+//! Time is measured in bit times throughout, as a wrapping `u32`.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod wire;
+pub mod crc;
+pub mod frag;
+pub mod route;
+pub mod discovery;
+pub mod node;
+pub mod router;
+pub mod timesync;
+pub mod update;
+#[cfg(feature = "std")]
+pub mod sim;
+
+/// The frame queues behind a node or router
+///
+/// For a router, outgoing frames start with the address of the node they are
+/// for (or a broadcast or group address), and incoming frames start with the
+/// address of the node they came from. For a node, incoming frames start with
+/// the address they were sent to: the node's own, its group, or
+/// [wire::BROADCAST_ADDR]. None of these are sent over the bus as part of the
+/// payload. After that, frames carry the routing header of [route], if used.
+pub trait Queues {
+    /// The frame at the head of the outgoing queue, if any
+    fn tx_head(&mut self) -> Option<&[u8]>;
+
+    /// Remove the frame at the head of the outgoing queue, once it was sent
+    fn tx_pop(&mut self);
+
+    /// Room for the next incoming frame
+    fn rx_capacity(&mut self) -> usize;
+
+    /// Store an incoming frame. Returns false if it was dropped.
+    fn rx_push(&mut self, frame: &[u8]) -> bool;
+}
+
+/// Counters kept by each state machine
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Stats {
+    /// Polls started (router), or answered (node)
+    pub polls: u32,
+    /// Frames sent, and acknowledged
+    pub frames_sent: u32,
+    /// Frames received
+    pub frames_received: u32,
+    /// Payload bytes sent
+    pub bytes_sent: u32,
+    /// Payload bytes received
+    pub bytes_received: u32,
+    /// Polls abandoned because the other end went quiet
+    pub timeouts: u32,
+    /// Polls abandoned because of a malformed or unexpected word
+    pub errors: u32,
+    /// Frames from the other end that there was no room for, including
+    /// broadcasts
+    pub refused: u32,
+    /// Frames from the other end that stopped short, and were dropped
+    pub truncated: u32,
+    /// Frames from the other end that failed their CRC, and were dropped
+    pub crc_errors: u32,
+    /// Frames sent again, as they were not acknowledged
+    pub retries: u32,
+    /// Partly reassembled frames dropped, as a fragment was missed or overdue
+    pub frag_dropped: u32,
+}
+
+impl Stats {
+    pub const fn new() -> Self {
+        Self {
+            polls: 0,
+            frames_sent: 0,
+            frames_received: 0,
+            bytes_sent: 0,
+            bytes_received: 0,
+            timeouts: 0,
+            errors: 0,
+            refused: 0,
+            truncated: 0,
+            crc_errors: 0,
+            retries: 0,
+            frag_dropped: 0,
+        }
+    }
+}
+
+#[inline]
+fn elapsed(now: u32, since: u32) -> u32 {
+    now.wrapping_sub(since)
+}
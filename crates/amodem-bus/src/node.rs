@@ -0,0 +1,439 @@
+//! The node end of the bus
+//!
+//! A node sits in mute mode until it hears its own address word. It then reads
+//! the rest of the router's header, replies with what it will send and
+//! receive, sends its frame, and receives the router's frame.
+//!
+//! A frame sent to the router stays at the head of the outgoing queue until the
+//! router acknowledges it in a later header.
+//!
+//! Once awake, a node also takes broadcasts, and multicasts to its group. These
+//! are received without a reply.
+//!
+//! Frames go one fragment per poll, see [crate::frag]. After the router
+//! acknowledges a fragment, the next is sent in the following poll.
+//!
+//! A node follows the router's time syncs with a [SyncClock], see
+//! [crate::timesync].
+//!
+//! A node made with [UNASSIGNED_ADDR] answers the router's enumerations
+//! instead, until it is assigned an address, see [crate::discovery].
+
+use crate::{
+    crc::{crc16, crc16_update},
+    discovery::{self, Backoff, Uid, ASSIGN_LEN, UID_LEN, UNASSIGNED_ADDR},
+    elapsed,
+    frag::{self, Accepted, Reassembly, FRAG_HEADER_LEN, MAX_FRAME, REASSEMBLY_TIMEOUT_BITS},
+    timesync::{SyncClock, SyncMsg},
+    wire::{
+        self, Header, Reply, ADDR_MASK, BROADCAST_ADDR, CRC_WORDS, HEADER_WORDS, MAX_PAYLOAD,
+        REPLY_WORDS, STATUS_ACK, STATUS_NAK, STATUS_NONE,
+    },
+    Queues, Stats,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for our address word
+    Muted,
+    /// Reading the router's header, `got` words so far
+    Header { got: usize },
+    /// Sending our reply, then our frame and its CRC, `sent` words so far
+    Sending { sent: usize },
+    /// Receiving the router's fragment and its CRC, `got` bytes so far. For a
+    /// broadcast, there was no reply.
+    Receiving { got: usize },
+    /// Sending our reply to an enumeration, then our offer, `sent` words so far
+    Offering { sent: usize },
+    /// Receiving the router's assignment, `got` bytes so far
+    Assigning { got: usize },
+}
+
+pub struct Node {
+    addr: u8,
+    group: Option<u8>,
+    state: State,
+    since: u32,
+    header: [u16; HEADER_WORDS],
+    reply: Reply,
+    /// Our last fragment was sent, and is waiting for the router's status
+    tx_pending: bool,
+    /// How much of the frame at the head of the queue the router has
+    tx_offset: usize,
+    /// Frame bytes in the fragment in `tx_buf`
+    tx_frag: usize,
+    tx_buf: [u8; MAX_PAYLOAD + CRC_WORDS],
+    /// Our status for the router's last fragment, sent in our next reply
+    rx_status: u8,
+    rx_header: u8,
+    rx_crc: [u8; CRC_WORDS],
+    // Starts with the address the frame was sent to
+    rx_buf: [u8; 1 + MAX_FRAME],
+    reassembly: Reassembly,
+    clock: SyncClock,
+    uid: Uid,
+    backoff: Backoff,
+    assign_buf: [u8; ASSIGN_LEN + CRC_WORDS],
+    stats: Stats,
+}
+
+impl Node {
+    pub const fn new(addr: u8) -> Self {
+        Self {
+            addr: addr & ADDR_MASK,
+            group: None,
+            state: State::Muted,
+            since: 0,
+            header: [0; HEADER_WORDS],
+            reply: Reply { tx_amt: 0, rx_amt: 0, status: STATUS_NONE },
+            tx_pending: false,
+            tx_offset: 0,
+            tx_frag: 0,
+            tx_buf: [0; MAX_PAYLOAD + CRC_WORDS],
+            rx_status: STATUS_NONE,
+            rx_header: 0,
+            rx_crc: [0; CRC_WORDS],
+            rx_buf: [0; 1 + MAX_FRAME],
+            reassembly: Reassembly::new(),
+            clock: SyncClock::new(),
+            uid: [0; UID_LEN],
+            backoff: Backoff::new(&[0; UID_LEN]),
+            assign_buf: [0; ASSIGN_LEN + CRC_WORDS],
+            stats: Stats::new(),
+        }
+    }
+
+    pub fn addr(&self) -> u8 {
+        self.addr
+    }
+
+    /// Set the unique ID offered when enumerated. Each node's must differ.
+    pub fn set_uid(&mut self, uid: Uid) {
+        self.uid = uid;
+        self.backoff = Backoff::new(&uid);
+    }
+
+    /// Also take multicasts to `group`, which should be a group address
+    pub fn set_group(&mut self, group: Option<u8>) {
+        self.group = group.map(|g| g & ADDR_MASK);
+    }
+
+    /// Our estimate of the router's time
+    pub fn clock(&self) -> &SyncClock {
+        &self.clock
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Is the node in mute mode, between polls?
+    pub fn is_muted(&self) -> bool {
+        self.state == State::Muted
+    }
+
+    /// Handle a word heard on the bus
+    pub fn receive<Q: Queues>(&mut self, now: u32, word: u16, queues: &mut Q) {
+        if wire::is_addr(word) {
+            match self.state {
+                // Still in a run of address words, the last one counts
+                State::Header { got: 1 } => {
+                    self.header[0] = word;
+                    self.since = now;
+                    return;
+                },
+                State::Muted => {},
+                _ => {
+                    // The router moved on without us
+                    self.stats.errors += 1;
+                    self.drop_rx();
+                    self.state = State::Muted;
+                },
+            }
+            // Mute mode only wakes on our own address
+            if ((word as u8) & ADDR_MASK) == self.addr {
+                self.header[0] = word;
+                self.state = State::Header { got: 1 };
+                self.since = now;
+            }
+            return;
+        }
+
+        match self.state {
+            State::Header { got: 1 } if !self.listens_to(self.header_addr()) => {
+                // Woken for someone else's header
+                self.state = State::Muted;
+            },
+            State::Header { got } => {
+                self.header[got] = word;
+                if (got + 1) == HEADER_WORDS {
+                    self.answer(now, queues);
+                } else {
+                    self.state = State::Header { got: got + 1 };
+                }
+            },
+            State::Receiving { got } => {
+                let len = self.reply.rx_amt as usize;
+                match got {
+                    0 => self.rx_header = word as u8,
+                    _ if got < len => self.rx_buf[self.reassembly.offset() + got] = word as u8,
+                    _ => self.rx_crc[got - len] = word as u8,
+                }
+                let got = got + 1;
+                if got == wire::payload_words(len) {
+                    self.deliver(now, queues);
+                } else {
+                    self.state = State::Receiving { got };
+                    self.since = now;
+                }
+            },
+            State::Assigning { got } => {
+                self.assign_buf[got] = word as u8;
+                let got = got + 1;
+                if got == self.assign_buf.len() {
+                    self.assigned();
+                } else {
+                    self.state = State::Assigning { got };
+                    self.since = now;
+                }
+            },
+            // Nothing for us, or our own turn to talk
+            State::Muted | State::Sending { .. } | State::Offering { .. } => {},
+        }
+    }
+
+    /// The next word to drive onto the bus, if it is our turn
+    pub fn transmit(&mut self, now: u32) -> Option<u16> {
+        let (State::Sending { sent } | State::Offering { sent }) = self.state else {
+            return None;
+        };
+
+        let tx_amt = self.reply.tx_amt as usize;
+        let word = match self.reply.to_words().get(sent) {
+            Some(w) => *w,
+            None => self.tx_buf[sent - REPLY_WORDS] as u16,
+        };
+
+        let sent = sent + 1;
+        let offering = matches!(self.state, State::Offering { .. });
+        if sent < (REPLY_WORDS + wire::payload_words(tx_amt)) {
+            self.state = match offering {
+                true => State::Offering { sent },
+                false => State::Sending { sent },
+            };
+            return Some(word);
+        }
+        if offering {
+            self.state = State::Assigning { got: 0 };
+            self.since = now;
+            return Some(word);
+        }
+
+        // Keep the frame until the router says it arrived
+        self.tx_pending = tx_amt != 0;
+        if self.reply.rx_amt != 0 {
+            self.state = State::Receiving { got: 0 };
+            self.since = now;
+        } else {
+            self.state = State::Muted;
+        }
+        Some(word)
+    }
+
+    /// Check for timeouts. A payload that stops partway through is dropped, as
+    /// is a partly reassembled frame whose next fragment is overdue.
+    pub fn poll(&mut self, now: u32) {
+        if self.reassembly.expire(now, REASSEMBLY_TIMEOUT_BITS) {
+            self.stats.frag_dropped += 1;
+        }
+
+        let limit = match self.state {
+            State::Header { .. } => wire::HEADER_TIMEOUT_BITS,
+            State::Receiving { got: 0 } => {
+                wire::data_timeout_bits(wire::payload_words(self.reply.rx_amt as usize))
+            },
+            State::Receiving { .. } => wire::RX_GAP_BITS,
+            State::Assigning { got: 0 } => wire::data_timeout_bits(ASSIGN_LEN + CRC_WORDS),
+            State::Assigning { .. } => wire::RX_GAP_BITS,
+            State::Muted | State::Sending { .. } | State::Offering { .. } => return,
+        };
+        if elapsed(now, self.since) <= limit {
+            return;
+        }
+        match self.state {
+            State::Receiving { got } if got != 0 => self.stats.truncated += 1,
+            // No assignment for us, most likely as our offer collided
+            State::Assigning { .. } => self.backoff.collided(),
+            _ => self.stats.timeouts += 1,
+        }
+        self.drop_rx();
+        self.state = State::Muted;
+    }
+
+    /// The whole header is in, decide what to send and receive
+    fn answer<Q: Queues>(&mut self, now: u32, queues: &mut Q) {
+        let Some(header) = Header::from_words(&self.header) else {
+            self.stats.errors += 1;
+            self.state = State::Muted;
+            return;
+        };
+        match SyncMsg::from_header(&header) {
+            Some(msg) => {
+                match msg {
+                    SyncMsg::Sync => self.clock.sync(now),
+                    SyncMsg::FollowUp(time) => {
+                        self.clock.follow_up(time);
+                    },
+                }
+                self.state = State::Muted;
+                return;
+            },
+            None => self.clock.cancel(),
+        }
+        if header.addr == UNASSIGNED_ADDR {
+            self.enumerated(now, &header);
+            return;
+        }
+        if header.addr != self.addr {
+            self.listen(now, &header, queues);
+            return;
+        }
+        self.stats.polls += 1;
+
+        // Our last fragment is either done with, or to be sent again. Once it
+        // is done with, wait for the next poll to send the next one.
+        let mut acked = false;
+        if self.tx_pending {
+            self.tx_pending = false;
+            if header.status == STATUS_ACK {
+                self.tx_offset += self.tx_frag;
+                let len = queues.tx_head().map_or(0, <[u8]>::len);
+                if self.tx_offset >= len {
+                    self.stats.frames_sent += 1;
+                    self.stats.bytes_sent += len as u32;
+                    self.tx_offset = 0;
+                    queues.tx_pop();
+                }
+                acked = true;
+            } else {
+                self.stats.retries += 1;
+            }
+        }
+
+        let tx_len = match queues.tx_head() {
+            Some(frame) if !acked && (frame.len() <= MAX_FRAME) => {
+                let (frag_header, len) = frag::fragment(frame.len(), self.tx_offset);
+                let payload = FRAG_HEADER_LEN + len;
+                self.tx_buf[0] = frag_header;
+                self.tx_buf[1..payload].copy_from_slice(&frame[self.tx_offset..][..len]);
+                let crc = crc16(&self.tx_buf[..payload]);
+                self.tx_buf[payload..payload + CRC_WORDS].copy_from_slice(&crc.to_le_bytes());
+                self.tx_frag = len;
+                payload
+            },
+            _ => 0,
+        };
+        let rx_cap = self.rx_capacity(queues);
+
+        self.reply = Reply::negotiate(&header, tx_len, rx_cap, self.rx_status);
+        self.rx_status = STATUS_NONE;
+        if (header.tx_len != 0) && (self.reply.rx_amt == 0) {
+            self.stats.refused += 1;
+        }
+        self.state = State::Sending { sent: 0 };
+        self.since = now;
+    }
+
+    /// A broadcast or multicast header is in, receive its frame if there is
+    /// room for it
+    fn listen<Q: Queues>(&mut self, now: u32, header: &Header, queues: &mut Q) {
+        let len = header.tx_len as usize;
+        if (len == 0) || (len > self.rx_capacity(queues)) {
+            if len != 0 {
+                self.stats.refused += 1;
+            }
+            self.state = State::Muted;
+            return;
+        }
+        self.reply = Reply { tx_amt: 0, rx_amt: len as u16, status: STATUS_NONE };
+        self.state = State::Receiving { got: 0 };
+        self.since = now;
+    }
+
+    /// The router is enumerating nodes with no address. Make an offer, if the
+    /// back-off picks this round.
+    fn enumerated(&mut self, now: u32, header: &Header) {
+        let offer = self.backoff.offer(header.status);
+        if !offer || (header.rx_cap as usize != UID_LEN) || (header.tx_len as usize != ASSIGN_LEN) {
+            self.state = State::Muted;
+            return;
+        }
+        self.tx_buf[..UID_LEN + CRC_WORDS].copy_from_slice(&discovery::offer(&self.uid));
+        self.reply = Reply { tx_amt: UID_LEN as u16, rx_amt: ASSIGN_LEN as u16, status: STATUS_NONE };
+        self.state = State::Offering { sent: 0 };
+        self.since = now;
+    }
+
+    /// The router's assignment is in. Take the address, if it is for us.
+    fn assigned(&mut self) {
+        match discovery::parse_assignment(&self.assign_buf, &self.uid) {
+            Some(addr) => self.addr = addr,
+            None => self.backoff.collided(),
+        }
+        self.state = State::Muted;
+    }
+
+    /// Room for the router's next fragment
+    fn rx_capacity<Q: Queues>(&self, queues: &mut Q) -> usize {
+        frag::payload_room(queues.rx_capacity(), self.reassembly.offset())
+    }
+
+    /// The router's fragment and its CRC are in. Keep it only if the CRC
+    /// matches, and deliver the frame once it is whole.
+    fn deliver<Q: Queues>(&mut self, now: u32, queues: &mut Q) {
+        let len = self.reply.rx_amt as usize - FRAG_HEADER_LEN;
+        let offset = self.reassembly.offset();
+        let data = &self.rx_buf[1 + offset..][..len];
+        let intact = crc16_update(crc16(&[self.rx_header]), data).to_le_bytes() == self.rx_crc;
+        if !intact {
+            self.stats.crc_errors += 1;
+        } else {
+            match self.reassembly.accept(now, &mut self.rx_buf[1..], self.rx_header, len) {
+                Accepted::Complete(len) => {
+                    self.rx_buf[0] = self.header_addr();
+                    if queues.rx_push(&self.rx_buf[..1 + len]) {
+                        self.stats.frames_received += 1;
+                        self.stats.bytes_received += len as u32;
+                    }
+                },
+                Accepted::Dropped => self.stats.frag_dropped += 1,
+                Accepted::Partial | Accepted::Repeat => {},
+            }
+        }
+        if !self.is_multicast() {
+            self.rx_status = if intact { STATUS_ACK } else { STATUS_NAK };
+        }
+        self.state = State::Muted;
+    }
+
+    /// A frame from the router stopped short, ask for it again
+    fn drop_rx(&mut self) {
+        if matches!(self.state, State::Receiving { got } if got != 0) && !self.is_multicast() {
+            self.rx_status = STATUS_NAK;
+        }
+    }
+
+    /// The address of the header being received, or last received
+    fn header_addr(&self) -> u8 {
+        (self.header[0] as u8) & ADDR_MASK
+    }
+
+    /// Was the last header a broadcast or multicast?
+    fn is_multicast(&self) -> bool {
+        self.header_addr() != self.addr
+    }
+
+    fn listens_to(&self, addr: u8) -> bool {
+        (addr == self.addr) || (addr == BROADCAST_ADDR) || (Some(addr) == self.group)
+    }
+}
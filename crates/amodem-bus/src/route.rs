@@ -0,0 +1,89 @@
+//! Routing between nodes
+//!
+//! Frames from a node's host always start with a one byte destination header:
+//! the address they are for, or [HOST_ADDR] for the router's host. The header
+//! is not marked in any way, so it is required whether or not the router has
+//! routes set: otherwise, the first byte of a frame meant for the router's
+//! host could match a route set later, and the frame be forwarded in its
+//! place. The router tags each frame it receives with the node it came from,
+//! so a frame from a node arrives at the router as:
+//!
+//! | Bytes | Contents                    |
+//! | :--   | :--                         |
+//! | 1     | Source: the sending node    |
+//! | 1     | Destination header          |
+//! | n     | Payload                     |
+//!
+//! The router looks the destination up in its routing table with [next_hop].
+//! A frame with a route is forwarded whole, source and destination included,
+//! to the node the route goes via: the destination itself, or a node whose
+//! host bridges on to it. There, it is delivered to the host behind the
+//! address it was sent to, as usual. Anything else goes to the router's host,
+//! header included. Frames of the full [crate::frag::MAX_FRAME] bytes from a
+//! node's host, header included, have no room for the source, and also go to
+//! the router's host.
+//!
+//! Frames from the router's own host are not routed. By convention, they start
+//! with [HOST_ADDR] as the source, then the destination, in the same way.
+
+use crate::wire::{self, ADDR_MASK};
+
+/// Destination of frames for the router's host, and source of frames from it.
+/// Not a node address.
+pub const HOST_ADDR: u8 = 0x00;
+
+/// Bytes of source and destination in front of a routed frame's payload
+pub const ROUTE_HEADER_LEN: usize = 2;
+
+/// Most entries in a router's routing table, as in the `ROUTE` register
+pub const MAX_ROUTES: usize = 8;
+
+/// A routing table entry: frames for `dest` are forwarded to `via`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Route {
+    pub dest: u8,
+    pub via: u8,
+}
+
+/// The node a router forwards a frame for `dest` to, or `None` if it goes to
+/// the router's host.
+///
+/// The first route in `routes` for `dest` is used, if the node it goes via is
+/// polled. Broadcasts and multicasts are not forwarded.
+pub fn next_hop<R, P>(dest: u8, routes: R, polled: P) -> Option<u8>
+where
+    R: IntoIterator<Item = Route>,
+    P: Fn(u8) -> bool,
+{
+    let dest = dest & ADDR_MASK;
+    if (dest == HOST_ADDR) || wire::is_multicast(dest) {
+        return None;
+    }
+    routes
+        .into_iter()
+        .find(|r| (r.dest & ADDR_MASK) == dest)
+        .map(|r| r.via & ADDR_MASK)
+        .filter(|via| polled(*via))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hops() {
+        let routes = [
+            Route { dest: 2, via: 2 },
+            Route { dest: 0x20, via: 2 },
+            Route { dest: 0x21, via: 9 },
+        ];
+        let polled = |a| (1..=3).contains(&a);
+        assert_eq!(next_hop(2, routes, polled), Some(2));
+        assert_eq!(next_hop(0x20, routes, polled), Some(2));
+        // No route, or via a node we do not poll
+        assert_eq!(next_hop(3, routes, polled), None);
+        assert_eq!(next_hop(0x21, routes, polled), None);
+        assert_eq!(next_hop(HOST_ADDR, routes, polled), None);
+        assert_eq!(next_hop(wire::BROADCAST_ADDR, routes, polled), None);
+    }
+}
@@ -0,0 +1,701 @@
+//! The router end of the bus
+//!
+//! The router polls each node in its list in turn, waiting at least the poll
+//! interval between polls. The frame at the head of its outgoing queue is only
+//! sent when the node it is addressed to is polled, and stays at the head of
+//! the queue until that node acknowledges it in a later poll.
+//!
+//! A frame for a broadcast or group address is sent to every node in the list
+//! at once, in place of the next poll, and popped straight away.
+//!
+//! Frames go one fragment per poll, see [crate::frag]. The router reassembles
+//! one node's frame at a time: while it holds part of a frame, it offers the
+//! other nodes no room.
+//!
+//! A frame from a node that [route::next_hop] routes to another node is kept
+//! where it was reassembled, and sent on ahead of the outgoing queue, see
+//! [crate::route]. While it waits, nodes are offered no room.
+//!
+//! With a sync interval set, the router also sends time syncs in place of
+//! polls, see [crate::timesync].
+//!
+//! While discovering, the router enumerates nodes with no address in place of
+//! every other poll, adding each address it assigns to the poll list, see
+//! [crate::discovery]. It waits for the new node to answer its first poll
+//! before the next round, and drops its address if it does not.
+//!
+//! The firmware's router role is separate code, and differs in the details:
+//! for example, it also drops a frame at the head of the queue, or one being
+//! forwarded, whose node is not polled, or stops answering.
+
+use crate::{
+    crc::{crc16, crc16_update},
+    discovery::{self, Discovery, Round, ASSIGN_LEN, UID_LEN, UNASSIGNED_ADDR},
+    elapsed,
+    frag::{self, Accepted, Reassembly, FRAG_HEADER_LEN, MAX_FRAME, REASSEMBLY_TIMEOUT_BITS},
+    route::{self, Route, MAX_ROUTES},
+    timesync::SyncMsg,
+    wire::{
+        self, Header, Reply, ADDR_FLAG, ADDR_MASK, CRC_WORDS, HEADER_WORDS, MAX_PAYLOAD,
+        REPLY_WORDS, STATUS_ACK, STATUS_NAK, STATUS_NONE,
+    },
+    Queues, Stats,
+};
+
+/// Most nodes a router will poll, as in the `ROUTER_NODES_*` registers
+pub const MAX_NODES: usize = 8;
+
+/// Default poll interval, in bit times (100uS at 8 Mbaud)
+pub const DEFAULT_INTERVAL_BITS: u32 = 800;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for the poll interval to pass
+    Idle,
+    /// Sending the header, `sent` words so far
+    Header { sent: usize },
+    /// Waiting for the node's reply, `got` words so far
+    Reply { got: usize },
+    /// Receiving the node's fragment and its CRC, `got` bytes so far
+    Receiving { got: usize },
+    /// Sending our fragment and its CRC, `sent` bytes so far
+    Sending { sent: usize },
+    /// Sending a broadcast: the address word of each node, the header, then
+    /// the fragment and its CRC, `sent` words so far
+    Broadcast { sent: usize },
+    /// Waiting for a reply to an enumeration, and its offer, `got` words so far
+    Offers { got: usize },
+    /// Sending an assignment and its CRC, `sent` bytes so far
+    Assign { sent: usize },
+}
+
+pub struct Router {
+    nodes: [u8; MAX_NODES],
+    node_count: usize,
+    routes: [Option<Route>; MAX_ROUTES],
+    next: usize,
+    /// Index in `nodes` of the node being polled
+    slot: usize,
+    interval_bits: u32,
+    state: State,
+    since: u32,
+    last_poll: u32,
+    header: Header,
+    reply_words: [u16; REPLY_WORDS],
+    reply: Reply,
+    send_amt: usize,
+    /// The node our last fragment was sent to, while we wait for its status
+    tx_pending: Option<u8>,
+    /// How much of the frame at the head of the queue has been sent, and
+    /// acknowledged
+    tx_offset: usize,
+    /// Frame bytes in the fragment in `tx_buf`
+    tx_frag: usize,
+    tx_buf: [u8; MAX_PAYLOAD + CRC_WORDS],
+    /// Is the frame being sent from `rx_buf`, rather than the queue?
+    tx_forward: bool,
+    /// Length of the frame to forward in `rx_buf`, or 0 for none
+    fwd_len: usize,
+    /// Our status for each node's last fragment, sent in its next header
+    rx_status: [u8; MAX_NODES],
+    rx_header: u8,
+    rx_crc: [u8; CRC_WORDS],
+    // The node a frame is forwarded to, the source address, then the frame.
+    // Nodes are offered no room while a frame waits to be forwarded from here.
+    rx_buf: [u8; 2 + MAX_FRAME],
+    reassembly: Reassembly,
+    /// Index in `nodes` of the node whose frame is being reassembled
+    reassembly_slot: usize,
+    sync_interval: Option<u32>,
+    last_sync: u32,
+    /// The time the last sync was sent, until it is followed up
+    follow_up: Option<u32>,
+    discovery: Option<Discovery>,
+    /// The next enumeration waits for a poll
+    enumerated: bool,
+    /// The address last assigned, until it answers a poll
+    probation: Option<u8>,
+    offer_buf: [u8; UID_LEN + CRC_WORDS],
+    stats: Stats,
+}
+
+impl Router {
+    /// A router polling `nodes`. Only the first [MAX_NODES] are used.
+    pub fn new(nodes: &[u8], interval_bits: u32) -> Self {
+        let mut router = Self {
+            nodes: [0; MAX_NODES],
+            node_count: 0,
+            routes: [None; MAX_ROUTES],
+            next: 0,
+            slot: 0,
+            interval_bits,
+            state: State::Idle,
+            since: 0,
+            last_poll: 0,
+            header: Header { addr: 0, rx_cap: 0, tx_len: 0, status: STATUS_NONE },
+            reply_words: [0; REPLY_WORDS],
+            reply: Reply { tx_amt: 0, rx_amt: 0, status: STATUS_NONE },
+            send_amt: 0,
+            tx_pending: None,
+            tx_offset: 0,
+            tx_frag: 0,
+            tx_buf: [0; MAX_PAYLOAD + CRC_WORDS],
+            tx_forward: false,
+            fwd_len: 0,
+            rx_status: [STATUS_NONE; MAX_NODES],
+            rx_header: 0,
+            rx_crc: [0; CRC_WORDS],
+            rx_buf: [0; 2 + MAX_FRAME],
+            reassembly: Reassembly::new(),
+            reassembly_slot: 0,
+            sync_interval: None,
+            last_sync: 0,
+            follow_up: None,
+            discovery: None,
+            enumerated: false,
+            probation: None,
+            offer_buf: [0; UID_LEN + CRC_WORDS],
+            stats: Stats::new(),
+        };
+        router.set_nodes(nodes);
+        router
+    }
+
+    /// Replace the poll list. Only the first [MAX_NODES] are used.
+    pub fn set_nodes(&mut self, nodes: &[u8]) {
+        self.node_count = nodes.len().min(MAX_NODES);
+        self.nodes.iter_mut().zip(nodes).for_each(|(d, s)| *d = *s & ADDR_MASK);
+        self.rx_status = [STATUS_NONE; MAX_NODES];
+        self.reassembly.reset();
+        self.next = 0;
+        self.probation = None;
+    }
+
+    /// Set, or clear, entry `index` of the routing table. Indexes past
+    /// [MAX_ROUTES] are ignored.
+    pub fn set_route(&mut self, index: usize, route: Option<Route>) {
+        if let Some(r) = self.routes.get_mut(index) {
+            *r = route;
+        }
+    }
+
+    /// The poll list
+    pub fn nodes(&self) -> &[u8] {
+        &self.nodes[..self.node_count]
+    }
+
+    /// Start assigning addresses to nodes that have none
+    pub fn start_discovery(&mut self) {
+        self.discovery = Some(Discovery::new());
+    }
+
+    /// Is discovery still running?
+    pub fn is_discovering(&self) -> bool {
+        self.discovery.is_some()
+    }
+
+    /// Send a time sync every `interval_bits`, or none
+    pub fn set_sync_interval(&mut self, interval_bits: Option<u32>) {
+        self.sync_interval = interval_bits;
+    }
+
+    pub fn stats(&self) -> &Stats {
+        &self.stats
+    }
+
+    /// Is the router between polls?
+    pub fn is_idle(&self) -> bool {
+        self.state == State::Idle
+    }
+
+    /// Start the next poll if the interval has passed, or check for timeouts
+    pub fn poll<Q: Queues>(&mut self, now: u32, queues: &mut Q) {
+        if self.reassembly.expire(now, REASSEMBLY_TIMEOUT_BITS) {
+            self.stats.frag_dropped += 1;
+        }
+
+        match self.state {
+            State::Idle => {},
+            State::Reply { .. } => {
+                if elapsed(now, self.since) > wire::REPLY_TIMEOUT_BITS {
+                    self.stats.timeouts += 1;
+                    if self.probation == Some(self.header.addr) {
+                        self.fail_probation();
+                    }
+                    self.finish(now);
+                }
+                return;
+            },
+            State::Offers { got } => {
+                let limit = wire::REPLY_TIMEOUT_BITS + wire::data_timeout_bits(UID_LEN + CRC_WORDS);
+                if elapsed(now, self.since) > limit {
+                    let round = if got == 0 { Round::Empty } else { Round::Collided };
+                    self.end_round(now, round);
+                }
+                return;
+            },
+            State::Receiving { .. } => {
+                let words = wire::payload_words(self.reply.tx_amt as usize);
+                if elapsed(now, self.since) > wire::data_timeout_bits(words) {
+                    self.stats.timeouts += 1;
+                    self.rx_status[self.slot] = STATUS_NAK;
+                    self.finish(now);
+                }
+                return;
+            },
+            State::Header { .. } | State::Sending { .. } | State::Broadcast { .. } | State::Assign { .. } => {
+                return
+            },
+        }
+
+        let idle = (self.node_count == 0) && self.discovery.is_none();
+        if idle || (elapsed(now, self.last_poll) < self.interval_bits) {
+            return;
+        }
+
+        if let Some(msg) = self.next_sync(now) {
+            self.header = msg.to_header();
+            self.send_amt = 0;
+            self.state = State::Broadcast { sent: 0 };
+            self.since = now;
+            return;
+        }
+
+        if self.start_enumeration(now) || (self.node_count == 0) {
+            return;
+        }
+
+        // A frame for a node no longer polled would wait forever
+        if (self.fwd_len != 0) && !self.nodes[..self.node_count].contains(&self.rx_buf[0]) {
+            self.stats.refused += 1;
+            self.tx_forward = false;
+            self.tx_offset = 0;
+            self.fwd_len = 0;
+        }
+
+        // Forwarded frames go first, unless part of a queued frame is out
+        if (self.tx_offset == 0) && self.tx_pending.is_none() {
+            self.tx_forward = self.fwd_len != 0;
+        }
+
+        let head = self.tx_forward.then(|| &self.rx_buf[..self.fwd_len]);
+        if let Some([dest, payload @ ..]) = head.or_else(|| queues.tx_head()) {
+            if wire::is_multicast(*dest) && (payload.len() <= MAX_FRAME) {
+                let dest = *dest;
+                self.start_broadcast(now, dest, queues);
+                return;
+            }
+        }
+
+        self.slot = self.next;
+        let addr = self.nodes[self.slot];
+        self.next = (self.next + 1) % self.node_count;
+
+        // Nothing new for a node until it has given its status for the last
+        let head = self.tx_forward.then(|| &self.rx_buf[..self.fwd_len]);
+        let tx_len = match head.or_else(|| queues.tx_head()) {
+            _ if self.tx_pending == Some(addr) => 0,
+            Some([dest, frame @ ..]) if (*dest == addr) && (frame.len() <= MAX_FRAME) => {
+                let (len, frag) = load_fragment(&mut self.tx_buf, self.tx_offset, frame);
+                self.tx_frag = frag;
+                len
+            },
+            Some([]) => {
+                // No address, nowhere to send it
+                queues.tx_pop();
+                0
+            },
+            _ => 0,
+        };
+        let rx_cap = self.rx_capacity(queues);
+
+        self.header = Header {
+            addr,
+            rx_cap: rx_cap as u16,
+            tx_len: tx_len as u16,
+            status: self.rx_status[self.slot],
+        };
+        self.rx_status[self.slot] = STATUS_NONE;
+        self.stats.polls += 1;
+        self.state = State::Header { sent: 0 };
+        self.since = now;
+    }
+
+    /// The next word to drive onto the bus, if it is our turn
+    pub fn transmit(&mut self, now: u32) -> Option<u16> {
+        match self.state {
+            State::Header { sent } => {
+                let word = self.header.to_words()[sent];
+                self.state = if (sent + 1) < HEADER_WORDS {
+                    State::Header { sent: sent + 1 }
+                } else if self.header.addr == UNASSIGNED_ADDR {
+                    State::Offers { got: 0 }
+                } else {
+                    State::Reply { got: 0 }
+                };
+                Some(word)
+            },
+            State::Assign { sent } => {
+                let word = self.tx_buf[sent] as u16;
+                let sent = sent + 1;
+                if sent == (ASSIGN_LEN + CRC_WORDS) {
+                    self.finish(now);
+                } else {
+                    self.state = State::Assign { sent };
+                }
+                Some(word)
+            },
+            State::Broadcast { sent } => {
+                let header_at = self.node_count;
+                let payload_at = header_at + HEADER_WORDS;
+                let word = if sent < header_at {
+                    ADDR_FLAG | (self.nodes[sent] as u16)
+                } else if sent < payload_at {
+                    self.header.to_words()[sent - header_at]
+                } else {
+                    self.tx_buf[sent - payload_at] as u16
+                };
+                let sent = sent + 1;
+                if (sent == payload_at) && (SyncMsg::from_header(&self.header) == Some(SyncMsg::Sync)) {
+                    self.follow_up = Some(now);
+                }
+                if sent == (payload_at + wire::payload_words(self.send_amt)) {
+                    self.finish(now);
+                } else {
+                    self.state = State::Broadcast { sent };
+                }
+                Some(word)
+            },
+            State::Sending { sent } => {
+                let word = self.tx_buf[sent] as u16;
+                let sent = sent + 1;
+                if sent == wire::payload_words(self.send_amt) {
+                    // Keep the frame until the node says it arrived
+                    self.tx_pending = Some(self.header.addr);
+                    self.finish(now);
+                } else {
+                    self.state = State::Sending { sent };
+                }
+                Some(word)
+            },
+            _ => None,
+        }
+    }
+
+    /// Handle a word heard on the bus
+    pub fn receive<Q: Queues>(&mut self, now: u32, word: u16, queues: &mut Q) {
+        let listening = matches!(self.state, State::Reply { .. } | State::Receiving { .. } | State::Offers { .. });
+        if !listening {
+            return;
+        }
+        if let State::Offers { got } = self.state {
+            self.offer_word(now, got, word);
+            return;
+        }
+        if wire::is_addr(word) {
+            // Someone else is polling
+            self.stats.errors += 1;
+            if matches!(self.state, State::Receiving { .. }) {
+                self.rx_status[self.slot] = STATUS_NAK;
+            }
+            self.finish(now);
+            return;
+        }
+
+        match self.state {
+            State::Reply { got } => {
+                self.reply_words[got] = word;
+                if (got + 1) == REPLY_WORDS {
+                    self.replied(now, queues);
+                } else {
+                    self.state = State::Reply { got: got + 1 };
+                }
+            },
+            State::Receiving { got } => {
+                let len = self.reply.tx_amt as usize;
+                match got {
+                    0 => self.rx_header = word as u8,
+                    _ if got < len => self.rx_buf[1 + self.reassembly.offset() + got] = word as u8,
+                    _ => self.rx_crc[got - len] = word as u8,
+                }
+                let got = got + 1;
+                if got == wire::payload_words(len) {
+                    self.deliver(now, queues);
+                    self.start_send(now);
+                } else {
+                    self.state = State::Receiving { got };
+                }
+            },
+            _ => {},
+        }
+    }
+
+    /// The node's reply is in
+    fn replied<Q: Queues>(&mut self, now: u32, queues: &mut Q) {
+        let reply = match Reply::from_words(&self.reply_words) {
+            Some(r) if r.tx_amt <= self.header.rx_cap => r,
+            _ => {
+                // The node must not send more than we offered
+                self.stats.errors += 1;
+                self.finish(now);
+                return;
+            },
+        };
+        self.reply = reply;
+        if self.probation == Some(self.header.addr) {
+            self.probation = None;
+        }
+
+        // Our last fragment to this node is either done with, or to be sent
+        // again
+        if self.tx_pending == Some(self.header.addr) {
+            self.tx_pending = None;
+            if reply.status == STATUS_ACK {
+                self.fragment_sent(queues);
+            } else {
+                self.stats.retries += 1;
+            }
+        }
+
+        // The node takes all of our frame, or none of it. Keep it for next time.
+        let tx_len = self.header.tx_len;
+        self.send_amt = if (tx_len != 0) && (reply.rx_amt == tx_len) {
+            tx_len as usize
+        } else {
+            if tx_len != 0 {
+                self.stats.refused += 1;
+            }
+            0
+        };
+
+        if reply.tx_amt != 0 {
+            self.state = State::Receiving { got: 0 };
+            self.since = now;
+        } else {
+            self.start_send(now);
+        }
+    }
+
+    /// Room for the polled node's next fragment. None while part of another
+    /// node's frame is held, or a frame waits to be forwarded.
+    fn rx_capacity<Q: Queues>(&self, queues: &mut Q) -> usize {
+        let other = self.reassembly.is_active() && (self.reassembly_slot != self.slot);
+        if other || (self.fwd_len != 0) {
+            return 0;
+        }
+        frag::payload_room(queues.rx_capacity(), self.reassembly.offset())
+    }
+
+    /// The fragment in `tx_buf` is done with. Pop its frame if it was the last.
+    fn fragment_sent<Q: Queues>(&mut self, queues: &mut Q) {
+        self.tx_offset += self.tx_frag;
+        let head = self.tx_forward.then(|| &self.rx_buf[..self.fwd_len]);
+        let len = match head.or_else(|| queues.tx_head()) {
+            Some([_, frame @ ..]) => frame.len(),
+            _ => 0,
+        };
+        if self.tx_offset >= len {
+            self.stats.frames_sent += 1;
+            self.stats.bytes_sent += len as u32;
+            self.tx_offset = 0;
+            if self.tx_forward {
+                self.tx_forward = false;
+                self.fwd_len = 0;
+            } else {
+                queues.tx_pop();
+            }
+        }
+    }
+
+    /// Where a frame from a node, `frame`, goes: the node to forward it to, or
+    /// `None` for the incoming queue
+    fn next_hop(&self, frame: &[u8]) -> Option<u8> {
+        let [_src, dest, ..] = frame else {
+            return None;
+        };
+        if frame.len() > MAX_FRAME {
+            // No room for the source
+            return None;
+        }
+        let routes = self.routes.iter().flatten().copied();
+        let nodes = &self.nodes[..self.node_count];
+        route::next_hop(*dest, routes, |addr| nodes.contains(&addr))
+    }
+
+    /// The node's fragment and its CRC are in. Keep it only if the CRC
+    /// matches, and deliver the frame once it is whole.
+    fn deliver<Q: Queues>(&mut self, now: u32, queues: &mut Q) {
+        let len = self.reply.tx_amt as usize - FRAG_HEADER_LEN;
+        let data = &self.rx_buf[2 + self.reassembly.offset()..][..len];
+        if crc16_update(crc16(&[self.rx_header]), data).to_le_bytes() != self.rx_crc {
+            self.stats.crc_errors += 1;
+            self.rx_status[self.slot] = STATUS_NAK;
+            return;
+        }
+        self.rx_status[self.slot] = STATUS_ACK;
+        self.reassembly_slot = self.slot;
+        match self.reassembly.accept(now, &mut self.rx_buf[2..], self.rx_header, len) {
+            Accepted::Complete(len) => {
+                self.rx_buf[1] = self.header.addr;
+                let frame = &self.rx_buf[1..2 + len];
+                let stored = match self.next_hop(frame) {
+                    Some(via) => {
+                        self.rx_buf[0] = via;
+                        self.fwd_len = 2 + len;
+                        true
+                    },
+                    None => queues.rx_push(frame),
+                };
+                if stored {
+                    self.stats.frames_received += 1;
+                    self.stats.bytes_received += len as u32;
+                }
+            },
+            Accepted::Dropped => self.stats.frag_dropped += 1,
+            Accepted::Partial | Accepted::Repeat => {},
+        }
+    }
+
+    /// The time sync due in place of the next poll, if any
+    fn next_sync(&mut self, now: u32) -> Option<SyncMsg> {
+        if let Some(time) = self.follow_up.take() {
+            return Some(SyncMsg::FollowUp(time));
+        }
+        let interval = self.sync_interval?;
+        if elapsed(now, self.last_sync) < interval {
+            return None;
+        }
+        self.last_sync = now;
+        Some(SyncMsg::Sync)
+    }
+
+    /// Start a round of enumeration in place of the next poll, if discovering,
+    /// and the last round is done with. Returns false if not.
+    fn start_enumeration(&mut self, now: u32) -> bool {
+        let Some(disc) = self.discovery else {
+            return false;
+        };
+        if self.probation.is_some() || (self.enumerated && (self.node_count != 0)) {
+            self.enumerated = false;
+            return false;
+        }
+
+        let nodes = &self.nodes[..self.node_count];
+        if (self.node_count == MAX_NODES) || discovery::free_addr(|a| nodes.contains(&a)).is_none() {
+            // No room for another node
+            self.discovery = None;
+            return false;
+        }
+
+        self.enumerated = true;
+        self.header = disc.header();
+        self.state = State::Header { sent: 0 };
+        self.since = now;
+        true
+    }
+
+    /// A word of a reply to an enumeration, or of its offer
+    fn offer_word(&mut self, now: u32, got: usize, word: u16) {
+        if wire::is_addr(word) {
+            // Someone else is polling
+            self.stats.errors += 1;
+            self.end_round(now, Round::Collided);
+            return;
+        }
+        match self.reply_words.get_mut(got) {
+            Some(w) => *w = word,
+            None => self.offer_buf[got - REPLY_WORDS] = word as u8,
+        }
+        let got = got + 1;
+        if got < (REPLY_WORDS + UID_LEN + CRC_WORDS) {
+            self.state = State::Offers { got };
+            return;
+        }
+
+        let expected = Reply { tx_amt: UID_LEN as u16, rx_amt: ASSIGN_LEN as u16, status: STATUS_NONE };
+        let uid = match Reply::from_words(&self.reply_words) {
+            Some(r) if r == expected => discovery::parse_offer(&self.offer_buf),
+            _ => None,
+        };
+        let nodes = &self.nodes[..self.node_count];
+        let addr = discovery::free_addr(|a| nodes.contains(&a));
+        let (Some(uid), Some(addr)) = (uid, addr) else {
+            self.end_round(now, Round::Collided);
+            return;
+        };
+
+        self.tx_buf[..ASSIGN_LEN + CRC_WORDS].copy_from_slice(&discovery::assignment(&uid, addr));
+        self.nodes[self.node_count] = addr;
+        self.rx_status[self.node_count] = STATUS_NONE;
+        self.node_count += 1;
+        self.probation = Some(addr);
+        self.end_round(now, Round::Assigned);
+        self.state = State::Assign { sent: 0 };
+    }
+
+    /// A round of enumeration is over, ending discovery if it was the last
+    fn end_round(&mut self, now: u32, round: Round) {
+        if let Some(disc) = &mut self.discovery {
+            if disc.round(round) {
+                self.discovery = None;
+            }
+        }
+        self.finish(now);
+    }
+
+    /// The address last assigned went unanswered. Drop it from the end of the
+    /// poll list, where it was added.
+    fn fail_probation(&mut self) {
+        self.probation = None;
+        self.node_count -= 1;
+        if self.next >= self.node_count {
+            self.next = 0;
+        }
+    }
+
+    /// Send the frame at the head of the queue to `dest`, a broadcast or group
+    /// address. Nobody replies, so it is done with once sent.
+    fn start_broadcast<Q: Queues>(&mut self, now: u32, dest: u8, queues: &mut Q) {
+        let Some([_, frame @ ..]) = queues.tx_head() else {
+            return;
+        };
+        let (len, frag) = load_fragment(&mut self.tx_buf, self.tx_offset, frame);
+        self.tx_frag = frag;
+        self.fragment_sent(queues);
+
+        self.header =
+            Header { addr: dest & ADDR_MASK, rx_cap: 0, tx_len: len as u16, status: STATUS_NONE };
+        self.send_amt = len;
+        self.state = State::Broadcast { sent: 0 };
+        self.since = now;
+    }
+
+    fn start_send(&mut self, now: u32) {
+        if self.send_amt != 0 {
+            self.state = State::Sending { sent: 0 };
+        } else {
+            self.finish(now);
+        }
+    }
+
+    fn finish(&mut self, now: u32) {
+        self.state = State::Idle;
+        self.last_poll = now;
+    }
+}
+
+/// Load the next fragment of `frame`, from `offset`, into `tx_buf`. Returns its
+/// payload length, and the frame bytes in it.
+fn load_fragment(
+    tx_buf: &mut [u8; MAX_PAYLOAD + CRC_WORDS],
+    offset: usize,
+    frame: &[u8],
+) -> (usize, usize) {
+    let (frag_header, len) = frag::fragment(frame.len(), offset);
+    let payload = FRAG_HEADER_LEN + len;
+    tx_buf[0] = frag_header;
+    tx_buf[1..payload].copy_from_slice(&frame[offset..][..len]);
+    let crc = crc16(&tx_buf[..payload]);
+    tx_buf[payload..payload + CRC_WORDS].copy_from_slice(&crc.to_le_bytes());
+    (payload, len)
+}
@@ -0,0 +1,753 @@
+//! A simulated multi-drop bus
+//!
+//! [SimBus] steps time one word ([WORD_BITS] bit times) at a time. In each
+//! step, every attached station checks its timeouts, and is asked for a word
+//! to drive. Then:
+//!
+//! * If nobody drives, the bus is idle, unless noise injects a glitch word.
+//! * If one station drives, every other station hears its word, unless noise
+//!   flips one of its bits.
+//! * If more than one station drives, it is a collision. The other stations
+//!   hear the drivers' words garbled together (modelled as a wired-AND).
+//!
+//! Noise is pseudo-random from a fixed seed, so runs are repeatable.
+//!
+//! Each station sees time through its own [Clock], which may be offset from
+//! the bus's, and run fast or slow.
+//!
+//! The stations are the [Node] and [Router] state machines, not the firmware,
+//! see the crate docs for what that covers.
+
+use std::collections::VecDeque;
+
+use crate::{
+    discovery::UID_LEN,
+    frag::MAX_FRAME,
+    node::Node,
+    router::Router,
+    wire::WORD_BITS,
+    Queues, Stats,
+};
+
+/// Frame queues for a simulated station
+#[derive(Debug, Default)]
+pub struct SimQueues {
+    /// Frames waiting to be sent
+    pub tx: VecDeque<Vec<u8>>,
+    /// Frames received
+    pub rx: Vec<Vec<u8>>,
+    /// Most frames held in `rx` before more are refused, or zero for no limit
+    pub rx_limit: usize,
+}
+
+impl Queues for SimQueues {
+    fn tx_head(&mut self) -> Option<&[u8]> {
+        self.tx.front().map(Vec::as_slice)
+    }
+
+    fn tx_pop(&mut self) {
+        self.tx.pop_front();
+    }
+
+    fn rx_capacity(&mut self) -> usize {
+        if (self.rx_limit != 0) && (self.rx.len() >= self.rx_limit) {
+            0
+        } else {
+            // Room for a frame, and its address prefix
+            MAX_FRAME + 1
+        }
+    }
+
+    fn rx_push(&mut self, frame: &[u8]) -> bool {
+        if self.rx_capacity() < frame.len() {
+            return false;
+        }
+        self.rx.push(frame.to_vec());
+        true
+    }
+}
+
+pub enum Role {
+    Node(Node),
+    Router(Router),
+}
+
+/// A station's clock, as it differs from the bus's
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Clock {
+    /// Added to the bus time
+    pub offset: u32,
+    /// Parts per million fast (or slow, if negative)
+    pub ppm: i32,
+}
+
+impl Clock {
+    /// This clock's time, at bus time `now`
+    pub fn local(&self, now: u32) -> u32 {
+        let drift = ((now as i64) * (self.ppm as i64)) / 1_000_000;
+        now.wrapping_add(self.offset).wrapping_add(drift as u32)
+    }
+}
+
+/// A node or router attached to the bus
+pub struct Station {
+    pub role: Role,
+    pub queues: SimQueues,
+    pub clock: Clock,
+    /// Detached stations neither drive nor hear the bus
+    pub attached: bool,
+    driving: bool,
+}
+
+impl Station {
+    pub fn stats(&self) -> &Stats {
+        match &self.role {
+            Role::Node(n) => n.stats(),
+            Role::Router(r) => r.stats(),
+        }
+    }
+
+    fn poll(&mut self, now: u32) {
+        let now = self.clock.local(now);
+        match &mut self.role {
+            Role::Node(n) => n.poll(now),
+            Role::Router(r) => r.poll(now, &mut self.queues),
+        }
+    }
+
+    fn transmit(&mut self, now: u32) -> Option<u16> {
+        let now = self.clock.local(now);
+        match &mut self.role {
+            Role::Node(n) => n.transmit(now),
+            Role::Router(r) => r.transmit(now),
+        }
+    }
+
+    fn receive(&mut self, now: u32, word: u16) {
+        let now = self.clock.local(now);
+        match &mut self.role {
+            Role::Node(n) => n.receive(now, word, &mut self.queues),
+            Role::Router(r) => r.receive(now, word, &mut self.queues),
+        }
+    }
+}
+
+/// Noise injected by the bus, in parts per million of word times
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Noise {
+    /// Chance that a driven word has one bit flipped
+    pub flip_ppm: u32,
+    /// Chance that an idle word time carries a random glitch word
+    pub glitch_ppm: u32,
+}
+
+/// Counters kept by the bus
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct BusStats {
+    /// Word times simulated
+    pub words: u64,
+    /// Word times with exactly one driver
+    pub busy: u64,
+    /// Word times with more than one driver
+    pub collisions: u64,
+    /// Words with a bit flipped by noise
+    pub flips: u64,
+    /// Glitch words injected on an idle bus
+    pub glitches: u64,
+}
+
+pub struct SimBus {
+    stations: Vec<Station>,
+    now: u32,
+    rng: u64,
+    noise: Noise,
+    stats: BusStats,
+}
+
+impl SimBus {
+    /// An empty, noiseless bus. `seed` seeds the noise.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            stations: Vec::new(),
+            now: 0,
+            // xorshift must not start from zero
+            rng: seed | 1,
+            noise: Noise::default(),
+            stats: BusStats::default(),
+        }
+    }
+
+    pub fn set_noise(&mut self, noise: Noise) {
+        self.noise = noise;
+    }
+
+    /// Attach a node, returning its station index. Its unique ID is made from
+    /// the station index.
+    pub fn add_node(&mut self, addr: u8) -> usize {
+        let mut node = Node::new(addr);
+        let mut uid = [0x5A; UID_LEN];
+        uid[..8].copy_from_slice(&(self.stations.len() as u64).to_le_bytes());
+        node.set_uid(uid);
+        self.add(Role::Node(node))
+    }
+
+    /// Attach a router, returning its station index
+    pub fn add_router(&mut self, nodes: &[u8], interval_bits: u32) -> usize {
+        self.add(Role::Router(Router::new(nodes, interval_bits)))
+    }
+
+    fn add(&mut self, role: Role) -> usize {
+        self.stations.push(Station {
+            role,
+            queues: SimQueues::default(),
+            clock: Clock::default(),
+            attached: true,
+            driving: false,
+        });
+        self.stations.len() - 1
+    }
+
+    pub fn station(&self, idx: usize) -> &Station {
+        &self.stations[idx]
+    }
+
+    pub fn station_mut(&mut self, idx: usize) -> &mut Station {
+        &mut self.stations[idx]
+    }
+
+    /// Current time, in bit times
+    pub fn now(&self) -> u32 {
+        self.now
+    }
+
+    pub fn stats(&self) -> &BusStats {
+        &self.stats
+    }
+
+    /// Simulate one word time
+    pub fn step(&mut self) {
+        let now = self.now;
+        let mut drivers = 0;
+        let mut driven = 0x1FF;
+
+        for st in self.stations.iter_mut() {
+            st.driving = false;
+            if !st.attached {
+                continue;
+            }
+            st.poll(now);
+            if let Some(word) = st.transmit(now) {
+                st.driving = true;
+                drivers += 1;
+                driven &= word;
+            }
+        }
+
+        let word = match drivers {
+            0 => {
+                if self.chance(self.noise.glitch_ppm) {
+                    self.stats.glitches += 1;
+                    Some((self.next_rand() as u16) & 0x1FF)
+                } else {
+                    None
+                }
+            },
+            1 => {
+                self.stats.busy += 1;
+                if self.chance(self.noise.flip_ppm) {
+                    self.stats.flips += 1;
+                    Some(driven ^ (1 << (self.next_rand() % 9)))
+                } else {
+                    Some(driven)
+                }
+            },
+            _ => {
+                self.stats.collisions += 1;
+                Some(driven)
+            },
+        };
+
+        if let Some(word) = word {
+            for st in self.stations.iter_mut() {
+                if st.attached && !st.driving {
+                    st.receive(now, word);
+                }
+            }
+        }
+
+        self.stats.words += 1;
+        self.now = self.now.wrapping_add(WORD_BITS);
+    }
+
+    /// Simulate for at least `bits` bit times
+    pub fn run(&mut self, bits: u32) {
+        let end = self.now.wrapping_add(bits);
+        while (end.wrapping_sub(self.now) as i32) > 0 {
+            self.step();
+        }
+    }
+
+    /// Simulate until `done` returns true, for at most `bits` bit times.
+    /// Returns false if `done` never returned true.
+    pub fn run_until<F: FnMut(&Self) -> bool>(&mut self, bits: u32, mut done: F) -> bool {
+        let end = self.now.wrapping_add(bits);
+        while (end.wrapping_sub(self.now) as i32) > 0 {
+            if done(self) {
+                return true;
+            }
+            self.step();
+        }
+        done(self)
+    }
+
+    fn chance(&mut self, ppm: u32) -> bool {
+        (ppm != 0) && ((self.next_rand() % 1_000_000) < ppm as u64)
+    }
+
+    // xorshift64*
+    fn next_rand(&mut self) -> u64 {
+        self.rng ^= self.rng >> 12;
+        self.rng ^= self.rng << 25;
+        self.rng ^= self.rng >> 27;
+        self.rng.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        frag::{FRAG_DATA_MAX, REASSEMBLY_TIMEOUT_BITS},
+        discovery::UNASSIGNED_ADDR,
+        route::{Route, HOST_ADDR},
+        router::DEFAULT_INTERVAL_BITS,
+        wire::{BROADCAST_ADDR, GROUP_ADDR_MIN},
+    };
+
+    const SECOND_AT_8M: u32 = 8_000_000;
+
+    fn frame(len: usize, seed: u8) -> Vec<u8> {
+        (0..len).map(|i| (i as u8).wrapping_mul(31).wrapping_add(seed)).collect()
+    }
+
+    #[test]
+    fn frames_both_ways() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1, 2], DEFAULT_INTERVAL_BITS);
+        let n1 = bus.add_node(1);
+        let n2 = bus.add_node(2);
+
+        let mut to_2 = vec![2];
+        to_2.extend(frame(40, 7));
+        bus.station_mut(r).queues.tx.push_back(to_2);
+        bus.station_mut(n1).queues.tx.push_back(frame(100, 1));
+        bus.station_mut(n2).queues.tx.push_back(frame(3, 2));
+
+        assert!(bus.run_until(100_000, |b| {
+            b.station(r).queues.rx.len() == 2 && b.station(n2).queues.rx.len() == 1
+        }));
+
+        let mut from_1 = vec![1];
+        from_1.extend(frame(100, 1));
+        let mut from_2 = vec![2];
+        from_2.extend(frame(3, 2));
+        assert_eq!(bus.station(r).queues.rx, vec![from_1, from_2]);
+        assert_eq!(bus.station(n2).queues.rx, vec![[&[2][..], &frame(40, 7)].concat()]);
+        assert!(bus.station(n1).queues.rx.is_empty());
+        assert_eq!(bus.stats().collisions, 0);
+    }
+
+    #[test]
+    fn head_of_line_waits_for_its_node() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1, 2], DEFAULT_INTERVAL_BITS);
+        let n1 = bus.add_node(1);
+        let n2 = bus.add_node(2);
+
+        bus.station_mut(r).queues.tx.push_back(vec![2, 0xAA]);
+        bus.station_mut(r).queues.tx.push_back(vec![1, 0xBB]);
+
+        assert!(bus.run_until(100_000, |b| b.station(r).queues.tx.is_empty()));
+        assert_eq!(bus.station(n1).queues.rx, vec![vec![1, 0xBB]]);
+        assert_eq!(bus.station(n2).queues.rx, vec![vec![2, 0xAA]]);
+    }
+
+    #[test]
+    fn full_node_refuses_and_retries() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1], DEFAULT_INTERVAL_BITS);
+        let n1 = bus.add_node(1);
+
+        bus.station_mut(n1).queues.rx_limit = 1;
+        bus.station_mut(n1).queues.rx.push(vec![0]);
+        bus.station_mut(r).queues.tx.push_back(vec![1, 0xCC]);
+
+        bus.run(10 * DEFAULT_INTERVAL_BITS);
+        assert!(bus.station(n1).stats().refused > 0);
+        assert_eq!(bus.station(r).queues.tx.len(), 1);
+
+        // Make room, and the frame gets through
+        bus.station_mut(n1).queues.rx.clear();
+        assert!(bus.run_until(100_000, |b| b.station(r).queues.tx.is_empty()));
+        assert_eq!(bus.station(n1).queues.rx, vec![vec![1, 0xCC]]);
+    }
+
+    #[test]
+    fn missing_node_times_out() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1, 2], DEFAULT_INTERVAL_BITS);
+        let n1 = bus.add_node(1);
+        let n2 = bus.add_node(2);
+        bus.station_mut(n2).attached = false;
+        bus.station_mut(n1).queues.tx.push_back(frame(10, 0));
+
+        bus.run(SECOND_AT_8M / 100);
+        let rs = *bus.station(r).stats();
+        assert!(rs.timeouts > 0);
+        // Every other poll is to the missing node, one may still be running
+        assert!(rs.timeouts.abs_diff(rs.polls / 2) <= 1);
+        assert_eq!(bus.station(r).queues.rx.len(), 1);
+    }
+
+    #[test]
+    fn router_gone_midframe_drops_and_resends() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1], DEFAULT_INTERVAL_BITS);
+        let n1 = bus.add_node(1);
+
+        let mut to_1 = vec![1];
+        to_1.extend(frame(200, 9));
+        bus.station_mut(r).queues.tx.push_back(to_1.clone());
+
+        // Header, reply, then part of the payload
+        assert!(bus.run_until(100_000, |b| match &b.station(r).role {
+            Role::Router(rt) => !rt.is_idle(),
+            Role::Node(_) => false,
+        }));
+        (0..(5 + 4 + 50)).for_each(|_| bus.step());
+        bus.station_mut(r).attached = false;
+        bus.run(10_000);
+
+        assert!(bus.station(n1).queues.rx.is_empty());
+        assert_eq!(bus.station(n1).stats().truncated, 1);
+
+        // The router kept the frame, and sends it again when it comes back
+        bus.station_mut(r).attached = true;
+        assert!(bus.run_until(100_000, |b| b.station(r).queues.tx.is_empty()));
+        assert_eq!(bus.station(n1).queues.rx, vec![to_1]);
+        assert!(bus.station(r).stats().retries > 0);
+    }
+
+    #[test]
+    fn duplicate_address_collides_and_recovers() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1], DEFAULT_INTERVAL_BITS);
+        let a = bus.add_node(1);
+        let b = bus.add_node(1);
+
+        // Both reply at once, garbling the reply
+        bus.station_mut(a).queues.tx.push_back(frame(20, 1));
+        bus.station_mut(b).queues.tx.push_back(frame(20, 2));
+        bus.run(SECOND_AT_8M / 1000);
+        assert!(bus.stats().collisions > 0);
+
+        // Unplug one, and the other gets through
+        bus.station_mut(b).attached = false;
+        bus.station_mut(a).queues.tx.push_back(frame(20, 3));
+        assert!(bus.run_until(100_000, |bus| bus.station(a).queues.tx.is_empty()));
+        let mut from_a = vec![1];
+        from_a.extend(frame(20, 3));
+        assert_eq!(bus.station(r).queues.rx.last(), Some(&from_a));
+    }
+
+    #[test]
+    fn two_routers_collide() {
+        let mut bus = SimBus::new(1);
+        bus.add_router(&[1], DEFAULT_INTERVAL_BITS);
+        bus.add_router(&[1], DEFAULT_INTERVAL_BITS);
+        bus.add_node(1);
+        bus.run(SECOND_AT_8M / 1000);
+        assert!(bus.stats().collisions > 0);
+    }
+
+    #[test]
+    fn survives_noise() {
+        let mut bus = SimBus::new(0x1234_5678);
+        bus.set_noise(Noise { flip_ppm: 2_000, glitch_ppm: 2_000 });
+        let r = bus.add_router(&[1, 2, 3], DEFAULT_INTERVAL_BITS);
+        let nodes = [bus.add_node(1), bus.add_node(2), bus.add_node(3)];
+
+        // Every frame is different, so each can be found at the other end
+        let mut sent_up = Vec::new();
+        let mut sent_down = vec![Vec::new(); nodes.len()];
+        for seq in 0..50u8 {
+            for (i, n) in nodes.iter().enumerate() {
+                let up = frame(64, seq.wrapping_mul(3).wrapping_add(i as u8));
+                bus.station_mut(*n).queues.tx.push_back(up.clone());
+                sent_up.push([&[i as u8 + 1][..], &up].concat());
+
+                if seq < 10 {
+                    let down = frame(32, seq.wrapping_mul(3).wrapping_add(i as u8).wrapping_add(100));
+                    let down = [&[i as u8 + 1][..], &down].concat();
+                    bus.station_mut(r).queues.tx.push_back(down.clone());
+                    sent_down[i].push(down);
+                }
+            }
+        }
+
+        assert!(bus.run_until(SECOND_AT_8M, |b| {
+            b.station(r).queues.tx.is_empty()
+                && nodes.iter().all(|n| b.station(*n).queues.tx.is_empty())
+        }));
+        assert!(bus.stats().flips > 0);
+        assert!(bus.stats().glitches > 0);
+        let rs = *bus.station(r).stats();
+        assert!(rs.crc_errors > 0);
+        assert!(rs.retries > 0);
+
+        // Everything arrives intact, if sometimes more than once
+        let got_up = &bus.station(r).queues.rx;
+        assert!(got_up.iter().all(|f| sent_up.contains(f)));
+        assert!(sent_up.iter().all(|f| got_up.contains(f)));
+        for (i, n) in nodes.iter().enumerate() {
+            let got_down = &bus.station(*n).queues.rx;
+            assert!(got_down.iter().all(|f| sent_down[i].contains(f)));
+            assert!(sent_down[i].iter().all(|f| got_down.contains(f)));
+        }
+    }
+
+    #[test]
+    fn broadcast_reaches_every_node() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1, 2, 3], DEFAULT_INTERVAL_BITS);
+        let nodes = [bus.add_node(1), bus.add_node(2), bus.add_node(3)];
+
+        // One node is full, and misses out
+        bus.station_mut(nodes[2]).queues.rx_limit = 1;
+        bus.station_mut(nodes[2]).queues.rx.push(vec![0]);
+        let bcast = [&[BROADCAST_ADDR][..], &frame(50, 3)].concat();
+        bus.station_mut(r).queues.tx.push_back(bcast.clone());
+        bus.station_mut(r).queues.tx.push_back(vec![2, 0xDD]);
+
+        assert!(bus.run_until(100_000, |b| b.station(r).queues.tx.is_empty()));
+        bus.run(10 * DEFAULT_INTERVAL_BITS);
+        assert_eq!(bus.station(nodes[0]).queues.rx, vec![bcast.clone()]);
+        assert_eq!(bus.station(nodes[1]).queues.rx, vec![bcast, vec![2, 0xDD]]);
+        assert_eq!(bus.station(nodes[2]).queues.rx, vec![vec![0]]);
+        assert_eq!(bus.station(nodes[2]).stats().refused, 1);
+        assert_eq!(bus.station(r).stats().frames_sent, 2);
+        assert_eq!(bus.stats().collisions, 0);
+    }
+
+    #[test]
+    fn group_reaches_members() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1, 2, 3], DEFAULT_INTERVAL_BITS);
+        let nodes = [bus.add_node(1), bus.add_node(2), bus.add_node(3)];
+        for n in [nodes[0], nodes[2]] {
+            if let Role::Node(node) = &mut bus.station_mut(n).role {
+                node.set_group(Some(GROUP_ADDR_MIN));
+            }
+        }
+
+        let to_group = [&[GROUP_ADDR_MIN][..], &frame(20, 4)].concat();
+        bus.station_mut(r).queues.tx.push_back(to_group.clone());
+        bus.station_mut(nodes[1]).queues.tx.push_back(frame(10, 5));
+
+        assert!(bus.run_until(100_000, |b| b.station(nodes[1]).queues.tx.is_empty()));
+        bus.run(10 * DEFAULT_INTERVAL_BITS);
+        assert_eq!(bus.station(nodes[0]).queues.rx, vec![to_group.clone()]);
+        assert!(bus.station(nodes[1]).queues.rx.is_empty());
+        assert_eq!(bus.station(nodes[2]).queues.rx, vec![to_group]);
+        assert_eq!(bus.station(r).queues.rx, vec![[&[2][..], &frame(10, 5)].concat()]);
+    }
+
+    #[test]
+    fn routed_between_nodes() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1, 2, 3], DEFAULT_INTERVAL_BITS);
+        let nodes = [bus.add_node(1), bus.add_node(2), bus.add_node(3)];
+        if let Role::Router(router) = &mut bus.station_mut(r).role {
+            router.set_route(0, Some(Route { dest: 2, via: 2 }));
+            // Node 3's host bridges to 0x20
+            router.set_route(1, Some(Route { dest: 0x20, via: 3 }));
+        }
+
+        let to_2 = [&[2][..], &frame(FRAG_DATA_MAX + 1, 1)].concat();
+        let to_20 = [&[0x20][..], &frame(10, 2)].concat();
+        let to_host = [&[HOST_ADDR][..], &frame(10, 3)].concat();
+        bus.station_mut(nodes[0]).queues.tx.extend([to_2.clone(), to_20.clone()]);
+        bus.station_mut(nodes[1]).queues.tx.push_back(to_host.clone());
+
+        assert!(bus.run_until(SECOND_AT_8M / 100, |b| {
+            !b.station(nodes[1]).queues.rx.is_empty() && !b.station(nodes[2]).queues.rx.is_empty()
+        }));
+        bus.run(10 * DEFAULT_INTERVAL_BITS);
+        assert_eq!(bus.station(nodes[1]).queues.rx, vec![[&[2, 1][..], &to_2].concat()]);
+        assert_eq!(bus.station(nodes[2]).queues.rx, vec![[&[3, 1][..], &to_20].concat()]);
+        assert_eq!(bus.station(r).queues.rx, vec![[&[2][..], &to_host].concat()]);
+        assert_eq!(bus.station(r).stats().refused, 0);
+        assert_eq!(bus.stats().collisions, 0);
+    }
+
+    #[test]
+    fn nodes_follow_router_time() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1, 2], DEFAULT_INTERVAL_BITS);
+        let nodes = [bus.add_node(1), bus.add_node(2)];
+        if let Role::Router(router) = &mut bus.station_mut(r).role {
+            router.set_sync_interval(Some(SECOND_AT_8M / 100));
+        }
+        bus.station_mut(r).clock = Clock { offset: 5_000, ppm: 0 };
+        bus.station_mut(nodes[0]).clock = Clock { offset: 0, ppm: 8_000 };
+        bus.station_mut(nodes[1]).clock = Clock { offset: u32::MAX - 1_000_000, ppm: -3_000 };
+
+        // Traffic carries on around the syncs
+        let to_2 = [&[2][..], &frame(FRAG_DATA_MAX + 1, 1)].concat();
+        bus.station_mut(r).queues.tx.push_back(to_2.clone());
+        bus.station_mut(nodes[0]).queues.tx.push_back(frame(300, 2));
+
+        bus.run(SECOND_AT_8M / 10);
+        for n in nodes {
+            let st = bus.station(n);
+            let Role::Node(node) = &st.role else {
+                unreachable!();
+            };
+            let router_time = bus.station(r).clock.local(bus.now());
+            let bus_time = node.clock().bus_time(st.clock.local(bus.now())).unwrap();
+            let off = bus_time.wrapping_sub(router_time) as i32;
+            assert!(off.abs() <= 2, "{off}");
+            assert!(node.clock().offset().unwrap().abs() <= 2);
+        }
+        assert_eq!(bus.station(nodes[1]).queues.rx, vec![to_2]);
+        assert_eq!(bus.station(r).queues.rx, vec![[&[1][..], &frame(300, 2)].concat()]);
+        assert_eq!(bus.stats().collisions, 0);
+    }
+
+    #[test]
+    fn discovers_nodes() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1], DEFAULT_INTERVAL_BITS);
+        let known = bus.add_node(1);
+        let new: Vec<usize> = (0..5).map(|_| bus.add_node(UNASSIGNED_ADDR)).collect();
+        bus.station_mut(known).queues.tx.push_back(frame(10, 1));
+        let Role::Router(router) = &mut bus.station_mut(r).role else {
+            unreachable!();
+        };
+        router.start_discovery();
+
+        assert!(bus.run_until(SECOND_AT_8M / 10, |b| {
+            matches!(&b.station(r).role, Role::Router(router) if !router.is_discovering())
+        }));
+        let Role::Router(router) = &bus.station(r).role else {
+            unreachable!();
+        };
+        let mut polled = router.nodes().to_vec();
+        let mut addrs: Vec<u8> = new
+            .iter()
+            .map(|n| match &bus.station(*n).role {
+                Role::Node(node) => node.addr(),
+                Role::Router(_) => unreachable!(),
+            })
+            .chain([1])
+            .collect();
+        polled.sort();
+        addrs.sort();
+        assert_eq!(addrs, vec![1, 2, 3, 4, 5, 6]);
+        assert_eq!(polled, addrs);
+        // All five offered in the first round
+        assert!(bus.stats().collisions > 0);
+
+        // And they are polled as usual
+        let to_6 = [&[6][..], &frame(10, 2)].concat();
+        bus.station_mut(r).queues.tx.push_back(to_6);
+        let six = new.iter().copied().find(|n| matches!(&bus.station(*n).role, Role::Node(n) if n.addr() == 6));
+        assert!(bus.run_until(SECOND_AT_8M / 100, |b| !b.station(six.unwrap()).queues.rx.is_empty()));
+        assert_eq!(bus.station(r).queues.rx, vec![[&[1][..], &frame(10, 1)].concat()]);
+    }
+
+    #[test]
+    fn large_frames_both_ways() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1, 2], DEFAULT_INTERVAL_BITS);
+        let n1 = bus.add_node(1);
+        let n2 = bus.add_node(2);
+
+        // Both nodes send at once, and the router takes one frame at a time
+        let to_1 = [&[1][..], &frame(MAX_FRAME, 1)].concat();
+        bus.station_mut(r).queues.tx.push_back(to_1.clone());
+        bus.station_mut(n1).queues.tx.push_back(frame(FRAG_DATA_MAX + 1, 2));
+        bus.station_mut(n2).queues.tx.push_back(frame(400, 3));
+
+        assert!(bus.run_until(SECOND_AT_8M / 100, |b| {
+            b.station(r).queues.rx.len() == 2 && b.station(r).queues.tx.is_empty()
+        }));
+        let rx = &bus.station(r).queues.rx;
+        assert!(rx.contains(&[&[1][..], &frame(FRAG_DATA_MAX + 1, 2)].concat()));
+        assert!(rx.contains(&[&[2][..], &frame(400, 3)].concat()));
+        assert_eq!(bus.station(n1).queues.rx, vec![to_1]);
+        assert_eq!(bus.station(r).stats().frag_dropped, 0);
+        assert_eq!(bus.stats().collisions, 0);
+    }
+
+    #[test]
+    fn large_broadcast() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1, 2], DEFAULT_INTERVAL_BITS);
+        let nodes = [bus.add_node(1), bus.add_node(2)];
+
+        let bcast = [&[BROADCAST_ADDR][..], &frame(MAX_FRAME, 6)].concat();
+        bus.station_mut(r).queues.tx.push_back(bcast.clone());
+
+        assert!(bus.run_until(100_000, |b| b.station(r).queues.tx.is_empty()));
+        bus.run(10 * DEFAULT_INTERVAL_BITS);
+        for n in nodes {
+            assert_eq!(bus.station(n).queues.rx, vec![bcast.clone()]);
+        }
+        assert_eq!(bus.station(r).stats().frames_sent, 1);
+    }
+
+    #[test]
+    fn stale_fragments_dropped() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1], DEFAULT_INTERVAL_BITS);
+        let n1 = bus.add_node(1);
+        bus.station_mut(n1).queues.tx.push_back(frame(MAX_FRAME, 7));
+
+        // The first fragment goes in one poll, and is acknowledged in the next
+        assert!(bus.run_until(100_000, |b| b.station(n1).stats().polls == 2));
+        bus.station_mut(n1).attached = false;
+        bus.run(REASSEMBLY_TIMEOUT_BITS + 10 * DEFAULT_INTERVAL_BITS);
+        assert_eq!(bus.station(r).stats().frag_dropped, 1);
+
+        // The rest of the frame is out of order by the time it arrives
+        bus.station_mut(n1).attached = true;
+        assert!(bus.run_until(100_000, |b| b.station(n1).queues.tx.is_empty()));
+        assert_eq!(bus.station(r).stats().frag_dropped, 2);
+        assert!(bus.station(r).queues.rx.is_empty());
+
+        // Later frames are unaffected
+        bus.station_mut(n1).queues.tx.push_back(frame(MAX_FRAME, 8));
+        assert!(bus.run_until(100_000, |b| !b.station(r).queues.rx.is_empty()));
+        assert_eq!(bus.station(r).queues.rx, vec![[&[1][..], &frame(MAX_FRAME, 8)].concat()]);
+    }
+
+    #[test]
+    fn throughput() {
+        let mut bus = SimBus::new(1);
+        let r = bus.add_router(&[1], 0);
+        let n = bus.add_node(1);
+        for _ in 0..100 {
+            bus.station_mut(n).queues.tx.push_back(frame(FRAG_DATA_MAX, 0));
+        }
+
+        let start = bus.now();
+        assert!(bus.run_until(SECOND_AT_8M, |b| b.station(n).queues.tx.is_empty()));
+        let bits = bus.now() - start;
+
+        // Full frames, with no poll interval, spend most of the bus on payload
+        let payload_bits = bus.station(r).stats().bytes_received * WORD_BITS;
+        assert_eq!(bus.station(r).stats().bytes_received, 100 * FRAG_DATA_MAX as u32);
+        assert!((payload_bits as u64 * 100) / (bits as u64) >= 90);
+    }
+}
@@ -0,0 +1,224 @@
+//! Time sync
+//!
+//! The router's clock is the bus time. Every so often, in place of a poll, the
+//! router broadcasts a sync, then a follow-up giving the time the sync was
+//! sent:
+//!
+//! | From   | Words | Contents                                                 |
+//! | :--    | :--   | :--                                                      |
+//! | Router | m     | The address word of each node the router polls           |
+//! | Router | 6     | Sync header: [BROADCAST_ADDR], zeroes, [STATUS_SYNC]     |
+//! | Router | m     | The address word of each node the router polls           |
+//! | Router | 6     | Follow-up header: [BROADCAST_ADDR], the time LE, [STATUS_FOLLOW_UP] |
+//!
+//! The time takes the place of the rx capacity (low half) and tx len (high
+//! half). Neither header has a payload, and no node replies.
+//!
+//! Each node takes the time it read the last word of the sync header. The
+//! router takes the time that word finished sending, less the time a receiver
+//! has the word before it ends, at the configured baud: half a bit, as the stop
+//! bit is sampled in its middle. That is the time sent in the follow-up.
+//!
+//! A [SyncClock] turns each pair of times into the bus time, correcting for
+//! the difference in both offset and rate between the node's clock and the
+//! router's. Times are in the clock's own units: bit times in the model, and
+//! microseconds in the firmware.
+
+use crate::wire::{Header, BROADCAST_ADDR, STATUS_FOLLOW_UP, STATUS_SYNC};
+
+/// Bits of fraction in [SyncClock]'s rate correction, about 0.06ppm
+const RATE_SHIFT: u32 = 24;
+
+/// Largest rate difference taken as real, 2%. More is taken as a damaged
+/// follow-up.
+const RATE_MAX: i64 = (1 << RATE_SHIFT) / 50;
+
+/// A time sync broadcast
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncMsg {
+    Sync,
+    /// The bus time the last sync was sent at
+    FollowUp(u32),
+}
+
+impl SyncMsg {
+    pub fn to_header(&self) -> Header {
+        let (time, status) = match self {
+            SyncMsg::Sync => (0, STATUS_SYNC),
+            SyncMsg::FollowUp(time) => (*time, STATUS_FOLLOW_UP),
+        };
+        Header { addr: BROADCAST_ADDR, rx_cap: time as u16, tx_len: (time >> 16) as u16, status }
+    }
+
+    /// The time sync broadcast `header` is for, or `None` if it is not one
+    pub fn from_header(header: &Header) -> Option<Self> {
+        if header.addr != BROADCAST_ADDR {
+            return None;
+        }
+        let time = ((header.tx_len as u32) << 16) | (header.rx_cap as u32);
+        match header.status {
+            STATUS_SYNC => Some(SyncMsg::Sync),
+            STATUS_FOLLOW_UP => Some(SyncMsg::FollowUp(time)),
+            _ => None,
+        }
+    }
+}
+
+/// A node's estimate of the bus time, from its own clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SyncClock {
+    /// Our time at the last sync, while waiting for its follow-up
+    pending: Option<u32>,
+    /// Our time, and the bus time, at the last sync followed up
+    anchor: Option<(u32, u32)>,
+    /// Bus time gained per tick of ours, in units of `2^-RATE_SHIFT`
+    rate: i32,
+    /// The bus time less our estimate of it, at the last sync followed up
+    offset: Option<i32>,
+    /// The last follow-up was not believed
+    rejected: bool,
+}
+
+impl Default for SyncClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SyncClock {
+    pub const fn new() -> Self {
+        Self { pending: None, anchor: None, rate: 0, offset: None, rejected: false }
+    }
+
+    /// Forget everything heard so far
+    pub fn reset(&mut self) {
+        *self = Self::new();
+    }
+
+    /// The bus time at our time `now`, once a sync was followed up
+    pub fn bus_time(&self, now: u32) -> Option<u32> {
+        let (ours, bus) = self.anchor?;
+        let since = now.wrapping_sub(ours);
+        let gained = ((since as i64) * (self.rate as i64)) >> RATE_SHIFT;
+        Some(bus.wrapping_add(since).wrapping_add(gained as u32))
+    }
+
+    /// How far off our estimate of the bus time was, at the last sync followed
+    /// up. `None` until two have been.
+    pub fn offset(&self) -> Option<i32> {
+        self.offset
+    }
+
+    /// A sync was heard at our time `now`
+    pub fn sync(&mut self, now: u32) {
+        self.pending = Some(now);
+    }
+
+    /// Something other than a follow-up was heard, so the last sync will not
+    /// be followed up
+    pub fn cancel(&mut self) {
+        self.pending = None;
+    }
+
+    /// The follow-up to the last sync was heard, giving the bus time it was
+    /// sent at. Returns the offset measured, as in [Self::offset].
+    pub fn follow_up(&mut self, bus: u32) -> Option<i32> {
+        let now = self.pending.take()?;
+        let Some((ours, was)) = self.anchor else {
+            self.anchor = Some((now, bus));
+            return None;
+        };
+
+        let since = now.wrapping_sub(ours) as i64;
+        let gained = (bus.wrapping_sub(was) as i64) - since;
+        let rate = match since {
+            0 => None,
+            _ => Some((gained << RATE_SHIFT) / since).filter(|r| r.abs() <= RATE_MAX),
+        };
+        let offset = self.bus_time(now).map(|t| bus.wrapping_sub(t) as i32);
+
+        match rate {
+            Some(rate) => {
+                self.rate = rate as i32;
+                self.offset = offset;
+            },
+            None if !self.rejected => {
+                // Keep going as we were, unless the next one agrees with it
+                self.rejected = true;
+                return None;
+            },
+            None => {
+                // The bus time jumped, perhaps to a new router. Start over.
+                self.rate = 0;
+                self.offset = None;
+            },
+        }
+        self.anchor = Some((now, bus));
+        self.rejected = false;
+        self.offset
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers() {
+        for msg in [SyncMsg::Sync, SyncMsg::FollowUp(0x1234_5678)] {
+            assert_eq!(SyncMsg::from_header(&msg.to_header()), Some(msg));
+        }
+        let mut header = SyncMsg::Sync.to_header();
+        header.addr = 0x70;
+        assert_eq!(SyncMsg::from_header(&header), None);
+    }
+
+    #[test]
+    fn follows_offset_and_rate() {
+        let mut clock = SyncClock::new();
+        assert_eq!(clock.bus_time(0), None);
+
+        // Our clock runs 0.5% fast, from well behind, and the bus time wraps
+        let bus = |t: u32| t.wrapping_add(u32::MAX - 150_000);
+        let ours = |t: u32| t.wrapping_add(t / 200).wrapping_add(1000);
+        for t in (0..4).map(|i| i * 100_000) {
+            clock.sync(ours(t));
+            clock.follow_up(bus(t));
+        }
+        let off = clock.bus_time(ours(450_000)).unwrap().wrapping_sub(bus(450_000)) as i32;
+        assert!(off.abs() <= 2, "{off}");
+        assert!(clock.offset().unwrap().abs() <= 2);
+    }
+
+    #[test]
+    fn unpaired_follow_up_ignored() {
+        let mut clock = SyncClock::new();
+        clock.sync(100);
+        clock.cancel();
+        assert_eq!(clock.follow_up(5000), None);
+        assert_eq!(clock.bus_time(200), None);
+    }
+
+    #[test]
+    fn one_bad_follow_up_ignored() {
+        let mut clock = SyncClock::new();
+        clock.sync(0);
+        clock.follow_up(10_000);
+        clock.sync(100_000);
+        assert_eq!(clock.follow_up(110_000), Some(0));
+
+        // Damaged, then back to normal
+        clock.sync(200_000);
+        assert_eq!(clock.follow_up(0x0100_0000), None);
+        assert_eq!(clock.bus_time(250_000), Some(260_000));
+        clock.sync(300_000);
+        assert_eq!(clock.follow_up(310_000), Some(0));
+
+        // Moved for good
+        for now in [400_000, 500_000] {
+            clock.sync(now);
+            clock.follow_up(now + 0x0100_0000);
+        }
+        assert_eq!(clock.bus_time(550_000), Some(550_000 + 0x0100_0000));
+    }
+}
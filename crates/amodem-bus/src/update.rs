@@ -0,0 +1,447 @@
+//! Firmware updates over the bus
+//!
+//! The router's host sends a new image to every node at once, as multicasts to
+//! [UPDATE_ADDR], then asks each node in turn which chunks it missed, and sends
+//! those again. Each node takes the image in its bootloader, and only starts it
+//! once the router's host commits the update.
+//!
+//! Every update message is one frame, starting with its type:
+//!
+//! | To             | Message | Contents after the type                          |
+//! | :--            | :--     | :--                                              |
+//! | [UPDATE_ADDR]  | Start   | Image length, CRC-32, and version, each LE       |
+//! | [UPDATE_ADDR]  | Chunk   | Image CRC LE, chunk index LE, then the chunk     |
+//! | [UPDATE_ADDR]  | Query   | The node to report, padded to [QUERY_LEN]        |
+//! | The node       | Commit  | Image CRC LE                                     |
+//! | [HOST_ADDR]    | Report  | State, image CRC LE, installed version LE, then the missing chunks |
+//!
+//! The image is split into chunks of [CHUNK_LEN] bytes, the last one shorter.
+//! The missing chunks are a bitmap ([ChunkMap]), one bit per chunk of the
+//! image, set while the chunk is missing, only sent while receiving.
+//!
+//! A node running its application goes to its bootloader as soon as it hears
+//! a header for [UPDATE_ADDR], so the first messages of an update are missed,
+//! and the host sends the start again until every node reports. A start for a
+//! new image erases the node's application, so the host waits for that before
+//! sending chunks.
+//!
+//! Queries are padded, so that each is at least as long as any report frame,
+//! and a 4-wire SPI host reads a report waiting for it in the same transfer.
+//! The commit is sent to each node on its own, and acknowledged, as the node
+//! resets to start the new image, and must not be sent back to its bootloader
+//! by any later multicast.
+//!
+//! Reports start with a destination header of [HOST_ADDR], so they reach the
+//! router's host with routes set, see [crate::route].
+
+use crate::{route::HOST_ADDR, wire::GROUP_ADDR_MIN};
+
+/// The group address of update messages, heard by every node. Must not be
+/// used as a group for anything else.
+pub const UPDATE_ADDR: u8 = 0x7E;
+
+/// Bytes of image in each chunk, but the last
+pub const CHUNK_LEN: usize = 128;
+
+/// Most chunks in an image, enough for all of a 64K flash
+pub const MAX_CHUNKS: usize = 512;
+
+/// Bytes in a full bitmap of missing chunks
+pub const BITMAP_LEN: usize = MAX_CHUNKS / 8;
+
+/// Message type of a start
+pub const MSG_START: u8 = 0xD0;
+
+/// Message type of a chunk
+pub const MSG_CHUNK: u8 = 0xD1;
+
+/// Message type of a query
+pub const MSG_QUERY: u8 = 0xD2;
+
+/// Message type of a commit
+pub const MSG_COMMIT: u8 = 0xD3;
+
+/// Message type of a report
+pub const MSG_REPORT: u8 = 0xD4;
+
+/// Bytes in a report before the missing chunks: destination header, type,
+/// state, CRC, and version
+pub const REPORT_HEADER_LEN: usize = 11;
+
+/// Bytes in the longest report
+pub const REPORT_MAX: usize = REPORT_HEADER_LEN + BITMAP_LEN;
+
+/// Bytes in a query, with its padding: a report, and its source address
+pub const QUERY_LEN: usize = REPORT_MAX + 1;
+
+/// Bytes in the longest update message, a full chunk
+pub const UPDATE_MSG_MAX: usize = 1 + CHUNK_BODY_MAX;
+
+/// Bytes in a full chunk after its type: CRC, index, then the chunk
+const CHUNK_BODY_MAX: usize = 6 + CHUNK_LEN;
+
+// Update messages go in one fragment, and the group address is one
+const _: () = assert!(UPDATE_MSG_MAX <= crate::frag::FRAG_DATA_MAX);
+const _: () = assert!(UPDATE_ADDR >= GROUP_ADDR_MIN);
+
+/// The image being sent
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Campaign {
+    /// Bytes in the image
+    pub len: u32,
+    /// CRC-32 of the image, as in its header. Identifies the update.
+    pub crc: u32,
+    pub version: u32,
+}
+
+impl Campaign {
+    /// Chunks in the image
+    pub fn chunks(&self) -> usize {
+        (self.len as usize).div_ceil(CHUNK_LEN)
+    }
+
+    /// Bytes in chunk `index`, or `None` if the image has no such chunk
+    pub fn chunk_len(&self, index: usize) -> Option<usize> {
+        let start = index.checked_mul(CHUNK_LEN)?;
+        Some((self.len as usize).checked_sub(start)?.min(CHUNK_LEN)).filter(|len| *len != 0)
+    }
+
+    /// Can the image be sent? It must have a chunk, and not too many.
+    pub fn is_valid(&self) -> bool {
+        (1..=MAX_CHUNKS).contains(&self.chunks())
+    }
+}
+
+/// An update message, from the router's host
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateMsg<'a> {
+    /// Take this image, if not already
+    Start(Campaign),
+    /// Chunk `index` of the image with CRC `crc`
+    Chunk { crc: u32, index: u16, data: &'a [u8] },
+    /// Node `addr`, report
+    Query { addr: u8 },
+    /// Start the image with CRC `crc`, if it is all there
+    Commit { crc: u32 },
+}
+
+impl UpdateMsg<'_> {
+    /// Write the message to `buf`, which must have room for it, returning its
+    /// length
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        let mut out = Writer { buf, len: 0 };
+        match self {
+            UpdateMsg::Start(c) => {
+                out.put(&[MSG_START]);
+                out.put(&c.len.to_le_bytes());
+                out.put(&c.crc.to_le_bytes());
+                out.put(&c.version.to_le_bytes());
+            },
+            UpdateMsg::Chunk { crc, index, data } => {
+                out.put(&[MSG_CHUNK]);
+                out.put(&crc.to_le_bytes());
+                out.put(&index.to_le_bytes());
+                out.put(data);
+            },
+            UpdateMsg::Query { addr } => {
+                out.put(&[MSG_QUERY, *addr]);
+                out.put(&[0; QUERY_LEN - 2]);
+            },
+            UpdateMsg::Commit { crc } => {
+                out.put(&[MSG_COMMIT]);
+                out.put(&crc.to_le_bytes());
+            },
+        }
+        out.len
+    }
+}
+
+impl<'a> UpdateMsg<'a> {
+    /// Parse a message, or `None` if it is not one. Chunks longer than
+    /// [CHUNK_LEN] are not.
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        let (ty, body) = frame.split_first()?;
+        let word = |at: usize| -> Option<u32> {
+            Some(u32::from_le_bytes(body.get(at..at + 4)?.try_into().ok()?))
+        };
+        match (*ty, body.len()) {
+            (MSG_START, 12) => Some(UpdateMsg::Start(Campaign { len: word(0)?, crc: word(4)?, version: word(8)? })),
+            (MSG_CHUNK, 7..=CHUNK_BODY_MAX) => Some(UpdateMsg::Chunk {
+                crc: word(0)?,
+                index: u16::from_le_bytes([body[4], body[5]]),
+                data: &body[6..],
+            }),
+            (MSG_QUERY, 1..) => Some(UpdateMsg::Query { addr: body[0] }),
+            (MSG_COMMIT, 4) => Some(UpdateMsg::Commit { crc: word(0)? }),
+            _ => None,
+        }
+    }
+}
+
+/// Where a node is with an update
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateState {
+    /// No update started since the bootloader did
+    Idle,
+    /// Taking chunks, some are missing
+    Receiving,
+    /// Every chunk is in, and the image checks out
+    Ready,
+    /// The image is installed, and starts once committed
+    Installed,
+    /// The image does not fit, failed its check, or the flash failed. Another
+    /// start tries again.
+    Failed,
+}
+
+impl UpdateState {
+    pub fn to_byte(self) -> u8 {
+        match self {
+            UpdateState::Idle => 0,
+            UpdateState::Receiving => 1,
+            UpdateState::Ready => 2,
+            UpdateState::Installed => 3,
+            UpdateState::Failed => 4,
+        }
+    }
+
+    pub fn parse(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(UpdateState::Idle),
+            1 => Some(UpdateState::Receiving),
+            2 => Some(UpdateState::Ready),
+            3 => Some(UpdateState::Installed),
+            4 => Some(UpdateState::Failed),
+            _ => None,
+        }
+    }
+}
+
+/// A node's report, in answer to a query
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Report<'a> {
+    pub state: UpdateState,
+    /// CRC of the image being taken, or zero with no update started
+    pub crc: u32,
+    /// Version of the image installed, or zero with none
+    pub version: u32,
+    /// Bitmap of the missing chunks while receiving, see [ChunkMap::as_bytes]
+    pub missing: &'a [u8],
+}
+
+impl<'a> Report<'a> {
+    /// Write the report to `buf`, which must have room for it, returning its
+    /// length
+    pub fn write(&self, buf: &mut [u8]) -> usize {
+        let mut out = Writer { buf, len: 0 };
+        out.put(&[HOST_ADDR, MSG_REPORT, self.state.to_byte()]);
+        out.put(&self.crc.to_le_bytes());
+        out.put(&self.version.to_le_bytes());
+        out.put(self.missing);
+        out.len
+    }
+
+    /// Parse a report, as sent by the node, starting with its destination
+    /// header
+    pub fn parse(frame: &'a [u8]) -> Option<Self> {
+        if (frame.len() < REPORT_HEADER_LEN) || (frame.len() > REPORT_MAX) {
+            return None;
+        }
+        if frame[..2] != [HOST_ADDR, MSG_REPORT] {
+            return None;
+        }
+        let word = |at: usize| u32::from_le_bytes([frame[at], frame[at + 1], frame[at + 2], frame[at + 3]]);
+        Some(Self {
+            state: UpdateState::parse(frame[2])?,
+            crc: word(3),
+            version: word(7),
+            missing: &frame[REPORT_HEADER_LEN..],
+        })
+    }
+}
+
+/// Which chunks of an image are missing
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkMap {
+    /// One bit per chunk, set while missing, LSB first
+    bits: [u8; BITMAP_LEN],
+    chunks: usize,
+}
+
+impl Default for ChunkMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ChunkMap {
+    /// A map of no chunks
+    pub const fn new() -> Self {
+        Self { bits: [0; BITMAP_LEN], chunks: 0 }
+    }
+
+    /// A map of `chunks` chunks, all of them missing, or `None` if there are
+    /// too many
+    pub fn all_missing(chunks: usize) -> Option<Self> {
+        let bytes = [0xFF; BITMAP_LEN];
+        Self::from_bytes(chunks, bytes.get(..chunks.div_ceil(8))?)
+    }
+
+    /// The map of `chunks` chunks sent in a report, or `None` if it is the
+    /// wrong length for them
+    pub fn from_bytes(chunks: usize, bytes: &[u8]) -> Option<Self> {
+        if (chunks > MAX_CHUNKS) || (bytes.len() != chunks.div_ceil(8)) {
+            return None;
+        }
+        let mut map = Self { bits: [0; BITMAP_LEN], chunks };
+        map.bits[..bytes.len()].copy_from_slice(bytes);
+        // Bits past the last chunk are never set
+        if let Some(last) = map.bits[..bytes.len()].last_mut() {
+            *last &= 0xFF >> ((8 - (chunks % 8)) % 8);
+        }
+        Some(map)
+    }
+
+    /// The bitmap to send in a report
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bits[..self.chunks.div_ceil(8)]
+    }
+
+    pub fn chunks(&self) -> usize {
+        self.chunks
+    }
+
+    pub fn is_missing(&self, index: usize) -> bool {
+        (index < self.chunks) && ((self.bits[index / 8] & (1 << (index % 8))) != 0)
+    }
+
+    /// Chunk `index` is in
+    pub fn received(&mut self, index: usize) {
+        if index < self.chunks {
+            self.bits[index / 8] &= !(1 << (index % 8));
+        }
+    }
+
+    /// How many chunks are missing
+    pub fn missing(&self) -> usize {
+        self.as_bytes().iter().map(|b| b.count_ones() as usize).sum()
+    }
+
+    /// The missing chunks, in order
+    pub fn iter_missing(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.chunks).filter(|i| self.is_missing(*i))
+    }
+
+    /// Also count chunks missing from `other` as missing, for a map of what
+    /// any node is missing. Both must be for the same image.
+    pub fn merge(&mut self, other: &ChunkMap) {
+        let len = self.chunks.div_ceil(8);
+        self.bits[..len].iter_mut().zip(other.bits.iter()).for_each(|(a, b)| *a |= *b);
+    }
+}
+
+/// Appends to a buffer, for writing messages
+struct Writer<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, data: &[u8]) {
+        self.buf[self.len..][..data.len()].copy_from_slice(data);
+        self.len += data.len();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chunks() {
+        let campaign = Campaign { len: 300, crc: 0, version: 0 };
+        assert_eq!(campaign.chunks(), 3);
+        assert_eq!(campaign.chunk_len(0), Some(CHUNK_LEN));
+        assert_eq!(campaign.chunk_len(2), Some(300 - (2 * CHUNK_LEN)));
+        assert_eq!(campaign.chunk_len(3), None);
+        assert!(campaign.is_valid());
+
+        let exact = Campaign { len: (2 * CHUNK_LEN) as u32, crc: 0, version: 0 };
+        assert_eq!(exact.chunks(), 2);
+        assert_eq!(exact.chunk_len(2), None);
+        assert!(!Campaign { len: 0, crc: 0, version: 0 }.is_valid());
+        assert!(!Campaign { len: (MAX_CHUNKS * CHUNK_LEN + 1) as u32, crc: 0, version: 0 }.is_valid());
+    }
+
+    #[test]
+    fn messages() {
+        let data = [0x55; CHUNK_LEN];
+        let msgs = [
+            UpdateMsg::Start(Campaign { len: 0x1234, crc: 0xDEAD_BEEF, version: 7 }),
+            UpdateMsg::Chunk { crc: 0xDEAD_BEEF, index: 300, data: &data },
+            UpdateMsg::Chunk { crc: 0xDEAD_BEEF, index: 301, data: &data[..1] },
+            UpdateMsg::Query { addr: 0x12 },
+            UpdateMsg::Commit { crc: 0xDEAD_BEEF },
+        ];
+        let mut buf = [0; UPDATE_MSG_MAX];
+        msgs.iter().for_each(|msg| {
+            let len = msg.write(&mut buf);
+            assert_eq!(UpdateMsg::parse(&buf[..len]), Some(*msg));
+        });
+
+        let len = UpdateMsg::Query { addr: 1 }.write(&mut buf);
+        assert_eq!(len, QUERY_LEN);
+        assert_eq!(&buf[..2], &[MSG_QUERY, 1]);
+
+        let len = UpdateMsg::Chunk { crc: 0, index: 0, data: &data }.write(&mut buf);
+        assert_eq!(len, UPDATE_MSG_MAX);
+        // Too long, empty, or cut short
+        let long = [buf.as_slice(), &[0]].concat();
+        assert_eq!(UpdateMsg::parse(&long), None);
+        assert_eq!(UpdateMsg::parse(&buf[..7]), None);
+        assert_eq!(UpdateMsg::parse(&[MSG_COMMIT, 1, 2, 3]), None);
+        assert_eq!(UpdateMsg::parse(&[MSG_REPORT]), None);
+        assert_eq!(UpdateMsg::parse(&[]), None);
+    }
+
+    #[test]
+    fn reports() {
+        let mut map = ChunkMap::all_missing(20).unwrap();
+        map.received(3);
+        let report = Report { state: UpdateState::Receiving, crc: 0xDEAD_BEEF, version: 2, missing: map.as_bytes() };
+        let mut buf = [0; REPORT_MAX];
+        let len = report.write(&mut buf);
+        assert_eq!(len, REPORT_HEADER_LEN + 3);
+        assert_eq!(&buf[..2], &[HOST_ADDR, MSG_REPORT]);
+        assert_eq!(Report::parse(&buf[..len]), Some(report));
+        assert_eq!(ChunkMap::from_bytes(20, report.missing), Some(map));
+
+        assert_eq!(Report::parse(&buf[..REPORT_HEADER_LEN - 1]), None);
+        buf[2] = 9;
+        assert_eq!(Report::parse(&buf[..len]), None);
+    }
+
+    #[test]
+    fn chunk_maps() {
+        let mut map = ChunkMap::all_missing(10).unwrap();
+        assert_eq!(map.as_bytes(), &[0xFF, 0x03]);
+        assert_eq!(map.missing(), 10);
+        map.received(0);
+        map.received(9);
+        map.received(10);
+        assert_eq!(map.missing(), 8);
+        assert!(!map.is_missing(9) && map.is_missing(8) && !map.is_missing(10));
+
+        let mut other = ChunkMap::all_missing(10).unwrap();
+        (0..10).filter(|i| *i != 9).for_each(|i| other.received(i));
+        map.merge(&other);
+        assert_eq!(map.iter_missing().collect::<Vec<_>>(), (1..10).collect::<Vec<_>>());
+
+        // Stray bits past the end are ignored
+        assert_eq!(ChunkMap::from_bytes(10, &[0, 0xFC]).unwrap().missing(), 0);
+        assert_eq!(ChunkMap::from_bytes(10, &[0]), None);
+        assert_eq!(ChunkMap::all_missing(MAX_CHUNKS).unwrap().as_bytes().len(), BITMAP_LEN);
+        assert_eq!(ChunkMap::all_missing(MAX_CHUNKS + 1), None);
+        assert_eq!(ChunkMap::new().missing(), 0);
+    }
+}
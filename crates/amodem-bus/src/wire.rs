@@ -0,0 +1,254 @@
+//! Words on the wire
+//!
+//! The bus runs 9-bit words. Words with the 9th bit ([ADDR_FLAG]) set are
+//! address words, which wake the addressed node out of mute mode. All other
+//! words carry one byte.
+//!
+//! A poll is made of:
+//!
+//! | From   | Words  | Contents                                                      |
+//! | :--    | :--    | :--                                                           |
+//! | Router | 6      | Address word, router rx capacity LE, router tx len LE, status |
+//! | Node   | 5      | Node tx amount LE, node rx amount LE, status                  |
+//! | Node   | n + 2  | Node payload, node tx amount bytes, then its CRC LE           |
+//! | Router | n + 2  | Router payload, node rx amount bytes, then its CRC LE         |
+//!
+//! Payloads are moved whole or not at all, so each amount in the node's reply
+//! is either the full length, or zero. Empty payloads have no CRC. See
+//! [crate::crc] for the CRC, and [crate::frag] for how frames longer than one
+//! payload are split up.
+//!
+//! Each status word reports on the last payload received from the other end,
+//! as one of the `STATUS_*` values. The sender keeps each frame until it sees
+//! [STATUS_ACK] for it, and sends it again otherwise, so frames are delivered
+//! at least once. A router waiting for a node's status does not send that node
+//! anything in the same poll, as it has to commit to the length in its header.
+//! Likewise, a node that sees [STATUS_ACK] sends nothing in that poll.
+//!
+//! ## Broadcasts
+//!
+//! Frames for [BROADCAST_ADDR], or for a group address (see [is_multicast]), go
+//! to every node listening on that address, and no node replies:
+//!
+//! | From   | Words  | Contents                                                    |
+//! | :--    | :--    | :--                                                         |
+//! | Router | m      | The address word of each node the router polls              |
+//! | Router | 6      | Header, for the broadcast or group address                  |
+//! | Router | n + 2  | Router payload, header tx len bytes, then its CRC LE        |
+//!
+//! In mute mode, a node only wakes on its own address word, so the router
+//! wakes each node first. Once awake, a node takes the last of a run of address
+//! words as the one the header is for. The header's rx capacity and status are
+//! zero. Nodes without room for the frame drop it. Broadcasts are not
+//! acknowledged, or sent again.
+//!
+//! Broadcast headers with a status of [STATUS_SYNC] or [STATUS_FOLLOW_UP] have
+//! no payload, and are for time sync, see [crate::timesync].
+
+/// Set on address words
+pub const ADDR_FLAG: u16 = 0x100;
+
+/// Bits of an address word holding the address
+pub const ADDR_MASK: u8 = 0x7F;
+
+/// Address of broadcasts, heard by every node
+pub const BROADCAST_ADDR: u8 = 0x7F;
+
+/// Lowest group address. Addresses from here up to [BROADCAST_ADDR] are for
+/// multicasts, and must not be used as node addresses. The last before
+/// [BROADCAST_ADDR] is kept for firmware updates, see [crate::update].
+pub const GROUP_ADDR_MIN: u8 = 0x70;
+
+/// Bit times per word: start bit, 9 data bits, stop bit
+pub const WORD_BITS: u32 = 11;
+
+/// Words in the router's header, including the address word
+pub const HEADER_WORDS: usize = 6;
+
+/// Words in the node's reply
+pub const REPLY_WORDS: usize = 5;
+
+/// Words in a payload's CRC trailer
+pub const CRC_WORDS: usize = 2;
+
+/// Status: no payload was received from you in your last poll
+pub const STATUS_NONE: u8 = 0x00;
+
+/// Status: your last payload was received intact. The status values differ in
+/// at least four bits, so noise does not turn one into another.
+pub const STATUS_ACK: u8 = 0xA5;
+
+/// Status: your last payload failed its CRC, or stopped short
+pub const STATUS_NAK: u8 = 0x5A;
+
+/// Status of a time sync broadcast
+pub const STATUS_SYNC: u8 = 0x3C;
+
+/// Status of the follow-up to a time sync broadcast
+pub const STATUS_FOLLOW_UP: u8 = 0xC3;
+
+/// Largest payload moved in one poll
+pub const MAX_PAYLOAD: usize = 256;
+
+/// Bit times a node waits for the rest of the header after its address word,
+/// the words themselves plus 20 bit times of slack
+pub const HEADER_TIMEOUT_BITS: u32 = ((HEADER_WORDS as u32 - 1) * WORD_BITS) + 20;
+
+/// Bit times the router waits for a reply after the start of its header: the
+/// header and reply words, plus [SLACK_BITS]
+pub const REPLY_TIMEOUT_BITS: u32 = ((HEADER_WORDS + REPLY_WORDS) as u32 * WORD_BITS) + SLACK_BITS;
+
+/// Bit times of slack allowed on top of the data itself, in the router's
+/// timeouts
+pub const SLACK_BITS: u32 = 64;
+
+/// Longest gap allowed between words of a payload, in bit times, before the
+/// receiver considers the frame truncated
+pub const RX_GAP_BITS: u32 = 4 * WORD_BITS;
+
+/// Words on the wire for a payload of `len` bytes, including its CRC
+pub const fn payload_words(len: usize) -> usize {
+    if len == 0 { 0 } else { len + CRC_WORDS }
+}
+
+/// Bit times for `words` words, plus [SLACK_BITS]
+pub const fn data_timeout_bits(words: usize) -> u32 {
+    (words as u32 * WORD_BITS) + SLACK_BITS
+}
+
+/// The router's header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    /// The node being polled
+    pub addr: u8,
+    /// How much the router can receive from the node
+    pub rx_cap: u16,
+    /// How much the router has to send to the node
+    pub tx_len: u16,
+    /// The router's `STATUS_*` for the node's last payload
+    pub status: u8,
+}
+
+impl Header {
+    pub fn to_words(&self) -> [u16; HEADER_WORDS] {
+        let [rc0, rc1] = self.rx_cap.to_le_bytes();
+        let [tl0, tl1] = self.tx_len.to_le_bytes();
+        [
+            ADDR_FLAG | (self.addr & ADDR_MASK) as u16,
+            rc0 as u16,
+            rc1 as u16,
+            tl0 as u16,
+            tl1 as u16,
+            self.status as u16,
+        ]
+    }
+
+    /// Parse a header, or `None` if the first word is not an address word,
+    /// or any other word is
+    pub fn from_words(words: &[u16; HEADER_WORDS]) -> Option<Self> {
+        if !is_addr(words[0]) || words[1..].iter().any(|w| is_addr(*w)) {
+            return None;
+        }
+        Some(Self {
+            addr: (words[0] as u8) & ADDR_MASK,
+            rx_cap: u16::from_le_bytes([words[1] as u8, words[2] as u8]),
+            tx_len: u16::from_le_bytes([words[3] as u8, words[4] as u8]),
+            status: words[5] as u8,
+        })
+    }
+}
+
+/// The node's reply to a [Header]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reply {
+    /// How much the node will send, its whole payload or zero
+    pub tx_amt: u16,
+    /// How much of the router's payload the node will receive, all or zero
+    pub rx_amt: u16,
+    /// The node's `STATUS_*` for the router's last payload
+    pub status: u8,
+}
+
+impl Reply {
+    /// The node's side of a poll. The node sends its frame of `tx_len` if the
+    /// router has room, and accepts the router's frame if it has `rx_cap`
+    /// room for it.
+    pub fn negotiate(header: &Header, tx_len: usize, rx_cap: usize, status: u8) -> Self {
+        let tx_amt = if (header.rx_cap as usize) >= tx_len { tx_len } else { 0 };
+        let rx_amt = if (header.tx_len as usize) <= rx_cap { header.tx_len as usize } else { 0 };
+        Self {
+            tx_amt: tx_amt as u16,
+            rx_amt: rx_amt as u16,
+            status,
+        }
+    }
+
+    pub fn to_words(&self) -> [u16; REPLY_WORDS] {
+        let [t0, t1] = self.tx_amt.to_le_bytes();
+        let [r0, r1] = self.rx_amt.to_le_bytes();
+        [t0 as u16, t1 as u16, r0 as u16, r1 as u16, self.status as u16]
+    }
+
+    /// Parse a reply, or `None` if any word is an address word
+    pub fn from_words(words: &[u16; REPLY_WORDS]) -> Option<Self> {
+        if words.iter().any(|w| is_addr(*w)) {
+            return None;
+        }
+        Some(Self {
+            tx_amt: u16::from_le_bytes([words[0] as u8, words[1] as u8]),
+            rx_amt: u16::from_le_bytes([words[2] as u8, words[3] as u8]),
+            status: words[4] as u8,
+        })
+    }
+}
+
+/// Is `word` an address word?
+#[inline]
+pub fn is_addr(word: u16) -> bool {
+    (word & ADDR_FLAG) != 0
+}
+
+/// Is `addr` a broadcast or group address?
+#[inline]
+pub fn is_multicast(addr: u8) -> bool {
+    (addr & ADDR_MASK) >= GROUP_ADDR_MIN
+}
+
+/// Microseconds (rounded up) to send `bits` bit times at `baud`
+pub fn bits_to_us(bits: u32, baud: u32) -> u32 {
+    (bits as u64 * 1_000_000).div_ceil(baud as u64) as u32
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn header_roundtrip() {
+        let hdr = Header { addr: 0x40, rx_cap: 255, tx_len: 0x1234, status: STATUS_NAK };
+        let words = hdr.to_words();
+        assert_eq!(words, [0x140, 0xFF, 0x00, 0x34, 0x12, 0x5A]);
+        assert_eq!(Header::from_words(&words), Some(hdr));
+    }
+
+    #[test]
+    fn header_rejects_misplaced_address() {
+        assert_eq!(Header::from_words(&[0x40, 0, 0, 0, 0, 0]), None);
+        assert_eq!(Header::from_words(&[0x140, 0, 0x141, 0, 0, 0]), None);
+    }
+
+    #[test]
+    fn negotiate_all_or_nothing() {
+        let hdr = Header { addr: 1, rx_cap: 10, tx_len: 20, status: STATUS_NONE };
+        let reply = |tx_amt, rx_amt| Reply { tx_amt, rx_amt, status: STATUS_ACK };
+        assert_eq!(Reply::negotiate(&hdr, 10, 20, STATUS_ACK), reply(10, 20));
+        assert_eq!(Reply::negotiate(&hdr, 11, 19, STATUS_ACK), reply(0, 0));
+        assert_eq!(Reply::negotiate(&hdr, 0, 0, STATUS_ACK), reply(0, 0));
+    }
+
+    #[test]
+    fn timing() {
+        // 10uS at the default 8 Mbaud
+        assert_eq!(bits_to_us(HEADER_TIMEOUT_BITS, 8_000_000), 10);
+    }
+}
@@ -0,0 +1,176 @@
+//! Bootloader commands
+//!
+//! The bootloader, at the start of flash, takes firmware images over SPI. It
+//! runs instead of the application when the host sets `SPI_CFG_BOOTLOADER`,
+//! or when there is no valid application. See `amodem_boot` for the image
+//! format.
+//!
+//! Its commands use [MODE_BOOT], with the operation in the low five bits. The
+//! application does not accept them, and the bootloader accepts nothing else.
+//! Each is either read-only or write-only, so they work in 3-wire mode too:
+//!
+//! | Command        | Host sends                    | Modem sends  |
+//! | :--            | :--                           | :--          |
+//! | [BOOT_STATUS]  | (none)                        | [BootStatus] |
+//! | [BOOT_ERASE]   | (none)                        | (none)       |
+//! | [BOOT_WRITE]   | Offset (u32 LE), then data    | (none)       |
+//! | [BOOT_VERIFY]  | (none)                        | (none)       |
+//! | [BOOT_RUN]     | (none)                        | (none)       |
+//!
+//! Offsets are from the start of the application region, which starts with
+//! the image header. Each write is a multiple of [BOOT_WRITE_ALIGN] bytes, at
+//! most [BOOT_WRITE_MAX], to an aligned offset, in flash erased since it was
+//! last written.
+//!
+//! Commands are carried out once CSn rises, and the outcome is reported in
+//! the next status. Until then, RXrdy (IO2) is low, and the bootloader does not
+//! answer: a status read reads as zeroes, which lack [BOOT_STATUS_VALID].
+//! Erasing takes about a second.
+
+use crate::cmd::{MODE_BOOT, MODE_MASK};
+
+/// Read the bootloader's [BootStatus]
+pub const BOOT_STATUS: u8 = 0x00;
+
+/// Erase the application region
+pub const BOOT_ERASE: u8 = 0x01;
+
+/// Write to the application region
+pub const BOOT_WRITE: u8 = 0x02;
+
+/// Check the image in the application region
+pub const BOOT_VERIFY: u8 = 0x03;
+
+/// Check the image, and start it if it is valid
+pub const BOOT_RUN: u8 = 0x04;
+
+/// Writes are whole multiples of this, at offsets that are too
+pub const BOOT_WRITE_ALIGN: usize = 8;
+
+/// Most data in one write, after its offset
+pub const BOOT_WRITE_MAX: usize = 256;
+
+/// Length of the [BootStatus], not including the command byte
+pub const BOOT_STATUS_LEN: usize = 6;
+
+/// `BOOT_STATUS_*`: Always set, to tell a status from a busy bootloader
+pub const BOOT_STATUS_VALID: u8 = 0b1000_0000;
+
+/// `BOOT_STATUS_*`: The last check found a valid image, and nothing was
+/// erased or written since
+pub const BOOT_STATUS_IMAGE_OK: u8 = 0b0000_0001;
+
+/// `BOOT_RESULT_*`: The last command succeeded
+pub const BOOT_RESULT_OK: u8 = 0x00;
+
+/// `BOOT_RESULT_*`: The last command was not known, or had the wrong length
+pub const BOOT_RESULT_BAD_COMMAND: u8 = 0x01;
+
+/// `BOOT_RESULT_*`: The last write was misaligned, or outside the region
+pub const BOOT_RESULT_BAD_RANGE: u8 = 0x02;
+
+/// `BOOT_RESULT_*`: Flash could not be erased or written, for example as it
+/// was written since the last erase
+pub const BOOT_RESULT_FLASH: u8 = 0x03;
+
+/// `BOOT_RESULT_*`: The image is not valid, see `amodem_boot`
+pub const BOOT_RESULT_BAD_IMAGE: u8 = 0x04;
+
+/// A decoded bootloader command byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BootCommand {
+    Status,
+    Erase,
+    Write,
+    Verify,
+    Run,
+}
+
+impl BootCommand {
+    /// Decode a command byte, returning `None` for anything but a known
+    /// bootloader command
+    pub fn parse(byte: u8) -> Option<Self> {
+        if (byte & MODE_MASK) != MODE_BOOT {
+            return None;
+        }
+        match byte & !MODE_MASK {
+            BOOT_STATUS => Some(Self::Status),
+            BOOT_ERASE => Some(Self::Erase),
+            BOOT_WRITE => Some(Self::Write),
+            BOOT_VERIFY => Some(Self::Verify),
+            BOOT_RUN => Some(Self::Run),
+            _ => None,
+        }
+    }
+
+    pub fn to_byte(self) -> u8 {
+        MODE_BOOT | match self {
+            Self::Status => BOOT_STATUS,
+            Self::Erase => BOOT_ERASE,
+            Self::Write => BOOT_WRITE,
+            Self::Verify => BOOT_VERIFY,
+            Self::Run => BOOT_RUN,
+        }
+    }
+}
+
+/// The bootloader's status, sent for [BOOT_STATUS]
+///
+/// | Byte | Contents                                             |
+/// | :--  | :--                                                  |
+/// | 0    | Flags, see `BOOT_STATUS_*`                           |
+/// | 1    | Outcome of the last command, see `BOOT_RESULT_*`     |
+/// | 2..6 | Version of the valid image, or zero, LE              |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BootStatus {
+    /// `BOOT_STATUS_*` flags, including `BOOT_STATUS_VALID`
+    pub flags: u8,
+    /// `BOOT_RESULT_*` of the last command
+    pub result: u8,
+    /// Version from the image header, with `BOOT_STATUS_IMAGE_OK`
+    pub version: u32,
+}
+
+impl BootStatus {
+    pub fn to_bytes(&self) -> [u8; BOOT_STATUS_LEN] {
+        let [v0, v1, v2, v3] = self.version.to_le_bytes();
+        [self.flags | BOOT_STATUS_VALID, self.result, v0, v1, v2, v3]
+    }
+
+    /// Decode a status, returning `None` if `BOOT_STATUS_VALID` is not set
+    pub fn from_bytes(bytes: &[u8; BOOT_STATUS_LEN]) -> Option<Self> {
+        let [flags, result, v0, v1, v2, v3] = *bytes;
+        if (flags & BOOT_STATUS_VALID) == 0 {
+            return None;
+        }
+        Some(Self { flags, result, version: u32::from_le_bytes([v0, v1, v2, v3]) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cmd::Command;
+
+    #[test]
+    fn commands() {
+        for byte in 0..=255u8 {
+            if let Some(cmd) = BootCommand::parse(byte) {
+                assert_eq!(cmd.to_byte(), byte);
+                assert_eq!(Command::parse(byte), None);
+            }
+        }
+        assert_eq!(BootCommand::parse(0b110_00010), Some(BootCommand::Write));
+        assert_eq!(BootCommand::parse(0b110_00101), None);
+        assert_eq!(BootCommand::parse(0b010_00000), None);
+    }
+
+    #[test]
+    fn status() {
+        let status = BootStatus { flags: BOOT_STATUS_VALID | BOOT_STATUS_IMAGE_OK, result: BOOT_RESULT_OK, version: 0x0102_0304 };
+        let bytes = status.to_bytes();
+        assert_eq!(bytes, [0x81, 0x00, 0x04, 0x03, 0x02, 0x01]);
+        assert_eq!(BootStatus::from_bytes(&bytes), Some(status));
+        assert_eq!(BootStatus::from_bytes(&[0; BOOT_STATUS_LEN]), None);
+    }
+}
@@ -0,0 +1,97 @@
+//! Command byte encoding
+//!
+//! The first byte of every host transaction is a command. The top three bits
+//! select the command, and the low five bits hold the register index, or for
+//! long packets, flags.
+
+/// Mask for the command bits of the command byte
+pub const MODE_MASK: u8 = 0b111_00000;
+
+/// Exchange frames with the modem, see the `long` module
+pub const MODE_LONG_PKT_READWRITE: u8 = 0b001_00000;
+
+/// Read registers, starting at the given index, until the transaction ends
+pub const MODE_BURST_REG_READ: u8 = 0b010_00000;
+
+/// Read a single register
+pub const MODE_SHORT_REG_READ: u8 = 0b011_00000;
+
+/// Write a single register
+pub const MODE_SHORT_REG_WRITE: u8 = 0b100_00000;
+
+/// Write registers, starting at the given index, until the transaction ends
+pub const MODE_BURST_REG_WRITE: u8 = 0b101_00000;
+
+/// Bootloader commands, with the operation in the low five bits, see the
+/// `boot` module. Only the bootloader accepts these, so they are not a
+/// [Command].
+pub const MODE_BOOT: u8 = 0b110_00000;
+
+/// A decoded command byte
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Long packet, with the low five bits as flags, see `LONG_*` in the `long` module
+    LongPacket(u8),
+    /// Burst read, starting at the given register
+    BurstRead(u8),
+    /// Read the given register
+    RegRead(u8),
+    /// Write the given register
+    RegWrite(u8),
+    /// Burst write, starting at the given register
+    BurstWrite(u8),
+}
+
+impl Command {
+    /// Decode a command byte, returning `None` for unused commands
+    pub fn parse(byte: u8) -> Option<Self> {
+        let low = byte & !MODE_MASK;
+        match byte & MODE_MASK {
+            MODE_LONG_PKT_READWRITE => Some(Command::LongPacket(low)),
+            MODE_BURST_REG_READ => Some(Command::BurstRead(low)),
+            MODE_SHORT_REG_READ => Some(Command::RegRead(low)),
+            MODE_SHORT_REG_WRITE => Some(Command::RegWrite(low)),
+            MODE_BURST_REG_WRITE => Some(Command::BurstWrite(low)),
+            _ => None,
+        }
+    }
+
+    /// Encode this command as a command byte. Out of range indexes or flags
+    /// are truncated to five bits.
+    pub fn to_byte(self) -> u8 {
+        let (mode, low) = match self {
+            Command::LongPacket(flags) => (MODE_LONG_PKT_READWRITE, flags),
+            Command::BurstRead(idx) => (MODE_BURST_REG_READ, idx),
+            Command::RegRead(idx) => (MODE_SHORT_REG_READ, idx),
+            Command::RegWrite(idx) => (MODE_SHORT_REG_WRITE, idx),
+            Command::BurstWrite(idx) => (MODE_BURST_REG_WRITE, idx),
+        };
+        mode | (low & !MODE_MASK)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for byte in 0..=255u8 {
+            match Command::parse(byte) {
+                Some(cmd) => assert_eq!(cmd.to_byte(), byte),
+                None => {
+                    let mode = byte & MODE_MASK;
+                    assert!((mode == 0b000_00000) || (mode == 0b110_00000) || (mode == 0b111_00000));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn decode() {
+        assert_eq!(Command::parse(0b011_00101), Some(Command::RegRead(5)));
+        assert_eq!(Command::parse(0b101_11111), Some(Command::BurstWrite(31)));
+        assert_eq!(Command::parse(0b001_00001), Some(Command::LongPacket(1)));
+        assert_eq!(Command::parse(0b111_00000), None);
+    }
+}
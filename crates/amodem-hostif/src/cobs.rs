@@ -0,0 +1,145 @@
+//! Consistent Overhead Byte Stuffing
+//!
+//! Used to frame the UART host link, where frames in both directions are COBS
+//! encoded and terminated with a zero byte. Neither function here handles the
+//! terminator, the caller strips or appends it.
+
+/// Largest encoded length of a `len` byte frame, not including the terminator
+pub const fn max_encoded_len(len: usize) -> usize {
+    len + (len / 254) + 1
+}
+
+/// COBS encode `src` into `dst`, returning the encoded length, not including
+/// the terminating zero. `dst` must be at least [max_encoded_len] bytes long.
+pub fn encode(src: &[u8], dst: &mut [u8]) -> usize {
+    let mut code_idx = 0;
+    let mut out = 1;
+    let mut code = 1u8;
+
+    for &byte in src {
+        if byte != 0 {
+            dst[out] = byte;
+            out += 1;
+            code += 1;
+        }
+        if (byte == 0) || (code == 0xFF) {
+            dst[code_idx] = code;
+            code_idx = out;
+            out += 1;
+            code = 1;
+        }
+    }
+    dst[code_idx] = code;
+    out
+}
+
+/// COBS decode `buf` in place, not including the terminating zero. Returns the
+/// decoded length, or `None` if the encoding is invalid.
+pub fn decode_in_place(buf: &mut [u8]) -> Option<usize> {
+    let mut rd = 0;
+    let mut wr = 0;
+
+    while rd < buf.len() {
+        let code = buf[rd] as usize;
+        if (code == 0) || (rd + code > buf.len()) {
+            return None;
+        }
+        rd += 1;
+        for _ in 1..code {
+            buf[wr] = buf[rd];
+            wr += 1;
+            rd += 1;
+        }
+        if (code != 0xFF) && (rd != buf.len()) {
+            buf[wr] = 0;
+            wr += 1;
+        }
+    }
+
+    Some(wr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{vec, vec::Vec};
+
+    fn encoded(src: &[u8]) -> Vec<u8> {
+        let mut dst = vec![0xAA; max_encoded_len(src.len())];
+        let len = encode(src, &mut dst);
+        dst.truncate(len);
+        dst
+    }
+
+    fn round_trip(src: &[u8]) {
+        let mut buf = encoded(src);
+        assert!(!buf.contains(&0));
+        assert!(buf.len() <= max_encoded_len(src.len()));
+        let len = decode_in_place(&mut buf).unwrap();
+        assert_eq!(&buf[..len], src);
+    }
+
+    #[test]
+    fn known_encodings() {
+        assert_eq!(encoded(&[]), [0x01]);
+        assert_eq!(encoded(&[0x00]), [0x01, 0x01]);
+        assert_eq!(encoded(&[0x11, 0x22, 0x00, 0x33]), [0x03, 0x11, 0x22, 0x02, 0x33]);
+        assert_eq!(encoded(&[0x11, 0x00, 0x00]), [0x02, 0x11, 0x01, 0x01]);
+    }
+
+    #[test]
+    fn round_trips() {
+        round_trip(&[]);
+        round_trip(&[0x01]);
+        round_trip(&[0x11, 0x22, 0x00, 0x33]);
+        round_trip(&(0..=255u8).collect::<Vec<_>>());
+        round_trip(&(0..=255u8).rev().collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn all_zeros() {
+        for len in [1, 2, 253, 254, 255, 600] {
+            let src = vec![0u8; len];
+            assert_eq!(encoded(&src), vec![0x01; len + 1]);
+            round_trip(&src);
+        }
+    }
+
+    #[test]
+    fn long_runs() {
+        // 254 non-zero bytes fill one block exactly, 255 spill into the next
+        for len in [253, 254, 255, 508, 509, 510] {
+            let src = vec![0x5A; len];
+            let enc = encoded(&src);
+            assert_eq!(enc[0] as usize, (len + 1).min(0xFF));
+            assert_eq!(enc.len(), max_encoded_len(len));
+            round_trip(&src);
+
+            let mut zero_end = src.clone();
+            zero_end.push(0);
+            round_trip(&zero_end);
+
+            let mut zero_start = vec![0];
+            zero_start.extend_from_slice(&src);
+            round_trip(&zero_start);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        // A zero code byte, first or later
+        assert_eq!(decode_in_place(&mut [0x00]), None);
+        assert_eq!(decode_in_place(&mut [0x02, 0x11, 0x00, 0x22]), None);
+
+        // A code that runs past the end of the frame
+        assert_eq!(decode_in_place(&mut [0x03, 0x11]), None);
+        assert_eq!(decode_in_place(&mut [0x02, 0x11, 0x05, 0x22]), None);
+        let mut short = vec![0xFF; 254];
+        assert_eq!(decode_in_place(&mut short), None);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(decode_in_place(&mut []), Some(0));
+    }
+}
@@ -0,0 +1,19 @@
+//! # amodem host interface
+//!
+//! The parts of the modem's host interface that don't depend on the transport:
+//! the register map, the command byte encoding, the long packet header, and a
+//! byte-at-a-time [session::Session] used by the I2C and UART front-ends.
+//! The COBS framing used by the UART front-end is in [cobs].
+//! The bootloader's commands are in [boot].
+//!
+//! This crate is `no_std`, and has no hardware dependencies, so the logic can
+//! be unit tested on the host with `cargo test`.
+
+#![cfg_attr(not(test), no_std)]
+
+pub mod boot;
+pub mod cmd;
+pub mod cobs;
+pub mod long;
+pub mod regmap;
+pub mod session;
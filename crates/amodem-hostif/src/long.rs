@@ -0,0 +1,128 @@
+//! Long packet header
+//!
+//! Long packet transactions move whole frames between the host and the modem.
+//! They start with a fixed size header from the modem, describing the frames
+//! available in each direction.
+//!
+//! Frames are at most 510 bytes (`amodem_bus::frag::MAX_FRAME`, two RS-485
+//! fragments), plus the address byte in front of each (see `RS485_CFG_ROUTER`),
+//! so neither the outgoing length nor the incoming capacity is ever more than
+//! 511. The ceiling comes from the modem's 1024 byte frame queues, which must
+//! hold about two frames of the largest size.
+
+/// Length of the header exchanged at the start of a long packet transaction,
+/// not including the command byte.
+///
+/// The modem sends:
+///
+/// | Byte | Contents                                          |
+/// | :--  | :--                                               |
+/// | 0    | Status, see `LONG_STATUS_*`                       |
+/// | 1    | Sequence number of this transaction               |
+/// | 2..4 | Length of the outgoing (modem to host) frame, LE  |
+/// | 4..6 | Capacity for an incoming (host to modem) frame, LE |
+///
+/// The host sends six bytes of zeroes, which are discarded. Payloads in both
+/// directions start immediately after the header.
+///
+/// If the host clocks out fewer bytes than the outgoing frame length, the
+/// frame is retained and offered again in the next transaction. If the host
+/// sends more bytes than the incoming capacity, the whole frame is discarded,
+/// on every host link, as it can't be sent on cut short. The
+/// outcome of each transaction is reported in the `LAST_XFER_*` registers.
+pub const LONG_HEADER_LEN: u8 = 6;
+
+/// `LONG_STATUS_*`: Always set, to distinguish a valid header from an idle bus
+pub const LONG_STATUS_VALID: u8 = 0b1000_0000;
+
+/// `LONG_STATUS_*`: An outgoing frame follows the header
+pub const LONG_STATUS_TX_FRAME: u8 = 0b0000_0001;
+
+/// `LONG_STATUS_*`: The modem can accept an incoming frame
+pub const LONG_STATUS_RX_READY: u8 = 0b0000_0010;
+
+/// `LONG_STATUS_*`: One or more enabled interrupt events are pending
+pub const LONG_STATUS_IRQ: u8 = 0b0000_0100;
+
+/// `LONG_STATUS_*`: The previous long packet transaction reported an error
+pub const LONG_STATUS_LAST_ERR: u8 = 0b0000_1000;
+
+/// `LONG_*`: Set in the long packet command byte to request a write-only
+/// transaction, for half duplex transports (3-wire SPI and I2C). The host sends
+/// its frame directly after the command byte, and no header is exchanged.
+///
+/// Without this flag, half duplex transports perform a read-only transaction,
+/// and the header's incoming capacity is the room for the next write.
+pub const LONG_WRITE_ONLY: u8 = 0b0000_0001;
+
+/// A decoded long packet header
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LongHeader {
+    /// `LONG_STATUS_*` flags, including `LONG_STATUS_VALID`
+    pub status: u8,
+    /// Sequence number of this transaction
+    pub seq: u8,
+    /// Length of the outgoing (modem to host) frame
+    pub out_len: u16,
+    /// Capacity for an incoming (host to modem) frame
+    pub in_cap: u16,
+}
+
+impl LongHeader {
+    /// Create a header, deriving the frame flags from the lengths. `irq` and
+    /// `last_err` set `LONG_STATUS_IRQ` and `LONG_STATUS_LAST_ERR`.
+    pub fn new(seq: u8, out_len: u16, in_cap: u16, irq: bool, last_err: bool) -> Self {
+        let mut status = LONG_STATUS_VALID;
+        if out_len != 0 {
+            status |= LONG_STATUS_TX_FRAME;
+        }
+        if in_cap != 0 {
+            status |= LONG_STATUS_RX_READY;
+        }
+        if irq {
+            status |= LONG_STATUS_IRQ;
+        }
+        if last_err {
+            status |= LONG_STATUS_LAST_ERR;
+        }
+        Self { status, seq, out_len, in_cap }
+    }
+
+    pub fn to_bytes(&self) -> [u8; LONG_HEADER_LEN as usize] {
+        let [ol0, ol1] = self.out_len.to_le_bytes();
+        let [ic0, ic1] = self.in_cap.to_le_bytes();
+        [self.status, self.seq, ol0, ol1, ic0, ic1]
+    }
+
+    /// Decode a header, returning `None` if `LONG_STATUS_VALID` is not set
+    pub fn from_bytes(bytes: &[u8; LONG_HEADER_LEN as usize]) -> Option<Self> {
+        if (bytes[0] & LONG_STATUS_VALID) == 0 {
+            return None;
+        }
+        Some(Self {
+            status: bytes[0],
+            seq: bytes[1],
+            out_len: u16::from_le_bytes([bytes[2], bytes[3]]),
+            in_cap: u16::from_le_bytes([bytes[4], bytes[5]]),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let hdr = LongHeader::new(0x12, 0x0105, 0, true, false);
+        assert_eq!(hdr.status, LONG_STATUS_VALID | LONG_STATUS_TX_FRAME | LONG_STATUS_IRQ);
+        let bytes = hdr.to_bytes();
+        assert_eq!(bytes, [0x85, 0x12, 0x05, 0x01, 0x00, 0x00]);
+        assert_eq!(LongHeader::from_bytes(&bytes), Some(hdr));
+    }
+
+    #[test]
+    fn idle_bus() {
+        assert_eq!(LongHeader::from_bytes(&[0; 6]), None);
+    }
+}
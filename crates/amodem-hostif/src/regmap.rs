@@ -0,0 +1,316 @@
+//! Register indexes and bit definitions
+//!
+//! See the `regs` module of the firmware for the full register table, and the
+//! side effects of host writes.
+
+/// Total number of registers addressable by the host
+pub const REG_COUNT: usize = 32;
+
+/// Baud rate divisor, written as-is to `USART1.BRR` on apply
+pub const RS485_BRR: u8 = 0x00;
+
+/// RS-485 line config
+pub const RS485_CFG: u8 = 0x01;
+
+/// RS-485 driver enable timings, in units of sample time (1/8 or 1/16 bit)
+pub const RS485_DE: u8 = 0x02;
+
+/// RS-485 config apply request/status
+pub const RS485_CTRL: u8 = 0x03;
+
+/// Pending interrupt events. Writing a one to a bit clears it.
+pub const IRQ_STATUS: u8 = 0x04;
+
+/// Interrupt event mask. Events set in both `IRQ_STATUS` and `IRQ_ENABLE`
+/// assert the IRQ line.
+pub const IRQ_ENABLE: u8 = 0x05;
+
+/// Outcome of the most recent long packet transaction. The upper byte holds the
+/// sequence number sent in that transaction's header, the lower byte holds
+/// `LAST_XFER_*` flags.
+pub const LAST_XFER: u8 = 0x06;
+
+/// Number of bytes from the host accepted in the most recent long packet transaction
+pub const LAST_XFER_RX_LEN: u8 = 0x07;
+
+/// Number of bytes delivered to the host in the most recent long packet transaction.
+/// This is either zero, or the full length of the outgoing frame.
+pub const LAST_XFER_TX_LEN: u8 = 0x08;
+
+/// Number of SPI receive overruns
+pub const SPI_OVR_COUNT: u8 = 0x09;
+
+/// Number of SPI frame format errors, mode faults, and commands received
+/// before the previous transaction was complete
+pub const SPI_FRE_COUNT: u8 = 0x0A;
+
+/// SPI mode and bit order. Reads back the staged value, which is the active
+/// value unless the host has changed it since boot.
+pub const SPI_CFG: u8 = 0x0B;
+
+/// Router poll list, entries 0 and 1. Each byte is one entry, see `ROUTER_NODE_*`.
+/// Entries are polled in order, skipping invalid entries.
+pub const ROUTER_NODES_0: u8 = 0x0C;
+
+/// Router poll list, entries 2 and 3
+pub const ROUTER_NODES_1: u8 = 0x0D;
+
+/// Router poll list, entries 4 and 5
+pub const ROUTER_NODES_2: u8 = 0x0E;
+
+/// Router poll list, entries 6 and 7
+pub const ROUTER_NODES_3: u8 = 0x0F;
+
+/// Minimum time from the end of one router poll to the start of the next, in microseconds
+pub const ROUTER_INTERVAL: u8 = 0x10;
+
+/// Number of RS-485 words received with a parity error. Only counted with
+/// `RS485_CFG_PARITY` set.
+pub const RS485_PE_COUNT: u8 = 0x11;
+
+/// Router: number of polls that a node did not answer in time, or whose
+/// payloads stopped short.
+///
+/// Node: number of transactions abandoned because the router went quiet
+/// partway through, in the header, or either payload.
+pub const RS485_TIMEOUT_COUNT: u8 = 0x12;
+
+/// Number of incoming RS-485 frames that stopped short of their announced length.
+/// These are never delivered, even in part: they are dropped, and NAK'd, so the
+/// other end sends them again.
+pub const RS485_TRUNC_COUNT: u8 = 0x13;
+
+/// Number of incoming RS-485 frames that failed their CRC
+pub const RS485_CRC_COUNT: u8 = 0x14;
+
+/// Number of outgoing RS-485 frames sent again, as the other end did not
+/// acknowledge them
+pub const RS485_RETRY_COUNT: u8 = 0x15;
+
+/// Number of RS-485 words received without a valid stop bit
+pub const RS485_FE_COUNT: u8 = 0x16;
+
+/// Number of RS-485 words received with noise on a bit
+pub const RS485_NE_COUNT: u8 = 0x17;
+
+/// Number of RS-485 receive overruns, where a word arrived with the receive
+/// FIFO full
+pub const RS485_ORE_COUNT: u8 = 0x18;
+
+/// Node: group address to receive multicasts for, in addition to its own
+/// address and broadcasts. See `RS485_GROUP_*`.
+pub const RS485_GROUP: u8 = 0x19;
+
+/// Number of partly reassembled RS-485 frames dropped, as a fragment was
+/// missed, or the next one did not arrive in time
+pub const RS485_FRAG_DROP_COUNT: u8 = 0x1A;
+
+/// Router: index of the routing table entry that `ROUTE` reads and writes,
+/// `0..ROUTES_MAX`
+pub const ROUTE_SEL: u8 = 0x1B;
+
+/// Router: the routing table entry selected by `ROUTE_SEL`, see `ROUTE_*`.
+/// Frames from a node with a destination header matching a valid entry are
+/// forwarded to the node it goes via, instead of to the host.
+pub const ROUTE: u8 = 0x1C;
+
+/// Bus time in microseconds, low half, as of the last write to this register.
+/// Any write latches the current time into it and `SYNC_TIME_HI`. On a node,
+/// this is the router's time once it has synchronized, and its own until then.
+pub const SYNC_TIME_LO: u8 = 0x1D;
+
+/// Bus time in microseconds, high half, latched with `SYNC_TIME_LO`
+pub const SYNC_TIME_HI: u8 = 0x1E;
+
+/// Node: how far off its bus time was at the last sync heard, in microseconds,
+/// as a signed value saturating at +/-0x7FFF. Reads `SYNC_OFFSET_NONE` until
+/// the node has synchronized, and zero on the router.
+pub const SYNC_OFFSET: u8 = 0x1F;
+
+/// Number of entries in the router poll list
+pub const ROUTER_NODES_MAX: usize = 8;
+
+/// Number of entries in the routing table
+pub const ROUTES_MAX: usize = 8;
+
+/// `RS485_CFG`: Set for 8x oversampling, clear for 16x oversampling
+pub const RS485_CFG_OVER8: u16 = 0b0000_0001;
+
+/// `RS485_CFG`: Set to act as the bus router (master), polling the nodes in the
+/// `ROUTER_NODES_*` registers. Clear to act as a node.
+///
+/// As a node, frames to the host start with the address they were sent to:
+/// the node's own, its `RS485_GROUP`, or the broadcast address, `0x7F`.
+///
+/// Frames from a node's host must start with a destination header: the address
+/// to route them to, or `0x00` for the router's host, whether or not routes are
+/// set. The router's host receives them with the source node's address in
+/// front. See `amodem_bus::route`.
+pub const RS485_CFG_ROUTER: u16 = 0b0000_0010;
+
+/// `RS485_CFG`: Router: set to broadcast a time sync every 100 ms, see
+/// `SYNC_TIME_LO`. Nodes follow any syncs they hear, regardless.
+pub const RS485_CFG_SYNC: u16 = 0b0000_0100;
+
+/// `RS485_CFG`: Set for even parity on every word, counted in `RS485_PE_COUNT`
+/// when wrong. The parity bit takes the place of the address mark, so nodes
+/// wake on an idle line instead, and check each address themselves: the router
+/// leaves the line idle ahead of every header. Every node on the bus must
+/// agree on this bit.
+///
+/// A node may mistake the first word after a gap in another node's poll for a
+/// header. The header checks, and the payload CRC, catch most of these. The
+/// bootloader does not support parity, and gets no bus link with it set.
+pub const RS485_CFG_PARITY: u16 = 0b0000_1000;
+
+/// `RS485_CFG`: Node: the 7-bit node address, `0x01..=0x6F`. Zero for none, in
+/// which case the node waits for the router's discovery to assign it one,
+/// which is saved along with the rest of the applied config.
+pub const RS485_CFG_ADDR_MASK: u16 = 0b0111_1111_0000_0000;
+
+/// `RS485_CFG`: Shift of the `RS485_CFG_ADDR_MASK` field
+pub const RS485_CFG_ADDR_SHIFT: u32 = 8;
+
+/// `SYNC_OFFSET`: The node has not synchronized to the router yet
+pub const SYNC_OFFSET_NONE: u16 = 0x8000;
+
+/// `ROUTER_NODE_*`: Set if this poll list entry is in use
+pub const ROUTER_NODE_VALID: u8 = 0b1000_0000;
+
+/// `ROUTER_NODE_*`: The 7-bit node address of this poll list entry
+pub const ROUTER_NODE_ADDR_MASK: u8 = 0b0111_1111;
+
+/// `RS485_GROUP`: Set if the node is in a group
+pub const RS485_GROUP_VALID: u16 = 0b1000_0000;
+
+/// `RS485_GROUP`: The 7-bit group address, one of `0x70..=0x7D`, as
+/// `0x7E` is kept for firmware updates
+pub const RS485_GROUP_ADDR_MASK: u16 = 0b0111_1111;
+
+/// `ROUTE`: Set if this routing table entry is in use
+pub const ROUTE_VALID: u16 = 0b0000_0000_1000_0000;
+
+/// `ROUTE`: The 7-bit destination address this entry matches
+pub const ROUTE_DEST_MASK: u16 = 0b0000_0000_0111_1111;
+
+/// `ROUTE`: The 7-bit address of the polled node that frames for the
+/// destination are forwarded to
+pub const ROUTE_VIA_MASK: u16 = 0b0111_1111_0000_0000;
+
+/// `ROUTE`: Shift of the `ROUTE_VIA_MASK` field
+pub const ROUTE_VIA_SHIFT: u32 = 8;
+
+/// `RS485_CTRL`: Written by the host to request that the staged config be applied.
+/// Cleared by the modem once the request has been handled. The applied config
+/// is saved to flash, and applied again at boot.
+pub const RS485_CTRL_APPLY: u16 = 0b0000_0001;
+
+/// `RS485_CTRL`: Set by the modem if the last apply request was rejected. The
+/// `RS485_*` config registers are restored to the active config when this occurs.
+pub const RS485_CTRL_INVALID: u16 = 0b0000_0010;
+
+/// `RS485_CTRL`: Router: set by the host to assign addresses to nodes that have
+/// none, adding them to the poll list. Cleared by the router once no more
+/// answer, or the poll list is full, raising `IRQ_DISCOVERY`.
+pub const RS485_CTRL_DISCOVER: u16 = 0b0000_0100;
+
+/// `SPI_CFG`: Clock phase. Set to sample on the second clock edge.
+pub const SPI_CFG_CPHA: u16 = 0b0000_0001;
+
+/// `SPI_CFG`: Clock polarity. Set for SCLK to idle high.
+pub const SPI_CFG_CPOL: u16 = 0b0000_0010;
+
+/// `SPI_CFG`: Set to send and receive the least significant bit first
+pub const SPI_CFG_LSBFIRST: u16 = 0b0000_0100;
+
+/// `SPI_CFG`: Set to move long packet payloads with 16-bit DMA transfers,
+/// allowing for higher SCLK rates.
+pub const SPI_CFG_PACKED: u16 = 0b0000_1000;
+
+/// `SPI_CFG`: Set for 3-wire, half duplex operation, using MISO as the only
+/// data line. See the `spi` module for the protocol changes this requires.
+pub const SPI_CFG_3WIRE: u16 = 0b0001_0000;
+
+/// `SPI_CFG`: All persisted bits
+pub const SPI_CFG_MASK: u16 = SPI_CFG_CPHA
+    | SPI_CFG_CPOL
+    | SPI_CFG_LSBFIRST
+    | SPI_CFG_PACKED
+    | SPI_CFG_3WIRE;
+
+/// `SPI_CFG`: Write with this bit set to save the config to flash and reset.
+/// Always reads as zero.
+pub const SPI_CFG_SAVE: u16 = 0b1000_0000_0000_0000;
+
+/// `SPI_CFG`: Write with this bit set to erase the saved SPI and RS-485 config,
+/// and reset, to start with the defaults. Always reads as zero.
+pub const SPI_CFG_FACTORY_RESET: u16 = 0b0100_0000_0000_0000;
+
+/// `SPI_CFG`: Write with this bit set to reset into the bootloader, to update
+/// the firmware. See the `boot` module. Always reads as zero.
+pub const SPI_CFG_BOOTLOADER: u16 = 0b0010_0000_0000_0000;
+
+/// `LAST_XFER_*`: The outgoing frame was not fully clocked out, and was retained
+pub const LAST_XFER_TX_RETAINED: u16 = 0b0000_0001;
+
+/// `LAST_XFER_*`: The host sent more than the incoming capacity, the whole frame
+/// was discarded. The capacity is never more than 511 bytes, see the `long`
+/// module.
+pub const LAST_XFER_RX_OVERFLOW: u16 = 0b0000_0010;
+
+/// `LAST_XFER_*`: CSn rose before the header was complete, no data was exchanged
+pub const LAST_XFER_HDR_INCOMPLETE: u16 = 0b0000_0100;
+
+/// `LAST_XFER_*`: The transaction was aborted due to a SPI error
+pub const LAST_XFER_ABORTED: u16 = 0b0000_1000;
+
+/// `IRQ_*`: A frame received over RS-485 is ready for the host to read
+pub const IRQ_FRAME_RECEIVED: u16 = 0b0000_0001;
+
+/// `IRQ_*`: A frame from the host has been sent over RS-485, and acknowledged
+pub const IRQ_FRAME_SENT: u16 = 0b0000_0010;
+
+/// `IRQ_*`: An RS-485 transaction timed out
+pub const IRQ_RS485_TIMEOUT: u16 = 0b0000_0100;
+
+/// `IRQ_*`: An incoming frame could not be taken, as there was no room to store
+/// it. A frame sent to our own address is kept by the other end and sent again
+/// later; a group or broadcast frame is lost.
+pub const IRQ_QUEUE_OVERFLOW: u16 = 0b0000_1000;
+
+/// `IRQ_*`: An incoming RS-485 frame failed its CRC, and was dropped. The
+/// other end sends it again.
+pub const IRQ_CRC_ERROR: u16 = 0b0001_0000;
+
+/// `IRQ_*`: A SPI error occurred, a command was malformed, or a command ran
+/// past the end of the register map
+pub const IRQ_SPI_ERROR: u16 = 0b0010_0000;
+
+/// `IRQ_*`: An incoming RS-485 frame stopped short of its announced length,
+/// and was dropped. The other end sends it again. Also raised when a partly
+/// reassembled frame is dropped, which is not sent again.
+pub const IRQ_FRAME_TRUNCATED: u16 = 0b0100_0000;
+
+/// `IRQ_*`: A framing error, noise, or an overrun was seen on the RS-485 bus.
+/// Any frame being received at the time was dropped, and is sent again.
+pub const IRQ_LINE_ERROR: u16 = 0b1000_0000;
+
+/// `IRQ_*`: Router: discovery is done, see `RS485_CTRL_DISCOVER`. Node: this
+/// node was assigned an address.
+pub const IRQ_DISCOVERY: u16 = 0b0000_0001_0000_0000;
+
+/// `IRQ_*`: Router: a frame from the host, or one being forwarded between
+/// nodes, could not be delivered, and was dropped. Its node is not in the poll
+/// list, or stopped answering polls.
+pub const IRQ_FRAME_DROPPED: u16 = 0b0000_0010_0000_0000;
+
+/// `IRQ_ENABLE`: Set to use IO1 as an active-low, open drain IRQ line, instead
+/// of as the TX ready signal.
+pub const IRQ_PIN_EN: u16 = 0b1000_0000_0000_0000;
+
+/// Are any enabled interrupt events pending, given the values of the
+/// `IRQ_STATUS` and `IRQ_ENABLE` registers?
+#[inline]
+pub fn irq_pending(status: u16, enable: u16) -> bool {
+    (status & enable & !IRQ_PIN_EN) != 0
+}
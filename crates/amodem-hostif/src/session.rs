@@ -0,0 +1,546 @@
+//! Byte-at-a-time host sessions
+//!
+//! [Session] implements the register and long packet commands for transports
+//! that move one byte at a time, and can pause between bytes. The modem's I2C
+//! and UART front-ends use it, the SPI front-end doesn't, as its long packets
+//! are moved by DMA. These are half duplex, so like 3-wire SPI, long packets
+//! are split into long reads and long writes (see `LONG_WRITE_ONLY`).
+//!
+//! A transaction is made of a write phase, where the host sends the command
+//! byte and any data, optionally followed by a read phase, where the modem
+//! sends data. For I2C, this is a write to the modem's address, followed by a
+//! (repeated start) read. For UART, it's a request frame and its response.
+//! Read phases use the most recently written command, so a host can poll a
+//! register, or for frames, by repeating only the read.
+//!
+//! | Command        | Write phase       | Read phase                      |
+//! | :--            | :--               | :--                             |
+//! | Reg Read       | (none)            | Register, LE                    |
+//! | Reg Write      | Value, LE         | (none)                          |
+//! | Burst Read     | (none)            | Registers `i`, `i + 1`, ..., LE |
+//! | Burst Write    | Values, LE        | (none)                          |
+//! | Long Read      | (none)            | Header, then outgoing frame     |
+//! | Long Write     | Incoming frame    | (none)                          |
+//!
+//! Bytes the modem has nothing to send for read as zero. Bytes written that
+//! the modem can't accept are refused (NAK'd, for I2C).
+
+use crate::{
+    cmd::Command,
+    long::{LongHeader, LONG_HEADER_LEN, LONG_WRITE_ONLY},
+    regmap::{self, REG_COUNT},
+};
+
+const HDR_LEN: usize = LONG_HEADER_LEN as usize;
+
+/// Access to the modem's registers
+pub trait Registers {
+    /// Read a register. Out of range indexes read as zero.
+    fn read(&mut self, idx: u8) -> u16;
+
+    /// Handle a register write made by the host, including any side effects
+    fn host_write(&mut self, idx: u8, val: u16);
+
+    /// Set a register on behalf of the modem, bypassing host write side
+    /// effects, for example to update the read-only `LAST_XFER` registers
+    fn set(&mut self, idx: u8, val: u16);
+
+    /// Latch one or more `IRQ_*` events
+    fn raise(&mut self, events: u16);
+}
+
+/// Access to the frame queues
+pub trait Mailbox {
+    /// Room for the next incoming (host to modem) frame, without claiming it
+    fn in_capacity(&mut self) -> usize;
+
+    /// Store byte `idx` of the incoming frame. Called with `idx` counting up
+    /// from zero. Returns false if the byte does not fit.
+    fn in_write(&mut self, idx: usize, byte: u8) -> bool;
+
+    /// Finish the incoming frame, keeping the first `len` bytes. A `len` of
+    /// zero discards it.
+    fn in_commit(&mut self, len: usize);
+
+    /// Claim the next outgoing (modem to host) frame, returning its length,
+    /// or zero if there is none.
+    fn out_claim(&mut self) -> usize;
+
+    /// Byte `idx` of the claimed outgoing frame
+    fn out_read(&mut self, idx: usize) -> u8;
+
+    /// Finish with the claimed outgoing frame. If `release` is false, it is
+    /// kept, and offered again by the next [Mailbox::out_claim].
+    fn out_finish(&mut self, release: bool);
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Write,
+    Read,
+}
+
+/// Transport independent state for one host link
+#[derive(Debug)]
+pub struct Session {
+    cmd: Option<Command>,
+    phase: Phase,
+    /// The command byte has been received in this write phase
+    have_cmd: bool,
+    /// Data bytes moved in the current phase, not including the command
+    count: usize,
+    /// Low byte of a register value being written
+    low: u8,
+    /// Next register for burst reads and writes
+    idx: u8,
+    /// Burst has run off the end of the register map
+    overrun: bool,
+    seq: u8,
+    hdr: [u8; HDR_LEN],
+    out_len: usize,
+    in_full: bool,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    pub const fn new() -> Self {
+        Self {
+            cmd: None,
+            phase: Phase::Idle,
+            have_cmd: false,
+            count: 0,
+            low: 0,
+            idx: 0,
+            overrun: false,
+            seq: 0,
+            hdr: [0; HDR_LEN],
+            out_len: 0,
+            in_full: false,
+        }
+    }
+
+    /// The host has started a write phase
+    pub fn begin_write<R: Registers, M: Mailbox>(&mut self, regs: &mut R, mbox: &mut M) {
+        self.end(regs, mbox);
+        self.phase = Phase::Write;
+        self.have_cmd = false;
+    }
+
+    /// The host has sent a byte. Returns false if the byte was refused.
+    pub fn write<R: Registers, M: Mailbox>(&mut self, regs: &mut R, mbox: &mut M, byte: u8) -> bool {
+        if self.phase != Phase::Write {
+            return false;
+        }
+
+        if !self.have_cmd {
+            self.have_cmd = true;
+            self.count = 0;
+            self.overrun = false;
+            self.in_full = false;
+            self.cmd = Command::parse(byte);
+            return match self.cmd {
+                Some(Command::RegRead(idx)) | Some(Command::RegWrite(idx)) => (idx as usize) < REG_COUNT,
+                Some(Command::BurstRead(idx)) | Some(Command::BurstWrite(idx)) => {
+                    self.idx = idx;
+                    true
+                },
+                Some(Command::LongPacket(flags)) => (flags & !LONG_WRITE_ONLY) == 0,
+                None => {
+                    regs.raise(regmap::IRQ_SPI_ERROR);
+                    false
+                },
+            };
+        }
+
+        let count = self.count;
+        let ack = match self.cmd {
+            Some(Command::RegWrite(idx)) if count < 2 => {
+                if count == 0 {
+                    self.low = byte;
+                } else {
+                    regs.host_write(idx, u16::from_le_bytes([self.low, byte]));
+                }
+                true
+            },
+            Some(Command::BurstWrite(_)) => {
+                if (count & 1) == 0 {
+                    self.low = byte;
+                    true
+                } else if (self.idx as usize) < REG_COUNT {
+                    regs.host_write(self.idx, u16::from_le_bytes([self.low, byte]));
+                    self.idx += 1;
+                    true
+                } else {
+                    self.burst_overrun(regs);
+                    false
+                }
+            },
+            Some(Command::LongPacket(flags)) if (flags & LONG_WRITE_ONLY) != 0 => {
+                let ok = !self.in_full && mbox.in_write(count, byte);
+                self.in_full = !ok;
+                ok
+            },
+            _ => false,
+        };
+
+        if ack {
+            self.count += 1;
+        }
+        ack
+    }
+
+    /// The host has started a read phase
+    pub fn begin_read<R: Registers, M: Mailbox>(&mut self, regs: &mut R, mbox: &mut M) {
+        self.end(regs, mbox);
+        self.phase = Phase::Read;
+        self.count = 0;
+        self.overrun = false;
+
+        match self.cmd {
+            Some(Command::BurstRead(idx)) => self.idx = idx,
+            Some(Command::LongPacket(flags)) if (flags & LONG_WRITE_ONLY) == 0 => {
+                self.out_len = mbox.out_claim();
+                let in_cap = mbox.in_capacity();
+                self.seq = self.seq.wrapping_add(1);
+                let irq = regmap::irq_pending(regs.read(regmap::IRQ_STATUS), regs.read(regmap::IRQ_ENABLE));
+                let last_err = (regs.read(regmap::LAST_XFER) & 0x00FF) != 0;
+                self.hdr = LongHeader::new(self.seq, self.out_len as u16, in_cap as u16, irq, last_err).to_bytes();
+            },
+            _ => {},
+        }
+    }
+
+    /// The host wants another byte
+    pub fn read<R: Registers, M: Mailbox>(&mut self, regs: &mut R, mbox: &mut M) -> u8 {
+        if self.phase != Phase::Read {
+            return 0;
+        }
+
+        let count = self.count;
+        self.count += 1;
+
+        match self.cmd {
+            Some(Command::RegRead(idx)) if count < 2 => regs.read(idx).to_le_bytes()[count],
+            Some(Command::BurstRead(_)) => {
+                if (self.idx as usize) >= REG_COUNT {
+                    self.burst_overrun(regs);
+                    return 0;
+                }
+                let byte = regs.read(self.idx).to_le_bytes()[count & 1];
+                if (count & 1) != 0 {
+                    self.idx += 1;
+                }
+                byte
+            },
+            Some(Command::LongPacket(flags)) if (flags & LONG_WRITE_ONLY) == 0 => {
+                if count < HDR_LEN {
+                    self.hdr[count]
+                } else if (count - HDR_LEN) < self.out_len {
+                    mbox.out_read(count - HDR_LEN)
+                } else {
+                    0
+                }
+            },
+            _ => 0,
+        }
+    }
+
+    /// The host has ended the transaction (for I2C, a stop condition)
+    pub fn end<R: Registers, M: Mailbox>(&mut self, regs: &mut R, mbox: &mut M) {
+        let phase = core::mem::replace(&mut self.phase, Phase::Idle);
+
+        let flags = match (phase, self.cmd) {
+            (Phase::Write, Some(Command::LongPacket(f))) if self.have_cmd && (f & LONG_WRITE_ONLY) != 0 => f,
+            (Phase::Read, Some(Command::LongPacket(f))) if (f & LONG_WRITE_ONLY) == 0 => f,
+            _ => return,
+        };
+
+        let mut result = 0;
+        let mut received = 0;
+        let mut sent = 0;
+
+        if (flags & LONG_WRITE_ONLY) != 0 {
+            self.seq = self.seq.wrapping_add(1);
+            // A frame is taken whole, or not at all
+            if self.in_full {
+                result |= regmap::LAST_XFER_RX_OVERFLOW;
+            } else {
+                received = self.count;
+            }
+            mbox.in_commit(received);
+            // Only one long write per write phase
+            self.cmd = None;
+        } else {
+            if self.count < HDR_LEN {
+                result |= regmap::LAST_XFER_HDR_INCOMPLETE;
+            }
+            if self.out_len != 0 {
+                let done = self.count >= (HDR_LEN + self.out_len);
+                mbox.out_finish(done);
+                if done {
+                    sent = self.out_len;
+                } else {
+                    result |= regmap::LAST_XFER_TX_RETAINED;
+                }
+                self.out_len = 0;
+            }
+        }
+
+        regs.set(regmap::LAST_XFER, ((self.seq as u16) << 8) | result);
+        regs.set(regmap::LAST_XFER_RX_LEN, received as u16);
+        regs.set(regmap::LAST_XFER_TX_LEN, sent as u16);
+    }
+
+    fn burst_overrun<R: Registers>(&mut self, regs: &mut R) {
+        if !self.overrun {
+            self.overrun = true;
+            regs.raise(regmap::IRQ_SPI_ERROR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::long::{LONG_STATUS_RX_READY, LONG_STATUS_TX_FRAME, LONG_STATUS_VALID};
+    use std::{collections::VecDeque, vec::Vec};
+
+    #[derive(Default)]
+    struct FakeRegs {
+        regs: [u16; REG_COUNT],
+        raised: u16,
+    }
+
+    impl Registers for FakeRegs {
+        fn read(&mut self, idx: u8) -> u16 {
+            self.regs.get(idx as usize).copied().unwrap_or(0)
+        }
+
+        fn host_write(&mut self, idx: u8, val: u16) {
+            if idx != regmap::LAST_XFER {
+                self.set(idx, val);
+            }
+        }
+
+        fn set(&mut self, idx: u8, val: u16) {
+            if let Some(reg) = self.regs.get_mut(idx as usize) {
+                *reg = val;
+            }
+        }
+
+        fn raise(&mut self, events: u16) {
+            self.raised |= events;
+        }
+    }
+
+    #[derive(Default)]
+    struct FakeMailbox {
+        cap: usize,
+        incoming: Vec<Vec<u8>>,
+        staging: Vec<u8>,
+        outgoing: VecDeque<Vec<u8>>,
+        claimed: bool,
+    }
+
+    impl Mailbox for FakeMailbox {
+        fn in_capacity(&mut self) -> usize {
+            self.cap
+        }
+
+        fn in_write(&mut self, idx: usize, byte: u8) -> bool {
+            assert_eq!(idx, self.staging.len());
+            if idx < self.cap {
+                self.staging.push(byte);
+                true
+            } else {
+                false
+            }
+        }
+
+        fn in_commit(&mut self, len: usize) {
+            let mut frame = core::mem::take(&mut self.staging);
+            frame.truncate(len);
+            if len != 0 {
+                self.incoming.push(frame);
+            }
+        }
+
+        fn out_claim(&mut self) -> usize {
+            assert!(!self.claimed);
+            self.claimed = !self.outgoing.is_empty();
+            self.outgoing.front().map(|f| f.len()).unwrap_or(0)
+        }
+
+        fn out_read(&mut self, idx: usize) -> u8 {
+            assert!(self.claimed);
+            self.outgoing[0][idx]
+        }
+
+        fn out_finish(&mut self, release: bool) {
+            assert!(self.claimed);
+            self.claimed = false;
+            if release {
+                self.outgoing.pop_front();
+            }
+        }
+    }
+
+    struct Host {
+        sess: Session,
+        regs: FakeRegs,
+        mbox: FakeMailbox,
+    }
+
+    impl Host {
+        fn new() -> Self {
+            Self {
+                sess: Session::new(),
+                regs: FakeRegs::default(),
+                mbox: FakeMailbox::default(),
+            }
+        }
+
+        /// Write `data`, returning how many bytes were accepted
+        fn write(&mut self, data: &[u8]) -> usize {
+            self.sess.begin_write(&mut self.regs, &mut self.mbox);
+            let acked = data
+                .iter()
+                .take_while(|b| self.sess.write(&mut self.regs, &mut self.mbox, **b))
+                .count();
+            self.sess.end(&mut self.regs, &mut self.mbox);
+            acked
+        }
+
+        /// Write the command, then read `len` bytes in a repeated start
+        fn write_read(&mut self, cmd: u8, len: usize) -> Vec<u8> {
+            self.sess.begin_write(&mut self.regs, &mut self.mbox);
+            assert!(self.sess.write(&mut self.regs, &mut self.mbox, cmd));
+            self.read(len)
+        }
+
+        fn read(&mut self, len: usize) -> Vec<u8> {
+            self.sess.begin_read(&mut self.regs, &mut self.mbox);
+            let out = (0..len)
+                .map(|_| self.sess.read(&mut self.regs, &mut self.mbox))
+                .collect();
+            self.sess.end(&mut self.regs, &mut self.mbox);
+            out
+        }
+    }
+
+    #[test]
+    fn reg_write_read() {
+        let mut host = Host::new();
+        assert_eq!(host.write(&[Command::RegWrite(0x1F).to_byte(), 0x34, 0x12]), 3);
+        assert_eq!(host.regs.regs[0x1F], 0x1234);
+        assert_eq!(host.write_read(Command::RegRead(0x1F).to_byte(), 2), [0x34, 0x12]);
+
+        // Repeated reads reuse the last command
+        host.regs.regs[0x1F] = 0xBEEF;
+        assert_eq!(host.read(3), [0xEF, 0xBE, 0x00]);
+
+        // Too many bytes are refused
+        assert_eq!(host.write(&[Command::RegWrite(0x1E).to_byte(), 1, 2, 3]), 3);
+        assert_eq!(host.regs.regs[0x1E], 0x0201);
+    }
+
+    #[test]
+    fn invalid_command() {
+        let mut host = Host::new();
+        assert_eq!(host.write(&[0b111_00000, 0x00]), 0);
+        assert_eq!(host.regs.raised, regmap::IRQ_SPI_ERROR);
+        assert_eq!(host.read(2), [0, 0]);
+    }
+
+    #[test]
+    fn burst() {
+        let mut host = Host::new();
+        let cmd = Command::BurstWrite(0x1D).to_byte();
+        assert_eq!(host.write(&[cmd, 1, 0, 2, 0, 3, 0]), 7);
+        assert_eq!(&host.regs.regs[0x1D..], &[1, 2, 3]);
+        assert_eq!(host.regs.raised, 0);
+
+        // Past the end: the low byte is accepted, the high byte is refused
+        assert_eq!(host.write(&[cmd, 1, 0, 2, 0, 3, 0, 4, 0]), 8);
+        assert_eq!(host.regs.raised, regmap::IRQ_SPI_ERROR);
+
+        host.regs.raised = 0;
+        let got = host.write_read(Command::BurstRead(0x1E).to_byte(), 6);
+        assert_eq!(got, [2, 0, 3, 0, 0, 0]);
+        assert_eq!(host.regs.raised, regmap::IRQ_SPI_ERROR);
+    }
+
+    #[test]
+    fn long_read() {
+        let mut host = Host::new();
+        host.mbox.cap = 200;
+        host.mbox.outgoing.push_back(vec![0xAA, 0xBB, 0xCC]);
+
+        let long_read = Command::LongPacket(0).to_byte();
+        let got = host.write_read(long_read, HDR_LEN + 3);
+        let hdr = LongHeader::from_bytes(&got[..HDR_LEN].try_into().unwrap()).unwrap();
+        assert_eq!(hdr.status, LONG_STATUS_VALID | LONG_STATUS_TX_FRAME | LONG_STATUS_RX_READY);
+        assert_eq!(hdr.seq, 1);
+        assert_eq!(hdr.out_len, 3);
+        assert_eq!(hdr.in_cap, 200);
+        assert_eq!(&got[HDR_LEN..], &[0xAA, 0xBB, 0xCC]);
+        assert!(host.mbox.outgoing.is_empty());
+        assert_eq!(host.regs.regs[regmap::LAST_XFER as usize], 0x0100);
+        assert_eq!(host.regs.regs[regmap::LAST_XFER_TX_LEN as usize], 3);
+
+        // Nothing left
+        let got = host.read(HDR_LEN);
+        assert_eq!(got[1], 2);
+        assert_eq!(u16::from_le_bytes([got[2], got[3]]), 0);
+    }
+
+    #[test]
+    fn long_read_short() {
+        let mut host = Host::new();
+        host.mbox.outgoing.push_back(vec![1, 2, 3, 4]);
+
+        let long_read = Command::LongPacket(0).to_byte();
+        host.write_read(long_read, HDR_LEN + 2);
+        assert_eq!(host.mbox.outgoing.len(), 1);
+        assert_eq!(
+            host.regs.regs[regmap::LAST_XFER as usize],
+            0x0100 | regmap::LAST_XFER_TX_RETAINED,
+        );
+
+        let got = host.read(HDR_LEN + 4);
+        assert_eq!(&got[HDR_LEN..], &[1, 2, 3, 4]);
+        assert!(host.mbox.outgoing.is_empty());
+        assert_eq!(host.regs.regs[regmap::LAST_XFER as usize], 0x0200);
+    }
+
+    #[test]
+    fn long_write() {
+        let mut host = Host::new();
+        host.mbox.cap = 4;
+
+        let long_write = Command::LongPacket(LONG_WRITE_ONLY).to_byte();
+        assert_eq!(host.write(&[long_write, 1, 2, 3]), 4);
+        assert_eq!(host.mbox.incoming, [vec![1, 2, 3]]);
+        assert_eq!(host.regs.regs[regmap::LAST_XFER as usize], 0x0100);
+        assert_eq!(host.regs.regs[regmap::LAST_XFER_RX_LEN as usize], 3);
+
+        // Overflow drops the whole frame, and is reported
+        assert_eq!(host.write(&[long_write, 1, 2, 3, 4, 5, 6]), 5);
+        assert_eq!(host.mbox.incoming, [vec![1, 2, 3]]);
+        assert_eq!(
+            host.regs.regs[regmap::LAST_XFER as usize],
+            0x0200 | regmap::LAST_XFER_RX_OVERFLOW,
+        );
+        assert_eq!(host.regs.regs[regmap::LAST_XFER_RX_LEN as usize], 0);
+
+        // A read after a long write returns nothing, and isn't a transaction
+        assert_eq!(host.read(2), [0, 0]);
+        assert_eq!(host.regs.regs[regmap::LAST_XFER as usize] >> 8, 2);
+    }
+}
@@ -0,0 +1,83 @@
+//! The modem's saved config
+//!
+//! Saved as a [VERSION] record of four LE words, each as in its register:
+//! `SPI_CFG`, `RS485_BRR`, `RS485_CFG`, and `RS485_DE`. A later version may
+//! add more, but must still load older records, with defaults for what they
+//! lack. A record of a version not known here, like one saved by newer
+//! firmware, is not loaded at all.
+
+use crate::{
+    flash::{Flash, FlashError},
+    store::{Record, Store},
+};
+
+/// The version of the records saved
+pub const VERSION: u8 = 1;
+
+const LEN: usize = 8;
+
+/// Settings kept across resets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SavedConfig {
+    pub spi_cfg: u16,
+    pub rs485_brr: u16,
+    pub rs485_cfg: u16,
+    pub rs485_de: u16,
+}
+
+impl SavedConfig {
+    pub fn to_record(&self) -> Record {
+        let mut buf = [0; LEN];
+        [self.spi_cfg, self.rs485_brr, self.rs485_cfg, self.rs485_de]
+            .iter()
+            .zip(buf.chunks_exact_mut(2))
+            .for_each(|(val, b)| b.copy_from_slice(&val.to_le_bytes()));
+        // Never too long
+        Record::new(VERSION, &buf).unwrap_or_else(|| unreachable!())
+    }
+
+    pub fn from_record(record: &Record) -> Option<Self> {
+        let data: &[u8; LEN] = match record.version {
+            VERSION => record.data().try_into().ok()?,
+            _ => return None,
+        };
+        let word = |i: usize| u16::from_le_bytes([data[2 * i], data[(2 * i) + 1]]);
+        Some(Self { spi_cfg: word(0), rs485_brr: word(1), rs485_cfg: word(2), rs485_de: word(3) })
+    }
+
+    /// The config last saved in `store`, if any
+    pub fn load<F: Flash>(store: &Store<F>) -> Option<Self> {
+        Self::from_record(&store.load()?)
+    }
+
+    /// Save this config in `store`, unless it is there already
+    pub fn save<F: Flash>(&self, store: &mut Store<F>) -> Result<(), FlashError> {
+        store.save(&self.to_record())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::RamFlash;
+
+    const CFG: SavedConfig = SavedConfig { spi_cfg: 0x0003, rs485_brr: 0x022B, rs485_cfg: 0x2102, rs485_de: 0x0404 };
+
+    #[test]
+    fn saved_and_loaded() {
+        let mut store = Store::new(RamFlash::<2048, 2>::new());
+        assert_eq!(SavedConfig::load(&store), None);
+        CFG.save(&mut store).unwrap();
+        assert_eq!(SavedConfig::load(&Store::new(store.flash().clone())), Some(CFG));
+        assert_eq!(CFG.to_record().data(), &[0x03, 0x00, 0x2B, 0x02, 0x02, 0x21, 0x04, 0x04]);
+    }
+
+    #[test]
+    fn unknown_versions_are_not_loaded() {
+        let mut store = Store::new(RamFlash::<2048, 2>::new());
+        CFG.save(&mut store).unwrap();
+        store.save(&Record::new(VERSION + 1, &[0; 12]).unwrap()).unwrap();
+        assert_eq!(SavedConfig::load(&store), None);
+        assert_eq!(SavedConfig::from_record(&Record::new(VERSION, &[0; 6]).unwrap()), None);
+    }
+}
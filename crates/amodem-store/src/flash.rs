@@ -0,0 +1,154 @@
+//! Access to a region of flash
+//!
+//! The region is some whole pages of NOR flash. Erasing a page sets every byte
+//! to [ERASED], and each write unit can then be programmed once, until the page
+//! is erased again. On the STM32G0, pages are 2K and write units are a double
+//! word.
+
+/// The value of an erased byte
+pub const ERASED: u8 = 0xFF;
+
+/// A flash operation failed, leaving what it touched in an unknown state
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FlashError;
+
+/// A region of flash, addressed from its start
+pub trait Flash {
+    /// Bytes in a page, the unit of erase
+    const PAGE_SIZE: usize;
+
+    /// Pages in the region
+    const PAGES: usize;
+
+    /// Bytes in a write unit. The store needs this to divide 8.
+    const WRITE_SIZE: usize;
+
+    /// Read `buf.len()` bytes from `offset`
+    fn read(&self, offset: usize, buf: &mut [u8]);
+
+    /// Erase page `page`
+    fn erase(&mut self, page: usize) -> Result<(), FlashError>;
+
+    /// Program `data` at `offset`. Both are whole write units, and the units
+    /// must be erased.
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError>;
+}
+
+/// A flash in RAM, of `PAGES` pages of `PAGE_SIZE` bytes, with 8 byte write
+/// units, as on the STM32G0
+///
+/// As on the real thing, programming a unit that is not erased fails. The
+/// power can be cut part way through a later write, and erases are counted,
+/// to check the wear.
+#[cfg(feature = "std")]
+#[derive(Debug, Clone)]
+pub struct RamFlash<const PAGE_SIZE: usize, const PAGES: usize> {
+    data: Vec<u8>,
+    erases: [u32; PAGES],
+    power_cut: Option<usize>,
+}
+
+#[cfg(feature = "std")]
+impl<const PAGE_SIZE: usize, const PAGES: usize> Default for RamFlash<PAGE_SIZE, PAGES> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl<const PAGE_SIZE: usize, const PAGES: usize> RamFlash<PAGE_SIZE, PAGES> {
+    /// A flash with every page erased
+    pub fn new() -> Self {
+        Self { data: vec![ERASED; PAGE_SIZE * PAGES], erases: [0; PAGES], power_cut: None }
+    }
+
+    /// The whole region, to look at or damage
+    pub fn bytes_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Times each page was erased
+    pub fn erases(&self) -> &[u32; PAGES] {
+        &self.erases
+    }
+
+    /// Cut the power once `bytes` more bytes are programmed: any write or
+    /// erase from then on fails, doing nothing, until [RamFlash::power_on]
+    pub fn cut_power_after(&mut self, bytes: usize) {
+        self.power_cut = Some(bytes);
+    }
+
+    /// Restore the power
+    pub fn power_on(&mut self) {
+        self.power_cut = None;
+    }
+}
+
+#[cfg(feature = "std")]
+impl<const PAGE_SIZE: usize, const PAGES: usize> Flash for RamFlash<PAGE_SIZE, PAGES> {
+    const PAGE_SIZE: usize = PAGE_SIZE;
+    const PAGES: usize = PAGES;
+    const WRITE_SIZE: usize = 8;
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        buf.copy_from_slice(&self.data[offset..][..buf.len()]);
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), FlashError> {
+        if (page >= PAGES) || (self.power_cut == Some(0)) {
+            return Err(FlashError);
+        }
+        self.data[page * PAGE_SIZE..][..PAGE_SIZE].fill(ERASED);
+        self.erases[page] += 1;
+        Ok(())
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        let aligned = offset.is_multiple_of(Self::WRITE_SIZE) && data.len().is_multiple_of(Self::WRITE_SIZE);
+        let target = self.data.get_mut(offset..offset + data.len()).ok_or(FlashError)?;
+        if !aligned || target.iter().any(|b| *b != ERASED) {
+            return Err(FlashError);
+        }
+        let amt = self.power_cut.map_or(data.len(), |left| left.min(data.len()));
+        target[..amt].copy_from_slice(&data[..amt]);
+        if let Some(left) = self.power_cut.as_mut() {
+            *left -= amt;
+        }
+        match amt == data.len() {
+            true => Ok(()),
+            false => Err(FlashError),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn programs_once_per_erase() {
+        let mut flash = RamFlash::<64, 2>::new();
+        flash.write(8, &[1; 8]).unwrap();
+        assert_eq!(flash.write(8, &[0; 8]), Err(FlashError));
+        assert_eq!(flash.write(20, &[0; 8]), Err(FlashError));
+        flash.erase(0).unwrap();
+        flash.write(8, &[0; 8]).unwrap();
+
+        let mut buf = [0; 16];
+        flash.read(0, &mut buf);
+        assert_eq!(buf, [ERASED, ERASED, ERASED, ERASED, ERASED, ERASED, ERASED, ERASED, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(flash.erases(), &[1, 0]);
+    }
+
+    #[test]
+    fn power_cut_tears_writes() {
+        let mut flash = RamFlash::<64, 2>::new();
+        flash.cut_power_after(12);
+        flash.write(0, &[0; 8]).unwrap();
+        assert_eq!(flash.write(8, &[0; 8]), Err(FlashError));
+        assert_eq!(flash.erase(1), Err(FlashError));
+        assert_eq!(&flash.bytes_mut()[8..16], &[0, 0, 0, 0, ERASED, ERASED, ERASED, ERASED]);
+        flash.power_on();
+        flash.erase(0).unwrap();
+    }
+}
@@ -0,0 +1,20 @@
+//! # amodem config store
+//!
+//! Settings that must survive a reset are kept in the last pages of the
+//! modem's flash, as a log of records:
+//!
+//! * [flash] has the [flash::Flash] trait the store is written against, and,
+//!   with the `std` feature (on by default), a RAM-backed flash that behaves
+//!   like the real one, power cuts included.
+//! * [store] appends versioned, CRC checked records, moving on to the next
+//!   page when one fills, so every page is erased in turn.
+//! * [config] has the modem's saved config, as a record.
+//!
+//! This crate is `no_std`, and has no hardware dependencies, so the logic can
+//! be unit tested on the host with `cargo test`.
+
+#![cfg_attr(not(any(test, feature = "std")), no_std)]
+
+pub mod flash;
+pub mod store;
+pub mod config;
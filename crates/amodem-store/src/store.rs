@@ -0,0 +1,322 @@
+//! A log of records in flash, spread over its pages
+//!
+//! Each page in use starts with a header: its sequence number, then
+//! [PAGE_MAGIC], both LE. The page with the highest sequence number is the
+//! active one. Records follow the header, each padded to a multiple of 8
+//! bytes:
+//!
+//! | Bytes | Contents                                   |
+//! | :--   | :--                                        |
+//! | 0     | Version, never `0xFF`                      |
+//! | 1     | Length of the data                         |
+//! | 2..4  | CRC of the version, length and data, LE    |
+//! | 4..   | Data                                       |
+//!
+//! A record is appended to the active page while there is room. Once it is
+//! full, the record goes to the start of the next page instead, which is
+//! erased first, and only then given its header, with the next sequence
+//! number. So every page is erased in turn, and a page with a header always
+//! holds an intact record.
+//!
+//! The latest intact record is the one loaded. A record torn by a power cut
+//! fails its CRC, and the one before it is loaded instead. With two or more
+//! pages, the last record saved survives a power cut at any point.
+
+use amodem_bus::crc::{crc16, crc16_update};
+
+use crate::flash::{Flash, FlashError, ERASED};
+
+/// "AMS1"
+pub const PAGE_MAGIC: u32 = 0x414D_5331;
+
+/// Most bytes of data in a record
+pub const MAX_DATA: usize = 60;
+
+const PAGE_HEADER_LEN: usize = 8;
+const RECORD_HEADER_LEN: usize = 4;
+const MAX_RECORD: usize = RECORD_HEADER_LEN + MAX_DATA;
+
+/// Bytes taken by a record with `len` bytes of data
+const fn record_size(len: usize) -> usize {
+    (RECORD_HEADER_LEN + len + 7) & !7
+}
+
+/// Versioned data, as saved
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Record {
+    pub version: u8,
+    len: u8,
+    data: [u8; MAX_DATA],
+}
+
+impl Record {
+    /// A record of `data`, or `None` if the version is `0xFF`, or there is more
+    /// than [MAX_DATA]
+    pub fn new(version: u8, data: &[u8]) -> Option<Self> {
+        if (version == ERASED) || (data.len() > MAX_DATA) {
+            return None;
+        }
+        let mut buf = [0; MAX_DATA];
+        buf[..data.len()].copy_from_slice(data);
+        Some(Self { version, len: data.len() as u8, data: buf })
+    }
+
+    pub fn data(&self) -> &[u8] {
+        &self.data[..self.len as usize]
+    }
+
+    fn crc(&self) -> u16 {
+        crc16_update(crc16(&[self.version, self.len]), self.data())
+    }
+
+    /// Encode into `buf`, returning the bytes taken
+    fn encode(&self, buf: &mut [u8; MAX_RECORD]) -> usize {
+        buf[0] = self.version;
+        buf[1] = self.len;
+        buf[2..RECORD_HEADER_LEN].copy_from_slice(&self.crc().to_le_bytes());
+        buf[RECORD_HEADER_LEN..][..self.data().len()].copy_from_slice(self.data());
+        record_size(self.data().len())
+    }
+
+    /// The record in `buf`, header and data, if it is intact
+    fn decode(buf: &[u8]) -> Option<Self> {
+        let (header, data) = buf.split_at_checked(RECORD_HEADER_LEN)?;
+        let &[version, len, crc_lo, crc_hi] = header else {
+            return None;
+        };
+        let record = Self::new(version, data).filter(|r| r.len == len)?;
+        (record.crc() == u16::from_le_bytes([crc_lo, crc_hi])).then_some(record)
+    }
+}
+
+/// The sequence number of `page`, if it has a header
+fn page_seq<F: Flash>(flash: &F, page: usize) -> Option<u32> {
+    let mut buf = [0; PAGE_HEADER_LEN];
+    flash.read(page * F::PAGE_SIZE, &mut buf);
+    let [s0, s1, s2, s3, magic @ ..] = buf;
+    (magic == PAGE_MAGIC.to_le_bytes()).then(|| u32::from_le_bytes([s0, s1, s2, s3]))
+}
+
+/// Walk the records of `page`. Returns where the next one goes, which is the
+/// end of the page if a damaged record header hides the rest, and the latest
+/// intact record.
+fn scan<F: Flash>(flash: &F, page: usize) -> (usize, Option<Record>) {
+    let base = page * F::PAGE_SIZE;
+    let mut at = PAGE_HEADER_LEN;
+    let mut latest = None;
+    while at < F::PAGE_SIZE {
+        let mut buf = [0; MAX_RECORD];
+        flash.read(base + at, &mut buf[..RECORD_HEADER_LEN]);
+        if buf[..RECORD_HEADER_LEN].iter().all(|b| *b == ERASED) {
+            break;
+        }
+        let len = buf[1] as usize;
+        if (buf[0] == ERASED) || (len > MAX_DATA) || (at + record_size(len) > F::PAGE_SIZE) {
+            return (F::PAGE_SIZE, latest);
+        }
+        flash.read(base + at + RECORD_HEADER_LEN, &mut buf[RECORD_HEADER_LEN..][..len]);
+        latest = Record::decode(&buf[..RECORD_HEADER_LEN + len]).or(latest);
+        at += record_size(len);
+    }
+    (at, latest)
+}
+
+/// Records saved in a [Flash] region
+pub struct Store<F> {
+    flash: F,
+    /// The active page, and its sequence number, if any page has a header
+    active: Option<(usize, u32)>,
+    /// Where the next record goes in the active page
+    end: usize,
+}
+
+impl<F: Flash> Store<F> {
+    /// Find the active page, and the end of its records
+    pub fn new(flash: F) -> Self {
+        let active = (0..F::PAGES)
+            .filter_map(|page| Some((page, page_seq(&flash, page)?)))
+            .max_by_key(|(_, seq)| *seq);
+        let end = active.map_or(0, |(page, _)| scan(&flash, page).0);
+        Self { flash, active, end }
+    }
+
+    pub fn flash(&self) -> &F {
+        &self.flash
+    }
+
+    pub fn flash_mut(&mut self) -> &mut F {
+        &mut self.flash
+    }
+
+    /// The latest intact record, if any
+    pub fn load(&self) -> Option<Record> {
+        // Older pages only matter if every record in the newer ones is damaged
+        let mut below = None;
+        loop {
+            let (page, seq) = (0..F::PAGES)
+                .filter_map(|page| Some((page, page_seq(&self.flash, page)?)))
+                .filter(|(_, seq)| below.is_none_or(|b| *seq < b))
+                .max_by_key(|(_, seq)| *seq)?;
+            if let (_, Some(record)) = scan(&self.flash, page) {
+                return Some(record);
+            }
+            below = Some(seq);
+        }
+    }
+
+    /// Save `record`, unless it is the latest already
+    pub fn save(&mut self, record: &Record) -> Result<(), FlashError> {
+        if self.load().as_ref() == Some(record) {
+            return Ok(());
+        }
+        let mut buf = [ERASED; MAX_RECORD];
+        let size = record.encode(&mut buf);
+        match self.active {
+            Some((page, _)) if self.end + size <= F::PAGE_SIZE => {
+                // A failed write still uses up its space
+                let at = (page * F::PAGE_SIZE) + self.end;
+                self.end += size;
+                self.flash.write(at, &buf[..size])
+            },
+            _ => self.move_on(&buf[..size]),
+        }
+    }
+
+    /// Start the next page with the encoded record in `buf`
+    fn move_on(&mut self, buf: &[u8]) -> Result<(), FlashError> {
+        let (page, seq) = match self.active {
+            Some((page, seq)) => ((page + 1) % F::PAGES, seq.wrapping_add(1)),
+            None => (0, 0),
+        };
+        let base = page * F::PAGE_SIZE;
+        self.flash.erase(page)?;
+        self.flash.write(base + PAGE_HEADER_LEN, buf)?;
+
+        let mut header = [0; PAGE_HEADER_LEN];
+        header[..4].copy_from_slice(&seq.to_le_bytes());
+        header[4..].copy_from_slice(&PAGE_MAGIC.to_le_bytes());
+        self.flash.write(base, &header)?;
+
+        self.active = Some((page, seq));
+        self.end = PAGE_HEADER_LEN + buf.len();
+        Ok(())
+    }
+
+    /// Erase every page, forgetting all records
+    pub fn factory_reset(&mut self) -> Result<(), FlashError> {
+        self.active = None;
+        self.end = 0;
+        (0..F::PAGES).try_for_each(|page| self.flash.erase(page))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::flash::RamFlash;
+
+    /// Fifteen 8 byte records to a page
+    type Small = RamFlash<128, 3>;
+
+    fn rec(n: u32) -> Record {
+        Record::new(1, &n.to_le_bytes()).unwrap()
+    }
+
+    #[test]
+    fn records() {
+        assert_eq!(Record::new(ERASED, &[]), None);
+        assert_eq!(Record::new(0, &[0; MAX_DATA + 1]), None);
+        let record = Record::new(7, &[1, 2, 3]).unwrap();
+        let mut buf = [ERASED; MAX_RECORD];
+        assert_eq!(record.encode(&mut buf), 8);
+        assert_eq!(Record::decode(&buf[..7]), Some(record));
+        buf[5] ^= 0x10;
+        assert_eq!(Record::decode(&buf[..7]), None);
+    }
+
+    #[test]
+    fn saved_across_reopens() {
+        let mut store = Store::new(Small::new());
+        assert_eq!(store.load(), None);
+        store.save(&rec(1)).unwrap();
+        store.save(&rec(2)).unwrap();
+
+        let mut store = Store::new(store.flash.clone());
+        assert_eq!(store.load(), Some(rec(2)));
+        store.save(&rec(3)).unwrap();
+        assert_eq!(store.load(), Some(rec(3)));
+        assert_eq!(Store::new(store.flash.clone()).load(), Some(rec(3)));
+    }
+
+    #[test]
+    fn damaged_records_are_skipped() {
+        let mut store = Store::new(Small::new());
+        store.save(&rec(1)).unwrap();
+        store.save(&rec(2)).unwrap();
+        // The data of the second record
+        store.flash_mut().bytes_mut()[PAGE_HEADER_LEN + 8 + 5] ^= 0x01;
+        let mut store = Store::new(store.flash.clone());
+        assert_eq!(store.load(), Some(rec(1)));
+        store.save(&rec(3)).unwrap();
+        assert_eq!(store.load(), Some(rec(3)));
+    }
+
+    #[test]
+    fn unchanged_records_are_not_saved() {
+        let mut store = Store::new(Small::new());
+        store.save(&rec(1)).unwrap();
+        let before = store.flash.clone();
+        store.save(&rec(1)).unwrap();
+        assert_eq!(store.flash.bytes_mut(), before.clone().bytes_mut());
+    }
+
+    #[test]
+    fn wear_is_spread() {
+        let mut store = Store::new(Small::new());
+        (0..1000).for_each(|n| {
+            store.save(&rec(n)).unwrap();
+            assert_eq!(store.load(), Some(rec(n)));
+        });
+        assert_eq!(Store::new(store.flash.clone()).load(), Some(rec(999)));
+
+        // Fifteen records per erase, spread over the pages
+        let erases = store.flash.erases();
+        assert_eq!(erases.iter().sum::<u32>(), 1000_u32.div_ceil(15));
+        let (min, max) = (erases.iter().min().unwrap(), erases.iter().max().unwrap());
+        assert!(max - min <= 1, "{erases:?}");
+    }
+
+    #[test]
+    fn survives_power_cuts() {
+        // Saves that append, and saves that move on to the next page
+        for saved in [1, 15, 16] {
+            for cut in 0..40 {
+                let mut store = Store::new(Small::new());
+                (0..saved).for_each(|n| store.save(&rec(n)).unwrap());
+                store.flash_mut().cut_power_after(cut);
+                let res = store.save(&rec(100));
+                store.flash_mut().power_on();
+
+                let mut store = Store::new(store.flash.clone());
+                let latest = store.load();
+                match res {
+                    Ok(()) => assert_eq!(latest, Some(rec(100))),
+                    Err(_) => assert!([Some(rec(saved - 1)), Some(rec(100))].contains(&latest), "{saved} {cut}"),
+                }
+                store.save(&rec(200)).unwrap();
+                assert_eq!(Store::new(store.flash.clone()).load(), Some(rec(200)), "{saved} {cut}");
+            }
+        }
+    }
+
+    #[test]
+    fn factory_reset() {
+        let mut store = Store::new(Small::new());
+        (0..10).for_each(|n| store.save(&rec(n)).unwrap());
+        store.factory_reset().unwrap();
+        assert_eq!(store.load(), None);
+        assert_eq!(Store::new(store.flash.clone()).load(), None);
+        store.save(&rec(1)).unwrap();
+        assert_eq!(Store::new(store.flash.clone()).load(), Some(rec(1)));
+    }
+}
@@ -0,0 +1,398 @@
+//! Sending an image to every node
+//!
+//! An update goes in three steps, see `amodem_bus::update` for the messages:
+//!
+//! 1. Start: the start is sent until every node reports taking the image, or
+//!    having it already. The first sends the nodes to their bootloaders, which
+//!    then erase their applications, so the host waits between them.
+//! 2. Chunks: every chunk any node is missing is sent, then each node that is
+//!    still receiving is asked what it is missing, for a number of rounds.
+//! 3. Commit: each node that has the whole image is told to start it. Nothing
+//!    more is sent to [UPDATE_ADDR] once the first node is committed, as it
+//!    would send that node back to its bootloader.
+//!
+//! The router must be polling every node, and is not sent anything else while
+//! the update runs.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io,
+};
+
+use amodem_bus::update::{
+    Campaign, ChunkMap, Report, UpdateMsg, UpdateState, CHUNK_LEN, UPDATE_ADDR, UPDATE_MSG_MAX,
+};
+
+use crate::link::RouterLink;
+
+/// How long to leave the nodes and the router between messages, in
+/// microseconds, and how many times to try
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pacing {
+    /// After each start: long enough for the application to reset into the
+    /// bootloader, and for it to erase the application region
+    pub start_wait_us: u32,
+    /// After each chunk: long enough for the router to send it, and for each
+    /// node to write it
+    pub chunk_interval_us: u32,
+    /// Between queries to the same node, and between offers of a frame the
+    /// router had no room for
+    pub query_interval_us: u32,
+    /// How long a node has to report, or the router to take a frame
+    pub report_timeout_us: u32,
+    /// Starts sent, and rounds of chunks sent, before giving up
+    pub rounds: usize,
+}
+
+impl Pacing {
+    /// For a router on its default poll interval, at the default baudrate
+    pub const DEFAULT: Self = Self {
+        start_wait_us: 1_500_000,
+        chunk_interval_us: 5_000,
+        query_interval_us: 20_000,
+        report_timeout_us: 200_000,
+        rounds: 8,
+    };
+}
+
+/// How the update went for one node
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    /// Told to start the new image, which it has whole
+    Committed,
+    /// Never reported taking the image
+    NoReply,
+    /// Could not take the image: it did not fit, failed its checks once
+    /// written, or the flash failed
+    Failed,
+    /// Still missing this many chunks after every round
+    Incomplete(usize),
+}
+
+/// Where each node was, as of its last report for this update
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Status {
+    state: UpdateState,
+    missing: ChunkMap,
+}
+
+/// Send `image` to every node in `nodes`, installing it as `campaign`, and
+/// return how it went for each, in the same order
+pub fn run<L: RouterLink>(
+    link: &mut L,
+    campaign: &Campaign,
+    image: &[u8],
+    nodes: &[u8],
+    pacing: &Pacing,
+) -> io::Result<Vec<(u8, Outcome)>> {
+    let mut update = Update {
+        link,
+        campaign,
+        pacing,
+        status: BTreeMap::new(),
+        heard: BTreeSet::new(),
+        rx: Vec::new(),
+    };
+
+    for _ in 0..pacing.rounds {
+        update.send(&UpdateMsg::Start(*campaign))?;
+        update.link.wait(pacing.start_wait_us);
+        for &addr in nodes {
+            if !update.status.contains_key(&addr) {
+                update.query(addr)?;
+            }
+        }
+        if nodes.iter().all(|a| update.status.contains_key(a)) {
+            break;
+        }
+    }
+
+    for _ in 0..pacing.rounds {
+        let receiving: Vec<u8> = nodes
+            .iter()
+            .copied()
+            .filter(|a| update.status.get(a).is_some_and(|s| s.state == UpdateState::Receiving))
+            .collect();
+        let Some((first, rest)) = receiving.split_first() else {
+            break;
+        };
+        let mut wanted = update.status[first].missing.clone();
+        rest.iter().for_each(|a| wanted.merge(&update.status[a].missing));
+
+        for index in wanted.iter_missing() {
+            let start = index * CHUNK_LEN;
+            let data = &image[start..][..campaign.chunk_len(index).unwrap_or(0)];
+            update.send(&UpdateMsg::Chunk { crc: campaign.crc, index: index as u16, data })?;
+            update.link.wait(pacing.chunk_interval_us);
+        }
+        for &addr in &receiving {
+            update.query(addr)?;
+        }
+    }
+
+    let mut outcomes = Vec::new();
+    for &addr in nodes {
+        let outcome = match update.status.get(&addr) {
+            None => Outcome::NoReply,
+            Some(s) => match s.state {
+                UpdateState::Ready | UpdateState::Installed => {
+                    update.send_to(addr, &UpdateMsg::Commit { crc: campaign.crc })?;
+                    Outcome::Committed
+                },
+                UpdateState::Receiving => Outcome::Incomplete(s.missing.missing()),
+                UpdateState::Idle | UpdateState::Failed => Outcome::Failed,
+            },
+        };
+        outcomes.push((addr, outcome));
+    }
+    Ok(outcomes)
+}
+
+struct Update<'a, L> {
+    link: &'a mut L,
+    campaign: &'a Campaign,
+    pacing: &'a Pacing,
+    status: BTreeMap<u8, Status>,
+    /// Nodes that reported since last asked
+    heard: BTreeSet<u8>,
+    rx: Vec<u8>,
+}
+
+impl<L: RouterLink> Update<'_, L> {
+    /// Send `msg` to every node
+    fn send(&mut self, msg: &UpdateMsg) -> io::Result<()> {
+        self.send_to(UPDATE_ADDR, msg)
+    }
+
+    /// Send `msg` to `addr`, waiting for the router to have room for it
+    fn send_to(&mut self, addr: u8, msg: &UpdateMsg) -> io::Result<()> {
+        let mut frame = [0; 1 + UPDATE_MSG_MAX];
+        frame[0] = addr;
+        let len = 1 + msg.write(&mut frame[1..]);
+
+        let mut waited = 0;
+        loop {
+            let taken = self.link.exchange(&frame[..len], &mut self.rx)?;
+            self.take_report();
+            if taken {
+                return Ok(());
+            }
+            if waited >= self.pacing.report_timeout_us {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "the router has no room"));
+            }
+            self.link.wait(self.pacing.query_interval_us);
+            waited += self.pacing.query_interval_us;
+        }
+    }
+
+    /// Ask `addr` to report, until it does, or it times out. Reports from
+    /// other nodes are taken too.
+    fn query(&mut self, addr: u8) -> io::Result<()> {
+        self.heard.remove(&addr);
+        let mut waited = 0;
+        while waited < self.pacing.report_timeout_us {
+            // Each query also reads out the report the last one asked for
+            self.send(&UpdateMsg::Query { addr })?;
+            if self.heard.contains(&addr) {
+                break;
+            }
+            self.link.wait(self.pacing.query_interval_us);
+            waited += self.pacing.query_interval_us;
+        }
+        Ok(())
+    }
+
+    /// Keep the report just read, if it is one, and for this update
+    fn take_report(&mut self) {
+        let Some((&from, frame)) = self.rx.split_first() else {
+            return;
+        };
+        let Some(report) = Report::parse(frame) else {
+            return;
+        };
+        self.heard.insert(from);
+        if report.crc != self.campaign.crc {
+            return;
+        }
+        let missing = match report.state {
+            UpdateState::Receiving => ChunkMap::from_bytes(self.campaign.chunks(), report.missing),
+            _ => Some(ChunkMap::new()),
+        };
+        if let Some(missing) = missing {
+            self.status.insert(from, Status { state: report.state, missing });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use amodem_boot::{
+        image::{self, ImageHeader},
+        layout::{APP_PAGES, APP_VECTORS, BOOT_REQUEST_ADDR, PAGE_SIZE},
+        update::{Action, Updater},
+    };
+    use amodem_bus::{
+        router::DEFAULT_INTERVAL_BITS,
+        sim::{Noise, Role, SimBus},
+        update::REPORT_MAX,
+    };
+    use amodem_store::flash::{Flash, RamFlash};
+
+    type AppFlash = RamFlash<PAGE_SIZE, APP_PAGES>;
+
+    /// Bit times, as microseconds at 1 Mbaud
+    const PACING: Pacing = Pacing {
+        start_wait_us: 20_000,
+        chunk_interval_us: 3_000,
+        query_interval_us: 5_000,
+        report_timeout_us: 50_000,
+        rounds: 8,
+    };
+
+    /// A node, running its application until it hears an update, then its
+    /// bootloader
+    struct SimNode {
+        station: usize,
+        updater: Updater<AppFlash>,
+        in_bootloader: bool,
+        started: bool,
+    }
+
+    /// A router's host, and the nodes it updates
+    struct SimLink {
+        bus: SimBus,
+        router: usize,
+        nodes: Vec<SimNode>,
+    }
+
+    impl SimLink {
+        fn new(nodes: &[(u8, AppFlash)], polled: &[u8]) -> Self {
+            let mut bus = SimBus::new(7);
+            let router = bus.add_router(polled, DEFAULT_INTERVAL_BITS);
+            let nodes = nodes
+                .iter()
+                .map(|(addr, flash)| {
+                    let station = bus.add_node(*addr);
+                    if let Role::Node(n) = &mut bus.station_mut(station).role {
+                        n.set_group(Some(UPDATE_ADDR));
+                    }
+                    let updater = Updater::new(flash.clone(), *addr);
+                    SimNode { station, updater, in_bootloader: false, started: false }
+                })
+                .collect();
+            Self { bus, router, nodes }
+        }
+
+        /// Hand each node its frames, as its bootloader would
+        fn serve(&mut self) {
+            for node in self.nodes.iter_mut() {
+                let queues = &mut self.bus.station_mut(node.station).queues;
+                for frame in std::mem::take(&mut queues.rx) {
+                    if !node.in_bootloader {
+                        node.in_bootloader = frame[0] == UPDATE_ADDR;
+                        continue;
+                    }
+                    match node.updater.handle(&frame) {
+                        Action::None => {},
+                        Action::Report => {
+                            let mut buf = [0; REPORT_MAX];
+                            let len = node.updater.report(&mut buf);
+                            queues.tx.push_back(buf[..len].to_vec());
+                        },
+                        Action::Start => node.started = true,
+                    }
+                }
+            }
+        }
+    }
+
+    impl RouterLink for SimLink {
+        fn exchange(&mut self, frame: &[u8], rx: &mut Vec<u8>) -> io::Result<bool> {
+            let queues = &mut self.bus.station_mut(self.router).queues;
+            rx.clear();
+            if queues.rx.first().is_some_and(|f| f.len() <= frame.len()) {
+                *rx = queues.rx.remove(0);
+            }
+            let taken = queues.tx.len() < 2;
+            if taken {
+                queues.tx.push_back(frame.to_vec());
+            }
+            Ok(taken)
+        }
+
+        fn wait(&mut self, us: u32) {
+            let end = self.bus.now().wrapping_add(us);
+            while (end.wrapping_sub(self.bus.now()) as i32) > 0 {
+                self.bus.step();
+                self.serve();
+            }
+        }
+    }
+
+    /// An image of `len` bytes that verifies
+    fn test_image(len: usize, seed: u8) -> Vec<u8> {
+        let mut image: Vec<u8> = (0..len).map(|i| (i as u8).wrapping_mul(7).wrapping_add(seed)).collect();
+        image[..4].copy_from_slice(&(BOOT_REQUEST_ADDR as u32).to_le_bytes());
+        image[4..8].copy_from_slice(&((APP_VECTORS + 0xC0) as u32 | 1).to_le_bytes());
+        image
+    }
+
+    fn campaign(image: &[u8], version: u32) -> Campaign {
+        Campaign::from(&ImageHeader::for_image(image, version))
+    }
+
+    /// Flash with `image` installed
+    fn installed(image: &[u8], version: u32) -> AppFlash {
+        let mut flash = AppFlash::new();
+        flash.write(0, &image::package(image, version)).unwrap();
+        flash
+    }
+
+    #[test]
+    fn updated() {
+        let old = test_image(2000, 1);
+        let new = test_image(3000, 2);
+        let update = campaign(&new, 2);
+        let nodes = [(0x11, installed(&old, 1)), (0x12, AppFlash::new())];
+        let mut link = SimLink::new(&nodes, &[0x11, 0x12, 0x13]);
+        // Some chunks are garbled, and sent again
+        link.bus.set_noise(Noise { flip_ppm: 1_000, glitch_ppm: 0 });
+
+        let outcomes = run(&mut link, &update, &new, &[0x11, 0x12, 0x13], &PACING).unwrap();
+        assert_eq!(outcomes, [(0x11, Outcome::Committed), (0x12, Outcome::Committed), (0x13, Outcome::NoReply)]);
+        assert_ne!(link.bus.stats().flips, 0);
+
+        // The commits go out, and each node starts the new image
+        link.wait(50_000);
+        for node in &link.nodes {
+            assert!(node.started);
+            assert_eq!(image::verify(node.updater.flash()), Ok(ImageHeader::for_image(&new, 2)));
+        }
+    }
+
+    #[test]
+    fn installed_images_committed() {
+        let image = test_image(3000, 2);
+        let update = campaign(&image, 2);
+        let mut link = SimLink::new(&[(0x11, installed(&image, 2))], &[0x11]);
+
+        let outcomes = run(&mut link, &update, &image, &[0x11], &PACING).unwrap();
+        assert_eq!(outcomes, [(0x11, Outcome::Committed)]);
+        link.wait(50_000);
+        assert!(link.nodes[0].started);
+        assert_eq!(link.nodes[0].updater.flash().erases(), &[0; APP_PAGES]);
+    }
+
+    #[test]
+    fn bad_images_fail() {
+        let image = test_image(3000, 2);
+        // Chunks that do not match the CRC
+        let update = Campaign { crc: !campaign(&image, 2).crc, ..campaign(&image, 2) };
+        let mut link = SimLink::new(&[(0x11, AppFlash::new())], &[0x11]);
+
+        let outcomes = run(&mut link, &update, &image, &[0x11], &PACING).unwrap();
+        assert_eq!(outcomes, [(0x11, Outcome::Failed)]);
+        assert!(!link.nodes[0].started);
+    }
+}
@@ -0,0 +1,15 @@
+//! # amodem updates
+//!
+//! Host side of firmware updates over RS-485: the modem on the host is the
+//! bus router, and every node takes the image in its bootloader, see
+//! `amodem_bus::update` for the protocol, and `amodem_boot::update` for the
+//! node's side.
+//!
+//! * [link] reaches the bus through the router, over SPI
+//! * [campaign] sends an image to every node, and commits it
+//!
+//! The `amodem-update` binary also packages images, as the bootloader takes
+//! them over SPI.
+
+pub mod campaign;
+pub mod link;
@@ -0,0 +1,149 @@
+//! Reaching the bus through the router
+//!
+//! The router's host exchanges frames with it in long packets, see
+//! `amodem_hostif::long`. In 4-wire mode, the bytes the host clocks after the
+//! header are its frame, so each exchange sends a frame, and a frame from the
+//! router only comes back if it is no longer than the one sent. Update
+//! messages are sized for this, see `amodem_bus::update`.
+
+use std::{io, thread, time::Duration};
+
+use amodem_hostif::{
+    cmd::Command,
+    long::{LongHeader, LONG_HEADER_LEN, LONG_STATUS_TX_FRAME},
+};
+use spidev::{SpiModeFlags, Spidev, SpidevOptions, SpidevTransfer};
+
+/// The router, as seen by its host
+pub trait RouterLink {
+    /// Offer `frame`, starting with the address it is for, to the router, and
+    /// take the frame it has for the host into `rx`, starting with the address
+    /// it came from, or leave `rx` empty. Returns false if the router had no
+    /// room for `frame`, which should be offered again.
+    fn exchange(&mut self, frame: &[u8], rx: &mut Vec<u8>) -> io::Result<bool>;
+
+    /// Let `us` microseconds pass
+    fn wait(&mut self, us: u32);
+}
+
+/// A full duplex SPI bus, with the router on it
+pub trait Spi {
+    /// Clock out `tx`, in one transaction, reading as many bytes into `rx`
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()>;
+}
+
+impl Spi for Spidev {
+    fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+        Spidev::transfer(self, &mut SpidevTransfer::read_write(tx, rx))
+    }
+}
+
+/// Open a Linux spidev device, such as `/dev/spidev0.0`, in SPI mode 0, as a
+/// router with the default `SPI_CFG` expects
+pub fn open_spidev(path: &str, speed_hz: u32) -> io::Result<Spidev> {
+    let mut spi = Spidev::open(path)?;
+    let options = SpidevOptions::new()
+        .bits_per_word(8)
+        .max_speed_hz(speed_hz)
+        .mode(SpiModeFlags::SPI_MODE_0)
+        .build();
+    spi.configure(&options)?;
+    Ok(spi)
+}
+
+/// A router on 4-wire SPI
+pub struct SpiRouter<S> {
+    spi: S,
+    tx: Vec<u8>,
+    rx: Vec<u8>,
+}
+
+impl<S: Spi> SpiRouter<S> {
+    pub fn new(spi: S) -> Self {
+        Self { spi, tx: Vec::new(), rx: Vec::new() }
+    }
+}
+
+impl<S: Spi> RouterLink for SpiRouter<S> {
+    fn exchange(&mut self, frame: &[u8], rx: &mut Vec<u8>) -> io::Result<bool> {
+        let header_end = 1 + LONG_HEADER_LEN as usize;
+        self.tx.clear();
+        self.tx.push(Command::LongPacket(0).to_byte());
+        self.tx.resize(header_end, 0);
+        self.tx.extend_from_slice(frame);
+        self.rx.clear();
+        self.rx.resize(self.tx.len(), 0);
+        self.spi.transfer(&self.tx, &mut self.rx)?;
+
+        let header = self.rx[1..header_end]
+            .try_into()
+            .ok()
+            .and_then(LongHeader::from_bytes)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no long packet header"))?;
+
+        // A longer frame was not clocked out, and is kept for later
+        rx.clear();
+        let out_len = header.out_len as usize;
+        if ((header.status & LONG_STATUS_TX_FRAME) != 0) && (out_len <= frame.len()) {
+            rx.extend_from_slice(&self.rx[header_end..][..out_len]);
+        }
+        // Anything past the room is dropped
+        Ok(frame.len() <= header.in_cap as usize)
+    }
+
+    fn wait(&mut self, us: u32) {
+        thread::sleep(Duration::from_micros(us as u64));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Answers every transfer with `header`, then `frame`
+    struct FakeSpi {
+        header: LongHeader,
+        frame: Vec<u8>,
+        sent: Vec<u8>,
+    }
+
+    impl Spi for FakeSpi {
+        fn transfer(&mut self, tx: &[u8], rx: &mut [u8]) -> io::Result<()> {
+            self.sent = tx.to_vec();
+            let reply = [&[0][..], &self.header.to_bytes(), &self.frame].concat();
+            rx.iter_mut().zip(reply).for_each(|(r, b)| *r = b);
+            Ok(())
+        }
+    }
+
+    fn router(out_len: u16, in_cap: u16) -> SpiRouter<FakeSpi> {
+        let frame = (1..=out_len as u8).collect();
+        SpiRouter::new(FakeSpi { header: LongHeader::new(0, out_len, in_cap, false, false), frame, sent: vec![] })
+    }
+
+    #[test]
+    fn exchanged() {
+        let mut rx = vec![0xAA];
+        let mut link = router(0, 64);
+        assert!(link.exchange(&[0x12, 1, 2, 3], &mut rx).unwrap());
+        assert_eq!(link.spi.sent, [0x20, 0, 0, 0, 0, 0, 0, 0x12, 1, 2, 3]);
+        assert_eq!(rx, []);
+
+        let mut link = router(3, 64);
+        assert!(link.exchange(&[0x12, 1, 2, 3], &mut rx).unwrap());
+        assert_eq!(rx, [1, 2, 3]);
+    }
+
+    #[test]
+    fn not_exchanged() {
+        let mut rx = vec![];
+        // The router's frame is longer than ours, and ours does not fit
+        let mut link = router(5, 3);
+        assert!(!link.exchange(&[0x12, 1, 2, 3], &mut rx).unwrap());
+        assert_eq!(rx, []);
+
+        let mut link = router(0, 0);
+        link.spi.header.status = 0;
+        assert_eq!(link.exchange(&[0x12], &mut rx).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,80 @@
+//! amodem-update
+//!
+//! ```text
+//! amodem-update package <image.bin> <version> <package>
+//! amodem-update run <spidev> <package> <node>...
+//! ```
+//!
+//! `package` puts an image header in front of a raw application image, for
+//! loading with the bootloader's commands or over the bus. `run` sends a
+//! package to each node, given by address (such as `0x12`), through the router
+//! on the given spidev device (such as `/dev/spidev0.0`).
+
+use std::{env, fs, process::ExitCode};
+
+use amodem_boot::image;
+use amodem_bus::update::Campaign;
+use amodem_update::{
+    campaign::{self, Outcome, Pacing},
+    link::{open_spidev, SpiRouter},
+};
+
+/// SPI clock, well within what the router takes
+const SPEED_HZ: u32 = 1_000_000;
+
+const USAGE: &str = "usage:
+    amodem-update package <image.bin> <version> <package>
+    amodem-update run <spidev> <package> <node>...";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let res = match args.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+        ["package", image, version, out] => package(image, version, out),
+        ["run", spidev, package, nodes @ ..] if !nodes.is_empty() => run(spidev, package, nodes),
+        _ => Err(USAGE.to_string()),
+    };
+    match res {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::FAILURE
+        },
+    }
+}
+
+fn package(image: &str, version: &str, out: &str) -> Result<bool, String> {
+    let version = parse_num(version).ok_or(format!("bad version: {version}"))?;
+    let image = fs::read(image).map_err(|e| format!("{image}: {e}"))?;
+    fs::write(out, image::package(&image, version)).map_err(|e| format!("{out}: {e}"))?;
+    Ok(true)
+}
+
+fn run(spidev: &str, package: &str, nodes: &[&str]) -> Result<bool, String> {
+    let nodes = nodes
+        .iter()
+        .map(|n| parse_num(n).and_then(|a| u8::try_from(a).ok()).ok_or(format!("bad node address: {n}")))
+        .collect::<Result<Vec<u8>, _>>()?;
+    let package = fs::read(package).map_err(|e| format!("{package}: {e}"))?;
+    let (header, image) = image::unpackage(&package).map_err(|e| format!("bad package: {e:?}"))?;
+    let campaign = Campaign::from(&header);
+    if !campaign.is_valid() {
+        return Err("the image is too large to send".to_string());
+    }
+
+    let spi = open_spidev(spidev, SPEED_HZ).map_err(|e| format!("{spidev}: {e}"))?;
+    let mut link = SpiRouter::new(spi);
+    let outcomes = campaign::run(&mut link, &campaign, image, &nodes, &Pacing::DEFAULT)
+        .map_err(|e| format!("update stopped: {e}"))?;
+
+    outcomes.iter().for_each(|(addr, outcome)| println!("{addr:#04x}: {outcome:?}"));
+    Ok(outcomes.iter().all(|(_, o)| *o == Outcome::Committed))
+}
+
+/// A decimal number, or hex with a `0x` prefix
+fn parse_num(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s.parse().ok(),
+    }
+}
@@ -1,5 +1,9 @@
 MEMORY
 {
-  FLASH : ORIGIN = 0x08000000, LENGTH = 64K
-  RAM : ORIGIN = 0x20000000, LENGTH = 8K
+  /* The bootloader takes the first 12K, and the image header the next 256
+     bytes (see `amodem_boot::layout`). The last two 2K pages (0x0800F000)
+     are reserved for the saved config. */
+  FLASH : ORIGIN = 0x08003100, LENGTH = 0xBF00
+  /* The last 8 bytes hold the boot request word */
+  RAM : ORIGIN = 0x20000000, LENGTH = 8K - 8
 }
@@ -10,8 +10,11 @@ use amodem::{
         setup_sys_clocks,
         setup_rolling_timer,
         gpios::setup_gpios,
-        spi::{setup_spi, spi_int_unmask, exti_isr, spi_isr},
-        pipes::{PIPES, self}, rs485::{setup_rs485, rs485_isr}
+        spi::{spi_int_unmask, exti_isr, spi_isr},
+        pipes::{PIPES, self}, rs485::{setup_rs485, rs485_isr},
+        irq::setup_irq,
+        host::setup_host,
+        watchdog::{setup_watchdog, watchdog_isr},
     }, GlobalRollingTimer,
 };
 
@@ -48,8 +51,10 @@ fn imain() -> Option<()> {
         board.GPIOC,
         board.EXTI
     );
-    setup_spi(&mut rcc, board.SPI1);
+    setup_irq();
+    setup_host(&mut rcc, board.SPI1, board.USART2);
     setup_rs485(&mut rcc, board.USART1);
+    setup_watchdog(&mut rcc, board.TIM14);
 
     unsafe {
         PIPES.init(&mut rcc, board.DMA, board.DMAMUX);
@@ -57,11 +62,35 @@ fn imain() -> Option<()> {
 
     unsafe {
         // TODO: Priorities. Probably in this order, highest to lowest
-        NVIC::unmask(stm32g0xx_hal::pac::Interrupt::EXTI0_1);
-        NVIC::unmask(stm32g0xx_hal::pac::Interrupt::SPI1);
+        #[cfg(not(any(feature = "uart-host", feature = "i2c-host")))]
+        {
+            NVIC::unmask(stm32g0xx_hal::pac::Interrupt::EXTI0_1);
+            NVIC::unmask(stm32g0xx_hal::pac::Interrupt::SPI1);
+        }
+        #[cfg(feature = "uart-host")]
+        NVIC::unmask(stm32g0xx_hal::pac::Interrupt::USART2);
+        #[cfg(feature = "i2c-host")]
+        {
+            // The I2C target must see every edge, see the `i2c` module
+            use amodem::modem::i2c::{EXTI_PRIO, OTHER_PRIO};
+            use stm32g0xx_hal::pac::Interrupt;
+
+            let mut core = stm32::CorePeripherals::take()?;
+            core.NVIC.set_priority(Interrupt::EXTI4_15, EXTI_PRIO);
+            for irq in [
+                Interrupt::USART1,
+                Interrupt::DMA_CHANNEL2_3,
+                Interrupt::DMA_CHANNEL4_5_6_7,
+                Interrupt::TIM14,
+            ] {
+                core.NVIC.set_priority(irq, OTHER_PRIO);
+            }
+            NVIC::unmask(Interrupt::EXTI4_15);
+        }
         NVIC::unmask(stm32g0xx_hal::pac::Interrupt::USART1);
         NVIC::unmask(stm32g0xx_hal::pac::Interrupt::DMA_CHANNEL2_3);
         NVIC::unmask(stm32g0xx_hal::pac::Interrupt::DMA_CHANNEL4_5_6_7);
+        NVIC::unmask(stm32g0xx_hal::pac::Interrupt::TIM14);
     }
 
 
@@ -92,6 +121,11 @@ fn DMA_CHANNEL4_5_6_7() {
     // defmt::println!("DMA ISR 4567")
 }
 
+#[interrupt]
+fn TIM14() {
+    watchdog_isr();
+}
+
 #[interrupt]
 fn EXTI0_1() {
     exti_isr();
@@ -102,6 +136,18 @@ fn SPI1() {
     spi_isr();
 }
 
+#[cfg(feature = "uart-host")]
+#[interrupt]
+fn USART2() {
+    amodem::modem::uart::uart_isr();
+}
+
+#[cfg(feature = "i2c-host")]
+#[interrupt]
+fn EXTI4_15() {
+    amodem::modem::i2c::i2c_isr();
+}
+
 static ONESHOT: AtomicBool = AtomicBool::new(false);
 
 #[interrupt]
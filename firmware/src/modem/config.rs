@@ -0,0 +1,204 @@
+//! Persistent configuration
+//!
+//! The SPI and RS-485 config are saved in the last two pages of flash, as
+//! records of an `amodem_store` log, so each page is only erased once every
+//! 254 saves, and a power cut mid-save leaves the last config saved intact.
+//!
+//! Settings that must be decided before the host can talk to us (like the SPI
+//! mode) are read once at boot. The host stages new settings in the `SPI_CFG`
+//! register, and asks for them to be saved by setting `SPI_CFG_SAVE`. The
+//! modem then saves them, and resets itself to apply them.
+//!
+//! The RS-485 config is saved whenever one is applied, by the host or by
+//! discovery, and is staged to be applied again at boot. Saving one stalls the
+//! CPU for a fraction of a millisecond, or for tens of milliseconds when a page
+//! is erased, so polls made meanwhile may go unanswered.
+//!
+//! Setting `SPI_CFG_FACTORY_RESET` erases both pages, and resets, so that the
+//! defaults are used. Configs saved by older firmware, on a single page in
+//! another format, are not read.
+//!
+//! Setting `SPI_CFG_BOOTLOADER` leaves a boot request in RAM, and resets, so
+//! the bootloader stays to take a new image. It reads the saved SPI config
+//! too, see `amodem_boot::layout` for where everything is.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use amodem_boot::layout::{
+    BOOT_REQUEST_ADDR, BOOT_REQUEST_MAGIC, CONFIG_FIRST_PAGE, FLASH_BASE, PAGE_SIZE,
+};
+use amodem_store::{
+    config::SavedConfig,
+    flash::{Flash, FlashError},
+    store::Store,
+};
+use cortex_m::peripheral::SCB;
+use stm32g0xx_hal::pac::{FLASH, flash::RegisterBlock as FlashRb};
+
+use super::{regs, rs485};
+
+/// The flash pages used to save the config. These pages are excluded from the
+/// `FLASH` region in `memory.x`.
+const CONFIG_ADDR: usize = FLASH_BASE + (CONFIG_FIRST_PAGE * PAGE_SIZE);
+
+/// The error flags in `FLASH.SR`, each cleared by writing a one
+const SR_ERRORS: u32 = 0x0000_C3FA;
+
+static SAVE_PENDING: AtomicBool = AtomicBool::new(false);
+static RS485_SAVE_PENDING: AtomicBool = AtomicBool::new(false);
+static FACTORY_RESET_PENDING: AtomicBool = AtomicBool::new(false);
+static BOOTLOADER_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// The config pages, through the flash controller
+struct ConfigFlash;
+
+impl Flash for ConfigFlash {
+    const PAGE_SIZE: usize = PAGE_SIZE;
+    const PAGES: usize = 2;
+    const WRITE_SIZE: usize = 8;
+
+    fn read(&self, offset: usize, buf: &mut [u8]) {
+        let src = (CONFIG_ADDR + offset) as *const u8;
+        buf.iter_mut().enumerate().for_each(|(i, b)| {
+            *b = unsafe { src.add(i).read_volatile() };
+        });
+    }
+
+    fn erase(&mut self, page: usize) -> Result<(), FlashError> {
+        unlocked(|flash| {
+            flash.cr.modify(|_r, w| {
+                unsafe { w.pnb().bits((CONFIG_FIRST_PAGE + page) as u8) };
+                w.per().set_bit();
+                w
+            });
+            flash.cr.modify(|_r, w| w.strt().set_bit());
+            while flash.sr.read().bsy().bit_is_set() { }
+            flash.cr.modify(|_r, w| w.per().clear_bit());
+        })
+    }
+
+    fn write(&mut self, offset: usize, data: &[u8]) -> Result<(), FlashError> {
+        unlocked(|flash| {
+            // One double word at a time
+            flash.cr.modify(|_r, w| w.pg().set_bit());
+            data.chunks_exact(8).enumerate().for_each(|(i, dw)| {
+                let words = (CONFIG_ADDR + offset + (i * 8)) as *mut u32;
+                unsafe {
+                    words.write_volatile(u32::from_le_bytes([dw[0], dw[1], dw[2], dw[3]]));
+                    words.add(1).write_volatile(u32::from_le_bytes([dw[4], dw[5], dw[6], dw[7]]));
+                }
+                while flash.sr.read().bsy().bit_is_set() { }
+            });
+            flash.cr.modify(|_r, w| w.pg().clear_bit());
+        })
+    }
+}
+
+/// Run `op` with the flash controller unlocked, and interrupts disabled, as
+/// they could not be serviced from flash meanwhile anyway. Fails if `op` raised
+/// any errors.
+fn unlocked<F: FnOnce(&FlashRb)>(op: F) -> Result<(), FlashError> {
+    let flash = unsafe { &*FLASH::PTR };
+    cortex_m::interrupt::free(|_cs| {
+        if flash.cr.read().lock().bit_is_set() {
+            flash.keyr.write(|w| unsafe { w.keyr().bits(0x4567_0123) });
+            flash.keyr.write(|w| unsafe { w.keyr().bits(0xCDEF_89AB) });
+        }
+
+        // Clear any stale error flags
+        while flash.sr.read().bsy().bit_is_set() { }
+        flash.sr.write(|w| unsafe { w.bits(SR_ERRORS) });
+
+        op(flash);
+        let errors = flash.sr.read().bits() & SR_ERRORS;
+
+        flash.cr.modify(|_r, w| w.lock().set_bit());
+        match errors {
+            0 => Ok(()),
+            _ => Err(FlashError),
+        }
+    })
+}
+
+/// The config saved last, if any
+pub fn saved() -> Option<SavedConfig> {
+    SavedConfig::load(&Store::new(ConfigFlash))
+}
+
+/// Ask for the staged SPI config to be saved, followed by a reset. Called when
+/// the host sets `SPI_CFG_SAVE`.
+pub fn request_save() {
+    SAVE_PENDING.store(true, Ordering::Relaxed);
+}
+
+/// Ask for the RS-485 config in use to be saved, without a reset. Called when
+/// one is applied.
+pub fn request_rs485_save() {
+    RS485_SAVE_PENDING.store(true, Ordering::Relaxed);
+}
+
+/// Ask for the saved config to be erased, followed by a reset. Called when the
+/// host sets `SPI_CFG_FACTORY_RESET`.
+pub fn request_factory_reset() {
+    FACTORY_RESET_PENDING.store(true, Ordering::Relaxed);
+}
+
+/// Ask for a reset into the bootloader. Called when the host sets
+/// `SPI_CFG_BOOTLOADER`.
+pub fn request_bootloader() {
+    BOOTLOADER_PENDING.store(true, Ordering::Relaxed);
+}
+
+/// Save the config, or erase it, if requested, resetting if need be. This can
+/// take tens of milliseconds, so is done from the idle loop rather than an
+/// interrupt.
+pub fn service() {
+    if BOOTLOADER_PENDING.load(Ordering::Relaxed) {
+        defmt::println!("Resetting into the bootloader");
+        unsafe { (BOOT_REQUEST_ADDR as *mut u32).write_volatile(BOOT_REQUEST_MAGIC) };
+        SCB::sys_reset();
+    }
+
+    if FACTORY_RESET_PENDING.load(Ordering::Relaxed) {
+        defmt::println!("Erasing saved config, resetting");
+        if Store::new(ConfigFlash).factory_reset().is_err() {
+            defmt::println!("Erasing saved config failed!");
+        }
+        SCB::sys_reset();
+    }
+
+    let spi = SAVE_PENDING.load(Ordering::Relaxed);
+    let rs485 = RS485_SAVE_PENDING.load(Ordering::Relaxed);
+    if !spi && !rs485 {
+        return;
+    }
+    SAVE_PENDING.store(false, Ordering::Relaxed);
+    RS485_SAVE_PENDING.store(false, Ordering::Relaxed);
+
+    // Whichever part is not being saved keeps its saved value, or the one in
+    // use if there is none. With none saved, the SPI config in use is the
+    // default.
+    let mut store = Store::new(ConfigFlash);
+    let saved = SavedConfig::load(&store);
+    let spi_cfg = match (spi, saved) {
+        (true, _) => regs::read(regs::SPI_CFG) & regs::SPI_CFG_MASK,
+        (false, saved) => saved.map_or(0, |s| s.spi_cfg),
+    };
+    let (rs485_brr, rs485_cfg, rs485_de) = match (rs485, saved) {
+        (false, Some(s)) => (s.rs485_brr, s.rs485_cfg, s.rs485_de),
+        _ => rs485::active_config().to_regs(),
+    };
+    let cfg = SavedConfig { spi_cfg, rs485_brr, rs485_cfg, rs485_de };
+
+    match cfg.save(&mut store) {
+        Ok(()) => defmt::println!(
+            "Saved config: SPI {=u16:#x}, RS485 {=u16:#x} {=u16:#x} {=u16:#x}",
+            cfg.spi_cfg, cfg.rs485_brr, cfg.rs485_cfg, cfg.rs485_de,
+        ),
+        Err(_) => defmt::println!("Saving config failed!"),
+    }
+    if spi {
+        defmt::println!("Resetting for the new SPI config");
+        SCB::sys_reset();
+    }
+}
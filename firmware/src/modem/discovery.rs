@@ -0,0 +1,119 @@
+//! Node discovery, the node's side
+//!
+//! Until it has an address, a node listens on `UNASSIGNED_ADDR`, and answers
+//! the router's enumerations with its UID, see `amodem_bus::discovery`. The
+//! offer and the router's assignment are moved from the USART interrupt, as
+//! the header is: 34 words, about 50uS at the default baudrate.
+//!
+//! An address assigned to us is applied from the idle loop, by
+//! `rs485::apply_pending_config`, then saved to flash, and `IRQ_DISCOVERY` is
+//! raised. The router's side is in the `router` module.
+
+use core::{cell::UnsafeCell, sync::atomic::{AtomicU8, Ordering}};
+
+use amodem_bus::{
+    discovery::{self, Backoff, Uid, ASSIGN_LEN, UID_LEN},
+    wire::{self, Header, Reply, CRC_WORDS, STATUS_NONE},
+};
+use groundhog::RollingTimer;
+use stm32g0xx_hal::pac::usart1::RegisterBlock as Usart1Rb;
+
+use crate::{GlobalRollingTimer, modem::rs485};
+
+/// Address of the 96-bit unique device ID
+const UID_ADDR: usize = 0x1FFF_7590;
+
+/// [ASSIGNED] when no address waits to be applied
+const NO_ADDR: u8 = 0xFF;
+
+/// An address assigned to us, until it is applied
+static ASSIGNED: AtomicU8 = AtomicU8::new(NO_ADDR);
+
+struct BackoffCell(UnsafeCell<Option<Backoff>>);
+
+unsafe impl Sync for BackoffCell { }
+
+// Only accessed from the USART interrupt. Seeded from our UID on first use.
+static BACKOFF: BackoffCell = BackoffCell(UnsafeCell::new(None));
+
+/// This chip's unique ID
+pub fn uid() -> Uid {
+    let words = UID_ADDR as *const u32;
+    let mut uid = [0; UID_LEN];
+    uid.chunks_exact_mut(4).enumerate().for_each(|(i, chunk)| {
+        let word = unsafe { words.add(i).read_volatile() };
+        chunk.copy_from_slice(&word.to_le_bytes());
+    });
+    uid
+}
+
+/// An address assigned to us, waiting to be applied
+pub(super) fn assigned_addr() -> Option<u8> {
+    Some(ASSIGNED.load(Ordering::Relaxed)).filter(|a| *a != NO_ADDR)
+}
+
+/// The assigned address was applied
+pub(super) fn clear_assigned() {
+    ASSIGNED.store(NO_ADDR, Ordering::Relaxed);
+}
+
+/// The header of an enumeration is in. Make an offer if the back-off picks
+/// this round, and take the address assigned for it, if any. Called from the
+/// USART interrupt, which then goes back to mute mode.
+pub(super) fn enumerated(usart1: &Usart1Rb, header: &Header) {
+    let uid = uid();
+    let backoff = unsafe { (*BACKOFF.0.get()).get_or_insert_with(|| Backoff::new(&uid)) };
+    let offer = backoff.offer(header.status);
+    if !offer || (*header != discovery::enumeration(header.status)) {
+        return;
+    }
+
+    let reply = Reply { tx_amt: UID_LEN as u16, rx_amt: ASSIGN_LEN as u16, status: STATUS_NONE };
+    reply
+        .to_words()
+        .into_iter()
+        .chain(discovery::offer(&uid).map(u16::from))
+        .for_each(|w| {
+            while usart1.isr.read().txe().bit_is_clear() { }
+            usart1.tdr.write(|wr| wr.tdr().bits(w));
+        });
+
+    // Once the offer is out, forget anything heard meanwhile
+    while usart1.isr.read().tc().bit_is_clear() { }
+    usart1.rqr.write(|w| w.rxfrq().set_bit());
+    rs485::clear_line_errors(usart1);
+
+    let timer = GlobalRollingTimer::new();
+    let bits = wire::data_timeout_bits(ASSIGN_LEN + CRC_WORDS);
+    let timeout_us = rs485::active_config().bits_to_us(bits);
+    let start = timer.get_ticks();
+    let mut buf = [0u8; ASSIGN_LEN + CRC_WORDS];
+    let res = buf.iter_mut().try_for_each(|b| {
+        loop {
+            if usart1.isr.read().rxne().bit_is_set() {
+                let word = rs485::read_word(usart1);
+                if wire::is_addr(word) {
+                    // The router moved on
+                    return Err(());
+                }
+                *b = word as u8;
+                return Ok(());
+            }
+            if timer.micros_since(start) >= timeout_us {
+                return Err(());
+            }
+        }
+    });
+
+    let addr = res
+        .ok()
+        .filter(|()| !rs485::check_line_errors(usart1))
+        .and_then(|()| discovery::parse_assignment(&buf, &uid));
+    match addr {
+        Some(addr) => {
+            defmt::println!("Discovery: assigned address {=u8}", addr);
+            ASSIGNED.store(addr, Ordering::Relaxed);
+        },
+        None => backoff.collided(),
+    }
+}
@@ -1,3 +1,5 @@
+use core::sync::atomic::{AtomicBool, Ordering};
+
 use cortex_m::peripheral::NVIC;
 use stm32g0xx_hal::{rcc::{Rcc, Enable}, pac::{GPIOA, GPIOB, EXTI, GPIOC}, exti::{ExtiExt, Event}, gpio::SignalEdge};
 
@@ -13,7 +15,7 @@ use stm32g0xx_hal::{rcc::{Rcc, Enable}, pac::{GPIOA, GPIOB, EXTI, GPIOC}, exti::
 /// | GPIOA | PA02 | SPI MOSI  | AF0  |                             |
 /// | GPIOA | PA06 | SPI MISO  | AF0  |                             |
 /// | GPIOA | PA07 | SPI RXrdy | Out  | IO2                         |
-/// | GPIOA | PA11 | SPI TXrdy | Out  | IO1                         |
+/// | GPIOA | PA11 | SPI TXrdy | Out  | IO1, or IRQ (open drain)    |
 /// | GPIOA | PA12 | RS485 DE  | AF1  |                             |
 /// | GPIOB | PB00 | SPI CSn   | AF0  | interrupt on rising edge    |
 /// | GPIOB | PB06 | RS485 TXD | AF0  |                             |
@@ -21,12 +23,16 @@ use stm32g0xx_hal::{rcc::{Rcc, Enable}, pac::{GPIOA, GPIOB, EXTI, GPIOC}, exti::
 /// | GPIOB | PB09 | LED1      | Out  |                             |
 /// | GPIOC | PC15 | LED2      | Out  |                             |
 ///
-/// Also in the future I should set these up but I don't yet
+/// With the `uart-host` feature, PA02 is instead used as the host UART TXD
+/// (USART2, AF1), and PA03 as the host UART RXD (USART2, AF1).
+///
+/// With the `i2c-host` feature, these are also set up, for the bit-banged
+/// I2C target:
 ///
-/// | Port  | Pin  | Role      | Mode | Add'l |
-/// | :--   | :--  | :--       | :--  | :--   |
-/// | GPIOA | PA04 | SCL (BB)  |      |       |
-/// | GPIOA | PA05 | SDA (BB)  |      |       |
+/// | Port  | Pin  | Role      | Mode | Add'l                               |
+/// | :--   | :--  | :--       | :--  | :--                                 |
+/// | GPIOA | PA04 | SCL (BB)  | Out  | open drain, interrupt on both edges |
+/// | GPIOA | PA05 | SDA (BB)  | Out  | open drain, interrupt on both edges |
 
 #[inline]
 pub fn setup_gpios(
@@ -45,6 +51,11 @@ pub fn setup_gpios(
         w.afsel1().af0(); // SCLK
         w.afsel2().af0(); // MOSI
         w.afsel6().af0(); // MISO
+        #[cfg(feature = "uart-host")]
+        {
+            w.afsel2().af1(); // Host TXD
+            w.afsel3().af1(); // Host RXD
+        }
         w
     });
     gpioa.afrh.modify(|_r, w| {
@@ -72,6 +83,12 @@ pub fn setup_gpios(
         w.ot15().push_pull();
         w
     });
+    #[cfg(feature = "i2c-host")]
+    gpioa.otyper.modify(|_r, w| {
+        w.ot4().open_drain(); // SCL
+        w.ot5().open_drain(); // SDA
+        w
+    });
 
     // Set Mode Registers
     gpioa.moder.modify(|_r, w| {
@@ -81,6 +98,13 @@ pub fn setup_gpios(
         w.moder7().output();    // IO2
         w.moder11().output();   // IO1
         w.moder12().alternate();   // DE
+        #[cfg(feature = "uart-host")]
+        w.moder3().alternate(); // Host RXD
+        #[cfg(feature = "i2c-host")]
+        {
+            w.moder4().output(); // SCL
+            w.moder5().output(); // SDA
+        }
         w
     });
     gpiob.moder.modify(|_r, w| {
@@ -99,6 +123,11 @@ pub fn setup_gpios(
     gpioa.odr.modify(|_r, w| {
         w.odr11().low(); // IO1
         w.odr7().low();  // IO2
+        #[cfg(feature = "i2c-host")]
+        {
+            w.odr4().high(); // SCL, released
+            w.odr5().high(); // SDA, released
+        }
         w
     });
     gpiob.odr.modify(|_r, w| {
@@ -116,6 +145,13 @@ pub fn setup_gpios(
         w
     });
     exti.listen(Event::GPIO0, SignalEdge::Rising);
+
+    // Setup I2C SCL/SDA EXTI interrupts (port A is the default)
+    #[cfg(feature = "i2c-host")]
+    {
+        exti.listen(Event::GPIO4, SignalEdge::All);
+        exti.listen(Event::GPIO5, SignalEdge::All);
+    }
 }
 
 #[inline]
@@ -149,18 +185,71 @@ pub fn set_led2_inactive() {
     gpioc.odr.modify(|_r, w| w.odr15().high());
 }
 
+static IRQ_PIN_MODE: AtomicBool = AtomicBool::new(false);
+static TXRDY_ACTIVE: AtomicBool = AtomicBool::new(false);
+
 #[inline]
 pub fn set_txrdy_active() {
+    TXRDY_ACTIVE.store(true, Ordering::Relaxed);
+    if IRQ_PIN_MODE.load(Ordering::Relaxed) {
+        return;
+    }
     let gpioa = unsafe { &*GPIOA::PTR };
     gpioa.odr.modify(|_r, w| w.odr11().high());
 }
 
 #[inline]
 pub fn set_txrdy_inactive() {
+    TXRDY_ACTIVE.store(false, Ordering::Relaxed);
+    if IRQ_PIN_MODE.load(Ordering::Relaxed) {
+        return;
+    }
     let gpioa = unsafe { &*GPIOA::PTR };
     gpioa.odr.modify(|_r, w| w.odr11().low());
 }
 
+/// Switch IO1 between the push-pull TX ready signal and the open drain,
+/// active low IRQ line.
+///
+/// When switching back to TX ready mode, the current TX ready state is restored.
+pub fn set_irq_pin_mode(irq: bool) {
+    let gpioa = unsafe { &*GPIOA::PTR };
+
+    cortex_m::interrupt::free(|_cs| {
+        IRQ_PIN_MODE.store(irq, Ordering::Relaxed);
+
+        if irq {
+            gpioa.odr.modify(|_r, w| w.odr11().high());
+            gpioa.otyper.modify(|_r, w| w.ot11().open_drain());
+        } else {
+            gpioa.otyper.modify(|_r, w| w.ot11().push_pull());
+            if TXRDY_ACTIVE.load(Ordering::Relaxed) {
+                gpioa.odr.modify(|_r, w| w.odr11().high());
+            } else {
+                gpioa.odr.modify(|_r, w| w.odr11().low());
+            }
+        }
+    });
+}
+
+#[inline]
+pub fn set_irq_asserted() {
+    if !IRQ_PIN_MODE.load(Ordering::Relaxed) {
+        return;
+    }
+    let gpioa = unsafe { &*GPIOA::PTR };
+    gpioa.odr.modify(|_r, w| w.odr11().low());
+}
+
+#[inline]
+pub fn set_irq_released() {
+    if !IRQ_PIN_MODE.load(Ordering::Relaxed) {
+        return;
+    }
+    let gpioa = unsafe { &*GPIOA::PTR };
+    gpioa.odr.modify(|_r, w| w.odr11().high());
+}
+
 #[inline]
 pub fn set_rxrdy_active() {
     let gpioa = unsafe { &*GPIOA::PTR };
@@ -0,0 +1,146 @@
+//! Host link selection
+//!
+//! The `spi_to_rs485` and `rs485_to_spi` pipes are fed by exactly one host
+//! link, chosen at build time:
+//!
+//! * By default, the SPI interface in the `spi` module, using DMA directly
+//!   to and from the pipes.
+//! * With the `uart-host` feature, the COBS framed UART interface in the
+//!   `uart` module, handling each frame with a `Session` from the idle loop.
+//! * With the `i2c-host` feature, the bit-banged I2C target in the `i2c`
+//!   module, moving frames a byte at a time with a `Session`.
+//!
+//! All links use the same register map and command encoding, from the
+//! `amodem-hostif` crate. The UART and I2C links share the `Session` logic,
+//! which [ModemRegs] and [PipeMailbox] connect to the modem. SPI handles its
+//! commands itself, since its long packets are moved by DMA.
+
+use amodem_hostif::session::{Mailbox, Registers};
+use stm32g0xx_hal::{rcc::Rcc, pac::{SPI1, USART2}};
+
+use super::{pipes, regs, irq};
+
+#[cfg(all(feature = "uart-host", feature = "i2c-host"))]
+compile_error!("Only one of the `uart-host` and `i2c-host` features can be enabled");
+
+/// Set up the selected host link. The peripherals used by the other links
+/// are left disabled.
+#[cfg(not(any(feature = "uart-host", feature = "i2c-host")))]
+pub fn setup_host(rcc: &mut Rcc, spi1: SPI1, _usart2: USART2) {
+    super::spi::setup_spi(rcc, spi1);
+}
+
+/// Set up the selected host link. The peripherals used by the other links
+/// are left disabled.
+#[cfg(feature = "uart-host")]
+pub fn setup_host(rcc: &mut Rcc, _spi1: SPI1, usart2: USART2) {
+    super::uart::setup_uart(rcc, usart2);
+}
+
+/// Set up the selected host link. The peripherals used by the other links
+/// are left disabled.
+#[cfg(feature = "i2c-host")]
+pub fn setup_host(_rcc: &mut Rcc, _spi1: SPI1, _usart2: USART2) {
+    super::i2c::setup_i2c();
+}
+
+/// Service the selected host link, called from [super::pipes::DataPipes::idle_step]
+#[inline]
+pub fn idle_step() {
+    #[cfg(not(any(feature = "uart-host", feature = "i2c-host")))]
+    super::pipes::PIPES.spi_idle_step();
+
+    #[cfg(feature = "uart-host")]
+    super::uart::idle_step();
+
+    // The I2C target claims grants as it needs them, from its interrupt
+}
+
+/// The modem's register map, as seen by a `Session`
+pub struct ModemRegs;
+
+impl Registers for ModemRegs {
+    #[inline]
+    fn read(&mut self, idx: u8) -> u16 {
+        regs::read(idx)
+    }
+
+    #[inline]
+    fn host_write(&mut self, idx: u8, val: u16) {
+        regs::host_write(idx, val)
+    }
+
+    #[inline]
+    fn set(&mut self, idx: u8, val: u16) {
+        regs::write(idx, val)
+    }
+
+    #[inline]
+    fn raise(&mut self, events: u16) {
+        irq::raise(events)
+    }
+}
+
+/// The host side of the pipes, as seen by a `Session`
+///
+/// Grants are claimed lazily, so this must only be used from one context,
+/// and only when SPI is not the host link.
+pub struct PipeMailbox;
+
+impl Mailbox for PipeMailbox {
+    fn in_capacity(&mut self) -> usize {
+        let pipe = &pipes::PIPES.spi_to_rs485;
+        let _ = pipe.service_lowprio_wr();
+        pipe.peek_wr_len()
+    }
+
+    fn in_write(&mut self, idx: usize, byte: u8) -> bool {
+        let pipe = &pipes::PIPES.spi_to_rs485;
+        if idx == 0 {
+            let _ = pipe.service_lowprio_wr();
+            let _ = unsafe { pipe.get_prep_wr_dma() };
+        }
+        match unsafe { pipe.busy_wr_grant() } {
+            Some(buf) if idx < buf.len() => {
+                buf[idx] = byte;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn in_commit(&mut self, len: usize) {
+        let pipe = &pipes::PIPES.spi_to_rs485;
+        unsafe {
+            if len == 0 {
+                pipe.abort_wr_dma();
+            } else {
+                pipe.complete_wr_dma(|_buf| len);
+            }
+        }
+    }
+
+    fn out_claim(&mut self) -> usize {
+        let pipe = &pipes::PIPES.rs485_to_spi;
+        let _ = pipe.service_lowprio_rd();
+        unsafe { pipe.get_prep_rd_dma() }
+    }
+
+    fn out_read(&mut self, idx: usize) -> u8 {
+        let pipe = &pipes::PIPES.rs485_to_spi;
+        unsafe { pipe.busy_rd_grant() }
+            .and_then(|buf| buf.get(idx).copied())
+            .unwrap_or(0)
+    }
+
+    fn out_finish(&mut self, release: bool) {
+        let pipe = &pipes::PIPES.rs485_to_spi;
+        unsafe {
+            if release {
+                pipe.complete_rd_dma();
+            } else {
+                pipe.abort_rd_dma();
+            }
+        }
+    }
+}
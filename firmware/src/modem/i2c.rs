@@ -0,0 +1,290 @@
+//! Bit-banged I2C target host interface
+//!
+//! Enabled with the `i2c-host` feature, this replaces the SPI host interface,
+//! for hosts with only I2C. PA04 (SCL) and PA05 (SDA) have no I2C peripheral
+//! on this part, so the target is implemented in software, driven by EXTI
+//! interrupts on both edges of both lines. The host must provide pull-ups.
+//!
+//! The modem responds to the 7-bit address [I2C_ADDR], at up to 100kHz. The
+//! protocol is handled by `amodem_hostif::session::Session`: the host writes
+//! the command byte (and any data), and then reads, usually with a repeated
+//! start. Long packets are split into long reads and long writes, as with
+//! 3-wire SPI.
+//!
+//! SCL is held low (clock stretching) while each byte is handled. The other
+//! edges have to be handled within the SCL low time, so EXTI4_15 is given the
+//! highest priority (see [EXTI_PRIO]), and preempts the RS-485 and watchdog
+//! interrupts, including the busy waits in the USART1 handler. Nothing can
+//! preempt a critical section though, so these don't mix with `i2c-host`:
+//!
+//! * The router's time sync (`RS485_CFG_SYNC`), which sends the sync word with
+//!   interrupts masked, to time it exactly. See `router::time_sync`.
+//! * Saving the config, after `SPI_CFG_SAVE`, or applying an RS-485 config with
+//!   `RS485_CTRL`. Each flash erase and program runs with interrupts masked,
+//!   see `config::unlocked`, so the host must leave the bus idle for a while
+//!   after either.
+//!
+//! The EXTI lines also see the modem's own SDA changes. Those are made with SCL
+//! low, but when SCL is stretched, the host raises it as soon as it's let go,
+//! and the pending SDA edge would then be taken for a start or stop. So each
+//! time the modem drives SDA, it waits for the line to settle, and clears the
+//! SDA edges it made, see [sda_settle].
+
+use core::cell::UnsafeCell;
+
+use amodem_hostif::session::Session;
+use stm32g0xx_hal::pac::{EXTI, GPIOA};
+
+use super::host::{ModemRegs, PipeMailbox};
+
+/// 7-bit I2C address of the modem
+pub const I2C_ADDR: u8 = 0x42;
+
+/// NVIC priority of EXTI4_15, the highest
+pub const EXTI_PRIO: u8 = 0x00;
+
+/// NVIC priority of every other interrupt in use, one step down. Only the top
+/// two bits are implemented.
+pub const OTHER_PRIO: u8 = 0x40;
+
+const SCL_PIN: u32 = 4;
+const SDA_PIN: u32 = 5;
+const SCL_MASK: u32 = 1 << SCL_PIN;
+const SDA_MASK: u32 = 1 << SDA_PIN;
+
+/// Checks of SDA after driving it, before giving up on it settling. This is
+/// about 2us at 64MHz, more than the 1us rise time allowed at 100kHz.
+const SDA_SETTLE_SPINS: u32 = 16;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum State {
+    /// Waiting for a start condition
+    Idle,
+    /// Receiving the address byte
+    Address,
+    /// Acknowledging our address
+    AddrAck,
+    /// Receiving a data byte
+    Receive,
+    /// Acknowledging (or not) a data byte
+    RecvAck,
+    /// Sending a data byte
+    Transmit,
+    /// Waiting for the host to acknowledge a data byte
+    TransmitAck,
+    /// Not addressed to us, or the host is done reading
+    Ignore,
+}
+
+struct Target {
+    state: State,
+    byte: u8,
+    bits: u8,
+    read: bool,
+    nack: bool,
+    session: Session,
+}
+
+struct TargetCell(UnsafeCell<Target>);
+
+unsafe impl Sync for TargetCell { }
+
+// Only accessed from the EXTI4_15 interrupt
+static TARGET: TargetCell = TargetCell(UnsafeCell::new(Target {
+    state: State::Idle,
+    byte: 0,
+    bits: 0,
+    read: false,
+    nack: false,
+    session: Session::new(),
+}));
+
+/// Reset the target state. The pins and EXTI lines are configured by
+/// `setup_gpios`.
+pub fn setup_i2c() {
+    cortex_m::interrupt::free(|_cs| {
+        let target = unsafe { &mut *TARGET.0.get() };
+        target.state = State::Idle;
+        target.session = Session::new();
+    });
+    sda_release();
+    scl_release();
+}
+
+#[inline]
+fn pins() -> u32 {
+    let gpioa = unsafe { &*GPIOA::PTR };
+    gpioa.idr.read().bits()
+}
+
+#[inline]
+fn sda_low() {
+    let gpioa = unsafe { &*GPIOA::PTR };
+    gpioa.bsrr.write(|w| unsafe { w.bits(SDA_MASK << 16) });
+    sda_settle(false);
+}
+
+#[inline]
+fn sda_release() {
+    let gpioa = unsafe { &*GPIOA::PTR };
+    gpioa.bsrr.write(|w| unsafe { w.bits(SDA_MASK) });
+    sda_settle(true);
+}
+
+/// Wait for SDA to reach the level we just drove it to, or give up if the host
+/// holds it low, then clear the SDA edges pending: they were made with SCL low,
+/// and are not a start or stop.
+fn sda_settle(high: bool) {
+    let _ = (0..SDA_SETTLE_SPINS).find(|_| ((pins() & SDA_MASK) != 0) == high);
+    let exti = unsafe { &*EXTI::PTR };
+    exti.rpr1.write(|w| unsafe { w.bits(SDA_MASK) });
+    exti.fpr1.write(|w| unsafe { w.bits(SDA_MASK) });
+}
+
+#[inline]
+fn sda_bit(byte: u8, bit: u8) {
+    if (byte << bit) & 0x80 != 0 {
+        sda_release();
+    } else {
+        sda_low();
+    }
+}
+
+#[inline]
+fn scl_hold() {
+    let gpioa = unsafe { &*GPIOA::PTR };
+    gpioa.bsrr.write(|w| unsafe { w.bits(SCL_MASK << 16) });
+}
+
+#[inline]
+fn scl_release() {
+    let gpioa = unsafe { &*GPIOA::PTR };
+    gpioa.bsrr.write(|w| unsafe { w.bits(SCL_MASK) });
+}
+
+pub fn i2c_isr() {
+    let exti = unsafe { &*EXTI::PTR };
+    let rising = exti.rpr1.read().bits() & (SCL_MASK | SDA_MASK);
+    let falling = exti.fpr1.read().bits() & (SCL_MASK | SDA_MASK);
+    exti.rpr1.write(|w| unsafe { w.bits(rising) });
+    exti.fpr1.write(|w| unsafe { w.bits(falling) });
+
+    let target = unsafe { &mut *TARGET.0.get() };
+    let pins = pins();
+    let scl_high = (pins & SCL_MASK) != 0;
+
+    // SDA only changes while SCL is high for start and stop conditions. Our
+    // own SDA changes are made while SCL is low, and their edges are cleared
+    // by [sda_settle], so they aren't seen here once SCL rises.
+    if ((rising | falling) & SDA_MASK) != 0 && scl_high {
+        if (pins & SDA_MASK) == 0 {
+            target.start();
+        } else {
+            target.stop();
+        }
+        return;
+    }
+
+    if (rising & SCL_MASK) != 0 {
+        target.scl_rose((pins & SDA_MASK) != 0);
+    }
+    if (falling & SCL_MASK) != 0 {
+        target.scl_fell();
+    }
+}
+
+impl Target {
+    fn start(&mut self) {
+        sda_release();
+        self.state = State::Address;
+        self.byte = 0;
+        self.bits = 0;
+    }
+
+    fn stop(&mut self) {
+        sda_release();
+        self.session.end(&mut ModemRegs, &mut PipeMailbox);
+        self.state = State::Idle;
+    }
+
+    /// Sample SDA
+    fn scl_rose(&mut self, sda: bool) {
+        match self.state {
+            State::Address | State::Receive => {
+                self.byte = (self.byte << 1) | (sda as u8);
+                self.bits += 1;
+            },
+            State::TransmitAck => self.nack = sda,
+            _ => {},
+        }
+    }
+
+    /// Drive SDA for the next bit, if it's ours
+    fn scl_fell(&mut self) {
+        match self.state {
+            State::Address if self.bits == 8 => {
+                if (self.byte >> 1) == I2C_ADDR {
+                    sda_low();
+                    self.read = (self.byte & 1) != 0;
+                    self.state = State::AddrAck;
+                } else {
+                    self.state = State::Ignore;
+                }
+            },
+            State::AddrAck => {
+                scl_hold();
+                sda_release();
+                if self.read {
+                    self.session.begin_read(&mut ModemRegs, &mut PipeMailbox);
+                    self.send_next();
+                } else {
+                    self.session.begin_write(&mut ModemRegs, &mut PipeMailbox);
+                    self.state = State::Receive;
+                    self.byte = 0;
+                    self.bits = 0;
+                }
+                scl_release();
+            },
+            State::Receive if self.bits == 8 => {
+                scl_hold();
+                if self.session.write(&mut ModemRegs, &mut PipeMailbox, self.byte) {
+                    sda_low();
+                }
+                self.state = State::RecvAck;
+                scl_release();
+            },
+            State::RecvAck => {
+                sda_release();
+                self.state = State::Receive;
+                self.byte = 0;
+                self.bits = 0;
+            },
+            State::Transmit if self.bits < 8 => {
+                sda_bit(self.byte, self.bits);
+                self.bits += 1;
+            },
+            State::Transmit => {
+                sda_release();
+                self.nack = true;
+                self.state = State::TransmitAck;
+            },
+            State::TransmitAck if self.nack => {
+                self.state = State::Ignore;
+            },
+            State::TransmitAck => {
+                scl_hold();
+                self.send_next();
+                scl_release();
+            },
+            _ => {},
+        }
+    }
+
+    /// Fetch the next byte to send, and drive its first bit
+    fn send_next(&mut self) {
+        self.byte = self.session.read(&mut ModemRegs, &mut PipeMailbox);
+        sda_bit(self.byte, 0);
+        self.bits = 1;
+        self.state = State::Transmit;
+    }
+}
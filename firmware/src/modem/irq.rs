@@ -0,0 +1,53 @@
+//! Host interrupt notification
+//!
+//! Events are latched into the `IRQ_STATUS` register. When any latched event
+//! is also set in `IRQ_ENABLE` (and `IRQ_PIN_EN` is set), the IRQ line (IO1)
+//! is pulled low. The host acknowledges events by writing ones to the matching
+//! bits of `IRQ_STATUS`, which releases the line once no enabled events remain.
+//!
+//! The IRQ line is open drain, so the host must provide a pull-up.
+
+use super::{gpios, regs};
+
+/// Clear all pending events, and disable the IRQ line
+pub fn setup_irq() {
+    regs::write(regs::IRQ_STATUS, 0);
+    set_enabled(0);
+}
+
+/// Latch one or more `IRQ_*` events, asserting the IRQ line if enabled
+#[inline]
+pub fn raise(events: u16) {
+    regs::modify(regs::IRQ_STATUS, |v| v | events);
+    update_line();
+}
+
+/// Clear the given events. Called when the host writes to `IRQ_STATUS`.
+#[inline]
+pub fn acknowledge(events: u16) {
+    regs::modify(regs::IRQ_STATUS, |v| v & !events);
+    update_line();
+}
+
+/// Update the event mask. Called when the host writes to `IRQ_ENABLE`.
+pub fn set_enabled(mask: u16) {
+    regs::write(regs::IRQ_ENABLE, mask);
+    gpios::set_irq_pin_mode((mask & regs::IRQ_PIN_EN) != 0);
+    update_line();
+}
+
+/// Are any enabled events pending?
+#[inline]
+pub fn pending() -> bool {
+    regs::irq_pending(regs::read(regs::IRQ_STATUS), regs::read(regs::IRQ_ENABLE))
+}
+
+fn update_line() {
+    cortex_m::interrupt::free(|_cs| {
+        if pending() {
+            gpios::set_irq_asserted();
+        } else {
+            gpios::set_irq_released();
+        }
+    });
+}
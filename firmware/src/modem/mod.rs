@@ -6,6 +6,21 @@ pub mod spi;
 pub mod gpios;
 pub mod rs485;
 pub mod pipes;
+pub mod regs;
+pub mod irq;
+pub mod config;
+pub mod host;
+pub mod router;
+pub mod discovery;
+pub mod timesync;
+pub mod watchdog;
+#[cfg(feature = "uart-host")]
+pub mod uart;
+#[cfg(feature = "i2c-host")]
+pub mod i2c;
+
+/// System (and peripheral) clock frequency, as configured by [setup_sys_clocks]
+pub const SYSCLK_HZ: u32 = 64_000_000;
 
 #[inline]
 pub fn setup_sys_clocks(rcc: RCC) -> Rcc {
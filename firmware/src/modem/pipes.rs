@@ -1,10 +1,16 @@
 use core::{cell::UnsafeCell, sync::atomic::{AtomicU8, Ordering}, mem::MaybeUninit};
+use amodem_bus::frag::MAX_FRAME;
 use bbqueue_spicy::{BBBuffer, framed::{FrameGrantR, FrameGrantW}};
 use stm32g0xx_hal::{dma::{C1, C2, C3, C4, DmaExt, Channel, WordSize, Direction, Event}, rcc::Rcc, pac::{DMA, DMAMUX, SPI1, USART1}, dmamux::DmaMuxIndex};
 
 use crate::modem::rs485::enable_rs485_addr_match;
 
-use super::{gpios, spi::spi_int_unmask, rs485};
+use super::{gpios, spi::{self, spi_int_unmask}, rs485, config, host, router};
+
+/// Size of each write grant: the largest frame, and the address in front of it
+/// in the router role. With bbqueue's two byte frame header, about two grants
+/// fit in a pipe, which is what limits `MAX_FRAME`.
+pub const GRANT_LEN: usize = 1 + MAX_FRAME;
 
 pub static PIPES: DataPipes = DataPipes {
     spi_to_rs485: Pipe::new(),
@@ -52,7 +58,7 @@ impl Pipe {
         match self.wr_state.load(Ordering::Acquire) {
             Self::STATE_IDLE => {
                 let prod = unsafe { self.buffer.get_framed_producer() };
-                match prod.grant(256) {
+                match prod.grant(GRANT_LEN) {
                     Ok(mut wgr) => unsafe {
                         let ptrlen: (*mut u8, usize) = (wgr.as_mut_ptr(), wgr.len());
                         let mu_ptr = self.wr_grant.get();
@@ -97,6 +103,48 @@ impl Pipe {
         slice.len()
     }
 
+    /// Length of the ready write grant, without marking it busy. Zero if no
+    /// grant is ready.
+    #[inline]
+    pub fn peek_wr_len(&'static self) -> usize {
+        if self.wr_state.load(Ordering::Acquire) != Self::STATE_GRANT_READY {
+            return 0;
+        }
+        let mu_ptr = self.wr_grant.get();
+        unsafe { (*mu_ptr).assume_init_ref().len() }
+    }
+
+    /// The busy write grant, if any, for host links that fill it by hand
+    #[inline]
+    pub unsafe fn busy_wr_grant(&'static self) -> Option<&'static mut [u8]> {
+        if self.wr_state.load(Ordering::Relaxed) != Self::STATE_GRANT_BUSY {
+            return None;
+        }
+        let mu_ptr = self.wr_grant.get();
+        Some((*mu_ptr).assume_init_mut())
+    }
+
+    /// The write grant, ready or busy, for the router to send a frame held in
+    /// it by [Self::hold_wr_dma]
+    #[inline]
+    pub unsafe fn held_wr_grant(&'static self) -> Option<&'static mut [u8]> {
+        if self.wr_state.load(Ordering::Relaxed) == Self::STATE_IDLE {
+            return None;
+        }
+        let mu_ptr = self.wr_grant.get();
+        Some((*mu_ptr).assume_init_mut())
+    }
+
+    /// The busy read grant, if any, for host links that empty it by hand
+    #[inline]
+    pub unsafe fn busy_rd_grant(&'static self) -> Option<&'static [u8]> {
+        if self.rd_state.load(Ordering::Relaxed) != Self::STATE_GRANT_BUSY {
+            return None;
+        }
+        let mu_ptr = self.rd_grant.get();
+        Some((*mu_ptr).assume_init_ref())
+    }
+
     #[inline]
     pub unsafe fn get_prep_rd_dma(&'static self) -> usize {
         if self.rd_state.load(Ordering::Relaxed) != Self::STATE_GRANT_READY {
@@ -119,6 +167,15 @@ impl Pipe {
         }
     }
 
+    /// Put the busy write grant back, uncommitted and with its contents, to
+    /// be picked up again by [Self::get_prep_wr_dma]
+    #[inline]
+    pub unsafe fn hold_wr_dma(&'static self) {
+        if self.wr_state.load(Ordering::Relaxed) == Self::STATE_GRANT_BUSY {
+            self.wr_state.store(Self::STATE_GRANT_READY, Ordering::Relaxed);
+        }
+    }
+
     #[inline]
     pub unsafe fn abort_rd_dma(&'static self) {
         if self.rd_state.load(Ordering::Relaxed) == Self::STATE_GRANT_BUSY {
@@ -130,14 +187,16 @@ impl Pipe {
         }
     }
 
+    /// Commit the busy write grant. `f` is given the granted buffer, and
+    /// returns the number of bytes that were filled.
     #[inline]
-    pub unsafe fn complete_wr_dma<F: FnOnce(usize) -> usize>(&'static self, f: F) {
+    pub unsafe fn complete_wr_dma<F: FnOnce(&mut [u8]) -> usize>(&'static self, f: F) {
         if self.wr_state.load(Ordering::Relaxed) == Self::STATE_GRANT_BUSY {
             let mu_ptr = self.wr_grant.get();
             let mut garbo = MaybeUninit::zeroed();
             core::ptr::swap(mu_ptr, &mut garbo);
-            let grant = garbo.assume_init();
-            let used = f(grant.len());
+            let mut grant: FrameGrantW<'static, 1024> = garbo.assume_init();
+            let used = f(&mut *grant);
             grant.commit(used);
             self.wr_state.store(Self::STATE_IDLE, Ordering::Relaxed);
         }
@@ -234,6 +293,12 @@ impl DataPipes {
         rs485_rx.disable();
     }
 
+    /// Transfers left on the RS-485 receive DMA channel
+    #[inline]
+    pub unsafe fn rs485_rx_remaining(&'static self) -> u16 {
+        (*DMA::PTR).ch3().ndtr.read().ndt().bits()
+    }
+
     #[inline]
     pub unsafe fn disable_rs485_tx_dma(&'static self) {
         let rs485_tx: &mut C4 = (*self.rs485_tx.get()).assume_init_mut();
@@ -242,79 +307,160 @@ impl DataPipes {
         rs485_tx.disable();
     }
 
-    pub fn idle_step(&'static self) {
+    /// Obtain a new SPI write grant (incoming), and load the SPI receive DMA
+    /// channel with it, marking "ready to receive spi".
+    ///
+    /// This is called from the idle loop, and also from the CSn interrupt right
+    /// after the previous grant is committed, so the next receive window is ready
+    /// before the host can start another transaction. Only one caller can hold
+    /// the grant, so if the two race, the loser gets nothing and returns false.
+    ///
+    /// This is a single receive window, re-armed early, and not a pair of
+    /// pre-armed (ping-pong) grants: the queue only hands out one write grant
+    /// at a time, and the second could not be taken until the first is
+    /// committed anyway. RXrdy still drops while the queue is full.
+    ///
+    /// The SPI receive DMA channel must be disabled.
+    pub fn arm_spi_rx(&'static self) -> bool {
         let spi1 = unsafe { &*SPI1::PTR };
         let spi_dr8b: *mut u8 = spi1.dr.as_ptr().cast();
-        let usart1 = unsafe { &*USART1::PTR };
+
+        let (ptr, len) = match self.spi_to_rs485.service_lowprio_wr() {
+            Some(ptrlen) => ptrlen,
+            None => return false,
+        };
+
+        let spi_rx: &mut C1 = unsafe { (*self.spi_rx.get()).assume_init_mut() };
+
+        // Packed transfers need a half-word aligned buffer. A trailing
+        // odd byte is popped by hand at the end of the transaction.
+        let packed = spi::packed_mode() && ((ptr as usize & 1) == 0);
+        spi::set_rx_packed(packed);
+        if packed {
+            spi_rx.set_word_size(WordSize::BITS16);
+            spi_rx.set_transfer_length((len / 2) as u16);
+        } else {
+            spi_rx.set_word_size(WordSize::BITS8);
+            spi_rx.set_transfer_length(len as u16);
+        }
+        spi_rx.set_memory_address(ptr as usize as u32, true);
+        spi_rx.set_peripheral_address(spi_dr8b as usize as u32, false);
+
+        spi_rx.set_direction(Direction::FromPeripheral);
+        spi_rx.select_peripheral(DmaMuxIndex::SPI1_RX);
+
+        gpios::set_rxrdy_active();
+        true
+    }
+
+    /// Load the RS-485 transmit DMA channel with `len` bytes from `ptr`.
+    ///
+    /// The RS-485 transmit DMA channel must be disabled.
+    pub unsafe fn load_rs485_tx_dma(&'static self, ptr: *const u8, len: usize) {
+        let usart1 = &*USART1::PTR;
         let usart_tx_dr8b: *mut u8 = usart1.tdr.as_ptr().cast();
+        let rs485_tx: &mut C4 = (*self.rs485_tx.get()).assume_init_mut();
+
+        (*DMA::PTR).ch4().cr.modify(|_, w| {
+            w.psize().bits16();
+            w.msize().bits8();
+            w
+        });
+
+        rs485_tx.set_memory_address(ptr as usize as u32, true);
+        rs485_tx.set_peripheral_address(usart_tx_dr8b as usize as u32, false);
+        rs485_tx.set_transfer_length(len as u16);
+
+        rs485_tx.set_direction(Direction::FromMemory);
+        rs485_tx.select_peripheral(DmaMuxIndex::USART1_TX);
+    }
+
+    /// Load the RS-485 receive DMA channel with `len` bytes to `ptr`.
+    ///
+    /// The RS-485 receive DMA channel must be disabled.
+    pub unsafe fn load_rs485_rx_dma(&'static self, ptr: *mut u8, len: usize) {
+        let usart1 = &*USART1::PTR;
         let usart_rx_dr8b: *mut u8 = usart1.rdr.as_ptr().cast();
+        let rs485_rx: &mut C3 = (*self.rs485_rx.get()).assume_init_mut();
 
-        let mut did_restore_spi = false;
+        (*DMA::PTR).ch3().cr.modify(|_, w| {
+            w.psize().bits16();
+            w.msize().bits8();
+            w
+        });
+
+        rs485_rx.set_memory_address(ptr as usize as u32, true);
+        rs485_rx.set_peripheral_address(usart_rx_dr8b as usize as u32, false);
+        rs485_rx.set_transfer_length(len as u16);
+
+        rs485_rx.set_direction(Direction::FromPeripheral);
+        rs485_rx.select_peripheral(DmaMuxIndex::USART1_RX);
+    }
+
+    pub fn idle_step(&'static self) {
         let mut did_restore_rs485 = false;
 
+        config::service();
+        rs485::apply_pending_config();
+        rs485::expire_reassembly();
+
         if rs485::should_reload() {
             // rs485 read grant (outgoing)
             if let Some((ptr, len)) = self.spi_to_rs485.service_lowprio_rd() {
                 // setup rs485 transmit dma, enable interrupt
                 defmt::println!("Reloaded RS485 Read Grant (outgoing) - {}", len);
 
-                let rs485_tx: &mut C4 = unsafe { (*self.rs485_tx.get()).assume_init_mut() };
-
-                unsafe { &*DMA::PTR }.ch4().cr.modify(|_, w| {
-                    w.psize().bits16();
-                    w.msize().bits8();
-                    w
-                });
-
-                rs485_tx.set_memory_address(ptr as usize as u32, true);
-                rs485_tx.set_peripheral_address(usart_tx_dr8b as usize as u32, false);
-                rs485_tx.set_transfer_length(len as u16);
-
-                rs485_tx.set_direction(Direction::FromMemory);
-                rs485_tx.select_peripheral(DmaMuxIndex::USART1_TX);
-                did_restore_rs485 = true;
+                unsafe {
+                    let frame = core::slice::from_raw_parts(ptr, len);
+                    if len > MAX_FRAME {
+                        // Too long to fragment, there is no sending it
+                        defmt::println!("RS485 frame too long, dropped");
+                        self.spi_to_rs485.get_prep_rd_dma();
+                        self.spi_to_rs485.complete_rd_dma();
+                    } else {
+                        rs485::prepare_tx_fragment(frame);
+                        let chunk = rs485::tx_chunk(frame);
+                        self.load_rs485_tx_dma(chunk.as_ptr(), chunk.len());
+                        did_restore_rs485 = true;
+                    }
+                }
             }
 
-            // RS485 Write Grant (incoming)
-            if let Some((ptr, len)) = self.rs485_to_spi.service_lowprio_wr() {
-                // setup rs485 receive dma, enable interrupt
+            // RS485 Write Grant (incoming). The receive DMA is loaded once we
+            // know where the fragment goes, see `rs485::rx_target`.
+            if self.rs485_to_spi.service_lowprio_wr().is_some() {
                 defmt::println!("Reloaded RS485 Write Grant (incoming)");
-
-                let rs485_rx: &mut C3 = unsafe { (*self.rs485_rx.get()).assume_init_mut() };
-
-                unsafe { &*DMA::PTR }.ch3().cr.modify(|_, w| {
-                    w.psize().bits16();
-                    w.msize().bits8();
-                    w
-                });
-
-                rs485_rx.set_memory_address(ptr as usize as u32, true);
-                rs485_rx.set_peripheral_address(usart_rx_dr8b as usize as u32, false);
-                rs485_rx.set_transfer_length(len as u16);
-
-                rs485_rx.set_direction(Direction::FromPeripheral);
-                rs485_rx.select_peripheral(DmaMuxIndex::USART1_RX);
+                did_restore_rs485 = true;
+            } else if rs485::awaiting_reload() && (self.rs485_to_spi.peek_wr_len() != 0) {
+                // Still held, with part of a frame in it
                 did_restore_rs485 = true;
             }
         }
 
+        router::poll_step();
 
-        // spi write grant (incoming)
-        if let Some((ptr, len)) = self.spi_to_rs485.service_lowprio_wr() {
-            // setup spi receive dma, enable interrupt
-            // mark "ready to receive spi" IO
-            defmt::println!("Reloaded SPI Write Grant (incoming)");
-            let spi_rx: &mut C1 = unsafe { (*self.spi_rx.get()).assume_init_mut() };
+        host::idle_step();
 
-            spi_rx.set_word_size(WordSize::BITS8);
-            spi_rx.set_memory_address(ptr as usize as u32, true);
-            spi_rx.set_peripheral_address(spi_dr8b as usize as u32, false);
-            spi_rx.set_transfer_length(len as u16);
+        if did_restore_rs485 {
+            defmt::println!("unmasked rs485 int!");
+            enable_rs485_addr_match();
+        }
+    }
 
-            spi_rx.set_direction(Direction::FromPeripheral);
-            spi_rx.select_peripheral(DmaMuxIndex::SPI1_RX);
+    /// Reload the SPI grants, when SPI is the host link
+    pub fn spi_idle_step(&'static self) {
+        let spi1 = unsafe { &*SPI1::PTR };
+        let spi_dr8b: *mut u8 = spi1.dr.as_ptr().cast();
 
-            gpios::set_rxrdy_active();
+        let mut did_restore_spi = false;
+
+        // spi write grant (incoming)
+        //
+        // This is normally re-armed by the CSn interrupt as soon as the previous
+        // long packet completes, this only catches the case where the queue was
+        // full at that time.
+        if self.arm_spi_rx() {
+            defmt::println!("Reloaded SPI Write Grant (incoming)");
             did_restore_spi = true;
         }
 
@@ -328,10 +474,20 @@ impl DataPipes {
 
             let spi_tx: &mut C2 = unsafe { (*self.spi_tx.get()).assume_init_mut() };
 
-            spi_tx.set_word_size(WordSize::BITS8);
+            // Packed transfers need a half-word aligned buffer. For odd
+            // lengths, LDMA_TX makes the SPI only send the low byte of the
+            // last half-word.
+            let packed = spi::packed_mode() && ((ptr as usize & 1) == 0);
+            spi::set_tx_packed(packed, (len & 1) != 0);
+            if packed {
+                spi_tx.set_word_size(WordSize::BITS16);
+                spi_tx.set_transfer_length(((len + 1) / 2) as u16);
+            } else {
+                spi_tx.set_word_size(WordSize::BITS8);
+                spi_tx.set_transfer_length(len as u16);
+            }
             spi_tx.set_memory_address(ptr as usize as u32, true);
             spi_tx.set_peripheral_address(spi_dr8b as usize as u32, false);
-            spi_tx.set_transfer_length(len as u16);
 
             spi_tx.set_direction(Direction::FromMemory);
             spi_tx.select_peripheral(DmaMuxIndex::SPI1_TX);
@@ -344,10 +500,5 @@ impl DataPipes {
             defmt::println!("unmasked spi int!");
             spi_int_unmask();
         }
-
-        if did_restore_rs485 {
-            defmt::println!("unmasked rs485 int!");
-            enable_rs485_addr_match();
-        }
     }
 }
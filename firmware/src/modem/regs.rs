@@ -0,0 +1,156 @@
+//! Host-visible register map
+//!
+//! These registers are read and written by the host using the short register
+//! commands. Each register is 16 bits wide, and there are (at most) 32 of them,
+//! as the register index is encoded in the low five bits of the command byte.
+//!
+//! | Index | Name                    | Access | Description                                                    |
+//! | :--   | :--                     | :--    | :--                                                            |
+//! | 0x00  | `RS485_BRR`             | RW     | USART1 baud rate divisor (`BRR` register)                      |
+//! | 0x01  | `RS485_CFG`             | RW     | RS-485 line config, see `RS485_CFG_*`                          |
+//! | 0x02  | `RS485_DE`              | RW     | DE assert time (bits 0..5), deassert (8..13)                   |
+//! | 0x03  | `RS485_CTRL`            | RW     | Apply and discovery requests, status, see `RS485_CTRL_*`       |
+//! | 0x04  | `IRQ_STATUS`            | RW1C   | Pending events, see `IRQ_*`                                    |
+//! | 0x05  | `IRQ_ENABLE`            | RW     | Event mask, see `IRQ_*`, and `IRQ_PIN_EN`                      |
+//! | 0x06  | `LAST_XFER`             | R      | Last long packet: seq (bits 8..16), `LAST_XFER_*`              |
+//! | 0x07  | `LAST_XFER_RX_LEN`      | R      | Last long packet: bytes accepted from the host                 |
+//! | 0x08  | `LAST_XFER_TX_LEN`      | R      | Last long packet: bytes delivered to the host                  |
+//! | 0x09  | `SPI_OVR_COUNT`         | RC     | Count of SPI receive overruns                                  |
+//! | 0x0A  | `SPI_FRE_COUNT`         | RC     | Count of SPI frame format, mode fault, and command sync errors |
+//! | 0x0B  | `SPI_CFG`               | RW     | SPI mode, bit order, packing, and 3-wire, applied at boot      |
+//! | 0x0C  | `ROUTER_NODES_0`        | RW     | Router poll list, entries 0 (bits 0..8) and 1 (8..16)          |
+//! | 0x0D  | `ROUTER_NODES_1`        | RW     | Router poll list, entries 2 and 3                              |
+//! | 0x0E  | `ROUTER_NODES_2`        | RW     | Router poll list, entries 4 and 5                              |
+//! | 0x0F  | `ROUTER_NODES_3`        | RW     | Router poll list, entries 6 and 7                              |
+//! | 0x10  | `ROUTER_INTERVAL`       | RW     | Router: minimum gap between polls, in microseconds             |
+//! | 0x11  | `RS485_PE_COUNT`        | RC     | Count of RS-485 parity errors, with `RS485_CFG_PARITY`         |
+//! | 0x12  | `RS485_TIMEOUT_COUNT`   | RC     | Count of polls (router) or transactions (node) that timed out  |
+//! | 0x13  | `RS485_TRUNC_COUNT`     | RC     | Node: count of incoming frames cut short, and dropped          |
+//! | 0x14  | `RS485_CRC_COUNT`       | RC     | Count of incoming RS-485 frames that failed their CRC          |
+//! | 0x15  | `RS485_RETRY_COUNT`     | RC     | Count of outgoing RS-485 frames sent again, unacknowledged     |
+//! | 0x16  | `RS485_FE_COUNT`        | RC     | Count of RS-485 framing errors                                 |
+//! | 0x17  | `RS485_NE_COUNT`        | RC     | Count of RS-485 words received with noise                      |
+//! | 0x18  | `RS485_ORE_COUNT`       | RC     | Count of RS-485 receive overruns                               |
+//! | 0x19  | `RS485_GROUP`           | RW     | Node: multicast group address, see `RS485_GROUP_*`             |
+//! | 0x1A  | `RS485_FRAG_DROP_COUNT` | RC     | Count of partly reassembled RS-485 frames dropped              |
+//! | 0x1B  | `ROUTE_SEL`             | RW     | Router: routing table entry `ROUTE` accesses, `0..ROUTES_MAX`  |
+//! | 0x1C  | `ROUTE`                 | RW     | Router: selected routing table entry, see `ROUTE_*`            |
+//! | 0x1D  | `SYNC_TIME_LO`          | RW     | Bus time in microseconds, low half, latched by any write       |
+//! | 0x1E  | `SYNC_TIME_HI`          | R      | Bus time in microseconds, high half, latched with `_LO`        |
+//! | 0x1F  | `SYNC_OFFSET`           | R      | Node: bus time error at the last sync, see `SYNC_OFFSET_NONE`  |
+//!
+//! All 32 registers are in use, there is no scratch space.
+//! Counter registers (RC) saturate at `0xFFFF`, and are cleared by any write.
+//! Writing `ROUTE_SEL` loads the selected entry into `ROUTE`, and writing
+//! `ROUTE` stores it in the table. See the `timesync` module for the bus time.
+//! When built with the `uart-host` feature, the `SPI_*_COUNT` registers count
+//! the equivalent errors on the host UART instead.
+//!
+//! Registers are accessed with the following SPI commands, where `i` is the
+//! five bit register index:
+//!
+//! | Command       | Name        | Description                                       |
+//! | :--           | :--         | :--                                               |
+//! | `0b011_iiiii` | Reg Read    | Read one register, LE, after the command byte     |
+//! | `0b100_iiiii` | Reg Write   | Write one register, LE, after the command byte    |
+//! | `0b010_iiiii` | Burst Read  | Read registers `i`, `i + 1`, ... until CSn rises  |
+//! | `0b101_iiiii` | Burst Write | Write registers `i`, `i + 1`, ... until CSn rises |
+//!
+//! Burst reads past the last register return zero, and burst writes past the
+//! last register are discarded. Both also raise `IRQ_SPI_ERROR`, for reads only
+//! if the host clocks out one of the padding values.
+
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use super::{irq, config, router, timesync};
+
+pub use amodem_hostif::regmap::*;
+
+const ONE_ATOMIC: AtomicU16 = AtomicU16::new(0xACAB);
+static REGS: [AtomicU16; REG_COUNT] = [ONE_ATOMIC; REG_COUNT];
+
+/// Read a register. Out of range indexes read as zero.
+#[inline]
+pub fn read(idx: u8) -> u16 {
+    match REGS.get(idx as usize) {
+        Some(reg) => reg.load(Ordering::Relaxed),
+        None => 0,
+    }
+}
+
+/// Write a register. Out of range indexes are ignored.
+#[inline]
+pub fn write(idx: u8, val: u16) {
+    if let Some(reg) = REGS.get(idx as usize) {
+        reg.store(val, Ordering::Relaxed);
+    }
+}
+
+/// Read-modify-write a register, returning the new value
+///
+/// The target has no atomic read-modify-write instructions, so this is done
+/// in a critical section. Out of range indexes are ignored, and return zero.
+#[inline]
+pub fn modify<F: FnOnce(u16) -> u16>(idx: u8, f: F) -> u16 {
+    match REGS.get(idx as usize) {
+        Some(reg) => cortex_m::interrupt::free(|_cs| {
+            let val = f(reg.load(Ordering::Relaxed));
+            reg.store(val, Ordering::Relaxed);
+            val
+        }),
+        None => 0,
+    }
+}
+
+/// Increment a counter register, saturating at `0xFFFF`
+#[inline]
+pub fn increment(idx: u8) {
+    modify(idx, |v| v.saturating_add(1));
+}
+
+/// Handle a register write made by the host
+///
+/// Unlike [write], this applies any side effects the register has, such as
+/// write-one-to-clear behavior.
+pub fn host_write(idx: u8, val: u16) {
+    match idx {
+        IRQ_STATUS => irq::acknowledge(val),
+        IRQ_ENABLE => irq::set_enabled(val),
+        LAST_XFER | LAST_XFER_RX_LEN | LAST_XFER_TX_LEN | SYNC_TIME_HI | SYNC_OFFSET => {
+            // Read only
+        },
+        SPI_OVR_COUNT
+        | SPI_FRE_COUNT
+        | RS485_PE_COUNT
+        | RS485_TIMEOUT_COUNT
+        | RS485_TRUNC_COUNT
+        | RS485_CRC_COUNT
+        | RS485_RETRY_COUNT
+        | RS485_FE_COUNT
+        | RS485_NE_COUNT
+        | RS485_ORE_COUNT
+        | RS485_FRAG_DROP_COUNT => write(idx, 0),
+        SPI_CFG => {
+            write(idx, val & SPI_CFG_MASK);
+            if (val & SPI_CFG_FACTORY_RESET) != 0 {
+                config::request_factory_reset();
+            } else if (val & SPI_CFG_SAVE) != 0 {
+                config::request_save();
+            } else if (val & SPI_CFG_BOOTLOADER) != 0 {
+                config::request_bootloader();
+            }
+        },
+        ROUTE_SEL => {
+            let sel = val % (ROUTES_MAX as u16);
+            write(idx, sel);
+            write(ROUTE, router::route(sel as usize));
+        },
+        ROUTE => {
+            let val = val & (ROUTE_VALID | ROUTE_DEST_MASK | ROUTE_VIA_MASK);
+            router::set_route(read(ROUTE_SEL) as usize, val);
+            write(idx, val);
+        },
+        SYNC_TIME_LO => timesync::latch(),
+        _ => write(idx, val),
+    }
+}
@@ -0,0 +1,994 @@
+//! RS-485 bus router (master) role
+//!
+//! When `RS485_CFG_ROUTER` is applied, the modem stops acting as a node, and
+//! instead polls the nodes listed in the `ROUTER_NODES_*` registers, in order,
+//! one at a time. Each poll uses the same handshake the node side expects:
+//!
+//! 1. The router sends the node's address word (9th bit set), followed by
+//!    its incoming capacity (LE), the length of its frame for the node (LE),
+//!    and its status for the node's last frame.
+//! 2. The node answers with the length of its frame for the router (LE), how
+//!    much of the router's frame it will accept (LE), which is either all or
+//!    nothing, and its status for the router's last frame.
+//! 3. The node sends its frame and CRC, if any.
+//! 4. The router sends its frame and CRC, if the node accepted it.
+//!
+//! Steps 1 and 2 are done from the idle loop, with a timeout. Steps 3 and 4
+//! use DMA, and are completed from the DMA interrupts. The node's CRC is only
+//! checked once our frame is on its way, so the node is not kept waiting.
+//!
+//! In the router role, frames from the host must start with the address of the
+//! destination node, which is not sent over the bus. Frames to the host start
+//! with the address of the node they came from. Only the frame at the head of
+//! the host queue is sent, when its node is polled. It stays there until the
+//! node acknowledges it in a later poll, and is sent again otherwise. So that
+//! it can't hold up the queue for good, it is dropped if its node is not in
+//! the poll list, or if [TX_ATTEMPTS_MAX] polls of its node in a row time out
+//! or NAK it, raising `IRQ_FRAME_DROPPED`. A node that answers, but has no
+//! room for the frame yet, keeps it waiting. The same goes for a frame being
+//! forwarded, below.
+//!
+//! Frames for a broadcast or group address are sent to every node at once, in
+//! place of the next poll. The router sends the address word of each node in
+//! the poll list to wake it, then the header and frame. With
+//! `RS485_CFG_PARITY`, there are no address marks, and every node wakes on the
+//! idle line the router leaves ahead of each header instead. No node replies, and
+//! the frame is released once sent.
+//!
+//! Frames go one fragment per poll, see `amodem_bus::frag`, and are released
+//! once their last fragment is done with. Fragments from nodes are reassembled
+//! one node at a time in the write grant, which is kept between polls. The
+//! other nodes are offered no room until the frame is complete, or dropped.
+//!
+//! A whole frame from a node whose destination header has a valid `ROUTE`
+//! entry is not committed for the host, but held in the write grant, and sent
+//! on to the node the route goes via, ahead of the host queue. Nodes are
+//! offered no room until it is done with, or dropped as above. See
+//! `amodem_bus::route` for the frame layout.
+//!
+//! With `RS485_CFG_SYNC` set, a time sync is sent in place of a poll every
+//! `timesync::SYNC_INTERVAL_US`, and its follow-up in place of the next, the
+//! same way as a broadcast with no frame. See `amodem_bus::timesync`. The time
+//! the sync was sent is taken with interrupts disabled, for up to a word time.
+//!
+//! With `RS485_CTRL_DISCOVER` set, nodes with no address are enumerated in
+//! place of every other poll, and assigned the lowest free address, which is
+//! added to the poll list. See `amodem_bus::discovery`. An enumeration is done
+//! from the idle loop, with a timeout, as steps 1 and 2 are. No enumeration is
+//! sent while the last address assigned is yet to answer a poll, and it is
+//! dropped from the poll list if it does not.
+//!
+//! See `amodem_bus::wire` for the status values. `amodem_bus::router` is a
+//! separate, word-at-a-time model of this role, which the simulated bus runs.
+//! Its tests don't cover this module, so changes to one have to be made to the
+//! other by hand.
+
+use core::{cell::UnsafeCell, sync::atomic::{AtomicBool, AtomicU8, AtomicU16, AtomicU32, Ordering}};
+
+use amodem_bus::{
+    discovery::{self, Discovery, Round, ASSIGN_LEN, UID_LEN},
+    frag::MAX_FRAME,
+    route::{self, Route},
+    timesync::SyncMsg,
+    wire::{self, Header, Reply, ADDR_FLAG, ADDR_MASK, CRC_WORDS, HEADER_WORDS, REPLY_WORDS, SLACK_BITS, STATUS_ACK, STATUS_NAK, STATUS_NONE, WORD_BITS},
+};
+use cortex_m::peripheral::NVIC;
+use groundhog::RollingTimer;
+use stm32g0xx_hal::pac::{Interrupt, USART1};
+
+use crate::{GlobalRollingTimer, modem::{pipes, regs, irq, timesync, rs485::{
+    self,
+    MODE,
+    MODE_ROUTER_IDLE,
+    MODE_ROUTER_RECV,
+    MODE_ROUTER_SEND,
+}}};
+
+/// Default for `ROUTER_INTERVAL`
+pub const DEFAULT_INTERVAL_US: u16 = 100;
+
+/// Bit times allowed for the router to turn the bus around, before sending
+/// its frame to the node
+const TURNAROUND_BITS: u32 = 2;
+
+/// [TX_PENDING] when no frame is waiting for a node's status
+const NO_NODE: u8 = 0xFF;
+
+/// Failed polls in a row of the node the frame being sent is for, before the
+/// frame is dropped
+const TX_ATTEMPTS_MAX: u8 = 8;
+
+const ONE_STATUS: AtomicU8 = AtomicU8::new(STATUS_NONE);
+
+static NEXT_ENTRY: AtomicU8 = AtomicU8::new(0);
+static POLL_ENTRY: AtomicU8 = AtomicU8::new(0);
+static POLL_ADDR: AtomicU8 = AtomicU8::new(0);
+static LAST_POLL: AtomicU32 = AtomicU32::new(0);
+static XFER_START: AtomicU32 = AtomicU32::new(0);
+static XFER_TIMEOUT_US: AtomicU32 = AtomicU32::new(0);
+static RECV_AMT: AtomicU16 = AtomicU16::new(0);
+static SEND_AMT: AtomicU16 = AtomicU16::new(0);
+
+/// The CRC trailer of the node's frame, checked once our frame is sent
+static RECV_CRC: AtomicU16 = AtomicU16::new(0);
+
+/// A line error damaged the node's frame. It is dropped once the poll is over,
+/// as the node is still sending.
+static RECV_BAD: AtomicBool = AtomicBool::new(false);
+
+/// The node our last fragment was sent to, while its frame is kept at the head
+/// of the queue waiting for that node's status, or [NO_NODE]
+static TX_PENDING: AtomicU8 = AtomicU8::new(NO_NODE);
+
+/// Failed polls of the node the frame being sent is for, since it last
+/// acknowledged a fragment
+static TX_ATTEMPTS: AtomicU8 = AtomicU8::new(0);
+
+/// The poll list entry whose frame is being reassembled, while there is one
+static REASSEMBLY_ENTRY: AtomicU8 = AtomicU8::new(0);
+
+/// The frame being sent is a broadcast, and is released once sent
+static BROADCAST: AtomicBool = AtomicBool::new(false);
+
+/// Our status for each poll list entry's last frame, sent in its next header
+static RX_STATUS: [AtomicU8; regs::ROUTER_NODES_MAX] = [ONE_STATUS; regs::ROUTER_NODES_MAX];
+
+const ONE_ROUTE: AtomicU16 = AtomicU16::new(0);
+
+/// The routing table, each entry as in the `ROUTE` register
+static ROUTES: [AtomicU16; regs::ROUTES_MAX] = [ONE_ROUTE; regs::ROUTES_MAX];
+
+/// Length of the frame held in the write grant to be forwarded, source
+/// included, or 0 for none
+static FWD_LEN: AtomicU16 = AtomicU16::new(0);
+
+/// The node the held frame is forwarded to
+static FWD_VIA: AtomicU8 = AtomicU8::new(0);
+
+/// The frame being sent is the held one, rather than the host's
+static TX_FORWARD: AtomicBool = AtomicBool::new(false);
+
+/// When the last time sync was sent
+static LAST_SYNC: AtomicU32 = AtomicU32::new(0);
+
+/// The bus time the last sync was sent at, for its follow-up
+static SYNC_SENT_AT: AtomicU32 = AtomicU32::new(0);
+
+/// The last sync is to be followed up in place of the next poll
+static FOLLOW_UP_DUE: AtomicBool = AtomicBool::new(false);
+
+/// Discovery is running, as last seen in `RS485_CTRL`
+static DISCOVERING: AtomicBool = AtomicBool::new(false);
+
+/// The last poll was an enumeration, so the next is not
+static ENUMERATED: AtomicBool = AtomicBool::new(false);
+
+/// The poll list entry last assigned by discovery, until its node answers a
+/// poll, or [NO_ENTRY]
+static PROBATION: AtomicU8 = AtomicU8::new(NO_ENTRY);
+
+/// [PROBATION] when no entry is on probation
+const NO_ENTRY: u8 = 0xFF;
+
+struct DiscoveryCell(UnsafeCell<Discovery>);
+
+unsafe impl Sync for DiscoveryCell { }
+
+// Only accessed from the idle loop
+static DISCOVERY: DiscoveryCell = DiscoveryCell(UnsafeCell::new(Discovery::new()));
+
+/// Reset the router registers to their defaults: an empty poll list
+pub fn setup_router() {
+    regs::write(regs::ROUTER_NODES_0, 0);
+    regs::write(regs::ROUTER_NODES_1, 0);
+    regs::write(regs::ROUTER_NODES_2, 0);
+    regs::write(regs::ROUTER_NODES_3, 0);
+    regs::write(regs::ROUTER_INTERVAL, DEFAULT_INTERVAL_US);
+    regs::write(regs::RS485_TIMEOUT_COUNT, 0);
+    regs::write(regs::ROUTE_SEL, 0);
+    regs::write(regs::ROUTE, 0);
+    ROUTES.iter().for_each(|r| r.store(0, Ordering::Relaxed));
+    NEXT_ENTRY.store(0, Ordering::Relaxed);
+    TX_PENDING.store(NO_NODE, Ordering::Relaxed);
+    TX_ATTEMPTS.store(0, Ordering::Relaxed);
+    RX_STATUS.iter().for_each(|s| s.store(STATUS_NONE, Ordering::Relaxed));
+    FOLLOW_UP_DUE.store(false, Ordering::Relaxed);
+    DISCOVERING.store(false, Ordering::Relaxed);
+    PROBATION.store(NO_ENTRY, Ordering::Relaxed);
+}
+
+/// Poll list entry `idx`, if it is in use
+fn entry(idx: usize) -> Option<u8> {
+    let reg = regs::read(regs::ROUTER_NODES_0 + (idx / 2) as u8);
+    let byte = reg.to_le_bytes()[idx & 1];
+    if (byte & regs::ROUTER_NODE_VALID) != 0 {
+        Some(byte & regs::ROUTER_NODE_ADDR_MASK)
+    } else {
+        None
+    }
+}
+
+/// Set poll list entry `idx` to `addr`, or to unused
+fn set_entry(idx: usize, addr: Option<u8>) {
+    let byte = addr.map_or(0, |a| regs::ROUTER_NODE_VALID | a);
+    regs::modify(regs::ROUTER_NODES_0 + (idx / 2) as u8, |v| {
+        let mut bytes = v.to_le_bytes();
+        bytes[idx & 1] = byte;
+        u16::from_le_bytes(bytes)
+    });
+}
+
+/// Is `addr` in the poll list?
+fn polled(addr: u8) -> bool {
+    (0..regs::ROUTER_NODES_MAX).filter_map(entry).any(|a| a == addr)
+}
+
+/// Routing table entry `idx`, as read from the `ROUTE` register
+pub fn route(idx: usize) -> u16 {
+    ROUTES.get(idx).map_or(0, |r| r.load(Ordering::Relaxed))
+}
+
+/// Set routing table entry `idx`, as written to the `ROUTE` register
+pub fn set_route(idx: usize, val: u16) {
+    if let Some(r) = ROUTES.get(idx) {
+        r.store(val, Ordering::Relaxed);
+    }
+}
+
+/// The valid routing table entries
+fn routes() -> impl Iterator<Item = Route> {
+    ROUTES
+        .iter()
+        .map(|r| r.load(Ordering::Relaxed))
+        .filter(|r| (r & regs::ROUTE_VALID) != 0)
+        .map(|r| Route {
+            dest: (r & regs::ROUTE_DEST_MASK) as u8,
+            via: ((r & regs::ROUTE_VIA_MASK) >> regs::ROUTE_VIA_SHIFT) as u8,
+        })
+}
+
+/// A node's frame, `[src, dest, ..]`, is whole in the write grant. Returns true
+/// if it is to be held there and forwarded, rather than committed for the host.
+pub(super) fn forward(frame: &[u8]) -> bool {
+    if !rs485::active_config().router {
+        return false;
+    }
+    let [_src, dest, ..] = frame else {
+        return false;
+    };
+    if frame.len() > MAX_FRAME {
+        // No room for the source
+        return false;
+    }
+    let Some(via) = route::next_hop(*dest, routes(), polled) else {
+        return false;
+    };
+    FWD_VIA.store(via, Ordering::Relaxed);
+    FWD_LEN.store(frame.len() as u16, Ordering::Relaxed);
+    true
+}
+
+/// Is a frame held in the write grant, waiting to be forwarded?
+pub(super) fn forwarding() -> bool {
+    FWD_LEN.load(Ordering::Relaxed) != 0
+}
+
+/// Forget the frame waiting to be forwarded, if any. The write grant it was
+/// held in is given up by the next poll, or reused by the node role.
+pub(super) fn reset_forward() {
+    FWD_LEN.store(0, Ordering::Relaxed);
+    TX_FORWARD.store(false, Ordering::Relaxed);
+}
+
+/// The frame being sent, without the address of the node it is for: the
+/// host's, or the one being forwarded
+unsafe fn tx_frame() -> &'static [u8] {
+    if TX_FORWARD.load(Ordering::Relaxed) {
+        let len = FWD_LEN.load(Ordering::Relaxed) as usize;
+        pipes::PIPES.rs485_to_spi.held_wr_grant().and_then(|f| f.get(..len)).unwrap_or_default()
+    } else {
+        pipes::PIPES.spi_to_rs485.busy_rd_grant().and_then(|f| f.get(1..)).unwrap_or_default()
+    }
+}
+
+/// The frame being sent is done with. Release the host's, or forget the one
+/// being forwarded.
+unsafe fn release_tx_frame() {
+    TX_ATTEMPTS.store(0, Ordering::Relaxed);
+    if TX_FORWARD.load(Ordering::Relaxed) {
+        reset_forward();
+    } else {
+        pipes::PIPES.spi_to_rs485.complete_rd_dma();
+        irq::raise(regs::IRQ_FRAME_SENT);
+    }
+}
+
+/// Drop the frame being sent, as it can't be delivered, along with any
+/// fragments of it already sent: the host's at the head of the queue, or the
+/// one being forwarded
+unsafe fn drop_tx_frame() {
+    if TX_FORWARD.load(Ordering::Relaxed) {
+        reset_forward();
+    } else {
+        let _ = pipes::PIPES.spi_to_rs485.service_lowprio_rd();
+        pipes::PIPES.spi_to_rs485.get_prep_rd_dma();
+        pipes::PIPES.spi_to_rs485.complete_rd_dma();
+    }
+    TX_PENDING.store(NO_NODE, Ordering::Relaxed);
+    TX_ATTEMPTS.store(0, Ordering::Relaxed);
+    rs485::restart_tx();
+    irq::raise(regs::IRQ_FRAME_DROPPED);
+}
+
+/// A poll failed, or NAK'd our last fragment. If `ours`, the frame being sent
+/// was for the node polled, and is dropped once this has happened too often.
+fn tx_failed(ours: bool) {
+    if !ours {
+        return;
+    }
+    let attempts = TX_ATTEMPTS.load(Ordering::Relaxed) + 1;
+    if attempts < TX_ATTEMPTS_MAX {
+        TX_ATTEMPTS.store(attempts, Ordering::Relaxed);
+        return;
+    }
+    defmt::println!("Router: node {=u8} not answering, frame dropped", POLL_ADDR.load(Ordering::Relaxed));
+    unsafe { drop_tx_frame() };
+}
+
+/// Pick where the next frame is sent from: the held frame goes first, unless
+/// part of the host's is out
+fn select_tx_frame() {
+    if forwarding() && !polled(FWD_VIA.load(Ordering::Relaxed)) {
+        // It would wait forever
+        defmt::println!("Router: node {=u8} not polled, forwarded frame dropped", FWD_VIA.load(Ordering::Relaxed));
+        if TX_FORWARD.load(Ordering::Relaxed) {
+            unsafe { drop_tx_frame() };
+        } else {
+            reset_forward();
+            irq::raise(regs::IRQ_FRAME_DROPPED);
+        }
+    }
+
+    // Failed polls count against the frame being sent, not the one before it
+    let forward = forwarding();
+    let between = (TX_PENDING.load(Ordering::Relaxed) == NO_NODE) && !rs485::tx_started();
+    if between && (forward != TX_FORWARD.load(Ordering::Relaxed)) {
+        TX_FORWARD.store(forward, Ordering::Relaxed);
+        TX_ATTEMPTS.store(0, Ordering::Relaxed);
+    }
+
+    if !TX_FORWARD.load(Ordering::Relaxed) {
+        // The host's frame would wait forever if its node is never polled
+        let _ = pipes::PIPES.spi_to_rs485.service_lowprio_rd();
+        unsafe {
+            pipes::PIPES.spi_to_rs485.get_prep_rd_dma();
+            let unsendable = match pipes::PIPES.spi_to_rs485.busy_rd_grant() {
+                Some([dest, ..]) => !wire::is_multicast(*dest) && !polled(*dest),
+                // No address, nowhere to send it
+                Some([]) => true,
+                None => false,
+            };
+            if unsendable {
+                defmt::println!("Router: frame for a node not polled, dropped");
+                drop_tx_frame();
+            } else {
+                pipes::PIPES.spi_to_rs485.abort_rd_dma();
+            }
+        }
+    }
+}
+
+/// The next poll list entry and node to poll, round robin
+fn next_node() -> Option<(usize, u8)> {
+    let start = NEXT_ENTRY.load(Ordering::Relaxed) as usize;
+    (0..regs::ROUTER_NODES_MAX)
+        .map(|i| (start + i) % regs::ROUTER_NODES_MAX)
+        .find_map(|idx| {
+            let addr = entry(idx)?;
+            NEXT_ENTRY.store(((idx + 1) % regs::ROUTER_NODES_MAX) as u8, Ordering::Relaxed);
+            Some((idx, addr))
+        })
+}
+
+/// Run the router, called from the idle loop
+///
+/// Starts the next poll once the previous one is done and the poll interval has
+/// passed, or aborts the current poll if the node stopped sending.
+pub fn poll_step() {
+    let timer = GlobalRollingTimer::new();
+
+    match MODE.load(Ordering::Relaxed) {
+        MODE_ROUTER_IDLE => {},
+        MODE_ROUTER_RECV | MODE_ROUTER_SEND => {
+            let start = XFER_START.load(Ordering::Relaxed);
+            if timer.micros_since(start) >= XFER_TIMEOUT_US.load(Ordering::Relaxed) {
+                abort_xfer();
+            }
+            return;
+        },
+        _ => return,
+    }
+
+    let interval = regs::read(regs::ROUTER_INTERVAL) as u32;
+    if timer.micros_since(LAST_POLL.load(Ordering::Relaxed)) < interval {
+        return;
+    }
+
+    select_tx_frame();
+    if !time_sync() && !enumerate() && !broadcast() {
+        if let Some((idx, addr)) = next_node() {
+            poll(idx, addr);
+        }
+    }
+    LAST_POLL.store(timer.get_ticks(), Ordering::Relaxed);
+}
+
+/// The address word of every node in the poll list, sent ahead of a broadcast
+/// to wake them, as each only wakes on its own. With parity, every node wakes
+/// on the idle line ahead of the header, and there is none.
+fn wake_run(cfg: &rs485::Rs485Config) -> impl Iterator<Item = u16> {
+    let run = !cfg.parity;
+    (0..regs::ROUTER_NODES_MAX)
+        .filter(move |_| run)
+        .filter_map(entry)
+        .map(|addr| ADDR_FLAG | (addr as u16))
+}
+
+/// Send a time sync, or the follow-up to the last one, to every node in the
+/// poll list, if one is due. Returns false if neither is.
+fn time_sync() -> bool {
+    let timer = GlobalRollingTimer::new();
+    let cfg = rs485::active_config();
+
+    let msg = if FOLLOW_UP_DUE.load(Ordering::Relaxed) {
+        FOLLOW_UP_DUE.store(false, Ordering::Relaxed);
+        SyncMsg::FollowUp(SYNC_SENT_AT.load(Ordering::Relaxed))
+    } else if cfg.sync && (timer.micros_since(LAST_SYNC.load(Ordering::Relaxed)) >= timesync::SYNC_INTERVAL_US) {
+        LAST_SYNC.store(timer.get_ticks(), Ordering::Relaxed);
+        SyncMsg::Sync
+    } else {
+        return false;
+    };
+
+    let usart1 = unsafe { &*USART1::PTR };
+    let [words @ .., last] = msg.to_header().to_words();
+    rs485::start_gap(usart1);
+    wake_run(&cfg)
+        .chain(words)
+        .for_each(|w| {
+            while usart1.isr.read().txe().bit_is_clear() { }
+            usart1.tdr.write(|wr| wr.tdr().bits(w));
+        });
+
+    // Time the last word on its own, from an empty FIFO
+    while usart1.isr.read().txfe().bit_is_clear() { }
+    let sent_at = cortex_m::interrupt::free(|_cs| {
+        usart1.icr.write(|w| w.tccf().set_bit());
+        usart1.tdr.write(|wr| wr.tdr().bits(last));
+        while usart1.isr.read().tc().bit_is_clear() { }
+        timer.get_ticks()
+    });
+
+    if msg == SyncMsg::Sync {
+        SYNC_SENT_AT.store(sent_at.wrapping_sub(cfg.half_bit_us()), Ordering::Relaxed);
+        FOLLOW_UP_DUE.store(true, Ordering::Relaxed);
+    }
+    true
+}
+
+/// Discovery is over: the host is told, and may set `RS485_CTRL_DISCOVER` again
+fn end_discovery() {
+    DISCOVERING.store(false, Ordering::Relaxed);
+    regs::modify(regs::RS485_CTRL, |v| v & !regs::RS485_CTRL_DISCOVER);
+    irq::raise(regs::IRQ_DISCOVERY);
+}
+
+/// Send an enumeration to nodes with no address, and assign an address to the
+/// one that offers, if discovery is running and one is due. Returns false if
+/// none is.
+fn enumerate() -> bool {
+    let requested = (regs::read(regs::RS485_CTRL) & regs::RS485_CTRL_DISCOVER) != 0;
+    if !requested {
+        DISCOVERING.store(false, Ordering::Relaxed);
+        return false;
+    }
+    let disc = unsafe { &mut *DISCOVERY.0.get() };
+    if !DISCOVERING.load(Ordering::Relaxed) {
+        DISCOVERING.store(true, Ordering::Relaxed);
+        *disc = Discovery::new();
+        ENUMERATED.store(false, Ordering::Relaxed);
+    }
+
+    // Every other poll, unless there are none, and never while the last
+    // address assigned is on probation
+    let any_polled = (0..regs::ROUTER_NODES_MAX).filter_map(entry).next().is_some();
+    if (ENUMERATED.load(Ordering::Relaxed) && any_polled) || (PROBATION.load(Ordering::Relaxed) != NO_ENTRY) {
+        ENUMERATED.store(false, Ordering::Relaxed);
+        return false;
+    }
+    let free_entry = (0..regs::ROUTER_NODES_MAX).find(|idx| entry(*idx).is_none());
+    let (Some(idx), Some(addr)) = (free_entry, discovery::free_addr(polled)) else {
+        defmt::println!("Router: no room for more nodes, discovery over");
+        end_discovery();
+        return false;
+    };
+    ENUMERATED.store(true, Ordering::Relaxed);
+
+    let usart1 = unsafe { &*USART1::PTR };
+    let timer = GlobalRollingTimer::new();
+    let cfg = rs485::active_config();
+
+    // Forget anything left over from the last poll
+    rs485::start_gap(usart1);
+    usart1.rqr.write(|w| w.rxfrq().set_bit());
+    rs485::clear_line_errors(usart1);
+
+    disc.header().to_words().iter().for_each(|w| {
+        while usart1.isr.read().txe().bit_is_clear() { }
+        usart1.tdr.write(|wr| wr.tdr().bits(*w));
+    });
+
+    // The reply and the offer, at once
+    let bits = wire::REPLY_TIMEOUT_BITS + wire::data_timeout_bits(UID_LEN + CRC_WORDS);
+    let timeout_us = cfg.bits_to_us(bits);
+    let start = timer.get_ticks();
+    let mut words = [0u16; REPLY_WORDS + UID_LEN + CRC_WORDS];
+    let got = words
+        .iter_mut()
+        .map_while(|w| {
+            loop {
+                if usart1.isr.read().rxne().bit_is_set() {
+                    *w = rs485::read_word(usart1);
+                    return Some(());
+                }
+                if timer.micros_since(start) >= timeout_us {
+                    return None;
+                }
+            }
+        })
+        .count();
+    let line_error = rs485::check_line_errors(usart1);
+
+    let (reply, offer) = words.split_at(REPLY_WORDS);
+    let expected = Reply { tx_amt: UID_LEN as u16, rx_amt: ASSIGN_LEN as u16, status: STATUS_NONE };
+    let reply_ok = reply.try_into().ok().and_then(Reply::from_words) == Some(expected);
+    let mut offer_buf = [0u8; UID_LEN + CRC_WORDS];
+    offer_buf.iter_mut().zip(offer).for_each(|(b, w)| *b = *w as u8);
+    let uid = (reply_ok && (got == words.len()) && !line_error)
+        .then(|| discovery::parse_offer(&offer_buf))
+        .flatten();
+
+    let round = match uid {
+        Some(uid) => {
+            // Give the node time to turn the bus around
+            let guard_us = cfg.bits_to_us(TURNAROUND_BITS);
+            let start = timer.get_ticks();
+            while timer.micros_since(start) < guard_us { }
+
+            discovery::assignment(&uid, addr).iter().for_each(|b| {
+                while usart1.isr.read().txe().bit_is_clear() { }
+                usart1.tdr.write(|wr| wr.tdr().bits(*b as u16));
+            });
+            while usart1.isr.read().tc().bit_is_clear() { }
+
+            defmt::println!("Router: discovered node {=u8}", addr);
+            set_entry(idx, Some(addr));
+            RX_STATUS[idx].store(STATUS_NONE, Ordering::Relaxed);
+            PROBATION.store(idx as u8, Ordering::Relaxed);
+            Round::Assigned
+        },
+        None if (got == 0) && !line_error => Round::Empty,
+        None => Round::Collided,
+    };
+    if disc.round(round) {
+        defmt::println!("Router: discovery over");
+        end_discovery();
+    }
+    true
+}
+
+/// Send the frame at the head of the queue to every node in the poll list, if
+/// it is for a broadcast or group address. Returns false if it is not.
+fn broadcast() -> bool {
+    if TX_FORWARD.load(Ordering::Relaxed) {
+        // Never for a broadcast or group address
+        return false;
+    }
+    let _ = pipes::PIPES.spi_to_rs485.service_lowprio_rd();
+    let (dest, len) = unsafe {
+        pipes::PIPES.spi_to_rs485.get_prep_rd_dma();
+        match pipes::PIPES.spi_to_rs485.busy_rd_grant() {
+            Some([dest, payload @ ..]) if wire::is_multicast(*dest) => {
+                (*dest & ADDR_MASK, rs485::prepare_tx_fragment(payload))
+            },
+            _ => {
+                pipes::PIPES.spi_to_rs485.abort_rd_dma();
+                return false;
+            },
+        }
+    };
+
+    let usart1 = unsafe { &*USART1::PTR };
+    let timer = GlobalRollingTimer::new();
+    let cfg = rs485::active_config();
+
+    let header = Header { addr: dest, rx_cap: 0, tx_len: len as u16, status: STATUS_NONE };
+    rs485::start_gap(usart1);
+    wake_run(&cfg)
+        .chain(header.to_words())
+        .for_each(|w| {
+            while usart1.isr.read().txe().bit_is_clear() { }
+            usart1.tdr.write(|wr| wr.tdr().bits(w));
+        });
+    rs485::send_tx_frag_header(usart1);
+
+    POLL_ADDR.store(dest, Ordering::Relaxed);
+    RECV_AMT.store(0, Ordering::Relaxed);
+    SEND_AMT.store(len as u16, Ordering::Relaxed);
+    BROADCAST.store(true, Ordering::Relaxed);
+
+    // The end of the header may still be in the FIFO
+    let data_words = HEADER_WORDS + wire::payload_words(len);
+    let data_bits = (data_words as u32) * WORD_BITS;
+    XFER_TIMEOUT_US.store(cfg.bits_to_us(data_bits + SLACK_BITS), Ordering::Relaxed);
+    XFER_START.store(timer.get_ticks(), Ordering::Relaxed);
+
+    start_tx_dma();
+    true
+}
+
+/// Steps 1 and 2 of the handshake, then start the data transfers
+fn poll(idx: usize, addr: u8) {
+    let usart1 = unsafe { &*USART1::PTR };
+    let timer = GlobalRollingTimer::new();
+    let cfg = rs485::active_config();
+
+    // Outgoing: only if the frame being sent is for this node, and is not
+    // already waiting for its status
+    let pending = TX_PENDING.load(Ordering::Relaxed) == addr;
+    let send_len = if TX_FORWARD.load(Ordering::Relaxed) {
+        if (FWD_VIA.load(Ordering::Relaxed) == addr) && !pending {
+            rs485::prepare_tx_fragment(unsafe { tx_frame() })
+        } else {
+            0
+        }
+    } else {
+        let _ = pipes::PIPES.spi_to_rs485.service_lowprio_rd();
+        unsafe {
+            pipes::PIPES.spi_to_rs485.get_prep_rd_dma();
+            match pipes::PIPES.spi_to_rs485.busy_rd_grant() {
+                Some(_) if pending => 0,
+                Some([dest, payload @ ..]) if *dest == addr => rs485::prepare_tx_fragment(payload),
+                _ => {
+                    pipes::PIPES.spi_to_rs485.abort_rd_dma();
+                    0
+                },
+            }
+        }
+    };
+    POLL_ADDR.store(addr, Ordering::Relaxed);
+    let ours = pending || (send_len != 0);
+
+    // Incoming: only if this node's frame is the one being reassembled, if any,
+    // and no frame waits to be forwarded
+    let _ = pipes::PIPES.rs485_to_spi.service_lowprio_wr();
+    let grant_len = unsafe { pipes::PIPES.rs485_to_spi.get_prep_wr_dma() };
+    let other = rs485::reassembling() && (REASSEMBLY_ENTRY.load(Ordering::Relaxed) as usize != idx);
+    let recv_cap = if other || forwarding() {
+        0
+    } else {
+        rs485::rx_room(grant_len)
+    };
+
+    let header = Header {
+        addr,
+        rx_cap: recv_cap as u16,
+        tx_len: send_len as u16,
+        status: RX_STATUS[idx].load(Ordering::Relaxed),
+    };
+    RX_STATUS[idx].store(STATUS_NONE, Ordering::Relaxed);
+
+    // Forget anything left over from the last poll
+    rs485::start_gap(usart1);
+    usart1.rqr.write(|w| w.rxfrq().set_bit());
+    rs485::clear_line_errors(usart1);
+
+    header.to_words().iter().for_each(|w| {
+        while usart1.isr.read().txe().bit_is_clear() { }
+        usart1.tdr.write(|wr| wr.tdr().bits(*w));
+    });
+
+    let timeout_us = cfg.bits_to_us(wire::REPLY_TIMEOUT_BITS);
+    let start = timer.get_ticks();
+    let mut reply = [0u16; REPLY_WORDS];
+    let res = reply.iter_mut().try_for_each(|b| {
+        loop {
+            if usart1.isr.read().rxne().bit_is_set() {
+                *b = rs485::read_word(usart1);
+                return Ok(());
+            }
+            if timer.micros_since(start) >= timeout_us {
+                return Err(());
+            }
+        }
+    });
+
+    let (node_send, node_accept, node_status) = match res.ok().and_then(|()| Reply::from_words(&reply)) {
+        Some(r) => (r.tx_amt as usize, r.rx_amt as usize, r.status),
+        None => {
+            defmt::println!("Router: node {=u8} timed out", addr);
+            if PROBATION.load(Ordering::Relaxed) == idx as u8 {
+                // Its assignment went astray, or the node was already gone
+                defmt::println!("Router: node {=u8} dropped", addr);
+                PROBATION.store(NO_ENTRY, Ordering::Relaxed);
+                set_entry(idx, None);
+            }
+            regs::increment(regs::RS485_TIMEOUT_COUNT);
+            irq::raise(regs::IRQ_RS485_TIMEOUT);
+            unsafe {
+                pipes::PIPES.spi_to_rs485.abort_rd_dma();
+                rs485::drop_rx_grant();
+            }
+            tx_failed(ours);
+            return;
+        },
+    };
+
+    if rs485::check_line_errors(usart1) {
+        // Already counted. Give up on this poll, as with a timeout.
+        defmt::println!("Router: node {=u8} reply line error", addr);
+        unsafe {
+            pipes::PIPES.spi_to_rs485.abort_rd_dma();
+            rs485::drop_rx_grant();
+        }
+        tx_failed(ours);
+        return;
+    }
+
+    if PROBATION.load(Ordering::Relaxed) == idx as u8 {
+        PROBATION.store(NO_ENTRY, Ordering::Relaxed);
+    }
+
+    if node_send > recv_cap {
+        // The node must not send more than we offered. Give up on this poll,
+        // the node's frame will time out on its own side.
+        defmt::println!("Router: node {=u8} overran", addr);
+        irq::raise(regs::IRQ_QUEUE_OVERFLOW);
+        unsafe {
+            pipes::PIPES.spi_to_rs485.abort_rd_dma();
+            rs485::drop_rx_grant();
+        }
+        return;
+    }
+
+    // Our last fragment to this node is done with, or is sent again next time.
+    // Its frame is released after the last fragment.
+    if pending {
+        TX_PENDING.store(NO_NODE, Ordering::Relaxed);
+        if node_status == STATUS_ACK {
+            TX_ATTEMPTS.store(0, Ordering::Relaxed);
+            let frame_len = unsafe { tx_frame() }.len();
+            if rs485::tx_fragment_done(frame_len) {
+                unsafe { release_tx_frame() };
+            }
+        } else {
+            regs::increment(regs::RS485_RETRY_COUNT);
+            tx_failed(true);
+        }
+    }
+
+    // The node takes all of our fragment, or none of it. Keep it for next time.
+    let send_amt = if (send_len != 0) && (node_accept == send_len) {
+        send_len
+    } else {
+        unsafe { pipes::PIPES.spi_to_rs485.abort_rd_dma() };
+        0
+    };
+
+    POLL_ENTRY.store(idx as u8, Ordering::Relaxed);
+    RECV_AMT.store(node_send as u16, Ordering::Relaxed);
+    SEND_AMT.store(send_amt as u16, Ordering::Relaxed);
+
+    let data_words = wire::payload_words(node_send) + wire::payload_words(send_amt);
+    let data_bits = ((data_words as u32) * WORD_BITS) + TURNAROUND_BITS;
+    XFER_TIMEOUT_US.store(cfg.bits_to_us(data_bits + SLACK_BITS), Ordering::Relaxed);
+    XFER_START.store(timer.get_ticks(), Ordering::Relaxed);
+
+    if node_send != 0 {
+        unsafe {
+            let buf = pipes::PIPES.rs485_to_spi.busy_wr_grant().unwrap_or_default();
+            pipes::PIPES.load_rs485_rx_dma(rs485::rx_target(buf), node_send);
+            RECV_BAD.store(false, Ordering::Relaxed);
+            usart1.cr3.modify(|_r, w| w.dmar().enabled());
+            rs485::error_irqs(usart1, true);
+            MODE.store(MODE_ROUTER_RECV, Ordering::Relaxed);
+            pipes::PIPES.trigger_modified_rs485_rx_dma(node_send as u16);
+        }
+    } else {
+        unsafe { rs485::drop_rx_grant() };
+        start_send();
+    }
+}
+
+/// Step 4, if there is anything to send
+fn start_send() {
+    let send_amt = SEND_AMT.load(Ordering::Relaxed) as usize;
+    if send_amt == 0 {
+        finish_recv();
+        MODE.store(MODE_ROUTER_IDLE, Ordering::Relaxed);
+        return;
+    }
+
+    // Give the node time to turn the bus around
+    let timer = GlobalRollingTimer::new();
+    let guard_us = rs485::active_config().bits_to_us(TURNAROUND_BITS);
+    let start = timer.get_ticks();
+    while timer.micros_since(start) < guard_us { }
+
+    rs485::send_tx_frag_header(unsafe { &*USART1::PTR });
+    start_tx_dma();
+}
+
+/// Send the data of the fragment made ready by `rs485::prepare_tx_fragment`,
+/// once its fragment header is on its way
+fn start_tx_dma() {
+    MODE.store(MODE_ROUTER_SEND, Ordering::Relaxed);
+    unsafe {
+        let chunk = rs485::tx_chunk(tx_frame());
+        if chunk.is_empty() {
+            // An empty frame, there is only the trailer to send
+            send_complete();
+            return;
+        }
+        pipes::PIPES.load_rs485_tx_dma(chunk.as_ptr(), chunk.len());
+        let usart1 = &*USART1::PTR;
+        usart1.cr3.modify(|_r, w| w.dmat().enabled());
+        pipes::PIPES.trigger_rs485_tx_dma();
+    }
+}
+
+/// The node's frame has been received (DMA interrupt), or a word of it was
+/// damaged (line error, USART interrupt).
+///
+/// The CRC trailer is left in the FIFO by the DMA, and is read here before
+/// turning the bus around. It is checked in [finish_recv].
+pub fn recv_complete() {
+    let usart1 = unsafe { &*USART1::PTR };
+    if rs485::check_line_errors(usart1) {
+        RECV_BAD.store(true, Ordering::Relaxed);
+    }
+    if unsafe { pipes::PIPES.rs485_rx_remaining() } != 0 {
+        // A line error partway through. Let the node finish, once is enough.
+        rs485::error_irqs(usart1, false);
+        return;
+    }
+
+    unsafe {
+        pipes::PIPES.disable_rs485_rx_dma();
+    }
+    usart1.cr3.modify(|_r, w| w.dmar().disabled());
+    rs485::error_irqs(usart1, false);
+
+    let crc_bits = wire::data_timeout_bits(CRC_WORDS);
+    match rs485::recv_crc(usart1, rs485::active_config().bits_to_us(crc_bits)) {
+        Some(crc) => RECV_CRC.store(crc, Ordering::Relaxed),
+        None => {
+            abort_xfer();
+            return;
+        },
+    }
+    if rs485::check_line_errors(usart1) {
+        RECV_BAD.store(true, Ordering::Relaxed);
+    }
+
+    // Both may have fired, if the error hit just as the last word arrived
+    NVIC::unpend(Interrupt::USART1);
+    NVIC::unpend(Interrupt::DMA_CHANNEL2_3);
+
+    start_send();
+}
+
+/// Our fragment has been sent. Called from the DMA interrupt.
+pub fn send_complete() {
+    let usart1 = unsafe { &*USART1::PTR };
+    unsafe {
+        pipes::PIPES.disable_rs485_tx_dma();
+    }
+    usart1.cr3.modify(|_r, w| w.dmat().disabled());
+    rs485::send_tx_crc(usart1);
+
+    if BROADCAST.load(Ordering::Relaxed) {
+        // Nobody answers a broadcast, so the fragment is done with
+        BROADCAST.store(false, Ordering::Relaxed);
+        let frame_len = unsafe { tx_frame() }.len();
+        if rs485::tx_fragment_done(frame_len) {
+            unsafe {
+                release_tx_frame();
+            }
+        } else {
+            unsafe {
+                pipes::PIPES.spi_to_rs485.abort_rd_dma();
+            }
+        }
+    } else {
+        // Keep the frame until the node says the fragment arrived
+        unsafe {
+            pipes::PIPES.spi_to_rs485.abort_rd_dma();
+        }
+        TX_PENDING.store(POLL_ADDR.load(Ordering::Relaxed), Ordering::Relaxed);
+    }
+
+    finish_recv();
+    MODE.store(MODE_ROUTER_IDLE, Ordering::Relaxed);
+}
+
+/// Check the node's fragment against its CRC, if there was one, and keep it
+/// if it matches. The node hears how it went in its next header.
+fn finish_recv() {
+    let amt = RECV_AMT.load(Ordering::Relaxed) as usize;
+    if amt == 0 {
+        return;
+    }
+    RECV_AMT.store(0, Ordering::Relaxed);
+
+    let crc = match RECV_BAD.load(Ordering::Relaxed) {
+        true => None,
+        false => Some(RECV_CRC.load(Ordering::Relaxed)),
+    };
+    let intact = unsafe { rs485::reassemble(POLL_ADDR.load(Ordering::Relaxed), amt, crc) };
+
+    let idx = POLL_ENTRY.load(Ordering::Relaxed) as usize;
+    if intact {
+        RX_STATUS[idx].store(STATUS_ACK, Ordering::Relaxed);
+        REASSEMBLY_ENTRY.store(idx as u8, Ordering::Relaxed);
+    } else if RECV_BAD.load(Ordering::Relaxed) {
+        // Already counted
+        defmt::println!("Router: node {=u8} frame dropped, line error", POLL_ADDR.load(Ordering::Relaxed));
+        RX_STATUS[idx].store(STATUS_NAK, Ordering::Relaxed);
+    } else {
+        defmt::println!("Router: node {=u8} frame failed CRC", POLL_ADDR.load(Ordering::Relaxed));
+        RX_STATUS[idx].store(STATUS_NAK, Ordering::Relaxed);
+        regs::increment(regs::RS485_CRC_COUNT);
+        irq::raise(regs::IRQ_CRC_ERROR);
+    }
+}
+
+/// The node stopped sending partway through its fragment, or ours never
+/// finished sending. Drop the incoming fragment, asking the node to send it
+/// again, and keep the outgoing one, broadcasts included.
+fn abort_xfer() {
+    cortex_m::interrupt::free(|_cs| {
+        let mode = MODE.load(Ordering::Relaxed);
+        if (mode != MODE_ROUTER_RECV) && (mode != MODE_ROUTER_SEND) {
+            // Finished while we were deciding
+            return;
+        }
+
+        let usart1 = unsafe { &*USART1::PTR };
+        unsafe {
+            pipes::PIPES.disable_rs485_rx_dma();
+            pipes::PIPES.disable_rs485_tx_dma();
+            rs485::drop_rx_grant();
+            pipes::PIPES.spi_to_rs485.abort_rd_dma();
+        }
+        usart1.cr3.modify(|_r, w| {
+            w.dmar().disabled();
+            w.dmat().disabled();
+            w
+        });
+        rs485::error_irqs(usart1, false);
+        let ours = (SEND_AMT.load(Ordering::Relaxed) != 0) && !BROADCAST.load(Ordering::Relaxed);
+        BROADCAST.store(false, Ordering::Relaxed);
+        if RECV_AMT.load(Ordering::Relaxed) != 0 {
+            RECV_AMT.store(0, Ordering::Relaxed);
+            RX_STATUS[POLL_ENTRY.load(Ordering::Relaxed) as usize].store(STATUS_NAK, Ordering::Relaxed);
+        }
+
+        defmt::println!("Router: node {=u8} transfer timed out", POLL_ADDR.load(Ordering::Relaxed));
+        regs::increment(regs::RS485_TIMEOUT_COUNT);
+        irq::raise(regs::IRQ_RS485_TIMEOUT);
+        tx_failed(ours);
+        MODE.store(MODE_ROUTER_IDLE, Ordering::Relaxed);
+        rs485::unpend_interrupts();
+    });
+}
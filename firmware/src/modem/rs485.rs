@@ -1,14 +1,356 @@
-use core::sync::atomic::{AtomicU8, Ordering, AtomicU16};
+use core::{cell::UnsafeCell, sync::atomic::{AtomicBool, AtomicU8, Ordering, AtomicU16, AtomicU32}};
 
+use amodem_bus::{
+    crc::{crc16, crc16_update},
+    discovery::{is_assignable, UNASSIGNED_ADDR},
+    frag::{self, Accepted, Reassembly, FRAG_HEADER_LEN, REASSEMBLY_TIMEOUT_BITS},
+    timesync::SyncMsg,
+    update::UPDATE_ADDR,
+    wire::{self, Header, Reply, ADDR_FLAG, ADDR_MASK, BROADCAST_ADDR, CRC_WORDS, HEADER_WORDS, REPLY_WORDS, RX_GAP_BITS, STATUS_ACK, STATUS_NAK, STATUS_NONE},
+};
+use cortex_m::peripheral::NVIC;
 use groundhog::RollingTimer;
-use stm32g0xx_hal::{rcc::{Rcc, Enable, Reset}, pac::USART1};
+use stm32g0xx_hal::{rcc::{Rcc, Enable, Reset}, pac::{Interrupt, USART1, usart1::RegisterBlock as Usart1Rb}};
 
-use crate::{GlobalRollingTimer, modem::pipes};
+use crate::{GlobalRollingTimer, modem::{pipes, regs, irq, router, timesync, discovery, watchdog, config, SYSCLK_HZ}};
+
+/// Runtime configurable RS-485 line settings
+///
+/// These are staged by the host in the `RS485_*` registers, and applied
+/// between bus transactions by [apply_pending_config].
+#[derive(Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Rs485Config {
+    /// Raw `USART1.BRR` value
+    pub brr: u16,
+    /// 8x oversampling if true, 16x if false
+    pub over8: bool,
+    /// Driver enable assertion time, in sample times (0..=31)
+    pub deat: u8,
+    /// Driver enable deassertion time, in sample times (0..=31)
+    pub dedt: u8,
+    /// Act as the bus router if true, or as a node if false
+    pub router: bool,
+    /// As the router, send time syncs
+    pub sync: bool,
+    /// Even parity in place of address marks, with nodes waking on an idle line
+    pub parity: bool,
+    /// As a node, our address, or `UNASSIGNED_ADDR` until discovery assigns one
+    pub addr: u8,
+}
+
+impl Rs485Config {
+    /// 8MBaud, 8x oversampling, one bit time of DE assertion, half a bit time
+    /// of DE deassertion.
+    pub const DEFAULT: Self = Self {
+        brr: 0x0010,
+        over8: true,
+        deat: 8,
+        dedt: 4,
+        router: false,
+        sync: false,
+        parity: false,
+        addr: UNASSIGNED_ADDR,
+    };
+
+    /// The slowest baudrate we accept. Below this, the blocking header
+    /// receive in the address match interrupt would take too long.
+    pub const MIN_BAUD: u32 = 115_200;
+
+    /// Maximum value of the DEAT and DEDT fields
+    pub const MAX_DE_TIME: u8 = 0b1_1111;
+
+    pub fn from_regs() -> Self {
+        let de = regs::read(regs::RS485_DE);
+        let cfg = regs::read(regs::RS485_CFG);
+        Self {
+            brr: regs::read(regs::RS485_BRR),
+            over8: (cfg & regs::RS485_CFG_OVER8) != 0,
+            deat: (de & 0xFF) as u8,
+            dedt: (de >> 8) as u8,
+            router: (cfg & regs::RS485_CFG_ROUTER) != 0,
+            sync: (cfg & regs::RS485_CFG_SYNC) != 0,
+            parity: (cfg & regs::RS485_CFG_PARITY) != 0,
+            addr: ((cfg & regs::RS485_CFG_ADDR_MASK) >> regs::RS485_CFG_ADDR_SHIFT) as u8,
+        }
+    }
+
+    /// This config as `RS485_BRR`, `RS485_CFG`, and `RS485_DE`
+    pub fn to_regs(&self) -> (u16, u16, u16) {
+        let mut cfg = 0;
+        if self.over8 {
+            cfg |= regs::RS485_CFG_OVER8;
+        }
+        if self.router {
+            cfg |= regs::RS485_CFG_ROUTER;
+        }
+        if self.sync {
+            cfg |= regs::RS485_CFG_SYNC;
+        }
+        if self.parity {
+            cfg |= regs::RS485_CFG_PARITY;
+        }
+        cfg |= (self.addr as u16) << regs::RS485_CFG_ADDR_SHIFT;
+        (self.brr, cfg, ((self.dedt as u16) << 8) | (self.deat as u16))
+    }
+
+    pub fn store_regs(&self) {
+        let (brr, cfg, de) = self.to_regs();
+        regs::write(regs::RS485_BRR, brr);
+        regs::write(regs::RS485_CFG, cfg);
+        regs::write(regs::RS485_DE, de);
+    }
+
+    /// The USARTDIV value encoded by `brr`, or `None` if `brr` is not
+    /// a legal encoding for the selected oversampling mode.
+    fn usartdiv(&self) -> Option<u32> {
+        let brr = self.brr as u32;
+        if self.over8 {
+            // BRR[3] must be kept cleared, BRR[2:0] holds USARTDIV[3:0] >> 1.
+            if (brr & 0b1000) != 0 {
+                return None;
+            }
+            Some((brr & !0b1111) | ((brr & 0b0111) << 1))
+        } else {
+            Some(brr)
+        }
+    }
+
+    /// Actual baudrate produced by this config, assuming the USART is
+    /// clocked from [SYSCLK_HZ].
+    pub fn baud(&self) -> Option<u32> {
+        let usartdiv = self.usartdiv()?;
+
+        // USARTDIV must be greater than or equal to 16 in both modes
+        if usartdiv < 16 {
+            return None;
+        }
+
+        let mult = if self.over8 { 2 } else { 1 };
+        Some((SYSCLK_HZ * mult) / usartdiv)
+    }
+
+    pub fn validate(&self) -> Result<(), ()> {
+        match self.baud() {
+            Some(baud) if baud >= Self::MIN_BAUD => {},
+            _ => return Err(()),
+        }
+        if (self.deat > Self::MAX_DE_TIME) || (self.dedt > Self::MAX_DE_TIME) {
+            return Err(());
+        }
+        if (self.addr != UNASSIGNED_ADDR) && !is_assignable(self.addr) {
+            return Err(());
+        }
+        Ok(())
+    }
+
+    /// Time (in microseconds, rounded up) to receive the remainder of a
+    /// header after the address word, with some slack.
+    fn header_timeout_us(&self) -> u32 {
+        // This works out to 10uS at the default baudrate.
+        self.bits_to_us(wire::HEADER_TIMEOUT_BITS)
+    }
+
+    /// Time (in microseconds, rounded up) to send `bits` bit times, for
+    /// the router's timeouts.
+    pub fn bits_to_us(&self, bits: u32) -> u32 {
+        wire::bits_to_us(bits, self.baud().unwrap_or(Self::MIN_BAUD))
+    }
+
+    /// Half a bit time, in microseconds, rounded down: how long before the
+    /// end of a word's stop bit a receiver has the word
+    pub fn half_bit_us(&self) -> u32 {
+        500_000 / self.baud().unwrap_or(Self::MIN_BAUD)
+    }
+
+    /// Write the config to the USART. The USART MUST be disabled (UE = 0).
+    fn write_to(&self, usart1: &Usart1Rb) {
+        usart1.cr1.modify(|_r, w| {
+            w.deat().variant(self.deat);
+            w.dedt().variant(self.dedt);
+            if self.over8 {
+                w.over8().oversampling8();
+            } else {
+                w.over8().oversampling16();
+            }
+            // Only nodes use mute mode, the router hears everything
+            if self.router {
+                w.mme().disabled();
+            } else {
+                w.mme().enabled();
+            }
+            // The parity bit takes the place of the address mark, as all nine
+            // bits of the word are taken
+            if self.parity {
+                w.pce().enabled();
+                w.ps().even();
+                w.wake().idle();
+            } else {
+                w.pce().disabled();
+                w.wake().address();
+            }
+            w
+        });
+        usart1.brr.modify(|_r, w| {
+            w.brr().variant(self.brr);
+            w
+        });
+        usart1.cr2.modify(|_r, w| w.add().variant(self.addr));
+        HEADER_TIMEOUT_US.store(self.header_timeout_us(), Ordering::Relaxed);
+        PARITY.store(self.parity, Ordering::Relaxed);
+    }
+}
+
+static ACTIVE_CONFIG_RAW: AtomicU32 = AtomicU32::new(0);
+static ACTIVE_ADDR: AtomicU8 = AtomicU8::new(UNASSIGNED_ADDR);
+static HEADER_TIMEOUT_US: AtomicU32 = AtomicU32::new(10);
+
+/// The USART checks parity, and nodes wake on an idle line, see [read_word]
+static PARITY: AtomicBool = AtomicBool::new(false);
+
+/// Bit times the line is left idle ahead of a transaction, with parity. A node
+/// wakes once it has seen a whole word time of it, the second is slack.
+const START_GAP_BITS: u32 = 2 * wire::WORD_BITS;
+
+fn set_active_config(cfg: &Rs485Config) {
+    let raw = (cfg.brr as u32)
+        | ((cfg.deat as u32) << 16)
+        | ((cfg.dedt as u32) << 21)
+        | ((cfg.over8 as u32) << 26)
+        | ((cfg.router as u32) << 27)
+        | ((cfg.sync as u32) << 28)
+        | ((cfg.parity as u32) << 29);
+    ACTIVE_CONFIG_RAW.store(raw, Ordering::Relaxed);
+    ACTIVE_ADDR.store(cfg.addr, Ordering::Relaxed);
+}
+
+/// The config currently in use by the USART
+pub fn active_config() -> Rs485Config {
+    let raw = ACTIVE_CONFIG_RAW.load(Ordering::Relaxed);
+    Rs485Config {
+        brr: raw as u16,
+        deat: ((raw >> 16) & 0b1_1111) as u8,
+        dedt: ((raw >> 21) & 0b1_1111) as u8,
+        over8: ((raw >> 26) & 1) != 0,
+        router: ((raw >> 27) & 1) != 0,
+        sync: ((raw >> 28) & 1) != 0,
+        parity: ((raw >> 29) & 1) != 0,
+        addr: ACTIVE_ADDR.load(Ordering::Relaxed),
+    }
+}
+
+/// Request the receiver to enter mute mode, and wait for it to do so,
+/// then discard anything left in the receive FIFO.
+///
+/// Mute mode is turned off while a node is addressed (see [idle_start]), so
+/// this turns it back on first.
+fn enter_mute_mode(usart1: &Usart1Rb) {
+    usart1.cr1.modify(|_r, w| w.mme().enabled());
+
+    // Request to enter mute mode
+    usart1.rqr.write(|w| w.mmrq().set_bit());
+
+    // Wait until the "is in mute mode" bit is set
+    while usart1.isr.read().rwu().bit_is_clear() { }
+
+    // Empty the FIFO
+    usart1.rqr.write(|w| w.rxfrq().set_bit());
+}
+
+/// Apply a host-requested config change, or an address assigned by
+/// discovery, if one is pending
+///
+/// This only takes effect between bus transactions, e.g. when we are waiting
+/// for an address match, or waiting for grants to be reloaded. If a transaction
+/// is in flight, the request is left pending and will be retried on the next call.
+/// An assigned address goes first, and is also staged in `RS485_CFG`, so the
+/// host's request keeps it.
+pub fn apply_pending_config() {
+    let assigned = discovery::assigned_addr();
+    let requested = (regs::read(regs::RS485_CTRL) & regs::RS485_CTRL_APPLY) != 0;
+    let cfg = match assigned {
+        Some(addr) => Rs485Config { addr, ..active_config() },
+        None if requested => Rs485Config::from_regs(),
+        None => return,
+    };
+
+    if cfg.validate().is_err() {
+        defmt::println!("Rejected RS485 config: {:?}", cfg);
+        active_config().store_regs();
+        regs::modify(regs::RS485_CTRL, |v| (v & regs::RS485_CTRL_DISCOVER) | regs::RS485_CTRL_INVALID);
+        return;
+    }
+
+    let applied = cortex_m::interrupt::free(|_cs| {
+        let mode = MODE.load(Ordering::Relaxed);
+        if (mode != MODE_RELOAD) && (mode != MODE_READY) && (mode != MODE_ROUTER_IDLE) {
+            return false;
+        }
+
+        let usart1 = unsafe { &*USART1::PTR };
+
+        usart1.cr1.modify(|_r, w| {
+            w.cmie().disabled();
+            w.rxneie().disabled();
+            w.ue().disabled();
+            w
+        });
+
+        cfg.write_to(usart1);
+
+        usart1.cr1.modify(|_r, w| w.ue().enabled());
+        usart1.cr1.modify(|_r, w| {
+            w.te().enabled();
+            w.re().enabled();
+            w
+        });
+
+        if cfg.router {
+            usart1.rqr.write(|w| w.rxfrq().set_bit());
+            MODE.store(MODE_ROUTER_IDLE, Ordering::Relaxed);
+        } else {
+            enter_mute_mode(usart1);
+
+            if mode == MODE_READY {
+                enable_wake_irq(usart1);
+            } else if mode == MODE_ROUTER_IDLE {
+                // Wait for the idle loop to load the node grants
+                MODE.store(MODE_RELOAD, Ordering::Relaxed);
+            }
+        }
+
+        true
+    });
+
+    if applied {
+        defmt::println!("Applied RS485 config: {:?}", cfg);
+        let role_changed = cfg.router != active_config().router;
+        if role_changed {
+            // Partial frames belong to the old role
+            reset_fragments();
+        }
+        set_active_config(&cfg);
+        if role_changed {
+            // As does the bus time, once the role is in place
+            timesync::reset();
+        }
+        config::request_rs485_save();
+        if assigned.is_some() {
+            discovery::clear_assigned();
+            let addr = (cfg.addr as u16) << regs::RS485_CFG_ADDR_SHIFT;
+            regs::modify(regs::RS485_CFG, |v| (v & !regs::RS485_CFG_ADDR_MASK) | addr);
+            irq::raise(regs::IRQ_DISCOVERY);
+        } else {
+            regs::modify(regs::RS485_CTRL, |v| v & regs::RS485_CTRL_DISCOVER);
+        }
+    }
+}
 
 pub fn setup_rs485(rcc: &mut Rcc, usart1: USART1) {
     USART1::enable(rcc);
     USART1::reset(rcc);
 
+    let config = Rs485Config::DEFAULT;
+
     usart1.cr1.modify(|_r, w| {
         w.rxffie().disabled();
         w.txfeie().disabled();
@@ -16,15 +358,12 @@ pub fn setup_rs485(rcc: &mut Rcc, usart1: USART1) {
         w.m1().m0();
         w.eobie().disabled();
         w.rtoie().disabled();
-        w.deat().variant(8); // assertion: one bit time. TODO: check xcvr timing
-        w.dedt().variant(4); // deassertn: one bit time. TODO: check xcvr timing
-        w.over8().oversampling8();
         w.cmie().enabled();
         w.mme().enabled();
         w.m0().bit9();
-        w.wake().address();
-        w.pce().disabled();
-        // w.ps();
+        // Parity, and with it how nodes wake, is set by
+        // [Rs485Config::write_to]. Its interrupt is only enabled while
+        // receiving a payload, see [error_irqs].
         w.peie().disabled();
         w.txeie().disabled(); // This is txfnfie
         w.tcie().disabled();
@@ -38,8 +377,11 @@ pub fn setup_rs485(rcc: &mut Rcc, usart1: USART1) {
     });
 
     usart1.cr2.modify(|_r, w| {
-        w.add().variant(0x40); // TODO: variable address match
-        w.rtoen().disabled(); // TODO
+        // Only raises an interrupt while receiving a payload. This uses the
+        // receiver timeout rather than idle line detection, as idle line
+        // detection would fire on a gap of a single word, which the router's
+        // DMA may leave.
+        w.rtoen().enabled();
         // w.abrmod();
         w.abren().disabled();
         w.msbfirst().lsb();
@@ -73,7 +415,8 @@ pub fn setup_rs485(rcc: &mut Rcc, usart1: USART1) {
         w.dep().high();
         w.dem().enabled();
         w.ddre().disabled();
-        w.ovrdis().enabled();
+        // Count overruns, rather than silently dropping words
+        w.ovrdis().disabled();
         w.onebit().sample3();
         w.ctsie().disabled();
         w.ctse().disabled();
@@ -85,18 +428,38 @@ pub fn setup_rs485(rcc: &mut Rcc, usart1: USART1) {
         w.hdsel().not_selected();
         // w.irlp();
         w.iren().disabled();
+        // Only raises an interrupt while receiving a payload, see
+        // [check_line_errors]
         w.eie().disabled();
         w
     });
 
-    usart1.brr.modify(|_r, w| {
-        w.brr().variant(0x0010);
-        w
-    });
+    config.write_to(&usart1);
+    set_active_config(&config);
+    config.store_regs();
+    regs::write(regs::RS485_CTRL, 0);
+    regs::write(regs::RS485_TRUNC_COUNT, 0);
+    regs::write(regs::RS485_CRC_COUNT, 0);
+    regs::write(regs::RS485_RETRY_COUNT, 0);
+    regs::write(regs::RS485_FE_COUNT, 0);
+    regs::write(regs::RS485_NE_COUNT, 0);
+    regs::write(regs::RS485_ORE_COUNT, 0);
+    regs::write(regs::RS485_PE_COUNT, 0);
+    regs::write(regs::RS485_GROUP, 0);
+    regs::write(regs::RS485_FRAG_DROP_COUNT, 0);
+    reset_fragments();
+    router::setup_router();
+    timesync::reset();
+
+    // Stage the saved config, to be applied as if the host had asked for it
+    if let Some(saved) = config::saved() {
+        regs::write(regs::RS485_BRR, saved.rs485_brr);
+        regs::write(regs::RS485_CFG, saved.rs485_cfg);
+        regs::write(regs::RS485_DE, saved.rs485_de);
+        regs::write(regs::RS485_CTRL, regs::RS485_CTRL_APPLY);
+    }
 
-    // usart1.rtor.modify(|_r, w| {
-    //     w
-    // });
+    usart1.rtor.write(|w| unsafe { w.rto().bits(RX_GAP_BITS) });
 
     usart1.cr1.modify(|_r, w| w.ue().enabled());
 
@@ -110,19 +473,15 @@ pub fn setup_rs485(rcc: &mut Rcc, usart1: USART1) {
     });
 
     let start = timer.get_ticks();
-    // Request to enter mute mode
-    usart1.rqr.write(|w| w.mmrq().set_bit());
-
-    // Wait until the "is in mute mode" bit is set
-    while usart1.isr.read().rwu().bit_is_clear() { }
+    enter_mute_mode(&usart1);
     defmt::println!("Took {}us", timer.micros_since(start));
-    // Empty the FIFO
-    usart1.rqr.write(|w| w.rxfrq().set_bit());
 
     defmt::println!("ISR: {:08X}", usart1.isr.read().bits());
 
     MODE.store(MODE_RELOAD, Ordering::Relaxed);
     RECV_AMT.store(0, Ordering::Relaxed);
+    TX_PENDING.store(false, Ordering::Relaxed);
+    RX_STATUS.store(STATUS_NONE, Ordering::Relaxed);
 }
 
 pub fn should_reload() -> bool {
@@ -130,6 +489,12 @@ pub fn should_reload() -> bool {
     (mode == MODE_RELOAD) || (mode == MODE_READY)
 }
 
+/// Is the node between polls, waiting for the idle loop to re-enable address
+/// match?
+pub fn awaiting_reload() -> bool {
+    MODE.load(Ordering::Relaxed) == MODE_RELOAD
+}
+
 pub fn enable_rs485_addr_match() {
     let mode = MODE.load(Ordering::Relaxed);
     let ok = (mode == MODE_RELOAD) || (mode == MODE_READY);
@@ -141,30 +506,99 @@ pub fn enable_rs485_addr_match() {
 
     // defmt::println!("Mutin...");
 
-    usart1.icr.write(|w| w.cmcf().set_bit());
-    // usart1.rqr.write(|w| w.mmrq().set_bit());
-
-    // // Wait until the "is in mute mode" bit is set
-    // while usart1.isr.read().rwu().bit_is_clear() { }
-    // // Empty the FIFO
-    usart1.rqr.write(|w| w.rxfrq().set_bit());
-
+    // Anything heard since the last poll is not ours. Once ready, we are
+    // already muted, and may be woken at any moment.
+    if mode == MODE_RELOAD {
+        enter_mute_mode(usart1);
+        clear_line_errors(usart1);
+    }
     MODE.store(MODE_READY, Ordering::Relaxed);
+    enable_wake_irq(usart1);
+}
+
+/// Enable the interrupt that wakes us for a header: a character match on our
+/// address word, or with parity, the first word after an idle line, which may
+/// be anyone's
+fn enable_wake_irq(usart1: &Usart1Rb) {
+    usart1.icr.write(|w| w.cmcf().set_bit());
     usart1.cr1.modify(|_r, w| {
-        w.cmie().enabled();
+        if PARITY.load(Ordering::Relaxed) {
+            w.rxneie().enabled();
+        } else {
+            w.cmie().enabled();
+        }
         w
     });
 }
 
-static MODE: AtomicU8 = AtomicU8::new(MODE_RELOAD);
+/// Is `addr` one we take headers for: our own, our group, a broadcast, or an
+/// update? Only checked with parity, as otherwise the USART only wakes us for
+/// our own address word.
+fn listening_to(usart1: &Usart1Rb, addr: u8) -> bool {
+    let own_addr = usart1.cr2.read().add().bits() & ADDR_MASK;
+    let group = regs::read(regs::RS485_GROUP);
+    let in_group = ((group & regs::RS485_GROUP_VALID) != 0)
+        && ((group & regs::RS485_GROUP_ADDR_MASK) as u8 == addr);
+    (addr == own_addr) || (addr == BROADCAST_ADDR) || (addr == UPDATE_ADDR) || in_group
+}
+
+pub(super) static MODE: AtomicU8 = AtomicU8::new(MODE_RELOAD);
 static RECV_AMT: AtomicU16 = AtomicU16::new(0);
 
+/// CRC of the outgoing fragment, computed ahead of time by
+/// [prepare_tx_fragment]
+static TX_CRC: AtomicU16 = AtomicU16::new(0);
+
+/// How much of the outgoing frame has been sent, and acknowledged
+static TX_OFFSET: AtomicU16 = AtomicU16::new(0);
+
+/// Fragment header of the outgoing fragment, sent by hand ahead of its data
+static TX_FRAG_HEADER: AtomicU8 = AtomicU8::new(0);
+
+/// Frame bytes in the outgoing fragment
+static TX_FRAG_LEN: AtomicU16 = AtomicU16::new(0);
+
+/// The byte of the write grant the receive DMA puts the fragment header on,
+/// put back once the fragment is in, or [NOT_SAVED]. See [rx_target].
+static RECV_SAVED: AtomicU16 = AtomicU16::new(NOT_SAVED);
+
+const NOT_SAVED: u16 = 0xFFFF;
+
+struct ReassemblyCell(UnsafeCell<Reassembly>);
+
+unsafe impl Sync for ReassemblyCell { }
+
+// Only accessed from the USART and DMA interrupts, which do not preempt each
+// other, and from the idle loop while neither can fire: between router polls,
+// or with interrupts disabled
+static REASSEMBLY: ReassemblyCell = ReassemblyCell(UnsafeCell::new(Reassembly::new()));
+
+/// Our last frame was sent, and is still at the head of the queue, waiting for
+/// the router's status in its next header
+static TX_PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Our status for the router's last frame, sent in our next reply
+static RX_STATUS: AtomicU8 = AtomicU8::new(STATUS_NONE);
+
+/// The address the frame being received was sent to: ours, our group, or
+/// [BROADCAST_ADDR]. It is stored in front of the frame for the host.
+static RECV_ADDR: AtomicU8 = AtomicU8::new(0);
+
+/// The frame being received is a broadcast or multicast, which is not
+/// acknowledged
+static RECV_MULTICAST: AtomicBool = AtomicBool::new(false);
+
 const MODE_RELOAD: u8 = 0;
 const MODE_READY: u8 = 1;
 const MODE_SEND_NO_DMA: u8 = 2;
 const MODE_SEND_DMA: u8 = 3;
 const MODE_RECV: u8 = 4;
 
+// Router modes, see the `router` module
+pub(super) const MODE_ROUTER_IDLE: u8 = 5;
+pub(super) const MODE_ROUTER_RECV: u8 = 6;
+pub(super) const MODE_ROUTER_SEND: u8 = 7;
+
 
 pub fn rs485_isr() {
     let mode = MODE.load(Ordering::Relaxed);
@@ -177,23 +611,364 @@ pub fn rs485_isr() {
         MODE_READY => idle_start(),
         MODE_SEND_DMA => dma_tx_complete(),
         MODE_SEND_NO_DMA => no_dma_tx_complete(),
+        // DMA complete, receiver timeout, or line error
         MODE_RECV => recv_complete(),
-        // TX Done:
-        // On:
-        MODE_RELOAD | _ => defmt::panic!(),
+        MODE_ROUTER_RECV => router::recv_complete(),
+        MODE_ROUTER_SEND => router::send_complete(),
+        // Left pending by a transaction that was given up on, see
+        // [unpend_interrupts]. There is nothing to do.
+        MODE_RELOAD | MODE_ROUTER_IDLE => defmt::println!("RS485: spurious interrupt, mode {=u8}", mode),
+        _ => defmt::panic!(),
     }
 
     // defmt::println!("MODE WAS: {}, MODE IS: {}", mode, MODE.load(Ordering::Relaxed));
 }
 
+/// Forget any USART1 or DMA interrupt still pending for a transaction that was
+/// just given up on, so the handler doesn't take it for the next one
+pub(super) fn unpend_interrupts() {
+    NVIC::unpend(Interrupt::USART1);
+    NVIC::unpend(Interrupt::DMA_CHANNEL2_3);
+    NVIC::unpend(Interrupt::DMA_CHANNEL4_5_6_7);
+}
+
+/// Read a received word. With parity, the USART leaves the parity bit where
+/// the address mark would be, so it is masked off.
+pub(super) fn read_word(usart1: &Usart1Rb) -> u16 {
+    let word = usart1.rdr.read().rdr().bits();
+    if PARITY.load(Ordering::Relaxed) {
+        word & 0xFF
+    } else {
+        word
+    }
+}
+
+/// With parity, nodes only wake on an idle line. Wait until what we sent is
+/// out, and then for [START_GAP_BITS] more, so the next word starts a
+/// transaction.
+pub(super) fn start_gap(usart1: &Usart1Rb) {
+    if !PARITY.load(Ordering::Relaxed) {
+        return;
+    }
+    while usart1.isr.read().tc().bit_is_clear() { }
+    let timer = GlobalRollingTimer::new();
+    let gap_us = active_config().bits_to_us(START_GAP_BITS);
+    let start = timer.get_ticks();
+    while timer.micros_since(start) < gap_us { }
+}
+
+/// Enable or disable the line error interrupts, which are only enabled while
+/// receiving a payload
+pub(super) fn error_irqs(usart1: &Usart1Rb, enabled: bool) {
+    usart1.cr3.modify(|_r, w| w.eie().bit(enabled));
+    usart1.cr1.modify(|_r, w| w.peie().bit(enabled));
+}
+
+/// Check for line errors: framing errors, noise, overruns, and parity errors.
+/// Each one seen is counted in its `RS485_*_COUNT` register, raises
+/// `IRQ_LINE_ERROR`, and is cleared. Returns true if there were any.
+///
+/// The error interrupts are only enabled while receiving a payload, see
+/// [error_irqs]. Elsewhere, this is called once a header or reply has been
+/// read.
+pub(super) fn check_line_errors(usart1: &Usart1Rb) -> bool {
+    let isr = usart1.isr.read();
+    let errors = [
+        (isr.fe().bit_is_set(), regs::RS485_FE_COUNT),
+        (isr.ne().bit_is_set(), regs::RS485_NE_COUNT),
+        (isr.ore().bit_is_set(), regs::RS485_ORE_COUNT),
+        (isr.pe().bit_is_set(), regs::RS485_PE_COUNT),
+    ];
+    if errors.iter().all(|(seen, _)| !seen) {
+        return false;
+    }
+
+    clear_line_errors(usart1);
+    errors
+        .iter()
+        .filter(|(seen, _)| *seen)
+        .for_each(|(_, reg)| regs::increment(*reg));
+    irq::raise(regs::IRQ_LINE_ERROR);
+    true
+}
+
+/// Clear the line error flags, without counting them
+pub(super) fn clear_line_errors(usart1: &Usart1Rb) {
+    usart1.icr.write(|w| {
+        w.fecf().set_bit();
+        w.necf().set_bit();
+        w.orecf().set_bit();
+        w.pecf().set_bit();
+        w
+    });
+}
+
+/// Prepare the next fragment of the outgoing `frame`: its fragment header, and
+/// its CRC trailer. This is done when the frame is loaded, as there is no time
+/// to compute the CRC between the DMA and the trailer. Returns the fragment's
+/// payload length, fragment header included.
+pub(super) fn prepare_tx_fragment(frame: &[u8]) -> usize {
+    let offset = TX_OFFSET.load(Ordering::Relaxed) as usize;
+    let (header, len) = frag::fragment(frame.len(), offset);
+    let crc = crc16_update(crc16(&[header]), &frame[offset..][..len]);
+    TX_FRAG_HEADER.store(header, Ordering::Relaxed);
+    TX_FRAG_LEN.store(len as u16, Ordering::Relaxed);
+    TX_CRC.store(crc, Ordering::Relaxed);
+    FRAG_HEADER_LEN + len
+}
+
+/// The data of the fragment of `frame` made ready by [prepare_tx_fragment], for
+/// the transmit DMA
+pub(super) fn tx_chunk(frame: &[u8]) -> &[u8] {
+    let offset = TX_OFFSET.load(Ordering::Relaxed) as usize;
+    let len = TX_FRAG_LEN.load(Ordering::Relaxed) as usize;
+    frame.get(offset..offset + len).unwrap_or_default()
+}
+
+/// The outgoing fragment was acknowledged, or was part of a broadcast and was
+/// sent. Returns true if it was the last of its `frame_len` byte frame, which
+/// is done with.
+pub(super) fn tx_fragment_done(frame_len: usize) -> bool {
+    let offset = TX_OFFSET.load(Ordering::Relaxed) as usize + TX_FRAG_LEN.load(Ordering::Relaxed) as usize;
+    let done = offset >= frame_len;
+    TX_OFFSET.store(if done { 0 } else { offset as u16 }, Ordering::Relaxed);
+    done
+}
+
+/// Send the fragment header, ahead of the fragment's data
+pub(super) fn send_tx_frag_header(usart1: &Usart1Rb) {
+    while usart1.isr.read().txe().bit_is_clear() { }
+    usart1.tdr.write(|w| w.tdr().bits(TX_FRAG_HEADER.load(Ordering::Relaxed) as u16));
+}
+
+#[inline]
+unsafe fn reassembly() -> &'static mut Reassembly {
+    &mut *REASSEMBLY.0.get()
+}
+
+/// Forget any partly sent or reassembled frame, or frame waiting to be
+/// forwarded
+fn reset_fragments() {
+    cortex_m::interrupt::free(|_cs| unsafe { reassembly() }.reset());
+    TX_OFFSET.store(0, Ordering::Relaxed);
+    RECV_SAVED.store(NOT_SAVED, Ordering::Relaxed);
+    router::reset_forward();
+}
+
+/// Has part of the outgoing frame been sent, and acknowledged?
+pub(super) fn tx_started() -> bool {
+    TX_OFFSET.load(Ordering::Relaxed) != 0
+}
+
+/// Send the outgoing frame from its first fragment, as it was dropped
+pub(super) fn restart_tx() {
+    TX_OFFSET.store(0, Ordering::Relaxed);
+}
+
+/// Is part of a frame being reassembled in the write grant?
+pub(super) fn reassembling() -> bool {
+    unsafe { reassembly() }.is_active()
+}
+
+/// Largest fragment we can take, with a write grant of `grant_len`
+pub(super) fn rx_room(grant_len: usize) -> usize {
+    frag::payload_room(grant_len, unsafe { reassembly() }.offset())
+}
+
+/// Where the receive DMA puts the next fragment in the write grant `buf`. The
+/// fragment header lands on the byte before where its data goes, which is
+/// saved, and put back once the fragment is in.
+pub(super) fn rx_target(buf: &mut [u8]) -> *mut u8 {
+    let offset = unsafe { reassembly() }.offset();
+    RECV_SAVED.store(buf[offset] as u16, Ordering::Relaxed);
+    unsafe { buf.as_mut_ptr().add(offset) }
+}
+
+/// Put back the byte saved by [rx_target] at `offset`, if the receive DMA was
+/// loaded since
+fn restore_rx_target(buf: &mut [u8], offset: usize) {
+    let saved = RECV_SAVED.load(Ordering::Relaxed);
+    if saved != NOT_SAVED {
+        buf[offset] = saved as u8;
+        RECV_SAVED.store(NOT_SAVED, Ordering::Relaxed);
+    }
+}
+
+/// Give up the busy write grant, unless part of a frame is being reassembled
+/// in it, or a frame waits in it to be forwarded. Then it is kept.
+pub(super) unsafe fn drop_rx_grant() {
+    let pipe = &pipes::PIPES.rs485_to_spi;
+    if let Some(buf) = pipe.busy_wr_grant() {
+        restore_rx_target(buf, reassembly().offset());
+    }
+    if reassembling() || router::forwarding() {
+        pipe.hold_wr_dma();
+    } else {
+        pipe.abort_wr_dma();
+    }
+}
+
+/// An incoming fragment of `got` bytes, fragment header included, is in the
+/// busy write grant, and its CRC trailer was `crc`. Returns true if it was
+/// intact.
+///
+/// An intact fragment is added to the frame being reassembled. Once the frame
+/// is whole, it is committed with `addr` in front, or kept in the write grant
+/// if the router forwards it. A fragment out of order drops the partial frame.
+pub(super) unsafe fn reassemble(addr: u8, got: usize, crc: Option<u16>) -> bool {
+    let pipe = &pipes::PIPES.rs485_to_spi;
+    let Some(buf) = pipe.busy_wr_grant() else {
+        return false;
+    };
+    let reasm = reassembly();
+    let offset = reasm.offset();
+    let header = buf[offset];
+    let intact = (got != 0) && (crc == Some(crc16(&buf[offset..][..got])));
+    restore_rx_target(buf, offset);
+
+    if intact {
+        let now = GlobalRollingTimer::new().get_ticks();
+        match reasm.accept(now, &mut buf[1..], header, got - FRAG_HEADER_LEN) {
+            Accepted::Complete(len) => {
+                buf[0] = addr;
+                if router::forward(&buf[..len + 1]) {
+                    pipe.hold_wr_dma();
+                } else {
+                    pipe.complete_wr_dma(|_buf| len + 1);
+                    irq::raise(regs::IRQ_FRAME_RECEIVED);
+                }
+                return true;
+            },
+            Accepted::Dropped => frag_dropped(),
+            Accepted::Partial | Accepted::Repeat => {},
+        }
+    }
+    drop_rx_grant();
+    intact
+}
+
+/// Drop a partly reassembled frame whose next fragment is overdue. Called from
+/// the idle loop.
+pub fn expire_reassembly() {
+    let timeout_us = active_config().bits_to_us(REASSEMBLY_TIMEOUT_BITS);
+    let now = GlobalRollingTimer::new().get_ticks();
+    let expired = cortex_m::interrupt::free(|_cs| {
+        let mode = MODE.load(Ordering::Relaxed);
+        if (mode != MODE_RELOAD) && (mode != MODE_READY) && (mode != MODE_ROUTER_IDLE) {
+            // A fragment may be on its way in
+            return false;
+        }
+        unsafe { reassembly() }.expire(now, timeout_us)
+    });
+    if expired {
+        frag_dropped();
+    }
+}
+
+fn frag_dropped() {
+    defmt::println!("RS485 partial frame dropped");
+    regs::increment(regs::RS485_FRAG_DROP_COUNT);
+    irq::raise(regs::IRQ_FRAME_TRUNCATED);
+}
+
+/// Send the CRC trailer, once the DMA has queued the payload
+pub(super) fn send_tx_crc(usart1: &Usart1Rb) {
+    TX_CRC.load(Ordering::Relaxed).to_le_bytes().iter().for_each(|b| {
+        // This is TXFNF, the FIFO drains a word at a time
+        while usart1.isr.read().txe().bit_is_clear() { }
+        usart1.tdr.write(|w| w.tdr().bits(*b as u16));
+    });
+}
+
+/// Blocking wait for the CRC trailer that follows an incoming payload. Returns
+/// `None` if it did not arrive within `timeout_us`.
+pub(super) fn recv_crc(usart1: &Usart1Rb, timeout_us: u32) -> Option<u16> {
+    let timer = GlobalRollingTimer::new();
+    let start = timer.get_ticks();
+    let mut crc = [0u8; CRC_WORDS];
+    crc.iter_mut().try_for_each(|b| {
+        loop {
+            if usart1.isr.read().rxne().bit_is_set() {
+                let word = read_word(usart1);
+                if wire::is_addr(word) {
+                    return None;
+                }
+                *b = word as u8;
+                return Some(());
+            }
+            if timer.micros_since(start) >= timeout_us {
+                return None;
+            }
+        }
+    })?;
+    Some(u16::from_le_bytes(crc))
+}
+
+/// The payload is complete (DMA interrupt), the router stopped sending
+/// partway through it (receiver timeout, USART interrupt), or a word of it was
+/// damaged (line error, USART interrupt)
+///
+/// The fragment is only kept if all of it, and its CRC trailer, arrived
+/// intact. Either way, the router hears how it went in our next reply, unless
+/// it was a broadcast.
 fn recv_complete() {
-    unsafe {
+    let usart1 = unsafe { &*USART1::PTR };
+    usart1.cr1.modify(|_r, w| w.rtoie().disabled());
+    error_irqs(usart1, false);
+    usart1.icr.write(|w| w.rtocf().set_bit());
+
+    let expected = RECV_AMT.load(Ordering::Relaxed);
+    let got = unsafe {
+        let got = expected.saturating_sub(pipes::PIPES.rs485_rx_remaining());
         pipes::PIPES.disable_rs485_rx_dma();
-        pipes::PIPES.rs485_to_spi.complete_wr_dma(|_amt| {
-            RECV_AMT.load(Ordering::Relaxed) as usize
-        });
-        let usart1 = &*USART1::PTR;
         usart1.cr3.modify(|_r, w| w.dmar().disabled());
+        got
+    };
+
+    // The trailer is left in the FIFO by the DMA, and takes two word times.
+    // There is no point waiting for it after a line error, and the rest of
+    // the router's frame is ignored.
+    let mut line_ok = !check_line_errors(usart1);
+    let crc = if line_ok && (got == expected) {
+        let crc_bits = wire::data_timeout_bits(CRC_WORDS);
+        let crc = recv_crc(usart1, active_config().bits_to_us(crc_bits));
+        line_ok = !check_line_errors(usart1);
+        crc
+    } else {
+        None
+    };
+    if !line_ok {
+        enter_mute_mode(usart1);
+    }
+    watchdog::disarm();
+
+    // Both may have fired, if the timeout hit just as the last word arrived
+    NVIC::unpend(Interrupt::USART1);
+    NVIC::unpend(Interrupt::DMA_CHANNEL2_3);
+
+    let intact = unsafe {
+        reassemble(RECV_ADDR.load(Ordering::Relaxed), got as usize, crc.filter(|_| line_ok))
+    };
+
+    let status = if intact {
+        STATUS_ACK
+    } else if !line_ok {
+        // Already counted
+        defmt::println!("RS485 frame dropped, line error");
+        STATUS_NAK
+    } else if crc.is_none() {
+        defmt::println!("RS485 frame truncated: {=u16} of {=u16}", got, expected);
+        regs::increment(regs::RS485_TRUNC_COUNT);
+        irq::raise(regs::IRQ_FRAME_TRUNCATED);
+        STATUS_NAK
+    } else {
+        defmt::println!("RS485 frame failed CRC");
+        regs::increment(regs::RS485_CRC_COUNT);
+        irq::raise(regs::IRQ_CRC_ERROR);
+        STATUS_NAK
+    };
+    if !RECV_MULTICAST.load(Ordering::Relaxed) {
+        RX_STATUS.store(status, Ordering::Relaxed);
     }
 
     RECV_AMT.store(0, Ordering::Relaxed);
@@ -201,6 +976,7 @@ fn recv_complete() {
 }
 
 fn no_dma_tx_complete() {
+    watchdog::disarm();
     let usart1 = unsafe { &*USART1::PTR };
     usart1.cr1.modify(|_r, w| {
         w.tcie().disabled();
@@ -211,13 +987,21 @@ fn no_dma_tx_complete() {
 }
 
 fn dma_tx_complete() {
+    watchdog::disarm();
     let usart1 = unsafe { &*USART1::PTR };
-    usart1.cr3.modify(|_r, w| w.dmat().enabled());
+    // The trailer is written by hand
+    usart1.cr3.modify(|_r, w| w.dmat().disabled());
 
     unsafe {
         pipes::PIPES.disable_rs485_tx_dma();
-        pipes::PIPES.spi_to_rs485.complete_rd_dma();
     }
+    send_tx_crc(usart1);
+
+    // Keep the frame until the router says it arrived
+    unsafe {
+        pipes::PIPES.spi_to_rs485.abort_rd_dma();
+    }
+    TX_PENDING.store(true, Ordering::Relaxed);
 
     start_recv()
 }
@@ -229,109 +1013,263 @@ fn start_recv() {
         MODE.store(MODE_RELOAD, Ordering::Relaxed);
         return;
     }
+    let rx_bits = wire::data_timeout_bits(wire::payload_words(rx_amt as usize));
+    watchdog::arm(watchdog::PHASE_RX, active_config().bits_to_us(rx_bits));
     unsafe {
         let usart1 = &*USART1::PTR;
+        let buf = pipes::PIPES.rs485_to_spi.busy_wr_grant().unwrap_or_default();
+        pipes::PIPES.load_rs485_rx_dma(rx_target(buf), rx_amt as usize);
         usart1.cr3.modify(|_r, w| w.dmar().enabled());
         pipes::PIPES.trigger_modified_rs485_rx_dma(rx_amt);
+
+        // The timeout expired while we were sending, and only restarts once
+        // the router's first word arrives
+        usart1.icr.write(|w| w.rtocf().set_bit());
+        usart1.cr1.modify(|_r, w| w.rtoie().enabled());
+        error_irqs(usart1, true);
     }
     MODE.store(MODE_RECV, Ordering::Relaxed);
     // defmt::println!("TRIG {}", rx_amt);
 }
 
-// TODO: start some kind of timer for 1ms (?), if it hits,
-// abort current cycle, log error. If we hit the end of cycle
-// before that, defuse.
+/// Abandon the current transaction, called by the watchdog if the router went
+/// quiet while we were sending or receiving a frame
+pub(super) fn abort_transaction() {
+    cortex_m::interrupt::free(|_cs| {
+        let mode = MODE.load(Ordering::Relaxed);
+        if (mode != MODE_SEND_DMA) && (mode != MODE_SEND_NO_DMA) && (mode != MODE_RECV) {
+            // Finished while the watchdog was firing
+            return;
+        }
+
+        let usart1 = unsafe { &*USART1::PTR };
+        unsafe {
+            pipes::PIPES.disable_rs485_tx_dma();
+            pipes::PIPES.disable_rs485_rx_dma();
+        }
+        usart1.cr3.modify(|_r, w| {
+            w.dmat().disabled();
+            w.dmar().disabled();
+            w
+        });
+        usart1.cr1.modify(|_r, w| {
+            w.tcie().disabled();
+            w.rtoie().disabled();
+            w
+        });
+        error_irqs(usart1, false);
+        abandon(usart1);
+        unpend_interrupts();
+    });
+}
+
+/// Give up on a transaction that timed out
+fn abandon(usart1: &Usart1Rb) {
+    drop_transaction(usart1);
+    regs::increment(regs::RS485_TIMEOUT_COUNT);
+    irq::raise(regs::IRQ_RS485_TIMEOUT);
+}
+
+/// Release the grants, keeping our outgoing frame for the next poll, and go
+/// back to mute mode until the idle loop reloads the grants
+fn drop_transaction(usart1: &Usart1Rb) {
+    unsafe {
+        pipes::PIPES.spi_to_rs485.abort_rd_dma();
+        drop_rx_grant();
+    }
+    enter_mute_mode(usart1);
+    RECV_AMT.store(0, Ordering::Relaxed);
+    MODE.store(MODE_RELOAD, Ordering::Relaxed);
+}
 
 fn idle_start() {
-    // Blocking wait for five words.
+    // Blocking wait for the header.
     //
     // Since we were interrupted AFTER the first word arrived, it should take
-    // 44 bit periods, or 5.50uS to complete this processing. Since that is only
-    // (2 * 176) cycles, don't waste time waiting for another interrupt. SPI can still
+    // 55 bit periods, or 6.88uS to complete this processing. Since that is only
+    // 440 cycles, don't waste time waiting for another interrupt. SPI can still
     // interrupt us.
     //
-    // Set a timeout for 8uS to prevent deadlock.
+    // The watchdog bounds this (10uS at the default baudrate) to prevent deadlock.
+    //
+    // Before a broadcast, the router sends the address word of every node it
+    // polls, and the header follows the last one. Each of those restarts the
+    // watchdog.
+    //
+    // With parity, there are no address marks, and we are woken by the first
+    // word after an idle line, whoever it is for. Unless it is an address we
+    // listen to, we go back to mute mode until the next.
     let usart1 = unsafe { &*USART1::PTR };
-    let mut rxbuf = [0u16; 5];
     let timer = GlobalRollingTimer::new();
-    let start = timer.get_ticks();
+    let mut rxbuf = [0u16; HEADER_WORDS];
+    let mut got = 0;
+    if PARITY.load(Ordering::Relaxed) {
+        let word = read_word(usart1);
+        if !listening_to(usart1, word as u8) {
+            enter_mute_mode(usart1);
+            clear_line_errors(usart1);
+            return;
+        }
+        rxbuf[0] = ADDR_FLAG | word;
+        got = 1;
+    }
+    let timeout_us = HEADER_TIMEOUT_US.load(Ordering::Relaxed);
+    watchdog::arm(watchdog::PHASE_HEADER, timeout_us);
 
-    // Clear character match flag
-    usart1.cr1.modify(|_r, w| w.cmie().disabled());
+    // Clear character match flag. Stay awake for the rest of a broadcast's
+    // wake run, as another node's address word would mute us again. This must
+    // happen within a word time of the match.
+    usart1.cr1.modify(|_r, w| {
+        w.cmie().disabled();
+        w.rxneie().disabled();
+        w.mme().disabled();
+        w
+    });
 
-    let tx_amt_cap = unsafe { pipes::PIPES.spi_to_rs485.get_prep_rd_dma() };
-    let rx_amt_cap = unsafe { pipes::PIPES.rs485_to_spi.get_prep_wr_dma() };
+    // The outgoing fragment was made ready when the grant was loaded
+    let tx_frame_len = unsafe {
+        pipes::PIPES.spi_to_rs485.get_prep_rd_dma();
+        pipes::PIPES.spi_to_rs485.busy_rd_grant().map(|f| f.len())
+    };
+    let mut tx_amt_cap = match tx_frame_len {
+        Some(_) => FRAG_HEADER_LEN + TX_FRAG_LEN.load(Ordering::Relaxed) as usize,
+        None => 0,
+    };
+    let rx_amt_cap = rx_room(unsafe { pipes::PIPES.rs485_to_spi.get_prep_wr_dma() });
 
-    let res = rxbuf.iter_mut().try_for_each(|b| {
-        loop {
-            if usart1.isr.read().rxne().bit_is_set() {
-                *b = usart1.rdr.read().rdr().bits();
-                return Ok(());
-            }
-            if timer.micros_since(start) >= 8 {
-                return Err(());
+    // When the last word was read, for time syncs
+    let mut rx_at = 0;
+    let res: Result<(), ()> = loop {
+        if got == HEADER_WORDS {
+            break Ok(());
+        }
+        if usart1.isr.read().rxne().bit_is_set() {
+            let word = read_word(usart1);
+            if (got == 1) && wire::is_addr(word) {
+                // Still in the wake run, the last address word counts
+                rxbuf[0] = word;
+                watchdog::arm(watchdog::PHASE_HEADER, timeout_us);
+            } else {
+                rxbuf[got] = word;
+                got += 1;
+                rx_at = timer.get_ticks();
             }
+            continue;
         }
-    });
+        if watchdog::expired() {
+            break Err(());
+        }
+    };
+    watchdog::disarm();
 
-    // TODO: replace this with logging the timeout and
-    // a req to return to mute mode
-    if !res.is_ok() {
-        defmt::println!("RS485 timeout!");
-        unsafe {
-            pipes::PIPES.spi_to_rs485.abort_rd_dma();
-            pipes::PIPES.rs485_to_spi.abort_wr_dma();
+    if check_line_errors(usart1) {
+        // Already counted. The router gives up on this poll, and tries again.
+        defmt::println!("RS485 header line error!");
+        drop_transaction(usart1);
+        return;
+    }
+
+    let header = match res.ok().and_then(|()| Header::from_words(&rxbuf)) {
+        Some(h) => h,
+        None => {
+            defmt::println!("RS485 header timeout!");
+            abandon(usart1);
+            return;
         }
+    };
+
+    // Time syncs have no payload, and nobody replies
+    if let Some(msg) = SyncMsg::from_header(&header) {
+        timesync::heard(msg, rx_at);
+        drop_transaction(usart1);
         return;
     }
+    timesync::cancel();
 
-    let len_rx = [rxbuf[1] as u8, rxbuf[2] as u8];
-    let len_tx = [rxbuf[3] as u8, rxbuf[4] as u8];
-    // TODO mix _len with tx_amt to determine actual amount to send
-    let r_rx_cap = u16::from_le_bytes(len_rx);
-    let r_tx_cap = u16::from_le_bytes(len_tx);
-
-    // defmt::println!(
-    //     "{} {} {} {}",
-    //     r_rx_cap,
-    //     tx_amt_cap,
-    //     r_tx_cap,
-    //     rx_amt_cap,
-    // );
-
-    let tx_amt = if (r_rx_cap as usize) >= tx_amt_cap {
-        // Router can hold what we're sending (including if we want to
-        // send zero bytes)
-        tx_amt_cap
-    } else {
-        // Router CAN'T hold what we're sending, send nothing
+    // Enumerations are for nodes with no address, and nobody else listens in
+    let own_addr = usart1.cr2.read().add().bits() & ADDR_MASK;
+    if header.addr == UNASSIGNED_ADDR {
+        if own_addr == UNASSIGNED_ADDR {
+            discovery::enumerated(usart1, &header);
+        }
+        drop_transaction(usart1);
+        return;
+    }
+    // A firmware update is starting. The bootloader takes part in it, and
+    // needs our address to report which chunks it is missing.
+    if header.addr == UPDATE_ADDR {
+        if own_addr != UNASSIGNED_ADDR {
+            config::request_bootloader();
+        }
+        drop_transaction(usart1);
+        return;
+    }
+    if header.addr != own_addr {
+        listen(usart1, &header, rx_amt_cap);
+        return;
+    }
+    RECV_ADDR.store(own_addr, Ordering::Relaxed);
+    RECV_MULTICAST.store(false, Ordering::Relaxed);
+
+    // Our last fragment's frame is still at the head of the queue, and was
+    // reloaded. Move on to its next fragment once the router has it, dropping
+    // the frame after the last, or send the same fragment again. Either way,
+    // this is all we do with the read grant in this poll.
+    if TX_PENDING.load(Ordering::Relaxed) {
+        TX_PENDING.store(false, Ordering::Relaxed);
+        if header.status == STATUS_ACK {
+            if tx_fragment_done(tx_frame_len.unwrap_or(0)) {
+                unsafe {
+                    pipes::PIPES.spi_to_rs485.complete_rd_dma();
+                }
+                irq::raise(regs::IRQ_FRAME_SENT);
+            }
+            tx_amt_cap = 0;
+        } else {
+            regs::increment(regs::RS485_RETRY_COUNT);
+        }
+    }
+
+    // We send our fragment if the router can hold it, and receive the
+    // router's fragment if we can hold it, each all or nothing.
+    let reply = Reply::negotiate(&header, tx_amt_cap, rx_amt_cap, RX_STATUS.load(Ordering::Relaxed));
+    RX_STATUS.store(STATUS_NONE, Ordering::Relaxed);
+    let tx_amt = reply.tx_amt as usize;
+    let rx_amt = reply.rx_amt;
+
+    if tx_amt == 0 {
         unsafe {
             pipes::PIPES.spi_to_rs485.abort_rd_dma();
         }
-        0
-    };
-    let rx_amt = if (r_tx_cap as usize) <= rx_amt_cap {
-        // We can hold what the router is sending (including if it wants
-        // to send zero bytes)
-        r_tx_cap
-    } else {
+    }
+    if rx_amt == 0 {
         unsafe {
-            pipes::PIPES.rs485_to_spi.abort_wr_dma();
+            drop_rx_grant();
         }
-        0
-    };
-
-    let txb: [u8; 2] = (tx_amt as u16).to_le_bytes();
-    let rxb: [u8; 2] = (rx_amt as u16).to_le_bytes();
+        if header.tx_len != 0 {
+            // We CAN'T hold what the router is sending yet, it keeps the frame and
+            // sends it again in a later poll
+            irq::raise(regs::IRQ_QUEUE_OVERFLOW);
+        }
+    }
 
-    txb.iter().chain(rxb.iter()).for_each(|b| {
-        usart1.tdr.write(|w| w.tdr().bits((*b) as u16));
+    reply.to_words().iter().for_each(|w| {
+        usart1.tdr.write(|wr| wr.tdr().bits(*w));
     });
 
     // Store amount to receive
-    RECV_AMT.store(rx_amt as u16, Ordering::Relaxed);
+    RECV_AMT.store(rx_amt, Ordering::Relaxed);
+
+    let tx_bits = wire::data_timeout_bits(REPLY_WORDS + wire::payload_words(tx_amt));
+    watchdog::arm(watchdog::PHASE_TX, active_config().bits_to_us(tx_bits));
 
-    if tx_amt != 0 {
+    if tx_amt == FRAG_HEADER_LEN {
+        // An empty frame, there is only the trailer to send
+        send_tx_frag_header(usart1);
+        MODE.store(MODE_SEND_DMA, Ordering::Relaxed);
+        dma_tx_complete();
+    } else if tx_amt != 0 {
+        send_tx_frag_header(usart1);
         usart1.cr3.modify(|_r, w| w.dmat().enabled());
 
         MODE.store(MODE_SEND_DMA, Ordering::Relaxed);
@@ -350,3 +1288,35 @@ fn idle_start() {
 
     // defmt::println!("started dma...");
 }
+
+/// The header is for a broadcast or multicast. Receive its frame if we are
+/// listening to its address and have room for it, without replying.
+fn listen(usart1: &Usart1Rb, header: &Header, rx_amt_cap: usize) {
+    let group = regs::read(regs::RS485_GROUP);
+    let in_group = ((group & regs::RS485_GROUP_VALID) != 0)
+        && ((group & regs::RS485_GROUP_ADDR_MASK) as u8 == header.addr);
+    if (header.addr != BROADCAST_ADDR) && !in_group {
+        drop_transaction(usart1);
+        return;
+    }
+
+    let len = header.tx_len as usize;
+    if (len == 0) || (len > rx_amt_cap) {
+        if len != 0 {
+            // We CAN'T hold it, and it is not sent again
+            irq::raise(regs::IRQ_QUEUE_OVERFLOW);
+        }
+        drop_transaction(usart1);
+        return;
+    }
+
+    // Nothing is sent, our last fragment is still waiting for the router's
+    // status
+    unsafe {
+        pipes::PIPES.spi_to_rs485.abort_rd_dma();
+    }
+    RECV_ADDR.store(header.addr, Ordering::Relaxed);
+    RECV_MULTICAST.store(true, Ordering::Relaxed);
+    RECV_AMT.store(len as u16, Ordering::Relaxed);
+    start_recv();
+}
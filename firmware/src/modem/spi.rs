@@ -1,24 +1,89 @@
-use core::sync::atomic::{AtomicU8, Ordering, AtomicU16};
-
-use stm32g0xx_hal::{rcc::{Rcc, Enable, Reset}, pac::{SPI1, EXTI, DMA}};
-
-use super::{pipes, gpios};
+//! SPI host interface
+//!
+//! ## 3-wire mode
+//!
+//! When `SPI_CFG_3WIRE` is selected at boot, MISO is used as a single
+//! bidirectional data line, and MOSI is unused. The host drives the line for
+//! the command byte (and any data it sends), and for commands that return data,
+//! releases it immediately after the command byte. The modem drives the line
+//! from the next byte until CSn rises.
+//!
+//! As the bus can only carry data in one direction at a time, the long packet
+//! command is split in two, using bit 0 of the command byte:
+//!
+//! * `0b001_00000`: long read. The modem sends the usual header, followed by
+//!   the outgoing frame, if any. The incoming capacity in the header is how
+//!   much the host may send with the next long write.
+//! * `0b001_00001`: long write. The host sends its frame immediately after the
+//!   command byte, with no header.
+//!
+//! In 4-wire mode, the low bits of the long packet command should be zero.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU16, Ordering};
+
+use stm32g0xx_hal::{rcc::{Rcc, Enable, Reset}, pac::{SPI1, EXTI, DMA, RCC, spi1::RegisterBlock as Spi1Rb}};
+
+use super::{pipes, gpios, regs, irq, config};
 
 static SPI_MODE: AtomicU8 = AtomicU8::new(MODE_IDLE);
 
 // TODO: This should probably be an enum or something. Be careful when updating.
 //
-const MODE_MASK: u8 = 0b111_00000;
+// The command modes are shared with the other front-ends, the rest are SPI states.
+use amodem_hostif::cmd::{
+    MODE_MASK,
+    MODE_LONG_PKT_READWRITE,
+    MODE_BURST_REG_READ,
+    MODE_SHORT_REG_READ,
+    MODE_SHORT_REG_WRITE,
+    MODE_BURST_REG_WRITE,
+};
 const MODE_IDLE: u8 = 0b000_00000;
-const MODE_LONG_PKT_READWRITE: u8 = 0b001_00000;
-const MODE_SHORT_REG_READ: u8 = 0b011_00000;
-const MODE_SHORT_REG_WRITE: u8 = 0b100_00000;
 const MODE_INVALID_WAIT: u8 = 0b111_00000;
 //
 // ENDTODO
 
-const ONE_ATOMIC: AtomicU16 = AtomicU16::new(0xACAB);
-static REGS: [AtomicU16; 32] = [ONE_ATOMIC; 32];
+pub use amodem_hostif::long::{
+    LONG_HEADER_LEN,
+    LONG_STATUS_VALID,
+    LONG_STATUS_TX_FRAME,
+    LONG_STATUS_RX_READY,
+    LONG_STATUS_IRQ,
+    LONG_STATUS_LAST_ERR,
+};
+use amodem_hostif::long::{LongHeader, LONG_WRITE_ONLY};
+
+// Phase flags, held in the low bits of `SPI_MODE` during a long packet
+const LONG_PHASE_TX_HDR_DONE: u8 = 0b0000_0001;
+const LONG_PHASE_RX_HDR_DONE: u8 = 0b0000_0010;
+
+static LONG_SEQ: AtomicU8 = AtomicU8::new(0);
+static LONG_HDR_TAIL: AtomicU16 = AtomicU16::new(0);
+static LONG_HDR_RX: AtomicU8 = AtomicU8::new(0);
+static LONG_TX_AMT: AtomicU16 = AtomicU16::new(0);
+static LONG_RX_AMT: AtomicU16 = AtomicU16::new(0);
+static LONG_RX_OVR: AtomicBool = AtomicBool::new(false);
+
+// Packed (16-bit) DMA. `PACKED_MODE` is fixed at boot, the others are decided
+// for each frame when the DMA channels are reloaded.
+static PACKED_MODE: AtomicBool = AtomicBool::new(false);
+static THREE_WIRE: AtomicBool = AtomicBool::new(false);
+static TX_PACKED: AtomicBool = AtomicBool::new(false);
+static TX_PACKED_ODD: AtomicBool = AtomicBool::new(false);
+static RX_PACKED: AtomicBool = AtomicBool::new(false);
+
+/// Next register index to be sent or stored during a burst command. Burst
+/// commands start at the index given in the command byte, and auto-increment.
+///
+/// Reads past the end of the register map return zero, and writes past the
+/// end are discarded. In both cases, `IRQ_SPI_ERROR` is raised. For reads,
+/// that is only once the host has clocked out a padding value: the TX FIFO
+/// is filled ahead of the host, and may run past the end unread.
+static BURST_IDX: AtomicU8 = AtomicU8::new(0);
+/// Register a burst read started at
+static BURST_START: AtomicU8 = AtomicU8::new(0);
+/// Bytes clocked out to the host during a burst read
+static BURST_CLOCKED: AtomicU16 = AtomicU16::new(0);
 
 
 #[inline]
@@ -29,21 +94,42 @@ pub fn setup_spi(
     SPI1::enable(rcc);
     SPI1::reset(rcc);
 
+    let spi_cfg = config::saved().map_or(0, |c| c.spi_cfg & regs::SPI_CFG_MASK);
+    defmt::println!("SPI config: {=u16:#x}", spi_cfg);
+
+    let three_wire = (spi_cfg & regs::SPI_CFG_3WIRE) != 0;
+
     spi1.cr1.modify(|_r, w| {
-        w.bidimode().unidirectional();
-        // w.bidioe();
+        if three_wire {
+            w.bidimode().bidirectional();
+            w.bidioe().output_disabled();
+        } else {
+            w.bidimode().unidirectional();
+        }
         w.crcen().disabled();
         // w.crcnext();
         // w.crcl();
         w.rxonly().full_duplex();
         w.ssm().disabled();
         // w.ssi();
-        w.lsbfirst().msbfirst();
         // w.spe();
         w.br().div2();
         w.mstr().slave();
-        w.cpol().idle_low();
-        w.cpha().first_edge();
+        if (spi_cfg & regs::SPI_CFG_LSBFIRST) != 0 {
+            w.lsbfirst().lsbfirst();
+        } else {
+            w.lsbfirst().msbfirst();
+        }
+        if (spi_cfg & regs::SPI_CFG_CPOL) != 0 {
+            w.cpol().idle_high();
+        } else {
+            w.cpol().idle_low();
+        }
+        if (spi_cfg & regs::SPI_CFG_CPHA) != 0 {
+            w.cpha().second_edge();
+        } else {
+            w.cpha().first_edge();
+        }
         w
     });
 
@@ -54,7 +140,7 @@ pub fn setup_spi(
         w.ds().eight_bit();
         w.txeie().masked();
         w.rxneie().masked();
-        w.errie().masked();
+        w.errie().not_masked();
         w.frf().motorola();
         w.nssp().no_pulse();
         w.ssoe().disabled();
@@ -64,6 +150,57 @@ pub fn setup_spi(
     });
 
     spi1.cr1.modify(|_r, w| w.spe().enabled());
+
+    regs::write(regs::LAST_XFER, 0);
+    regs::write(regs::LAST_XFER_RX_LEN, 0);
+    regs::write(regs::LAST_XFER_TX_LEN, 0);
+    regs::write(regs::SPI_OVR_COUNT, 0);
+    regs::write(regs::SPI_FRE_COUNT, 0);
+    regs::write(regs::SPI_CFG, spi_cfg);
+    PACKED_MODE.store((spi_cfg & regs::SPI_CFG_PACKED) != 0, Ordering::Relaxed);
+    THREE_WIRE.store(three_wire, Ordering::Relaxed);
+}
+
+/// Was packed DMA mode selected at boot?
+#[inline]
+pub fn packed_mode() -> bool {
+    PACKED_MODE.load(Ordering::Relaxed)
+}
+
+/// Was 3-wire mode selected at boot?
+#[inline]
+pub fn three_wire() -> bool {
+    THREE_WIRE.load(Ordering::Relaxed)
+}
+
+/// In 3-wire mode, start driving the data line
+#[inline]
+fn bus_turnaround(spi1: &Spi1Rb) {
+    if three_wire() {
+        spi1.cr1.modify(|_r, w| w.bidioe().output_enabled());
+    }
+}
+
+/// In 3-wire mode, release the data line back to the host
+#[inline]
+fn bus_release(spi1: &Spi1Rb) {
+    if three_wire() {
+        spi1.cr1.modify(|_r, w| w.bidioe().output_disabled());
+    }
+}
+
+/// Set whether the SPI TX DMA channel was loaded for 16-bit transfers,
+/// and whether the frame has an odd length.
+#[inline]
+pub fn set_tx_packed(packed: bool, odd: bool) {
+    TX_PACKED.store(packed, Ordering::Relaxed);
+    TX_PACKED_ODD.store(packed && odd, Ordering::Relaxed);
+}
+
+/// Set whether the SPI RX DMA channel was loaded for 16-bit transfers
+#[inline]
+pub fn set_rx_packed(packed: bool) {
+    RX_PACKED.store(packed, Ordering::Relaxed);
 }
 
 pub fn spi_int_unmask() {
@@ -71,6 +208,323 @@ pub fn spi_int_unmask() {
     spi1.cr2.modify(|_r, w| w.rxneie().not_masked());
 }
 
+/// Discard any stale data in the SPI FIFOs
+///
+/// The TX FIFO can only be flushed by resetting the peripheral, so we save
+/// and restore the configuration around a reset.
+pub fn spi_reset_fifos() {
+    let spi1 = unsafe { &*SPI1::PTR };
+    let rcc = unsafe { &*RCC::PTR };
+
+    let cr1 = spi1.cr1.read().bits();
+    let cr2 = spi1.cr2.read().bits();
+
+    spi1.cr1.modify(|_r, w| w.spe().disabled());
+    rcc.apbrstr2.modify(|_r, w| w.spi1rst().set_bit());
+    rcc.apbrstr2.modify(|_r, w| w.spi1rst().clear_bit());
+
+    // SPE is the last thing enabled, after all other config is done.
+    spi1.cr2.write(|w| unsafe { w.bits(cr2) });
+    spi1.cr1.write(|w| unsafe { w.bits(cr1) });
+}
+
+/// Abandon the current transaction
+///
+/// Any in-flight pipe grants are released without being committed, and the
+/// FIFOs are flushed. Everything else the host sends is ignored until CSn
+/// rises, at which point we return to idle.
+fn spi_abort(spi1: &Spi1Rb) {
+    spi1.cr2.modify(|_r, w| {
+        w.txeie().masked();
+        w.rxneie().masked();
+        w.txdmaen().disabled();
+        w.rxdmaen().disabled();
+        w.ldma_tx().even();
+        w.frxth().quarter();
+        w
+    });
+    bus_release(spi1);
+
+    if (SPI_MODE.load(Ordering::Relaxed) & MODE_MASK) == MODE_LONG_PKT_READWRITE {
+        let seq = LONG_SEQ.load(Ordering::Relaxed) as u16;
+        regs::write(regs::LAST_XFER, (seq << 8) | regs::LAST_XFER_ABORTED);
+        regs::write(regs::LAST_XFER_RX_LEN, 0);
+        regs::write(regs::LAST_XFER_TX_LEN, 0);
+    }
+
+    unsafe {
+        pipes::PIPES.spi_to_rs485.abort_wr_dma();
+        pipes::PIPES.rs485_to_spi.abort_rd_dma();
+        pipes::PIPES.disable_spi_rx_dma();
+        pipes::PIPES.disable_spi_tx_dma();
+    }
+    pipes::PIPES.arm_spi_rx();
+
+    // This also clears any latched error flags
+    spi_reset_fifos();
+    irq::raise(regs::IRQ_SPI_ERROR);
+
+    SPI_MODE.store(MODE_INVALID_WAIT, Ordering::Relaxed);
+}
+
+/// Handle OVR, MODF, and FRE errors
+#[inline]
+fn spi_error_isr(spi1: &Spi1Rb) {
+    let dr8b: *mut u8 = spi1.dr.as_ptr().cast();
+    let sr = spi1.sr.read();
+    let val = SPI_MODE.load(Ordering::Relaxed);
+
+    if sr.ovr().bit_is_set() {
+        regs::increment(regs::SPI_OVR_COUNT);
+
+        let long_rx = ((val & MODE_MASK) == MODE_LONG_PKT_READWRITE)
+            && ((val & LONG_PHASE_RX_HDR_DONE) != 0);
+        let other_err = sr.modf().bit_is_set() || sr.fre().bit_is_set();
+
+        if long_rx && !other_err {
+            // The host sent more than we had room for. This doesn't affect
+            // the outgoing data, so just note it, and stop listening for
+            // errors until the end of the transaction. Reading DR then
+            // SR clears the flag.
+            let _ = unsafe { dr8b.read_volatile() };
+            let _ = spi1.sr.read();
+            LONG_RX_OVR.store(true, Ordering::Relaxed);
+            spi1.cr2.modify(|_r, w| w.errie().masked());
+            return;
+        }
+    }
+
+    if sr.modf().bit_is_set() || sr.fre().bit_is_set() {
+        regs::increment(regs::SPI_FRE_COUNT);
+    }
+
+    defmt::println!("SPI error, SR: {:04X}", sr.bits());
+    spi_abort(spi1);
+}
+
+/// Fetch the next register for a burst read, advancing the index. Past the
+/// end, this pads with zero, see [burst_read_discard].
+#[inline]
+fn burst_read_next() -> u16 {
+    let idx = BURST_IDX.load(Ordering::Relaxed);
+    if (idx as usize) < regs::REG_COUNT {
+        BURST_IDX.store(idx + 1, Ordering::Relaxed);
+        regs::read(idx)
+    } else {
+        0
+    }
+}
+
+/// Discard what the host is clocking in during a burst read, counting the
+/// bytes it has clocked out
+#[inline]
+fn burst_read_discard(spi1: &Spi1Rb) {
+    let dr8b: *mut u8 = spi1.dr.as_ptr().cast();
+    let mut clocked = BURST_CLOCKED.load(Ordering::Relaxed);
+    while !spi1.sr.read().rxne().is_empty() {
+        let _ = unsafe { dr8b.read_volatile() };
+        clocked = clocked.saturating_add(1);
+    }
+    BURST_CLOCKED.store(clocked, Ordering::Relaxed);
+}
+
+/// Raise `IRQ_SPI_ERROR` if the host read past the end of the register map,
+/// counting a partly clocked value as read
+#[inline]
+fn burst_read_check() {
+    let start = BURST_START.load(Ordering::Relaxed) as usize;
+    let read = (BURST_CLOCKED.load(Ordering::Relaxed) as usize).div_ceil(2);
+    if (start + read) > regs::REG_COUNT {
+        irq::raise(regs::IRQ_SPI_ERROR);
+    }
+}
+
+/// Store the next register for a burst write, advancing the index
+#[inline]
+fn burst_write_next(val: u16) {
+    let idx = BURST_IDX.load(Ordering::Relaxed);
+    if (idx as usize) < regs::REG_COUNT {
+        BURST_IDX.store(idx + 1, Ordering::Relaxed);
+        regs::host_write(idx, val);
+    } else {
+        irq::raise(regs::IRQ_SPI_ERROR);
+    }
+}
+
+/// Finish sending and receiving the long packet header, then hand
+/// the rest of the transaction over to DMA
+#[inline]
+fn long_pkt_header_service(spi1: &Spi1Rb) {
+    let dr8b: *mut u8 = spi1.dr.as_ptr().cast();
+    let dr16b: *mut u16 = spi1.dr.as_ptr().cast();
+
+    let mut phase = SPI_MODE.load(Ordering::Relaxed) & !MODE_MASK;
+
+    if ((phase & LONG_PHASE_TX_HDR_DONE) == 0) && spi1.sr.read().txe().is_empty() {
+        unsafe {
+            dr16b.write_volatile(LONG_HDR_TAIL.load(Ordering::Relaxed));
+        }
+        spi1.cr2.modify(|_r, w| w.txeie().masked());
+
+        if LONG_TX_AMT.load(Ordering::Relaxed) != 0 {
+            let odd = TX_PACKED_ODD.load(Ordering::Relaxed);
+            spi1.cr2.modify(|_r, w| {
+                if odd {
+                    w.ldma_tx().odd();
+                } else {
+                    w.ldma_tx().even();
+                }
+                w.txdmaen().enabled()
+            });
+            unsafe { pipes::PIPES.trigger_spi_tx_dma() };
+            gpios::set_txrdy_inactive();
+        }
+        phase |= LONG_PHASE_TX_HDR_DONE;
+    }
+
+    if (phase & LONG_PHASE_RX_HDR_DONE) == 0 {
+        // Only pop the header bytes, anything after that belongs to DMA
+        let mut rcvd = LONG_HDR_RX.load(Ordering::Relaxed);
+        while (rcvd < LONG_HEADER_LEN) && !spi1.sr.read().rxne().is_empty() {
+            let _ = unsafe { dr8b.read_volatile() };
+            rcvd += 1;
+        }
+        LONG_HDR_RX.store(rcvd, Ordering::Relaxed);
+
+        if rcvd == LONG_HEADER_LEN {
+            spi1.cr2.modify(|_r, w| w.rxneie().masked());
+
+            if LONG_RX_AMT.load(Ordering::Relaxed) != 0 {
+                // In packed mode, only request DMA once two bytes are ready
+                let packed = RX_PACKED.load(Ordering::Relaxed);
+                spi1.cr2.modify(|_r, w| {
+                    if packed {
+                        w.frxth().half();
+                    }
+                    w.rxdmaen().enabled()
+                });
+                unsafe { pipes::PIPES.trigger_spi_rx_dma() };
+                gpios::set_rxrdy_inactive();
+            }
+            phase |= LONG_PHASE_RX_HDR_DONE;
+        }
+    }
+
+    SPI_MODE.store(MODE_LONG_PKT_READWRITE | phase, Ordering::Relaxed);
+}
+
+/// Complete or abort the grants used by a long packet transaction, and
+/// report the outcome in the `LAST_XFER_*` registers.
+#[inline]
+fn long_pkt_complete(spi1: &Spi1Rb, phase: u8) {
+    let dr8b: *mut u8 = spi1.dr.as_ptr().cast();
+
+    spi1.cr2.modify(|_r, w| {
+        w.txeie().masked();
+        w.txdmaen().disabled();
+        w.rxdmaen().disabled();
+        w.ldma_tx().even();
+        w
+    });
+
+    let dma = unsafe { &*DMA::PTR };
+    let tx_amt = LONG_TX_AMT.load(Ordering::Relaxed);
+    let rx_amt = LONG_RX_AMT.load(Ordering::Relaxed);
+    let mut result = 0u16;
+    let mut received = 0u16;
+    let mut sent = 0u16;
+
+    if (phase & LONG_PHASE_RX_HDR_DONE) == 0 {
+        result |= regs::LAST_XFER_HDR_INCOMPLETE;
+    }
+
+    // Incoming (host to modem)
+    let rx_packed = RX_PACKED.load(Ordering::Relaxed);
+    let rx_remain = dma.ch1().ndtr.read().ndt().bits() as usize;
+
+    // Go back to byte-wise RXNE for the next command byte
+    spi1.cr2.modify(|_r, w| w.frxth().quarter());
+
+    if (phase & LONG_PHASE_RX_HDR_DONE) != 0 {
+        // No atomic swap on this target, but we are the only writer here
+        let overrun = LONG_RX_OVR.load(Ordering::Relaxed);
+        LONG_RX_OVR.store(false, Ordering::Relaxed);
+        let pipe = &pipes::PIPES.spi_to_rs485;
+        let mut used = 0;
+        if let Some(buf) = unsafe { pipe.busy_wr_grant() } {
+            used = if rx_packed {
+                ((buf.len() / 2).saturating_sub(rx_remain)) * 2
+            } else {
+                buf.len().saturating_sub(rx_remain)
+            };
+
+            // In packed mode, an odd trailing byte never fills a
+            // half-word, so it is left in the FIFO for us.
+            if rx_packed && (used < buf.len()) && !spi1.sr.read().rxne().is_empty() {
+                buf[used] = unsafe { dr8b.read_volatile() };
+                used += 1;
+            }
+        }
+
+        // A frame is taken whole, or not at all, as on the other host links
+        let rx_fifo_busy = !spi1.sr.read().rxne().is_empty();
+        let rx_full = (used == rx_amt as usize) || (rx_remain == 0);
+        if overrun || (rx_fifo_busy && rx_full) {
+            result |= regs::LAST_XFER_RX_OVERFLOW;
+            unsafe { pipe.abort_wr_dma() };
+        } else {
+            received = used as u16;
+            unsafe { pipe.complete_wr_dma(|_buf| used) };
+        }
+    } else {
+        unsafe { pipes::PIPES.spi_to_rs485.abort_wr_dma() };
+    }
+
+    // Outgoing (modem to host). Only release the frame if every byte
+    // made it out of the TX FIFO, otherwise keep it for next time.
+    let tx_remain = dma.ch2().ndtr.read().ndt().bits();
+    let tx_fifo_empty = spi1.sr.read().ftlvl().is_empty();
+    let tx_done = ((phase & LONG_PHASE_TX_HDR_DONE) != 0) && (tx_remain == 0) && tx_fifo_empty;
+    if tx_amt != 0 {
+        if tx_done {
+            unsafe { pipes::PIPES.rs485_to_spi.complete_rd_dma() };
+            sent = tx_amt;
+        } else {
+            unsafe { pipes::PIPES.rs485_to_spi.abort_rd_dma() };
+            result |= regs::LAST_XFER_TX_RETAINED;
+        }
+    }
+
+    unsafe {
+        pipes::PIPES.disable_spi_rx_dma();
+        pipes::PIPES.disable_spi_tx_dma();
+    }
+
+    // Immediately arm the next receive window, rather than waiting for the
+    // idle loop, so back-to-back writes from the host don't see RXrdy drop.
+    pipes::PIPES.arm_spi_rx();
+
+    let seq = LONG_SEQ.load(Ordering::Relaxed) as u16;
+    regs::write(regs::LAST_XFER, (seq << 8) | result);
+    regs::write(regs::LAST_XFER_RX_LEN, received);
+    regs::write(regs::LAST_XFER_TX_LEN, sent);
+}
+
+/// Pop complete (two byte) register values out of the RX FIFO
+#[inline]
+fn burst_write_drain(spi1: &Spi1Rb) {
+    let dr8b: *mut u8 = spi1.dr.as_ptr().cast();
+    loop {
+        let frlvl = spi1.sr.read().frlvl();
+        if frlvl.is_empty() || frlvl.is_quarter() {
+            return;
+        }
+        let a = unsafe { dr8b.read_volatile() };
+        let b = unsafe { dr8b.read_volatile() };
+        burst_write_next(u16::from_le_bytes([a, b]));
+    }
+}
+
 #[inline]
 pub fn spi_dr_u8() -> *mut u8 {
     let spi1 = unsafe { &*SPI1::PTR };
@@ -96,6 +550,8 @@ pub fn exti_isr() {
         return;
     }
 
+    bus_release(spi1);
+
     // TODO: Probably disable SPI via SPE, let main re-enable it
 
     match mode {
@@ -103,6 +559,19 @@ pub fn exti_isr() {
             // We have already sent the value, and we don't care about
             // the data sent to us here. The FIFO will be drained below.
         },
+        MODE_BURST_REG_READ => {
+            // Stop refilling the TX FIFO. Whatever we pre-loaded that the
+            // host didn't clock out is thrown away below.
+            spi1.cr2.modify(|_r, w| w.txeie().masked());
+            burst_read_discard(spi1);
+            burst_read_check();
+        },
+        MODE_BURST_REG_WRITE => {
+            // Store any complete values still in the FIFO. A trailing
+            // odd byte is discarded below, once RXNE is back to one byte.
+            burst_write_drain(spi1);
+            spi1.cr2.modify(|_r, w| w.frxth().quarter());
+        },
         MODE_SHORT_REG_WRITE => {
             // We need to get the next two bytes out of the FIFO to store to the
             // proper register.
@@ -117,28 +586,11 @@ pub fn exti_isr() {
             if let (Some(a), Some(b)) = (pop_byte(), pop_byte()) {
                 let val = u16::from_le_bytes([a, b]);
                 // defmt::println!("Wrote {:04X} to {:?}", val, low);
-                REGS[low as usize].store(val, Ordering::Relaxed);
+                regs::host_write(low, val);
             }
         },
         MODE_LONG_PKT_READWRITE => {
-            spi1.cr2.modify(|_r, w| {
-                w.txdmaen().disabled();
-                w.rxdmaen().disabled();
-                w
-            });
-            unsafe {
-                pipes::PIPES.spi_to_rs485.complete_wr_dma(|len| {
-                    let dma = &*DMA::PTR;
-                    let remain = dma.ch1().ndtr.read().ndt().bits();
-                    len.saturating_sub(remain as usize)
-                });
-                pipes::PIPES.rs485_to_spi.complete_rd_dma();
-            }
-            unsafe {
-                pipes::PIPES.disable_spi_rx_dma();
-                pipes::PIPES.disable_spi_tx_dma();
-            }
-
+            long_pkt_complete(spi1, low);
         },
         _ => {
             // Huh, that was weird.
@@ -149,8 +601,17 @@ pub fn exti_isr() {
         let _ = unsafe { dr8b.read_volatile() };
     }
 
-    // TODO: Drain TX FIFO?
+    // Anything the host didn't clock out would otherwise be sent at the
+    // start of the next transaction.
+    if !spi1.sr.read().ftlvl().is_empty() {
+        spi_reset_fifos();
+    }
+
     SPI_MODE.store(MODE_IDLE, Ordering::Relaxed);
+
+    // Ready for the next command
+    spi1.cr2.modify(|_r, w| w.errie().not_masked());
+    spi_int_unmask();
 }
 
 #[inline]
@@ -160,12 +621,52 @@ pub fn spi_isr() {
     let dr8b: *mut u8 = spi1.dr.as_ptr().cast();
     let dr16b: *mut u16 = spi1.dr.as_ptr().cast();
 
+    let sr = spi1.sr.read();
+    if sr.ovr().bit_is_set() || sr.modf().bit_is_set() || sr.fre().bit_is_set() {
+        spi_error_isr(spi1);
+        return;
+    }
+
+    // Burst commands are serviced by repeated interrupts for the
+    // duration of the transaction
+    match SPI_MODE.load(Ordering::Relaxed) & MODE_MASK {
+        MODE_BURST_REG_READ => {
+            // Refill the TX FIFO while there is room for another register,
+            // and discard whatever the host is clocking in.
+            while spi1.sr.read().txe().is_empty() {
+                let val = burst_read_next();
+                unsafe {
+                    dr16b.write_volatile(val);
+                }
+            }
+            burst_read_discard(spi1);
+            return;
+        },
+        MODE_BURST_REG_WRITE => {
+            burst_write_drain(spi1);
+            return;
+        },
+        MODE_LONG_PKT_READWRITE => {
+            long_pkt_header_service(spi1);
+            return;
+        },
+        _ => {},
+    }
+
     // Disable RXNE interrupt
     spi1.cr2.modify(|_r, w| w.rxneie().masked());
 
     let mode = SPI_MODE.load(Ordering::Relaxed);
+    if mode == MODE_INVALID_WAIT {
+        // Already abandoned, waiting for CSn to rise
+        return;
+    }
     if mode != MODE_IDLE {
-        defmt::panic!("Not idle?");
+        // We've lost track of where we are. Give up on this transaction.
+        defmt::println!("Not idle?");
+        regs::increment(regs::SPI_FRE_COUNT);
+        spi_abort(spi1);
+        return;
     }
 
     // Read first FIFO byte
@@ -177,7 +678,8 @@ pub fn spi_isr() {
     match mode {
         MODE_SHORT_REG_READ => {
             // Push two bytes into the FIFO.
-            let val = REGS[low as usize].load(Ordering::Relaxed);
+            let val = regs::read(low);
+            bus_turnaround(spi1);
             unsafe {
                 dr16b.write_volatile(val);
             };
@@ -188,30 +690,104 @@ pub fn spi_isr() {
             // Nothing else to do, just wait for EXTI.
             SPI_MODE.store(fbyte, Ordering::Relaxed);
         },
+        MODE_BURST_REG_READ => {
+            // Fill the TX FIFO with the first two registers, then keep it
+            // topped up from the TXE interrupt until CSn rises.
+            BURST_IDX.store(low, Ordering::Relaxed);
+            BURST_START.store(low, Ordering::Relaxed);
+            BURST_CLOCKED.store(0, Ordering::Relaxed);
+            bus_turnaround(spi1);
+            for _ in 0..2 {
+                let val = burst_read_next();
+                unsafe {
+                    dr16b.write_volatile(val);
+                }
+            }
+            SPI_MODE.store(MODE_BURST_REG_READ, Ordering::Relaxed);
+            spi1.cr2.modify(|_r, w| w.txeie().not_masked());
+        },
+        MODE_BURST_REG_WRITE => {
+            // Values are popped from the RX FIFO as they arrive, a whole
+            // value at a time. The threshold is put back when CSn rises.
+            BURST_IDX.store(low, Ordering::Relaxed);
+            SPI_MODE.store(MODE_BURST_REG_WRITE, Ordering::Relaxed);
+            spi1.cr2.modify(|_r, w| w.frxth().half());
+            spi_int_unmask();
+        },
+        MODE_LONG_PKT_READWRITE if three_wire() && ((low & LONG_WRITE_ONLY) != 0) => {
+            // 3-wire long write: no header, the payload follows the command byte
+            let rx_amt = unsafe { pipes::PIPES.spi_to_rs485.get_prep_wr_dma() } as u16;
+            let seq = LONG_SEQ.load(Ordering::Relaxed).wrapping_add(1);
+
+            if rx_amt != 0 {
+                let packed = RX_PACKED.load(Ordering::Relaxed);
+                spi1.cr2.modify(|_r, w| {
+                    if packed {
+                        w.frxth().half();
+                    }
+                    w.rxdmaen().enabled()
+                });
+                unsafe { pipes::PIPES.trigger_spi_rx_dma() };
+                gpios::set_rxrdy_inactive();
+            }
+
+            LONG_SEQ.store(seq, Ordering::Relaxed);
+            LONG_TX_AMT.store(0, Ordering::Relaxed);
+            LONG_RX_AMT.store(rx_amt, Ordering::Relaxed);
+            LONG_RX_OVR.store(false, Ordering::Relaxed);
+
+            SPI_MODE.store(
+                MODE_LONG_PKT_READWRITE | LONG_PHASE_TX_HDR_DONE | LONG_PHASE_RX_HDR_DONE,
+                Ordering::Relaxed,
+            );
+        },
         MODE_LONG_PKT_READWRITE => {
-            // Write len
-            let tx_amt = unsafe { pipes::PIPES.rs485_to_spi.get_prep_rd_dma() };
-            unsafe {
-                dr16b.write_volatile(tx_amt as u16);
+            // In 3-wire mode, this is a long read. We report our capacity,
+            // but don't take the write grant, that's for the next long write.
+            let three_wire = three_wire();
+            let tx_amt = unsafe { pipes::PIPES.rs485_to_spi.get_prep_rd_dma() } as u16;
+            let (rx_amt, rx_cap) = if three_wire {
+                (0, pipes::PIPES.spi_to_rs485.peek_wr_len() as u16)
+            } else {
+                let amt = unsafe { pipes::PIPES.spi_to_rs485.get_prep_wr_dma() } as u16;
+                (amt, amt)
             };
+            let seq = LONG_SEQ.load(Ordering::Relaxed).wrapping_add(1);
+
+            let last_err = (regs::read(regs::LAST_XFER) & 0x00FF) != 0;
+            let hdr = LongHeader::new(seq, tx_amt, rx_cap, irq::pending(), last_err);
+
             // This is the measuring point for "did we get a response back in time"
             // v
             // X
             // ^
-            let rx_amt = unsafe { pipes::PIPES.spi_to_rs485.get_prep_wr_dma() }; // START
-
-            if tx_amt != 0 {
-                spi1.cr2.modify(|_r, w| w.txdmaen().enabled());
-                unsafe { pipes::PIPES.trigger_spi_tx_dma() };
-                gpios::set_txrdy_inactive();
-            }
-            if rx_amt != 0 {
-                spi1.cr2.modify(|_r, w| w.rxdmaen().enabled());
-                unsafe { pipes::PIPES.trigger_spi_rx_dma() };
-                gpios::set_rxrdy_inactive();                                     // END - 108 cycles: TODO look at this
-            }
+            // The first four header bytes fit in the TX FIFO now, the last two
+            // are sent from the TXE interrupt. START
+            bus_turnaround(spi1);
+            unsafe {
+                dr16b.write_volatile(u16::from_le_bytes([hdr.status, hdr.seq]));
+                dr16b.write_volatile(tx_amt);
+            };
 
-            SPI_MODE.store(MODE_LONG_PKT_READWRITE, Ordering::Relaxed);
+            LONG_SEQ.store(seq, Ordering::Relaxed);
+            LONG_HDR_TAIL.store(rx_cap, Ordering::Relaxed);
+            LONG_HDR_RX.store(0, Ordering::Relaxed);
+            LONG_TX_AMT.store(tx_amt, Ordering::Relaxed);
+            LONG_RX_AMT.store(rx_amt, Ordering::Relaxed);
+            LONG_RX_OVR.store(false, Ordering::Relaxed);
+
+            if three_wire {
+                // Nothing to receive while we are driving the bus
+                SPI_MODE.store(MODE_LONG_PKT_READWRITE | LONG_PHASE_RX_HDR_DONE, Ordering::Relaxed);
+                spi1.cr2.modify(|_r, w| w.txeie().not_masked());
+            } else {
+                SPI_MODE.store(MODE_LONG_PKT_READWRITE, Ordering::Relaxed);
+                spi1.cr2.modify(|_r, w| {
+                    w.txeie().not_masked();
+                    w.rxneie().not_masked();
+                    w
+                });
+            } // END
         },
         _ => {
             // Nothing else to do, just wait for EXTI.
@@ -0,0 +1,79 @@
+//! Bus time
+//!
+//! The router's rolling timer is the bus time. With `RS485_CFG_SYNC` set, the
+//! router sends a sync every [SYNC_INTERVAL_US] in place of a poll, and its
+//! follow-up in place of the next, see the `router` module. Nodes follow any
+//! syncs they hear, whatever their config, with a [SyncClock].
+//!
+//! The host latches the bus time into `SYNC_TIME_LO` and `SYNC_TIME_HI` by
+//! writing `SYNC_TIME_LO`, and reads how far off a node was at the last sync
+//! in `SYNC_OFFSET`.
+//!
+//! See `amodem_bus::timesync` for the messages, and a model of the clock.
+
+use core::cell::UnsafeCell;
+
+use amodem_bus::timesync::{SyncClock, SyncMsg};
+use groundhog::RollingTimer;
+
+use crate::{GlobalRollingTimer, modem::{regs, rs485}};
+
+/// Time between syncs sent by the router, with `RS485_CFG_SYNC` set
+pub const SYNC_INTERVAL_US: u32 = 100_000;
+
+struct SyncClockCell(UnsafeCell<SyncClock>);
+
+unsafe impl Sync for SyncClockCell { }
+
+// Only accessed with interrupts disabled, as the host interface reads it too
+static CLOCK: SyncClockCell = SyncClockCell(UnsafeCell::new(SyncClock::new()));
+
+/// Forget all syncs heard, as the router may have changed
+pub fn reset() {
+    cortex_m::interrupt::free(|_cs| unsafe { (*CLOCK.0.get()).reset() });
+    let offset = match rs485::active_config().router {
+        true => 0,
+        false => regs::SYNC_OFFSET_NONE,
+    };
+    regs::write(regs::SYNC_OFFSET, offset);
+}
+
+/// A sync or follow-up header was heard, its last word read at our time `at`
+pub(super) fn heard(msg: SyncMsg, at: u32) {
+    let offset = cortex_m::interrupt::free(|_cs| {
+        let clock = unsafe { &mut *CLOCK.0.get() };
+        match msg {
+            SyncMsg::Sync => clock.sync(at),
+            SyncMsg::FollowUp(time) => {
+                clock.follow_up(time);
+            },
+        }
+        clock.offset()
+    });
+    let offset = match offset {
+        Some(off) => off.clamp(-0x7FFF, 0x7FFF) as i16 as u16,
+        None => regs::SYNC_OFFSET_NONE,
+    };
+    regs::write(regs::SYNC_OFFSET, offset);
+}
+
+/// Some other header was heard, so the last sync will not be followed up
+pub(super) fn cancel() {
+    cortex_m::interrupt::free(|_cs| unsafe { (*CLOCK.0.get()).cancel() });
+}
+
+/// The bus time now: our own, unless we are a node that has synchronized
+pub fn now() -> u32 {
+    let ticks = GlobalRollingTimer::new().get_ticks();
+    if rs485::active_config().router {
+        return ticks;
+    }
+    cortex_m::interrupt::free(|_cs| unsafe { (*CLOCK.0.get()).bus_time(ticks) }).unwrap_or(ticks)
+}
+
+/// Latch the bus time into `SYNC_TIME_LO` and `SYNC_TIME_HI`
+pub fn latch() {
+    let now = now();
+    regs::write(regs::SYNC_TIME_LO, now as u16);
+    regs::write(regs::SYNC_TIME_HI, (now >> 16) as u16);
+}
@@ -0,0 +1,320 @@
+//! UART host interface
+//!
+//! Enabled with the `uart-host` feature, this replaces the SPI host interface,
+//! so the modem can be driven from a PC with a USB-UART adapter. USART2 is used
+//! at [HOST_BAUD], 8N1, with TX on PA02 (the SPI MOSI pad) and RX on PA03.
+//!
+//! Frames in both directions are COBS encoded, and terminated with a zero byte.
+//! Each frame from the host starts with a command byte, using the same encoding
+//! as the SPI commands, and the modem answers each one with exactly one frame,
+//! starting with the same command byte:
+//!
+//! | Command       | Request body     | Response body                         |
+//! | :--           | :--              | :--                                   |
+//! | `0b011_iiiii` | (none)           | Register `i`, LE                      |
+//! | `0b100_iiiii` | Value, LE        | (none)                                |
+//! | `0b010_iiiii` | Count, one byte  | Registers `i`, `i + 1`, ..., LE       |
+//! | `0b101_iiiii` | Values, LE       | (none)                                |
+//! | `0b001_00000` | Outgoing frame   | Long packet header, incoming frame    |
+//!
+//! The commands are handled by `amodem_hostif::session::Session`, as for I2C:
+//! each frame is a write phase, and the response, if the command has one, is
+//! the read phase. The count of a burst read isn't passed on, it only sets how
+//! many registers are read. Burst reads and writes past the end of the
+//! register map raise `IRQ_SPI_ERROR`, once, when the end is reached.
+//!
+//! A long packet is a long write of the outgoing frame, if the body isn't
+//! empty, and then a long read. So each long packet can advance the sequence
+//! number by two, and the incoming capacity in the header is the room for the
+//! *next* long packet. A frame from the host is accepted whole, or not at all,
+//! and a frame that doesn't fit sets `LONG_STATUS_LAST_ERR` in the header
+//! sent back. Long packets with an empty body can be used to poll for
+//! incoming frames.
+//!
+//! Malformed frames are dropped without a response, count as a `SPI_FRE_COUNT`
+//! error, and raise `IRQ_SPI_ERROR`. Bytes received while the previous frame is
+//! still being handled count as `SPI_OVR_COUNT` errors, and cause the frame
+//! they belong to to be dropped.
+
+use core::{cell::UnsafeCell, sync::atomic::{AtomicBool, AtomicU16, Ordering}};
+
+use stm32g0xx_hal::{rcc::{Rcc, Enable, Reset}, pac::USART2};
+
+use amodem_hostif::{
+    cmd::Command,
+    cobs,
+    long::{LongHeader, LONG_HEADER_LEN, LONG_WRITE_ONLY},
+    session::Session,
+};
+
+use super::{pipes, regs, irq, SYSCLK_HZ};
+use super::host::{ModemRegs, PipeMailbox};
+
+/// Baudrate of the host link
+pub const HOST_BAUD: u32 = 921_600;
+
+const HDR_LEN: usize = LONG_HEADER_LEN as usize;
+
+// Largest decoded frame: command, long packet header, and a full grant
+const MAX_DECODED: usize = 1 + HDR_LEN + pipes::GRANT_LEN;
+
+// Largest encoded frame, plus the terminator
+const MAX_ENCODED: usize = cobs::max_encoded_len(MAX_DECODED) + 1;
+
+struct Buffers {
+    rx: UnsafeCell<[u8; MAX_ENCODED]>,
+    resp: UnsafeCell<[u8; MAX_DECODED]>,
+    tx: UnsafeCell<[u8; MAX_ENCODED]>,
+}
+
+unsafe impl Sync for Buffers { }
+
+// `rx` is owned by the ISR until `RX_READY` is set, then by the idle loop
+// until it is cleared. `resp` is only used by the idle loop. `tx` is owned by
+// the ISR while `TX_LEN` is non-zero.
+static BUFS: Buffers = Buffers {
+    rx: UnsafeCell::new([0; MAX_ENCODED]),
+    resp: UnsafeCell::new([0; MAX_DECODED]),
+    tx: UnsafeCell::new([0; MAX_ENCODED]),
+};
+
+static RX_LEN: AtomicU16 = AtomicU16::new(0);
+static RX_READY: AtomicBool = AtomicBool::new(false);
+static RX_DISCARD: AtomicBool = AtomicBool::new(false);
+static TX_POS: AtomicU16 = AtomicU16::new(0);
+static TX_LEN: AtomicU16 = AtomicU16::new(0);
+
+struct SessionCell(UnsafeCell<Session>);
+
+unsafe impl Sync for SessionCell { }
+
+// Only accessed from the idle loop
+static SESSION: SessionCell = SessionCell(UnsafeCell::new(Session::new()));
+
+pub fn setup_uart(rcc: &mut Rcc, usart2: USART2) {
+    USART2::enable(rcc);
+    USART2::reset(rcc);
+
+    usart2.cr1.modify(|_r, w| {
+        w.m1().m0();
+        w.m0().bit8();
+        w.over8().oversampling16();
+        w.pce().disabled();
+        w.txeie().disabled();
+        w.rxneie().enabled();
+        w.ue().disabled();
+        w
+    });
+    usart2.cr2.modify(|_r, w| w.stop().stop1());
+    usart2.cr3.modify(|_r, w| {
+        w.ovrdis().disabled();
+        w.eie().disabled();
+        w
+    });
+    usart2.brr.write(|w| unsafe { w.bits((SYSCLK_HZ + (HOST_BAUD / 2)) / HOST_BAUD) });
+
+    usart2.cr1.modify(|_r, w| w.ue().enabled());
+    usart2.cr1.modify(|_r, w| {
+        w.te().enabled();
+        w.re().enabled();
+        w
+    });
+
+    RX_LEN.store(0, Ordering::Relaxed);
+    RX_READY.store(false, Ordering::Relaxed);
+    RX_DISCARD.store(false, Ordering::Relaxed);
+    TX_POS.store(0, Ordering::Relaxed);
+    TX_LEN.store(0, Ordering::Relaxed);
+    unsafe { *SESSION.0.get() = Session::new() };
+
+    regs::write(regs::LAST_XFER, 0);
+    regs::write(regs::LAST_XFER_RX_LEN, 0);
+    regs::write(regs::LAST_XFER_TX_LEN, 0);
+    regs::write(regs::SPI_OVR_COUNT, 0);
+    regs::write(regs::SPI_FRE_COUNT, 0);
+}
+
+pub fn uart_isr() {
+    let usart2 = unsafe { &*USART2::PTR };
+    let isr = usart2.isr.read();
+
+    if isr.ore().bit_is_set() || isr.fe().bit_is_set() || isr.nf().bit_is_set() {
+        usart2.icr.write(|w| {
+            w.orecf().set_bit();
+            w.fecf().set_bit();
+            w.ncf().set_bit();
+            w
+        });
+        regs::increment(if isr.ore().bit_is_set() { regs::SPI_OVR_COUNT } else { regs::SPI_FRE_COUNT });
+        RX_DISCARD.store(true, Ordering::Relaxed);
+    }
+
+    if isr.rxne().bit_is_set() {
+        let byte = usart2.rdr.read().rdr().bits() as u8;
+        rx_byte(byte);
+    }
+
+    if isr.txe().bit_is_set() && usart2.cr1.read().txeie().bit_is_set() {
+        let pos = TX_POS.load(Ordering::Relaxed);
+        let len = TX_LEN.load(Ordering::Relaxed);
+        if pos < len {
+            let byte = unsafe { (*BUFS.tx.get())[pos as usize] };
+            usart2.tdr.write(|w| unsafe { w.tdr().bits(byte as u16) });
+            TX_POS.store(pos + 1, Ordering::Relaxed);
+        } else {
+            usart2.cr1.modify(|_r, w| w.txeie().disabled());
+            TX_LEN.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+fn rx_byte(byte: u8) {
+    if RX_READY.load(Ordering::Relaxed) {
+        // Still handling the last frame, drop this one
+        if !RX_DISCARD.load(Ordering::Relaxed) {
+            regs::increment(regs::SPI_OVR_COUNT);
+        }
+        RX_DISCARD.store(byte != 0, Ordering::Relaxed);
+        return;
+    }
+
+    let len = RX_LEN.load(Ordering::Relaxed);
+
+    if byte == 0 {
+        if RX_DISCARD.load(Ordering::Relaxed) {
+            irq::raise(regs::IRQ_SPI_ERROR);
+            RX_DISCARD.store(false, Ordering::Relaxed);
+            RX_LEN.store(0, Ordering::Relaxed);
+        } else if len != 0 {
+            RX_READY.store(true, Ordering::Release);
+        }
+        return;
+    }
+
+    if RX_DISCARD.load(Ordering::Relaxed) {
+        return;
+    }
+
+    if (len as usize) < MAX_ENCODED {
+        unsafe { (*BUFS.rx.get())[len as usize] = byte };
+        RX_LEN.store(len + 1, Ordering::Relaxed);
+    } else {
+        regs::increment(regs::SPI_FRE_COUNT);
+        RX_DISCARD.store(true, Ordering::Relaxed);
+    }
+}
+
+/// Handle a received frame, if any, and start sending the response.
+///
+/// Called from the idle loop in place of the SPI grant servicing. Frames are
+/// only handled once the previous response has been sent.
+pub fn idle_step() {
+    if !RX_READY.load(Ordering::Acquire) || (TX_LEN.load(Ordering::Relaxed) != 0) {
+        return;
+    }
+
+    let rx = unsafe { &mut *BUFS.rx.get() };
+    let rx_len = RX_LEN.load(Ordering::Relaxed) as usize;
+
+    let resp = unsafe { &mut *BUFS.resp.get() };
+    let resp_len = match cobs::decode_in_place(&mut rx[..rx_len]) {
+        Some(len) if len != 0 => handle_frame(&rx[..len], resp),
+        _ => None,
+    };
+
+    RX_LEN.store(0, Ordering::Relaxed);
+    RX_READY.store(false, Ordering::Release);
+
+    let resp_len = match resp_len {
+        Some(len) => len,
+        None => {
+            regs::increment(regs::SPI_FRE_COUNT);
+            irq::raise(regs::IRQ_SPI_ERROR);
+            return;
+        }
+    };
+
+    let tx = unsafe { &mut *BUFS.tx.get() };
+    let enc_len = cobs::encode(&resp[..resp_len], tx);
+    tx[enc_len] = 0;
+
+    TX_POS.store(0, Ordering::Relaxed);
+    TX_LEN.store((enc_len + 1) as u16, Ordering::Relaxed);
+
+    let usart2 = unsafe { &*USART2::PTR };
+    usart2.cr1.modify(|_r, w| w.txeie().enabled());
+}
+
+/// Handle one decoded frame, returning the length of the response written
+/// to `resp`, or `None` if the frame was malformed.
+///
+/// The frame is the write phase of a `Session` transaction, and the response,
+/// if the command has one, is its read phase.
+fn handle_frame(frame: &[u8], resp: &mut [u8; MAX_DECODED]) -> Option<usize> {
+    let (&cmd, body) = frame.split_first()?;
+    resp[0] = cmd;
+
+    // The data sent in the write phase, and the length of the read phase
+    let (write, read_len) = match Command::parse(cmd)? {
+        Command::RegRead(_) if body.is_empty() => (body, 2),
+        Command::RegWrite(_) if body.len() == 2 => (body, 0),
+        // The count only tells us where to stop reading
+        Command::BurstRead(_) if (body.len() == 1) && (body[0] as usize <= regs::REG_COUNT) => {
+            (&[][..], 2 * body[0] as usize)
+        },
+        Command::BurstWrite(_) if (body.len() & 1) == 0 => (body, 0),
+        Command::LongPacket(0) => return Some(1 + long_pkt(cmd, body, &mut resp[1..])),
+        _ => return None,
+    };
+
+    let sess = unsafe { &mut *SESSION.0.get() };
+    let (modem, mbox) = (&mut ModemRegs, &mut PipeMailbox);
+
+    sess.begin_write(modem, mbox);
+    let _ = sess.write(modem, mbox, cmd);
+    // Refused bytes are past the end of the register map, and already raised
+    let _ = write.iter().all(|&byte| sess.write(modem, mbox, byte));
+
+    if read_len != 0 {
+        sess.begin_read(modem, mbox);
+        for byte in &mut resp[1..][..read_len] {
+            *byte = sess.read(modem, mbox);
+        }
+    }
+    sess.end(modem, mbox);
+
+    Some(1 + read_len)
+}
+
+/// Handle a long packet, as a long write of the outgoing frame (if any), then
+/// a long read. Returns the length of the header and incoming frame written
+/// to `resp`.
+fn long_pkt(cmd: u8, body: &[u8], resp: &mut [u8]) -> usize {
+    let sess = unsafe { &mut *SESSION.0.get() };
+    let (modem, mbox) = (&mut ModemRegs, &mut PipeMailbox);
+
+    if !body.is_empty() {
+        sess.begin_write(modem, mbox);
+        let _ = sess.write(modem, mbox, Command::LongPacket(LONG_WRITE_ONLY).to_byte());
+        // A frame that doesn't fit is refused, and reported when the write ends
+        let _ = body.iter().all(|&byte| sess.write(modem, mbox, byte));
+    }
+
+    sess.begin_write(modem, mbox);
+    let _ = sess.write(modem, mbox, cmd);
+    sess.begin_read(modem, mbox);
+
+    let (hdr, payload) = resp.split_at_mut(HDR_LEN);
+    for byte in hdr.iter_mut() {
+        *byte = sess.read(modem, mbox);
+    }
+    let out_len = <&[u8; HDR_LEN]>::try_from(&*hdr).ok()
+        .and_then(LongHeader::from_bytes)
+        .map_or(0, |hdr| hdr.out_len as usize);
+    for byte in &mut payload[..out_len] {
+        *byte = sess.read(modem, mbox);
+    }
+    sess.end(modem, mbox);
+
+    HDR_LEN + out_len
+}
@@ -0,0 +1,100 @@
+//! RS-485 node transaction watchdog
+//!
+//! Once a node has been addressed, each phase of the transaction is bounded by
+//! a one-shot TIM14 timeout:
+//!
+//! | Phase  | Started                                     | Ends                    | Handled by             |
+//! | :--    | :--                                         | :--                     | :--                    |
+//! | Header | Address match                               | Last header word        | Polled by `idle_start` |
+//! | TX     | Reply (and frame) started                   | TX DMA or TC interrupt  | TIM14 interrupt        |
+//! | RX     | Reply (and frame) sent, or broadcast header | CRC or receiver timeout | TIM14 interrupt        |
+//!
+//! The receiver timeout only covers gaps once the router's frame has started,
+//! so the watchdog is still needed if the router never starts it.
+//!
+//! If the router goes quiet, the transaction is abandoned: the grants are
+//! released (keeping our outgoing frame for the next poll), the USART goes back
+//! to mute mode, `RS485_TIMEOUT_COUNT` is incremented, and `IRQ_RS485_TIMEOUT`
+//! is raised.
+//!
+//! TIM14 counts microseconds, so a phase can last at most 65ms.
+
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use cortex_m::peripheral::NVIC;
+use stm32g0xx_hal::{rcc::{Rcc, Enable, Reset}, pac::{Interrupt, TIM14}};
+
+use super::{regs, rs485, SYSCLK_HZ};
+
+pub const PHASE_NONE: u8 = 0;
+pub const PHASE_HEADER: u8 = 1;
+pub const PHASE_TX: u8 = 2;
+pub const PHASE_RX: u8 = 3;
+
+static PHASE: AtomicU8 = AtomicU8::new(PHASE_NONE);
+
+pub fn setup_watchdog(rcc: &mut Rcc, tim14: TIM14) {
+    TIM14::enable(rcc);
+    TIM14::reset(rcc);
+
+    // 1MHz, one-shot, and only overflows set the update flag
+    tim14.psc.write(|w| unsafe { w.psc().bits((SYSCLK_HZ / 1_000_000 - 1) as u16) });
+    tim14.cr1.modify(|_r, w| {
+        w.opm().set_bit();
+        w.urs().set_bit();
+        w
+    });
+    // Load the prescaler
+    tim14.egr.write(|w| w.ug().set_bit());
+    tim14.sr.write(|w| w.uif().clear_bit());
+
+    regs::write(regs::RS485_TIMEOUT_COUNT, 0);
+    PHASE.store(PHASE_NONE, Ordering::Relaxed);
+}
+
+/// Start (or restart) the watchdog for `phase`, expiring after `us` microseconds
+pub fn arm(phase: u8, us: u32) {
+    let tim14 = unsafe { &*TIM14::PTR };
+    stop(tim14);
+    PHASE.store(phase, Ordering::Relaxed);
+    // The header phase is polled, the others interrupt
+    tim14.dier.write(|w| w.uie().bit(phase != PHASE_HEADER));
+    tim14.arr.write(|w| unsafe { w.arr().bits(us.clamp(1, 0xFFFF) as u16) });
+    tim14.cnt.write(|w| unsafe { w.cnt().bits(0) });
+    tim14.cr1.modify(|_r, w| w.cen().set_bit());
+}
+
+/// Stop the watchdog, the phase completed in time
+pub fn disarm() {
+    let tim14 = unsafe { &*TIM14::PTR };
+    stop(tim14);
+    PHASE.store(PHASE_NONE, Ordering::Relaxed);
+}
+
+/// Has the current phase run out of time? For phases that busy-wait.
+#[inline]
+pub fn expired() -> bool {
+    let tim14 = unsafe { &*TIM14::PTR };
+    tim14.sr.read().uif().bit_is_set()
+}
+
+fn stop(tim14: &stm32g0xx_hal::pac::tim14::RegisterBlock) {
+    tim14.cr1.modify(|_r, w| w.cen().clear_bit());
+    tim14.sr.write(|w| w.uif().clear_bit());
+    NVIC::unpend(Interrupt::TIM14);
+}
+
+pub fn watchdog_isr() {
+    let tim14 = unsafe { &*TIM14::PTR };
+    if tim14.sr.read().uif().bit_is_clear() {
+        return;
+    }
+
+    let phase = PHASE.load(Ordering::Relaxed);
+    disarm();
+
+    if (phase == PHASE_TX) || (phase == PHASE_RX) {
+        defmt::println!("RS485 watchdog: phase {=u8} timed out", phase);
+        rs485::abort_transaction();
+    }
+}
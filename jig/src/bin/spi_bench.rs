@@ -0,0 +1,179 @@
+#![no_main]
+#![no_std]
+
+use cortex_m::singleton;
+use groundhog::RollingTimer;
+use jig::{self as _, GlobalRollingTimer}; // global logger + panicking-behavior + memory layout
+use nrf52840_hal::{self, Clocks, clocks::{ExternalOscillator, Internal, LfOscStopped}, spim::MODE_0, Spim, gpio::{Level, Output, Pin, PushPull}, pac::SPIM2};
+use nrf52840_hal::gpio::p1::Parts as P1Parts;
+use nrf52840_hal::spim::Pins as SpimPins;
+use nrf52840_hal::spim::Frequency as SpimFreq;
+
+// Long packet throughput, with byte-wise and packed DMA on the modem.
+//
+// The modem only accepts data while its RS-485 queue has room, so this
+// should be run with a router draining the bus. Transfers where the modem
+// reports no capacity are skipped, rather than counted as failures.
+
+// const MODE_LONG_PKT_READWRITE: u8 = 0b001_00000;
+// const MODE_BURST_REG_READ: u8 = 0b010_00000;
+// const MODE_SHORT_REG_WRITE: u8 = 0b100_00000;
+const REG_LAST_XFER: u8 = 0x06;
+const REG_SPI_CFG: u8 = 0x0B;
+const SPI_CFG_PACKED: u16 = 0x0008;
+const SPI_CFG_SAVE: u16 = 0x8000;
+
+const HEADER_LEN: usize = 7;
+const PAYLOAD_LEN: usize = 255;
+const ROUNDS: usize = 16;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::println!("Hello, world!");
+
+    let _ = imain();
+
+    jig::exit()
+}
+
+fn reg_write(spi: &mut Spim<SPIM2>, csn: &mut Pin<Output<PushPull>>, idx: u8, val: u16) -> Option<()> {
+    let timer = GlobalRollingTimer::new();
+    let start = timer.get_ticks();
+    let [a, b] = val.to_le_bytes();
+    let mut buf = [0b100_00000 | idx, a, b];
+
+    while timer.micros_since(start) < 100 { }
+
+    spi.transfer(csn, &mut buf).ok()
+}
+
+/// Returns (flags, accepted, delivered) for the last long packet
+fn last_xfer(spi: &mut Spim<SPIM2>, csn: &mut Pin<Output<PushPull>>) -> Option<(u16, u16, u16)> {
+    let timer = GlobalRollingTimer::new();
+    let start = timer.get_ticks();
+    let mut buf = [0u8; 7];
+    buf[0] = 0b010_00000 | REG_LAST_XFER;
+
+    while timer.micros_since(start) < 100 { }
+
+    spi.transfer(csn, &mut buf).ok()?;
+    Some((
+        u16::from_le_bytes([buf[1], buf[2]]) & 0x00FF,
+        u16::from_le_bytes([buf[3], buf[4]]),
+        u16::from_le_bytes([buf[5], buf[6]]),
+    ))
+}
+
+fn imain() -> Option<()> {
+    let device = nrf52840_hal::pac::Peripherals::take()?;
+
+    // Setup clocks early in the process. We need this for USB later
+    let clocks = Clocks::new(device.CLOCK);
+    let clocks = clocks.enable_ext_hfosc();
+    let _clocks = singleton!(: Clocks<ExternalOscillator, Internal, LfOscStopped> = clocks)?;
+
+    // Create GPIO ports for pin-mapping
+    let port1 = P1Parts::new(device.P1);
+
+    GlobalRollingTimer::init(device.TIMER0);
+    let timer = GlobalRollingTimer::new();
+
+    // SCLK: PA01
+    //     -> P1.04
+    // MOSI: PA02
+    //     -> P1.03
+    // MISO: PA06
+    //     -> P1.05
+    // CSn:  PB00 (also PB01,02, PA08)
+    //     -> P1.06
+
+    // Set up Spim
+    let mut csn = port1.p1_06.into_push_pull_output(Level::High).degrade();
+    let sck = port1.p1_04.into_push_pull_output(Level::Low).degrade();
+    let mosi = port1.p1_03.into_push_pull_output(Level::Low).degrade();
+    let miso = port1.p1_05.into_floating_input().degrade();
+    let mut spi = Spim::new(
+        device.SPIM2,
+        SpimPins {
+            sck,
+            miso: Some(miso),
+            mosi: Some(mosi),
+        },
+        SpimFreq::M1,
+        MODE_0,
+        0,
+    );
+
+    let freqs = [
+        (1, SpimFreq::M1),
+        (2, SpimFreq::M2),
+        (4, SpimFreq::M4),
+        (8, SpimFreq::M8),
+    ];
+    let mut buf = [0u8; HEADER_LEN + PAYLOAD_LEN];
+
+    for packed in [false, true].iter() {
+        // Switch the modem's DMA mode, then wait for it to save and reset
+        let cfg = if *packed { SPI_CFG_PACKED } else { 0 };
+        reg_write(&mut spi, &mut csn, REG_SPI_CFG, cfg | SPI_CFG_SAVE)?;
+        let start = timer.get_ticks();
+        while timer.millis_since(start) < 250 { }
+
+        for (mhz, freq) in freqs.iter() {
+            let (spim2, pins) = spi.free();
+            spi = Spim::new(spim2, pins, *freq, MODE_0, 0);
+
+            let mut good = 0;
+            let mut bad = 0;
+            let mut skipped = 0;
+            let mut bytes = 0u32;
+            let mut elapsed = 0u32;
+
+            for round in 0..ROUNDS {
+                buf[0] = 0b001_00000;
+                buf[1..HEADER_LEN].iter_mut().for_each(|b| *b = 0);
+                buf[HEADER_LEN..].iter_mut().enumerate().for_each(|(i, b)| {
+                    *b = (i + round) as u8;
+                });
+
+                let start = timer.get_ticks();
+                spi.transfer(&mut csn, &mut buf).ok()?;
+                let took = timer.micros_since(start);
+
+                let rx_cap = u16::from_le_bytes([buf[5], buf[6]]) as usize;
+                if rx_cap < PAYLOAD_LEN {
+                    skipped += 1;
+                    continue;
+                }
+
+                let (flags, accepted, _delivered) = last_xfer(&mut spi, &mut csn)?;
+                if (flags == 0) && (accepted as usize == PAYLOAD_LEN) {
+                    good += 1;
+                    bytes += PAYLOAD_LEN as u32;
+                    elapsed += took;
+                } else {
+                    bad += 1;
+                }
+            }
+
+            let kbps = if elapsed != 0 { (bytes * 1000) / elapsed } else { 0 };
+            defmt::println!(
+                "packed: {}, {}MHz: good {}, bad {}, skipped {}, {} KB/s",
+                packed,
+                mhz,
+                good,
+                bad,
+                skipped,
+                kbps,
+            );
+        }
+
+        let (spim2, pins) = spi.free();
+        spi = Spim::new(spim2, pins, SpimFreq::M1, MODE_0, 0);
+    }
+
+    // Leave the modem in byte-wise mode
+    reg_write(&mut spi, &mut csn, REG_SPI_CFG, SPI_CFG_SAVE)?;
+
+    Some(())
+}
@@ -69,27 +69,68 @@ fn imain() -> Option<()> {
     );
 
 
-    // const MODE_LONG_PKT_READ: u8 = 0b001_00000;
-    // const MODE_LONG_PKT_WRITE: u8 = 0b010_00000;
+    // const MODE_LONG_PKT_READWRITE: u8 = 0b001_00000;
     // const MODE_SHORT_REG_READ: u8 = 0b011_00000;
     // const MODE_SHORT_REG_WRITE: u8 = 0b100_00000;
     // const MODE_INVALID_WAIT: u8 = 0b111_00000;
 
     let mut bufout = [0x44u8; 128];
 
-    // Read
+    // Long packet read/write
+    //
+    // Byte 0 is the command, bytes 1..7 are the header (status, seq,
+    // outgoing len, incoming capacity), the payload follows.
     {
         let start = timer.get_ticks();
 
-        bufout[0] = 0b001_00000; // READ
-        // bufout[0] = 0b010_00000; // WRITE
+        bufout[0] = 0b001_00000;
+        bufout[1..7].copy_from_slice(&[0u8; 6]);
 
         while timer.millis_since(start) < 250 { }
 
         match spi.transfer(&mut csn, &mut bufout) {
             Ok(_) => {
                 defmt::println!("OK");
-                defmt::println!("{:02X}", &bufout);
+                let status = bufout[1];
+                let seq = bufout[2];
+                let tx_len = u16::from_le_bytes([bufout[3], bufout[4]]);
+                let rx_cap = u16::from_le_bytes([bufout[5], bufout[6]]);
+                defmt::println!(
+                    "status: {:02X}, seq: {}, tx_len: {}, rx_cap: {}",
+                    status,
+                    seq,
+                    tx_len,
+                    rx_cap,
+                );
+                defmt::println!("{:02X}", &bufout[7..]);
+            },
+            Err(_) => {
+                defmt::println!("ERR");
+            },
+        }
+    }
+
+    // Last transfer result
+    {
+        let start = timer.get_ticks();
+        let mut burst = [0u8; 7];
+        burst[0] = 0b010_00110;
+
+        while timer.micros_since(start) < 100 { }
+
+        match spi.transfer(&mut csn, &mut burst) {
+            Ok(_) => {
+                defmt::println!("OK");
+                let last = u16::from_le_bytes([burst[1], burst[2]]);
+                let rx_len = u16::from_le_bytes([burst[3], burst[4]]);
+                let tx_len = u16::from_le_bytes([burst[5], burst[6]]);
+                defmt::println!(
+                    "seq: {}, flags: {:02X}, accepted: {}, delivered: {}",
+                    last >> 8,
+                    last & 0xFF,
+                    rx_len,
+                    tx_len,
+                );
             },
             Err(_) => {
                 defmt::println!("ERR");
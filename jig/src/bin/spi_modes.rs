@@ -0,0 +1,148 @@
+#![no_main]
+#![no_std]
+
+use cortex_m::singleton;
+use groundhog::RollingTimer;
+use jig::{self as _, GlobalRollingTimer}; // global logger + panicking-behavior + memory layout
+use nrf52840_hal::{self, Clocks, clocks::{ExternalOscillator, Internal, LfOscStopped}, spim::{Mode, MODE_0, MODE_1, MODE_2, MODE_3}, Spim, gpio::{Level, Output, Pin, PushPull}, pac::SPIM2};
+use nrf52840_hal::gpio::p1::Parts as P1Parts;
+use nrf52840_hal::spim::Pins as SpimPins;
+use nrf52840_hal::spim::Frequency as SpimFreq;
+
+// const MODE_SHORT_REG_READ: u8 = 0b011_00000;
+// const MODE_SHORT_REG_WRITE: u8 = 0b100_00000;
+const REG_SPI_CFG: u8 = 0x0B;
+// Read back in every mode. Any RW register keeping all 16 bits will do, the
+// router poll interval has no effect on a node.
+const REG_ROUTER_INTERVAL: u8 = 0x10;
+const SPI_CFG_LSBFIRST: u16 = 0x0004;
+const SPI_CFG_SAVE: u16 = 0x8000;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    defmt::println!("Hello, world!");
+
+    if let Some(_) = imain() {
+        defmt::println!("PASS");
+    } else {
+        defmt::println!("FAIL");
+    }
+
+    jig::exit()
+}
+
+fn reg_read(spi: &mut Spim<SPIM2>, csn: &mut Pin<Output<PushPull>>, idx: u8) -> Option<u16> {
+    let timer = GlobalRollingTimer::new();
+    let start = timer.get_ticks();
+    let mut buf = [0b011_00000 | idx, 0x00, 0x00, 0x00];
+
+    while timer.micros_since(start) < 100 { }
+
+    spi.transfer(csn, &mut buf).ok()?;
+    Some(u16::from_le_bytes([buf[1], buf[2]]))
+}
+
+fn reg_write(spi: &mut Spim<SPIM2>, csn: &mut Pin<Output<PushPull>>, idx: u8, val: u16) -> Option<()> {
+    let timer = GlobalRollingTimer::new();
+    let start = timer.get_ticks();
+    let [a, b] = val.to_le_bytes();
+    let mut buf = [0b100_00000 | idx, a, b];
+
+    while timer.micros_since(start) < 100 { }
+
+    spi.transfer(csn, &mut buf).ok()
+}
+
+fn imain() -> Option<()> {
+    let device = nrf52840_hal::pac::Peripherals::take()?;
+
+    // Setup clocks early in the process. We need this for USB later
+    let clocks = Clocks::new(device.CLOCK);
+    let clocks = clocks.enable_ext_hfosc();
+    let _clocks = singleton!(: Clocks<ExternalOscillator, Internal, LfOscStopped> = clocks)?;
+
+    // Create GPIO ports for pin-mapping
+    let port1 = P1Parts::new(device.P1);
+
+    GlobalRollingTimer::init(device.TIMER0);
+    let timer = GlobalRollingTimer::new();
+
+    // SCLK: PA01
+    //     -> P1.04
+    // MOSI: PA02
+    //     -> P1.03
+    // MISO: PA06
+    //     -> P1.05
+    // CSn:  PB00 (also PB01,02, PA08)
+    //     -> P1.06
+
+    // Set up Spim, starting in the modem's default mode
+    let mut csn = port1.p1_06.into_push_pull_output(Level::High).degrade();
+    let sck = port1.p1_04.into_push_pull_output(Level::Low).degrade();
+    let mosi = port1.p1_03.into_push_pull_output(Level::Low).degrade();
+    let miso = port1.p1_05.into_floating_input().degrade();
+    let mut spi = Spim::new(
+        device.SPIM2,
+        SpimPins {
+            sck,
+            miso: Some(miso),
+            mosi: Some(mosi),
+        },
+        SpimFreq::M1,
+        MODE_0,
+        0,
+    );
+
+    // Step through every mode, and LSB first, then back to mode 0 so the
+    // modem is left in its default state.
+    let modes: [(u16, Mode); 5] = [
+        (1, MODE_1),
+        (2, MODE_2),
+        (3, MODE_3),
+        (SPI_CFG_LSBFIRST, MODE_0),
+        (0, MODE_0),
+    ];
+    let mut pass = true;
+
+    for (cfg, mode) in modes.iter() {
+        // Ask the modem to switch modes, then wait for it to save and reset
+        reg_write(&mut spi, &mut csn, REG_SPI_CFG, *cfg | SPI_CFG_SAVE)?;
+        let start = timer.get_ticks();
+        while timer.millis_since(start) < 250 { }
+
+        // Follow it into the new mode
+        let (spim2, pins) = spi.free();
+        spi = Spim::new(spim2, pins, SpimFreq::M1, *mode, 0);
+        let lsb_first = (*cfg & SPI_CFG_LSBFIRST) != 0;
+        // The HAL always sets up MSB first
+        unsafe { &*SPIM2::ptr() }.config.modify(|_r, w| {
+            if lsb_first {
+                w.order().lsb_first()
+            } else {
+                w.order().msb_first()
+            }
+        });
+
+        let got_cfg = reg_read(&mut spi, &mut csn, REG_SPI_CFG)?;
+        let pattern = 0xA500 | *cfg;
+        reg_write(&mut spi, &mut csn, REG_ROUTER_INTERVAL, pattern)?;
+        let got_pattern = reg_read(&mut spi, &mut csn, REG_ROUTER_INTERVAL)?;
+
+        let ok = (got_cfg == *cfg) && (got_pattern == pattern);
+        defmt::println!(
+            "mode {}{}: cfg {:04X}, interval {:04X} - {}",
+            *cfg & 0b11,
+            if lsb_first { ", LSB first" } else { "" },
+            got_cfg,
+            got_pattern,
+            if ok { "ok" } else { "BAD" },
+        );
+        pass &= ok;
+    }
+
+    if pass {
+        Some(())
+    } else {
+        None
+    }
+}
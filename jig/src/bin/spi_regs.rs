@@ -69,10 +69,11 @@ fn imain() -> Option<()> {
     );
 
 
-    // const MODE_LONG_PKT_READ: u8 = 0b001_00000;
-    // const MODE_LONG_PKT_WRITE: u8 = 0b010_00000;
+    // const MODE_LONG_PKT_READWRITE: u8 = 0b001_00000;
+    // const MODE_BURST_REG_READ: u8 = 0b010_00000;
     // const MODE_SHORT_REG_READ: u8 = 0b011_00000;
     // const MODE_SHORT_REG_WRITE: u8 = 0b100_00000;
+    // const MODE_BURST_REG_WRITE: u8 = 0b101_00000;
     // const MODE_INVALID_WAIT: u8 = 0b111_00000;
 
     let mut bufout = [0u8; 4];
@@ -148,5 +149,52 @@ fn imain() -> Option<()> {
         }
     }
 
+    // Burst Write, registers 0x0C..=0x0E. These are router poll list entries,
+    // with the valid bit clear, so a router skips them.
+    {
+        let start = timer.get_ticks();
+        let mut burst = [0u8; 7];
+
+        burst.copy_from_slice(&[
+            0b101_01100,
+            0x11, 0x11,
+            0x22, 0x22,
+            0x33, 0x33,
+        ]);
+
+        while timer.micros_since(start) < 100 { }
+
+        match spi.transfer(&mut csn, &mut burst) {
+            Ok(_) => {
+                defmt::println!("OK");
+                defmt::println!("{:02X}", &burst);
+            },
+            Err(_) => {
+                defmt::println!("ERR");
+            },
+        }
+    }
+
+    // Burst Read, registers 0x0C..=0x0F, which should read back the above
+    {
+        let start = timer.get_ticks();
+        let mut burst = [0u8; 9];
+        burst[0] = 0b010_01100;
+
+        while timer.micros_since(start) < 100 { }
+
+        match spi.transfer(&mut csn, &mut burst) {
+            Ok(_) => {
+                defmt::println!("OK");
+                defmt::println!("{:02X}", &burst);
+            },
+            Err(_) => {
+                defmt::println!("ERR");
+            },
+        }
+
+        defmt::assert_eq!(burst[1..7], [0x11, 0x11, 0x22, 0x22, 0x33, 0x33]);
+    }
+
     Some(())
 }